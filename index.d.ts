@@ -1,8 +1,46 @@
 /* auto-generated by NAPI-RS */
 /* eslint-disable */
+/**
+ * Apply a chain of patch files in sequence, keeping intermediate versions in
+ * memory (async).
+ */
+export declare function applyChain(oldStr: string, patches: Array<string>, newStr: string, options?: PatchOptionsJs | undefined | null, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<PerformanceStatsJs>
+
+/**
+ * Apply a chain of patch files in sequence, keeping intermediate versions in
+ * memory (sync).
+ */
+export declare function applyChainSync(oldStr: string, patches: Array<string>, newStr: string, options?: PatchOptionsJs | undefined | null): PerformanceStatsJs
+
+/** Compression of a patch block exposed to JavaScript. */
+export declare enum BlockCompression {
+  Bzip2 = 'bzip2',
+  Gzip = 'gzip',
+  Zstd = 'zstd',
+  Xz = 'xz',
+  Brotli = 'brotli',
+  /** The block is stored uncompressed, or is empty. */
+  None = 'none',
+  Unknown = 'unknown'
+}
+
 /** 检查文件访问权限 */
 export declare function checkFileAccessSync(filePath: string): void
 
+/**
+ * Compose a chain of patch files into one patch from the base file to the
+ * last version (async). The chain is applied within the limits in
+ * `patchOptions`.
+ */
+export declare function composePatches(baseStr: string, patches: Array<string>, patch: string, options?: DiffOptionsJs | undefined | null, patchOptions?: PatchOptionsJs | undefined | null, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<PerformanceStatsJs>
+
+/**
+ * Compose a chain of patch files into one patch from the base file to the
+ * last version (sync). The chain is applied within the limits in
+ * `patchOptions`.
+ */
+export declare function composePatchesSync(baseStr: string, patches: Array<string>, patch: string, options?: DiffOptionsJs | undefined | null, patchOptions?: PatchOptionsJs | undefined | null): PerformanceStatsJs
+
 /** JavaScript 压缩比信息结构 */
 export interface CompressionRatioJs {
   oldSize: number
@@ -11,20 +49,90 @@ export interface CompressionRatioJs {
   ratio: number
 }
 
-export declare function diff(oldStr: string, newStr: string, patch: string): Promise<void>
+export declare function diff(oldStr: string, newStr: string, patch: string, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<void>
+
+/** Generate a patch from in-memory buffers (async). */
+export declare function diffBuffers(oldBuf: Buffer, newBuf: Buffer, options?: DiffOptionsJs | undefined | null, signal?: AbortSignal | undefined | null): Promise<Buffer>
+
+/** Generate a patch from in-memory buffers (sync). */
+export declare function diffBuffersSync(oldBuf: Buffer, newBuf: Buffer, options?: DiffOptionsJs | undefined | null): Buffer
+
+/** Diff two directory trees into a single patch file (async). */
+export declare function diffDirectory(oldDir: string, newDir: string, patch: string, options?: DiffOptionsJs | undefined | null, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<PerformanceStatsJs>
+
+/** Diff two directory trees into a single patch file (sync). */
+export declare function diffDirectorySync(oldDir: string, newDir: string, patch: string, options?: DiffOptionsJs | undefined | null): PerformanceStatsJs
 
 /** JavaScript Diff 配置选项 */
 export interface DiffOptionsJs {
-  /** 压缩级别 (0-9, 默认 6) */
+  /** 压缩级别 (1-9, 默认 6) */
   compressionLevel?: number
+  /**
+   * Compression of the patch blocks (default bzip2). Anything else produces
+   * a variant patch, which only this library can apply.
+   */
+  compression?: PatchCompression
+  /**
+   * Stream format of the patch (default bsdiff40). The endsley format is
+   * always compressed with bzip2 and vcdiff is not compressed, so neither
+   * can be combined with `compression`.
+   */
+  format?: OutputFormat
   /** 是否启用并行处理（默认 true） */
   enableParallel?: boolean
+  /**
+   * Wrap the patch in a container recording the SHA-256 and size of the old
+   * and new files, so patching rejects the wrong base file (default false).
+   */
+  container?: boolean
+  /**
+   * Diff the new file in windows of this many bytes, each against a matching
+   * region of the old file. Required for inputs larger than about 2 GiB.
+   */
+  windowSize?: number
+  /** Memory-map the old and new files instead of reading them (default false). */
+  mmap?: boolean
+  /**
+   * Exact matches shorter than this many bytes are encoded as diff data
+   * instead; zero keeps every match (default 12).
+   */
+  smallMatch?: number
+  /**
+   * Size of the buffer used to compute delta data, at least 128 bytes
+   * (default 4096).
+   */
+  bufferSize?: number
+  /**
+   * Search the new file in parallel chunks of this many bytes, at least
+   * 256 KiB. Requires `enableParallel`.
+   */
+  parallelChunkSize?: number
+  /**
+   * Search the new file in at most this many parallel jobs. Requires
+   * `enableParallel`; cannot be combined with `parallelChunkSize`.
+   */
+  parallelJobs?: number
+  /**
+   * Worker threads for this diff's own thread pool, capped by
+   * `setMaxThreads` (default: that cap, or one per core).
+   */
+  threads?: number
+  /**
+   * Diff the entries of ZIP-based inputs (zip, jar, apk, docx) uncompressed,
+   * deflating them again when patching (default false).
+   */
+  archive?: boolean
+  /**
+   * Convert relative branch targets in executable code before diffing, and
+   * back when patching (default none). Cannot be combined with `archive`.
+   */
+  filter?: ExecutableFilter
 }
 
 export declare function diffSync(oldStr: string, newStr: string, patch: string): void
 
 /** 生成补丁文件，支持自定义选项（异步） */
-export declare function diffWithOptions(oldStr: string, newStr: string, patch: string, options: DiffOptionsJs): Promise<void>
+export declare function diffWithOptions(oldStr: string, newStr: string, patch: string, options: DiffOptionsJs, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<void>
 
 /** 生成补丁文件，支持自定义选项并返回性能统计（同步） */
 export declare function diffWithOptionsAndStatsSync(oldStr: string, newStr: string, patch: string, options: DiffOptionsJs): PerformanceStatsJs
@@ -32,12 +140,99 @@ export declare function diffWithOptionsAndStatsSync(oldStr: string, newStr: stri
 /** 生成补丁文件，支持自定义选项（同步） */
 export declare function diffWithOptionsSync(oldStr: string, newStr: string, patch: string, options: DiffOptionsJs): void
 
+/**
+ * Generate a patch file and the reverse (new to old) patch for rolling
+ * back, and return performance statistics (async).
+ */
+export declare function diffWithReverse(oldStr: string, newStr: string, patch: string, reversePatch: string, options?: DiffOptionsJs | undefined | null, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<PerformanceStatsJs>
+
+/**
+ * Generate a patch file and the reverse (new to old) patch for rolling
+ * back, and return performance statistics (sync).
+ */
+export declare function diffWithReverseSync(oldStr: string, newStr: string, patch: string, reversePatch: string, options?: DiffOptionsJs | undefined | null): PerformanceStatsJs
+
 /** 生成补丁文件并返回性能统计（异步） */
-export declare function diffWithStats(oldStr: string, newStr: string, patch: string): Promise<PerformanceStatsJs>
+export declare function diffWithStats(oldStr: string, newStr: string, patch: string, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<PerformanceStatsJs>
 
 /** 生成补丁文件并返回性能统计（同步） */
 export declare function diffWithStatsSync(oldStr: string, newStr: string, patch: string): PerformanceStatsJs
 
+/** Digest algorithm exposed to JavaScript. */
+export declare enum DigestAlgorithm {
+  Sha256 = 'sha256',
+  Blake3 = 'blake3'
+}
+
+/** Result of verifying patch output against an expected digest, exposed to JavaScript. */
+export interface DigestVerificationJs {
+  /** Whether both the digest and the size matched. */
+  valid: boolean
+  /** Whether the output digest equals the expected digest. */
+  hashMatched: boolean
+  /** Whether the output size equals the expected size. */
+  sizeMatched: boolean
+  /** Number of bytes the patch produced. */
+  bytesProduced: number
+  /** Lowercase hex digest of the output. */
+  actualDigest: string
+}
+
+/** Stable error codes exposed to JavaScript as `err.code`. */
+export declare enum ErrorCode {
+  /** An input file does not exist. */
+  NotFound = 'ERR_NOT_FOUND',
+  /** An input exceeds a size limit. */
+  TooLarge = 'ERR_TOO_LARGE',
+  /** The patch is malformed or was not made for this old file. */
+  CorruptPatch = 'ERR_CORRUPT_PATCH',
+  /** Reading or writing failed. */
+  IoError = 'ERR_IO',
+  /** The operation was cancelled through its `AbortSignal`. */
+  Cancelled = 'ERR_CANCELLED',
+  /** Data does not match its expected digest. */
+  ChecksumMismatch = 'ERR_CHECKSUM_MISMATCH',
+  /** A patch needs more than a `PatchOptionsJs` limit allows. */
+  LimitExceeded = 'ERR_LIMIT_EXCEEDED',
+  /** A patch produces a different size than `expectedTargetSize`. */
+  SizeMismatch = 'ERR_SIZE_MISMATCH',
+  /** An option is out of range. */
+  InvalidOption = 'ERR_INVALID_OPTION'
+}
+
+/** Properties set on errors thrown or rejected by this module. */
+export interface ErrorDetailsJs {
+  code: ErrorCode
+  /** File the error refers to. */
+  path?: string
+  /** Actual size in bytes (`ERR_TOO_LARGE`, `ERR_LIMIT_EXCEEDED`, `ERR_SIZE_MISMATCH`). */
+  size?: number
+  /** Size limit in bytes (`ERR_TOO_LARGE`, `ERR_LIMIT_EXCEEDED`). */
+  limit?: number
+  /** Expected digest (`ERR_CHECKSUM_MISMATCH`). */
+  expected?: string
+  /** Actual digest (`ERR_CHECKSUM_MISMATCH`). */
+  actual?: string
+  /** Option whose limit was exceeded, `maxTargetSize` or `maxMemory` (`ERR_LIMIT_EXCEEDED`). */
+  limitName?: string
+  /** Expected size in bytes (`ERR_SIZE_MISMATCH`). */
+  expectedSize?: number
+  /** Name of the rejected option (`ERR_INVALID_OPTION`). */
+  option?: string
+}
+
+/** Conversion of branch targets in executable code, exposed to JavaScript. */
+export declare enum ExecutableFilter {
+  /** Diff the inputs as they are. */
+  None = 'none',
+  /** x86 or ARM64, as read from the ELF, PE or Mach-O headers of both files. */
+  Auto = 'auto',
+  /** x86 and x86-64 CALL and JMP targets. */
+  X86 = 'x86',
+  /** ARM64 BL and ADRP targets. */
+  Arm64 = 'arm64'
+}
+
 /** 获取压缩比信息 */
 export declare function getCompressionRatioSync(oldStr: string, newStr: string, patch: string): CompressionRatioJs
 
@@ -47,18 +242,154 @@ export declare function getFileSizeSync(filePath: string): number
 /** 获取补丁文件信息 */
 export declare function getPatchInfoSync(patch: string): PatchInfoJs
 
-export declare function patch(oldStr: string, newStr: string, patch: string): Promise<void>
+/** Stream format of a generated patch, exposed to JavaScript. */
+export declare enum OutputFormat {
+  /** Classic BSDIFF40. */
+  Bsdiff40 = 'bsdiff40',
+  /** ENDSLEY/BSDIFF43, as written by mendsley/bsdiff. */
+  Endsley = 'endsley',
+  /** VCDIFF (RFC 3284), as read by xdelta3 and open-vcdiff. */
+  Vcdiff = 'vcdiff'
+}
+
+export declare function patch(oldStr: string, newStr: string, patch: string, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<void>
+
+/** A block of a patch file exposed to JavaScript. */
+export interface PatchBlockJs {
+  /**
+   * `control`, `diff` or `extra` for BSDIFF40 and each window of a windowed
+   * patch, `body` for ENDSLEY/BSDIFF43, `file` for each changed or added
+   * file of a directory patch.
+   */
+  name: string
+  /** Offset of the block within the patch file. */
+  offset: number
+  /** Length of the block in bytes. */
+  length: number
+  compression: BlockCompression
+}
+
+/** Apply a patch to an in-memory buffer (async). */
+export declare function patchBuffers(oldBuf: Buffer, patchBuf: Buffer, options?: PatchOptionsJs | undefined | null, signal?: AbortSignal | undefined | null): Promise<Buffer>
+
+/** Apply a patch to an in-memory buffer (sync). */
+export declare function patchBuffersSync(oldBuf: Buffer, patchBuf: Buffer, options?: PatchOptionsJs | undefined | null): Buffer
+
+/** Compression of the blocks of a generated patch, exposed to JavaScript. */
+export declare enum PatchCompression {
+  /** bzip2, written as standard BSDIFF40. */
+  Bzip2 = 'bzip2',
+  Zstd = 'zstd',
+  Xz = 'xz',
+  Brotli = 'brotli',
+  /** Uncompressed blocks, for patches that are compressed in transit. */
+  None = 'none'
+}
+
+/** Container header of a checksummed patch exposed to JavaScript. */
+export interface PatchContainerJs {
+  oldSize: number
+  newSize: number
+  /** Hex SHA-256 of the old file. */
+  oldSha256: string
+  /** Hex SHA-256 of the new file. */
+  newSha256: string
+}
+
+/**
+ * Rebuild the new directory tree of a directory patch into `out_dir`
+ * (async).
+ */
+export declare function patchDirectory(oldDir: string, outDir: string, patch: string, options?: PatchOptionsJs | undefined | null, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<PerformanceStatsJs>
+
+/**
+ * Rebuild the new directory tree of a directory patch into `out_dir`
+ * (sync).
+ */
+export declare function patchDirectorySync(oldDir: string, outDir: string, patch: string, options?: PatchOptionsJs | undefined | null): PerformanceStatsJs
+
+/** Patch format exposed to JavaScript. */
+export declare enum PatchFormat {
+  /** Classic BSDIFF40. */
+  Bsdiff40 = 'bsdiff40',
+  /** BSDIFF40 wrapped in this library's checksummed container. */
+  Container = 'container',
+  /** Windowed sub-patches, as written with `windowSize`. */
+  Windowed = 'windowed',
+  /**
+   * BSDIFF40 blocks compressed with zstd, xz, brotli or not at all, as
+   * written with `compression`.
+   */
+  Variant = 'variant',
+  /** ENDSLEY/BSDIFF43, as written by mendsley/bsdiff. */
+  Endsley = 'endsley',
+  /** VCDIFF (RFC 3284), as written by xdelta3 and open-vcdiff. */
+  Vcdiff = 'vcdiff',
+  /** ZIP entries diffed uncompressed, as written with `archive`. */
+  Archive = 'archive',
+  /** Executable code with converted branch targets, as written with `filter`. */
+  Filtered = 'filtered',
+  /** A directory tree patch, as written by `diffDirectory`. */
+  Directory = 'directory',
+  /** A gzip-compressed file; see `innerFormat`. */
+  Gzip = 'gzip',
+  Unknown = 'unknown'
+}
 
 /** JavaScript 补丁信息结构 */
 export interface PatchInfoJs {
   size: number
   compressed: boolean
+  format: PatchFormat
+  /** Size of the output declared by the header. */
+  targetSize?: number
+  blocks: Array<PatchBlockJs>
+  container?: PatchContainerJs
+  /** Format of the decompressed data, for gzip-wrapped patches. */
+  innerFormat?: PatchFormat
+  /** Inconsistencies found in the header. Empty for a well-formed patch. */
+  issues: Array<string>
 }
 
+/** Limits for applying untrusted patches, exposed to JavaScript. */
+export interface PatchOptionsJs {
+  /** Largest output the patch may produce, in bytes. */
+  maxTargetSize?: number
+  /** Largest estimated peak memory (inputs plus output buffers), in bytes. */
+  maxMemory?: number
+  /** Exact output size the patch must produce, in bytes. */
+  expectedTargetSize?: number
+  /**
+   * Memory-map the old and patch files instead of reading them (default
+   * false). Mapped files still count towards `maxMemory`.
+   */
+  mmap?: boolean
+}
+
+/** Apply a patch file, streaming the output to disk in bounded chunks (async). */
+export declare function patchStreaming(oldStr: string, newStr: string, patch: string, options?: PatchOptionsJs | undefined | null, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<PerformanceStatsJs>
+
+/** Apply a patch file, streaming the output to disk in bounded chunks (sync). */
+export declare function patchStreamingSync(oldStr: string, newStr: string, patch: string, options?: PatchOptionsJs | undefined | null): PerformanceStatsJs
+
 export declare function patchSync(oldStr: string, newStr: string, patch: string): void
 
+/**
+ * Apply a patch file, writing the output to a Node.js `Writable` in bounded chunks.
+ *
+ * Each chunk waits for its `write` callback before the next is produced. The
+ * stream is not ended; call `writable.end()` once the promise resolves.
+ */
+export declare function patchToWritable(oldStr: string, patch: string, writable: import('stream').Writable, options?: PatchOptionsJs | undefined | null, signal?: AbortSignal | undefined | null): Promise<PerformanceStatsJs>
+
+/** Apply a patch file within the given limits (async). */
+export declare function patchWithOptions(oldStr: string, newStr: string, patch: string, options: PatchOptionsJs, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<void>
+
+/** Apply a patch file within the given limits (sync). */
+export declare function patchWithOptionsSync(oldStr: string, newStr: string, patch: string, options: PatchOptionsJs): void
+
 /** 应用补丁文件并返回性能统计（异步） */
-export declare function patchWithStats(oldStr: string, newStr: string, patch: string): Promise<PerformanceStatsJs>
+export declare function patchWithStats(oldStr: string, newStr: string, patch: string, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<PerformanceStatsJs>
 
 /** 应用补丁文件并返回性能统计（同步） */
 export declare function patchWithStatsSync(oldStr: string, newStr: string, patch: string): PerformanceStatsJs
@@ -75,9 +406,83 @@ export interface PerformanceStatsJs {
   patchSize: number
   /** 压缩比（百分比） */
   compressionRatio: number
+  /**
+   * Peak memory held by the operation's buffers in bytes, the sum of
+   * `heapMemory` and `mappedMemory`. It is worked out from the sizes of the
+   * buffers each step holds, not measured.
+   */
+  estimatedPeakMemory: number
+  /** Part of `estimatedPeakMemory` allocated on the heap. */
+  heapMemory: number
+  /** Part of `estimatedPeakMemory` taken by memory-mapped input files. */
+  mappedMemory: number
+}
+
+/** Progress update exposed to JavaScript. */
+export interface ProgressJs {
+  phase: ProgressPhase
+  /** Bytes processed so far in this phase. */
+  bytesProcessed: number
+  /** Total bytes expected in this phase. */
+  totalBytes: number
+}
+
+/** Operation phase exposed to JavaScript. */
+export declare enum ProgressPhase {
+  /** Reading input files. */
+  Reading = 'reading',
+  /**
+   * Suffix sorting, matching and bzip2 compression, which qbsdiff runs as
+   * one step reported only when it starts and finishes.
+   */
+  Diffing = 'diffing',
+  /** Converting the patch to another format or compression. */
+  Encoding = 'encoding',
+  /** Applying the patch to the old data. */
+  Patching = 'patching',
+  /** Writing the output file. */
+  Writing = 'writing'
 }
 
-export declare function verifyPatch(oldStr: string, newStr: string, patch: string): Promise<boolean>
+/**
+ * Derive the reverse (new to old) patch of a patch file from the old file,
+ * and write it to `reverse` (async). The forward patch is applied within the
+ * limits in `patchOptions`.
+ */
+export declare function reversePatch(oldStr: string, patch: string, reverse: string, options?: DiffOptionsJs | undefined | null, patchOptions?: PatchOptionsJs | undefined | null, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<PerformanceStatsJs>
+
+/**
+ * Derive the reverse (new to old) patch of a patch file from the old file,
+ * and write it to `reverse` (sync). The forward patch is applied within the
+ * limits in `patchOptions`.
+ */
+export declare function reversePatchSync(oldStr: string, patch: string, reverse: string, options?: DiffOptionsJs | undefined | null, patchOptions?: PatchOptionsJs | undefined | null): PerformanceStatsJs
+
+/**
+ * Limit how many diffs run their search at the same time; further diffs wait
+ * for a running one to finish. Zero removes the limit.
+ */
+export declare function setMaxConcurrentDiffs(limit: number): void
+
+/**
+ * Cap the worker threads of every parallel diff, including those that set
+ * `threads`. Zero removes the cap.
+ */
+export declare function setMaxThreads(threads: number): void
+
+export declare function verifyPatch(oldStr: string, newStr: string, patch: string, signal?: AbortSignal | undefined | null): Promise<boolean>
+
+/**
+ * Verify that a patch produces output with the expected digest and size,
+ * without writing it to disk (async).
+ */
+export declare function verifyPatchDigest(oldStr: string, patch: string, expectedDigest: string, expectedSize: number, algorithm?: DigestAlgorithm | undefined | null, onProgress?: ((arg: ProgressJs) => void) | undefined | null, signal?: AbortSignal | undefined | null): Promise<DigestVerificationJs>
+
+/**
+ * Verify that a patch produces output with the expected digest and size,
+ * without writing it to disk (sync).
+ */
+export declare function verifyPatchDigestSync(oldStr: string, patch: string, expectedDigest: string, expectedSize: number, algorithm?: DigestAlgorithm | undefined | null): DigestVerificationJs
 
 /** 验证补丁文件完整性 */
 export declare function verifyPatchSync(oldStr: string, newStr: string, patch: string): boolean
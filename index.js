@@ -572,20 +572,53 @@ if (!nativeBinding) {
 }
 
 module.exports = nativeBinding
+module.exports.applyChain = nativeBinding.applyChain
+module.exports.applyChainSync = nativeBinding.applyChainSync
+module.exports.BlockCompression = nativeBinding.BlockCompression
 module.exports.checkFileAccessSync = nativeBinding.checkFileAccessSync
+module.exports.composePatches = nativeBinding.composePatches
+module.exports.composePatchesSync = nativeBinding.composePatchesSync
 module.exports.diff = nativeBinding.diff
+module.exports.diffBuffers = nativeBinding.diffBuffers
+module.exports.diffBuffersSync = nativeBinding.diffBuffersSync
+module.exports.diffDirectory = nativeBinding.diffDirectory
+module.exports.diffDirectorySync = nativeBinding.diffDirectorySync
 module.exports.diffSync = nativeBinding.diffSync
 module.exports.diffWithOptions = nativeBinding.diffWithOptions
 module.exports.diffWithOptionsAndStatsSync = nativeBinding.diffWithOptionsAndStatsSync
 module.exports.diffWithOptionsSync = nativeBinding.diffWithOptionsSync
+module.exports.diffWithReverse = nativeBinding.diffWithReverse
+module.exports.diffWithReverseSync = nativeBinding.diffWithReverseSync
 module.exports.diffWithStats = nativeBinding.diffWithStats
 module.exports.diffWithStatsSync = nativeBinding.diffWithStatsSync
+module.exports.DigestAlgorithm = nativeBinding.DigestAlgorithm
+module.exports.ErrorCode = nativeBinding.ErrorCode
+module.exports.ExecutableFilter = nativeBinding.ExecutableFilter
 module.exports.getCompressionRatioSync = nativeBinding.getCompressionRatioSync
 module.exports.getFileSizeSync = nativeBinding.getFileSizeSync
 module.exports.getPatchInfoSync = nativeBinding.getPatchInfoSync
+module.exports.OutputFormat = nativeBinding.OutputFormat
 module.exports.patch = nativeBinding.patch
+module.exports.patchBuffers = nativeBinding.patchBuffers
+module.exports.patchBuffersSync = nativeBinding.patchBuffersSync
+module.exports.PatchCompression = nativeBinding.PatchCompression
+module.exports.patchDirectory = nativeBinding.patchDirectory
+module.exports.patchDirectorySync = nativeBinding.patchDirectorySync
+module.exports.PatchFormat = nativeBinding.PatchFormat
+module.exports.patchStreaming = nativeBinding.patchStreaming
+module.exports.patchStreamingSync = nativeBinding.patchStreamingSync
 module.exports.patchSync = nativeBinding.patchSync
+module.exports.patchToWritable = nativeBinding.patchToWritable
+module.exports.patchWithOptions = nativeBinding.patchWithOptions
+module.exports.patchWithOptionsSync = nativeBinding.patchWithOptionsSync
 module.exports.patchWithStats = nativeBinding.patchWithStats
 module.exports.patchWithStatsSync = nativeBinding.patchWithStatsSync
+module.exports.ProgressPhase = nativeBinding.ProgressPhase
+module.exports.reversePatch = nativeBinding.reversePatch
+module.exports.reversePatchSync = nativeBinding.reversePatchSync
+module.exports.setMaxConcurrentDiffs = nativeBinding.setMaxConcurrentDiffs
+module.exports.setMaxThreads = nativeBinding.setMaxThreads
 module.exports.verifyPatch = nativeBinding.verifyPatch
+module.exports.verifyPatchDigest = nativeBinding.verifyPatchDigest
+module.exports.verifyPatchDigestSync = nativeBinding.verifyPatchDigestSync
 module.exports.verifyPatchSync = nativeBinding.verifyPatchSync
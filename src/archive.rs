@@ -0,0 +1,469 @@
+//! ZIP-aware patches, as written with `DiffOptions::archive`.
+//!
+//! Small changes to a file scramble its deflate stream, so diffing two ZIP
+//! archives byte for byte finds few matches. Like Google's archive-patcher,
+//! an archive patch instead inflates the changed entries of both archives in
+//! place, diffs the expanded files, and deflates the new entries again when
+//! it is applied, with the zlib settings recorded for each. Entries whose
+//! compressed bytes `deflate::find_params` cannot reproduce are left as they
+//! are and diffed raw.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::ops::Range;
+use flate2::read::DeflateDecoder;
+use rayon::prelude::*;
+
+use crate::bsdiff_rust::{BsdiffError, Hooks};
+use crate::deflate::{self, Params};
+
+/// Magic bytes that open an archive patch.
+pub const MAGIC: &[u8; 8] = b"BSDIFFRA";
+
+/// Archive format version written by this library.
+pub const VERSION: u8 = 1;
+
+/// Size of the archive header in bytes.
+///
+/// Layout (integers little-endian):
+///
+/// | Offset | Size | Field                         |
+/// | ------ | ---- | ----------------------------- |
+/// | 0      | 8    | `MAGIC`                       |
+/// | 8      | 1    | version                       |
+/// | 9      | 7    | reserved, zero                |
+/// | 16     | 8    | old size                      |
+/// | 24     | 8    | new size                      |
+/// | 32     | 8    | old entry count               |
+/// | 40     | 8    | new entry count               |
+///
+/// The old entries follow, `OLD_ENTRY_LEN` bytes each, then the new entries,
+/// `NEW_ENTRY_LEN` bytes each, both in offset order. The rest is a BSDIFF40,
+/// variant, ENDSLEY/BSDIFF43, VCDIFF or windowed patch from the expanded old
+/// file to the expanded new file.
+pub const HEADER_LEN: usize = 48;
+
+/// Size of an old entry: offset, compressed length and inflated length, each
+/// a little-endian u64.
+pub const OLD_ENTRY_LEN: usize = 24;
+
+/// Size of a new entry: the fields of an old entry, then the zlib level,
+/// memory level and strategy (0 default, 1 filtered) it is deflated with.
+pub const NEW_ENTRY_LEN: usize = 27;
+
+/// A deflated entry of an archive: `compressed_len` bytes at `offset` that
+/// inflate to `len` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub offset: u64,
+    pub compressed_len: u64,
+    pub len: u64,
+}
+
+impl Span {
+    fn compressed(&self) -> Range<usize> {
+        self.offset as usize..(self.offset + self.compressed_len) as usize
+    }
+}
+
+/// A parsed archive patch.
+#[derive(Debug, Clone)]
+pub struct ArchivePatch<'p> {
+    pub old_size: u64,
+    pub new_size: u64,
+    /// Entries of the old file to inflate before patching.
+    pub old_entries: Vec<Span>,
+    /// Entries of the new file to deflate again after patching.
+    pub new_entries: Vec<(Span, Params)>,
+    /// Patch from the expanded old file to the expanded new file.
+    pub patch: &'p [u8],
+}
+
+impl<'p> ArchivePatch<'p> {
+    /// Parse an archive patch, checking that its entries are in order, do
+    /// not overlap and lie inside the files they belong to.
+    pub fn parse(data: &'p [u8]) -> Result<Self, BsdiffError> {
+        let corrupt = |reason: String| BsdiffError::CorruptPatch { reason };
+        if !is_archive(data) {
+            return Err(corrupt("missing archive magic".into()));
+        }
+        if data.len() < HEADER_LEN {
+            return Err(corrupt("truncated archive header".into()));
+        }
+        if data[8] != VERSION {
+            return Err(corrupt(format!("unsupported archive version {}", data[8])));
+        }
+
+        let u64_at = |data: &[u8], offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let (old_size, new_size) = (u64_at(data, 16), u64_at(data, 24));
+        let (old_count, new_count) = (u64_at(data, 32), u64_at(data, 40));
+        let tables_len = old_count
+            .checked_mul(OLD_ENTRY_LEN as u64)
+            .zip(new_count.checked_mul(NEW_ENTRY_LEN as u64))
+            .and_then(|(old, new)| old.checked_add(new))
+            .filter(|&len| len <= (data.len() - HEADER_LEN) as u64)
+            .ok_or_else(|| corrupt("archive entry tables extend past the end of the patch".into()))?;
+        let (old_table, rest) = data[HEADER_LEN..].split_at(old_count as usize * OLD_ENTRY_LEN);
+        let (new_table, patch) = rest.split_at(tables_len as usize - old_table.len());
+
+        let span = |entry: &[u8]| Span { offset: u64_at(entry, 0), compressed_len: u64_at(entry, 8), len: u64_at(entry, 16) };
+        let old_entries: Vec<Span> = old_table.chunks_exact(OLD_ENTRY_LEN).map(span).collect();
+        let new_entries = new_table
+            .chunks_exact(NEW_ENTRY_LEN)
+            .map(|entry| {
+                let params = Params::from_bytes(entry[24..27].try_into().unwrap())
+                    .ok_or_else(|| corrupt(format!("invalid deflate settings {:?}", &entry[24..27])))?;
+                Ok((span(entry), params))
+            })
+            .collect::<Result<Vec<_>, BsdiffError>>()?;
+        check_spans("old", old_entries.iter(), old_size)?;
+        check_spans("new", new_entries.iter().map(|(span, _)| span), new_size)?;
+
+        let patch = Self { old_size, new_size, old_entries, new_entries, patch };
+        patch
+            .expanded_old_size()
+            .zip(patch.expanded_new_size())
+            .ok_or_else(|| corrupt("expanded archive sizes overflow".into()))?;
+        Ok(patch)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let tables_len = self.old_entries.len() * OLD_ENTRY_LEN + self.new_entries.len() * NEW_ENTRY_LEN;
+        let mut data = Vec::with_capacity(HEADER_LEN + tables_len + self.patch.len());
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.extend_from_slice(&[0; 7]);
+        for field in [self.old_size, self.new_size, self.old_entries.len() as u64, self.new_entries.len() as u64] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        let spans = self.old_entries.iter().map(|span| (span, None));
+        for (span, params) in spans.chain(self.new_entries.iter().map(|(span, params)| (span, Some(params)))) {
+            for field in [span.offset, span.compressed_len, span.len] {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+            if let Some(params) = params {
+                data.extend_from_slice(&params.to_bytes());
+            }
+        }
+        data.extend_from_slice(self.patch);
+        data
+    }
+
+    /// Size of the old file with its listed entries inflated.
+    pub fn expanded_old_size(&self) -> Option<u64> {
+        expanded_size(self.old_size, self.old_entries.iter())
+    }
+
+    /// Size of the new file with its listed entries inflated, which is what
+    /// the inner patch produces.
+    pub fn expanded_new_size(&self) -> Option<u64> {
+        expanded_size(self.new_size, self.new_entries.iter().map(|(span, _)| span))
+    }
+
+    /// Fail with `CorruptPatch` unless `old_data` has the size this patch was made for.
+    pub fn check_old(&self, old_data: &[u8]) -> Result<(), BsdiffError> {
+        if old_data.len() as u64 != self.old_size {
+            return Err(BsdiffError::CorruptPatch {
+                reason: format!("patch was made for an old file of {} bytes, got {}", self.old_size, old_data.len()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Inflate the listed entries of `old_data` in place.
+    pub fn expand_old(&self, old_data: &[u8]) -> Result<Vec<u8>, BsdiffError> {
+        expand(old_data, &self.old_entries)
+    }
+
+    /// Deflate the listed entries of the expanded new file again and write
+    /// the result to `writer`. Returns the number of bytes written.
+    pub fn write_new<W: Write>(&self, expanded: &[u8], mut writer: W) -> Result<u64, BsdiffError> {
+        let mut read = 0;
+        let mut written = 0;
+        for (span, params) in &self.new_entries {
+            let gap = (span.offset - written) as usize;
+            writer.write_all(&expanded[read..read + gap])?;
+            read += gap;
+            let compressed = deflate::deflate(&expanded[read..read + span.len as usize], *params);
+            if compressed.len() as u64 != span.compressed_len {
+                return Err(BsdiffError::CorruptPatch {
+                    reason: format!(
+                        "entry at offset {} deflated to {} bytes instead of {}",
+                        span.offset,
+                        compressed.len(),
+                        span.compressed_len
+                    ),
+                });
+            }
+            writer.write_all(&compressed)?;
+            read += span.len as usize;
+            written = span.offset + span.compressed_len;
+        }
+        writer.write_all(&expanded[read..])?;
+        Ok(written + (expanded.len() - read) as u64)
+    }
+}
+
+/// Whether `patch_data` starts with the archive magic.
+pub fn is_archive(patch_data: &[u8]) -> bool {
+    patch_data.starts_with(MAGIC)
+}
+
+/// The entries to expand in a pair of archives.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub old_entries: Vec<Span>,
+    pub new_entries: Vec<(Span, Params)>,
+}
+
+impl Plan {
+    /// Decide which entries of two ZIP archives to diff uncompressed.
+    ///
+    /// Entries are paired by name. A changed pair is expanded when the new
+    /// entry is stored, or deflated in a way `deflate::find_params` can
+    /// reproduce; the old entry is then inflated too unless it is stored.
+    /// Returns `None` if either input is not a ZIP archive or no entry is
+    /// worth expanding.
+    pub fn new(old_data: &[u8], new_data: &[u8], parallel: bool, hooks: &Hooks) -> Result<Option<Self>, BsdiffError> {
+        let (Some(old_zip), Some(new_zip)) = (read_zip(old_data), read_zip(new_data)) else {
+            return Ok(None);
+        };
+        let mut old_by_name = HashMap::new();
+        for entry in &old_zip {
+            old_by_name.entry(entry.name).or_insert(entry);
+        }
+        let pairs: Vec<_> = new_zip
+            .iter()
+            .filter_map(|new| Some((*old_by_name.get(new.name)?, new)))
+            .filter(|(old, new)| old_data[old.data.clone()] != new_data[new.data.clone()])
+            .collect();
+
+        let plan_pair = |&(old, new): &(&ZipEntry, &ZipEntry)| -> Result<PlannedPair, BsdiffError> {
+            hooks.check_cancelled()?;
+            let new_entry = match new.method {
+                STORED => None,
+                DEFLATED => {
+                    let compressed = &new_data[new.data.clone()];
+                    let Some(params) = inflate(compressed, new.len).and_then(|data| deflate::find_params(&data, compressed)) else {
+                        return Ok((None, None));
+                    };
+                    Some((new.span(), params))
+                }
+                _ => return Ok((None, None)),
+            };
+            let old_entry = match old.method {
+                DEFLATED => match inflate(&old_data[old.data.clone()], old.len) {
+                    Some(_) => Some(old.span()),
+                    None => return Ok((None, None)),
+                },
+                _ => None,
+            };
+            Ok((old_entry, new_entry))
+        };
+        let planned: Vec<_> = if parallel {
+            pairs.par_iter().map(plan_pair).collect::<Result<_, _>>()?
+        } else {
+            pairs.iter().map(plan_pair).collect::<Result<_, _>>()?
+        };
+
+        let (old_entries, new_entries): (Vec<_>, Vec<_>) = planned.into_iter().unzip();
+        let plan = Self {
+            old_entries: disjoint(old_entries.into_iter().flatten().collect(), |span| span),
+            new_entries: disjoint(new_entries.into_iter().flatten().collect(), |(span, _)| span),
+        };
+        if plan.old_entries.is_empty() && plan.new_entries.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(plan))
+    }
+
+    /// The new file with the planned entries inflated in place.
+    pub fn expand_new(&self, new_data: &[u8]) -> Result<Vec<u8>, BsdiffError> {
+        let spans: Vec<Span> = self.new_entries.iter().map(|(span, _)| *span).collect();
+        expand(new_data, &spans)
+    }
+
+    /// The old file with the planned entries inflated in place.
+    pub fn expand_old(&self, old_data: &[u8]) -> Result<Vec<u8>, BsdiffError> {
+        expand(old_data, &self.old_entries)
+    }
+
+    /// Wrap `patch`, made from the expanded old file to the expanded new one.
+    pub fn encode(self, old_data: &[u8], new_data: &[u8], patch: &[u8]) -> Vec<u8> {
+        ArchivePatch {
+            old_size: old_data.len() as u64,
+            new_size: new_data.len() as u64,
+            old_entries: self.old_entries,
+            new_entries: self.new_entries,
+            patch,
+        }
+        .encode()
+    }
+}
+
+/// The old and new span to expand for a pair of entries, if any.
+type PlannedPair = (Option<Span>, Option<(Span, Params)>);
+
+/// Sort `items` by offset, dropping any that overlap an earlier one, as
+/// the entries of a malformed archive can.
+fn disjoint<T>(mut items: Vec<T>, span: impl Fn(&T) -> &Span) -> Vec<T> {
+    items.sort_by_key(|item| span(item).offset);
+    let mut end = 0;
+    items.retain(|item| {
+        let span = span(item);
+        let keep = span.offset >= end;
+        if keep {
+            end = span.offset + span.compressed_len;
+        }
+        keep
+    });
+    items
+}
+
+fn check_spans<'s>(which: &str, spans: impl Iterator<Item = &'s Span>, size: u64) -> Result<(), BsdiffError> {
+    let mut end = 0;
+    for span in spans {
+        if span.offset < end || span.offset.checked_add(span.compressed_len).is_none_or(|e| e > size) {
+            return Err(BsdiffError::CorruptPatch {
+                reason: format!(
+                    "{} entry {}+{} overlaps another or lies outside the {} file of {} bytes",
+                    which, span.offset, span.compressed_len, which, size
+                ),
+            });
+        }
+        end = span.offset + span.compressed_len;
+    }
+    Ok(())
+}
+
+fn expanded_size<'s>(size: u64, mut spans: impl Iterator<Item = &'s Span>) -> Option<u64> {
+    spans.try_fold(size, |size, span| (size - span.compressed_len).checked_add(span.len))
+}
+
+/// Copy `data` with the deflated `spans` inflated in place.
+fn expand(data: &[u8], spans: &[Span]) -> Result<Vec<u8>, BsdiffError> {
+    let mut expanded = Vec::new();
+    let mut read = 0;
+    for span in spans {
+        let range = span.compressed();
+        expanded.extend_from_slice(&data[read..range.start]);
+        let inflated = inflate(&data[range.clone()], span.len).ok_or_else(|| BsdiffError::CorruptPatch {
+            reason: format!("entry at offset {} does not inflate to {} bytes", span.offset, span.len),
+        })?;
+        expanded.extend_from_slice(&inflated);
+        read = range.end;
+    }
+    expanded.extend_from_slice(&data[read..]);
+    Ok(expanded)
+}
+
+/// Inflate a raw deflate stream that should hold exactly `len` bytes.
+fn inflate(compressed: &[u8], len: u64) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(len.min(compressed.len() as u64 * 4) as usize);
+    DeflateDecoder::new(compressed).take(len.saturating_add(1)).read_to_end(&mut data).ok()?;
+    (data.len() as u64 == len).then_some(data)
+}
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+const LOCAL_HEADER: &[u8; 4] = b"PK\x03\x04";
+const CENTRAL_HEADER: &[u8; 4] = b"PK\x01\x02";
+const END_OF_CENTRAL_DIRECTORY: &[u8; 4] = b"PK\x05\x06";
+const ZIP64_END_LOCATOR: &[u8; 4] = b"PK\x06\x07";
+const ZIP64_END_OF_CENTRAL_DIRECTORY: &[u8; 4] = b"PK\x06\x06";
+
+/// An entry of a ZIP archive, as listed in its central directory.
+struct ZipEntry<'a> {
+    name: &'a [u8],
+    method: u16,
+    /// The entry's data, compressed or stored, within the archive.
+    data: Range<usize>,
+    /// Uncompressed size.
+    len: u64,
+}
+
+impl ZipEntry<'_> {
+    fn span(&self) -> Span {
+        Span { offset: self.data.start as u64, compressed_len: self.data.len() as u64, len: self.len }
+    }
+}
+
+fn le(data: &[u8], offset: usize, size: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + size)?;
+    Some(bytes.iter().rev().fold(0, |value, &b| value << 8 | b as u64))
+}
+
+/// List the unencrypted entries of a ZIP archive, including ZIP64 ones, or
+/// `None` if `data` has no valid central directory. Entries whose local
+/// header is missing are left out.
+fn read_zip(data: &[u8]) -> Option<Vec<ZipEntry<'_>>> {
+    // The end record is 22 bytes plus a comment of up to 64 KiB
+    let search_start = data.len().saturating_sub(22 + 0xFFFF);
+    let eocd = (search_start..=data.len().checked_sub(22)?)
+        .rev()
+        .find(|&pos| data[pos..].starts_with(END_OF_CENTRAL_DIRECTORY))?;
+    let (mut count, mut cd_offset) = (le(data, eocd + 10, 2)?, le(data, eocd + 16, 4)?);
+    if count == 0xFFFF || cd_offset == 0xFFFF_FFFF {
+        let locator = eocd.checked_sub(20)?;
+        if !data[locator..].starts_with(ZIP64_END_LOCATOR) {
+            return None;
+        }
+        let record = usize::try_from(le(data, locator + 8, 8)?).ok()?;
+        if !data.get(record..)?.starts_with(ZIP64_END_OF_CENTRAL_DIRECTORY) {
+            return None;
+        }
+        count = le(data, record + 32, 8)?;
+        cd_offset = le(data, record + 48, 8)?;
+    }
+
+    let mut entries = Vec::new();
+    let mut pos = usize::try_from(cd_offset).ok()?;
+    for _ in 0..count {
+        if !data.get(pos..)?.starts_with(CENTRAL_HEADER) {
+            return None;
+        }
+        let flags = le(data, pos + 8, 2)?;
+        let method = le(data, pos + 10, 2)? as u16;
+        let (mut compressed_len, mut len) = (le(data, pos + 20, 4)?, le(data, pos + 24, 4)?);
+        let (name_len, extra_len, comment_len) =
+            (le(data, pos + 28, 2)? as usize, le(data, pos + 30, 2)? as usize, le(data, pos + 32, 2)? as usize);
+        let mut local = le(data, pos + 42, 4)?;
+        let name = data.get(pos + 46..pos + 46 + name_len)?;
+        let extra = data.get(pos + 46 + name_len..pos + 46 + name_len + extra_len)?;
+        pos += 46 + name_len + extra_len + comment_len;
+
+        // ZIP64 sizes and offset replace the 32-bit fields that are saturated
+        let mut fields = zip64_extra(extra).unwrap_or_default().into_iter();
+        for field in [&mut len, &mut compressed_len, &mut local] {
+            if *field == 0xFFFF_FFFF {
+                *field = fields.next()?;
+            }
+        }
+
+        // Bit 0 marks encrypted entries
+        let Ok(local) = usize::try_from(local) else { continue };
+        if flags & 1 != 0 || !data.get(local..).is_some_and(|rest| rest.starts_with(LOCAL_HEADER)) {
+            continue;
+        }
+        let start = local + 30 + le(data, local + 26, 2)? as usize + le(data, local + 28, 2)? as usize;
+        let Some(end) = usize::try_from(compressed_len).ok().and_then(|len| start.checked_add(len)) else { continue };
+        if end <= data.len() {
+            entries.push(ZipEntry { name, method, data: start..end, len });
+        }
+    }
+    Some(entries)
+}
+
+/// The values of the ZIP64 extended information field in `extra`.
+fn zip64_extra(mut extra: &[u8]) -> Option<Vec<u64>> {
+    while extra.len() >= 4 {
+        let (id, size) = (le(extra, 0, 2)?, le(extra, 2, 2)? as usize);
+        let body = extra.get(4..4 + size)?;
+        if id == 1 {
+            return Some(body.chunks_exact(8).map(|field| le(field, 0, 8).unwrap_or_default()).collect());
+        }
+        extra = &extra[4 + size..];
+    }
+    None
+}
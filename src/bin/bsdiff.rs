@@ -0,0 +1,8 @@
+//! `bsdiff [options] <old> <new> <patch>`, plus the `patch`, `info` and
+//! `verify` subcommands shared with `bspatch`.
+
+mod cli;
+
+fn main() {
+    cli::main()
+}
@@ -0,0 +1,8 @@
+//! `bspatch [options] <old> <new> <patch>`, plus the `diff`, `info` and
+//! `verify` subcommands shared with `bsdiff`.
+
+mod cli;
+
+fn main() {
+    cli::main()
+}
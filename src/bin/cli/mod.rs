@@ -0,0 +1,827 @@
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+
+use bsdiff_rust::{
+    BsdiffError, BsdiffRust, DiffOptions, ExecutableFilter, Hooks, OutputFormat, PatchCompression, PatchOptions,
+    PerformanceStats,
+};
+use bsdiff_rust::container;
+use bsdiff_rust::utils::{self, BlockCompression, DigestAlgorithm, PatchFormat, PatchInfo};
+
+// Shared by the `bsdiff` and `bspatch` binaries. Both accept every
+// subcommand; they only differ in the one run when none is given, so the
+// classic `bsdiff old new patch` and `bspatch old new patch` work unchanged.
+
+/// Exit status for each kind of failure.
+///
+/// | Status | Meaning                                           |
+/// | ------ | ------------------------------------------------- |
+/// | 0      | success                                           |
+/// | 1      | `verify`: the patch does not reproduce the target |
+/// | 2      | bad usage or an invalid option                    |
+/// | 3      | an input file does not exist                      |
+/// | 4      | reading or writing failed                         |
+/// | 5      | the patch is corrupt                              |
+/// | 6      | a checksum does not match                         |
+/// | 7      | an input is too large                             |
+/// | 8      | a patch limit was exceeded                        |
+/// | 9      | the output has a different size than expected     |
+/// | 130    | cancelled                                         |
+fn exit_code(err: &BsdiffError) -> i32 {
+    match err {
+        BsdiffError::InvalidOption { .. } => 2,
+        BsdiffError::NotFound { .. } => 3,
+        BsdiffError::Io { .. } => 4,
+        BsdiffError::CorruptPatch { .. } => 5,
+        BsdiffError::ChecksumMismatch { .. } => 6,
+        BsdiffError::TooLarge { .. } => 7,
+        BsdiffError::LimitExceeded { .. } => 8,
+        BsdiffError::SizeMismatch { .. } => 9,
+        BsdiffError::Cancelled => 130,
+    }
+}
+
+/// Exit status of a `verify` that ran but found a mismatch.
+const VERIFY_FAILED: i32 = 1;
+
+/// Exit status for bad usage.
+const USAGE: i32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tool {
+    Bsdiff,
+    Bspatch,
+}
+
+impl Tool {
+    /// The binary being built.
+    fn current() -> Self {
+        match env!("CARGO_BIN_NAME") {
+            "bspatch" => Self::Bspatch,
+            _ => Self::Bsdiff,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Bsdiff => "bsdiff",
+            Self::Bspatch => "bspatch",
+        }
+    }
+
+    /// Subcommand run when the first argument names none.
+    fn default_command(self) -> Command {
+        match self {
+            Self::Bsdiff => Command::Diff,
+            Self::Bspatch => Command::Patch,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Diff,
+    Patch,
+    Info,
+    Verify,
+}
+
+impl Command {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "diff" => Some(Self::Diff),
+            "patch" => Some(Self::Patch),
+            "info" => Some(Self::Info),
+            "verify" => Some(Self::Verify),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Diff => "diff",
+            Self::Patch => "patch",
+            Self::Info => "info",
+            Self::Verify => "verify",
+        }
+    }
+}
+
+/// Command-line flag for each option name that errors mention.
+const FLAGS: &[(&str, &str)] = &[
+    ("compressionLevel", "--compression-level"),
+    ("compression", "--compression"),
+    ("format", "--format"),
+    ("enableParallel", "parallel diffing"),
+    ("windowSize", "--window-size"),
+    ("smallMatch", "--small-match"),
+    ("bufferSize", "--buffer-size"),
+    ("parallelChunkSize", "--parallel-chunk-size"),
+    ("parallelJobs", "--parallel-jobs"),
+    ("threads", "--threads"),
+    ("reversePatch", "--reverse-patch"),
+    ("filter", "--filter"),
+    ("maxTargetSize", "--max-target-size"),
+    ("maxMemory", "--max-memory"),
+];
+
+fn usage(tool: Tool) -> String {
+    let name = tool.name();
+    let default = match tool {
+        Tool::Bsdiff => format!("  {} [diff options] <old> <new> <patch>     write a patch from <old> to <new>", name),
+        Tool::Bspatch => format!("  {} [patch options] <old> <new> <patch>    apply <patch> to <old>, writing <new>", name),
+    };
+    format!(
+        "Usage:
+{default}
+  {name} diff [diff options] <old> <new> <patch>
+  {name} patch [patch options] <old> <new> <patch>
+  {name} info [--json] <patch>                  describe a patch
+  {name} verify <old> <new> <patch>             check that <patch> turns <old> into <new>
+  {name} verify --digest <hex> --size <bytes> [--algorithm sha256|blake3] <old> <patch>
+
+Diff options:
+  --compression-level <1-9>    compression level (default 6)
+  --compression <codec>        bzip2 (default), zstd, xz, brotli or none
+  --format <format>            bsdiff40 (default), endsley or vcdiff
+  --no-parallel                search on a single thread
+  --container                  record the SHA-256 and size of both files
+  --archive                    diff the entries of ZIP files uncompressed
+  --filter <filter>            convert branch targets: none (default), auto, x86 or arm64
+  --window-size <bytes>        diff in windows, for inputs over about 2 GiB
+  --small-match <bytes>        shortest exact match worth encoding (default 12)
+  --buffer-size <bytes>        delta buffer size (default 4096)
+  --parallel-chunk-size <bytes>
+                               search the new file in chunks of this size
+  --parallel-jobs <n>          search the new file in at most this many jobs
+  --threads <n>                worker threads (default one per core)
+  --reverse-patch <path>       also write the patch from <new> back to <old>
+  --mmap                       memory-map the input files
+
+Patch options:
+  --max-target-size <bytes>    refuse patches producing more than this
+  --max-memory <bytes>         refuse patches needing more memory than this
+  --expected-target-size <bytes>
+                               require the output to be exactly this size
+  --mmap                       memory-map the input files
+
+Other options:
+  --stats                      print performance statistics as JSON (diff, patch)
+  -h, --help                   show this help
+  -V, --version                show the version
+
+When <old> is a directory, diff and patch work on whole directory trees: the
+patch holds every changed, added, renamed and deleted file, and patch builds
+the new tree at <new>, which must not exist or be empty.
+
+Sizes accept a K, M or G suffix (powers of 1024). Put `--` before file names
+that start with `-` or match a subcommand.
+
+Exit status: 0 success, 1 verification failed, 2 usage, 3 not found, 4 I/O,
+5 corrupt patch, 6 checksum mismatch, 7 too large, 8 limit exceeded,
+9 size mismatch."
+    )
+}
+
+/// A parsed command line.
+struct Invocation {
+    command: Command,
+    paths: Vec<PathBuf>,
+    diff: DiffOptions,
+    reverse: Option<PathBuf>,
+    patch: PatchOptions,
+    stats: bool,
+    json: bool,
+    digest: Option<String>,
+    size: Option<u64>,
+    algorithm: DigestAlgorithm,
+}
+
+enum Failure {
+    /// Bad command line, reported with a pointer to `--help`.
+    Usage(String),
+    Error(BsdiffError),
+}
+
+impl From<BsdiffError> for Failure {
+    fn from(err: BsdiffError) -> Self {
+        Self::Error(err)
+    }
+}
+
+/// Run the current binary with the process arguments and exit with its status.
+pub fn main() -> ! {
+    let tool = Tool::current();
+    let status = match run(tool, std::env::args_os().skip(1)) {
+        Ok(status) => status,
+        Err(Failure::Usage(message)) => {
+            eprintln!("{}: {}", tool.name(), message);
+            eprintln!("Try '{} --help' for more information.", tool.name());
+            USAGE
+        }
+        Err(Failure::Error(err)) => {
+            eprintln!("{}: {}", tool.name(), describe(&err));
+            exit_code(&err)
+        }
+    };
+    process::exit(status)
+}
+
+fn run(tool: Tool, args: impl Iterator<Item = OsString>) -> Result<i32, Failure> {
+    let args = args.collect::<Vec<_>>();
+    let options_end = args.iter().position(|arg| arg == "--").unwrap_or(args.len());
+    if args[..options_end].iter().any(|arg| arg == "-h" || arg == "--help") {
+        emit(|out| writeln!(out, "{}", usage(tool)))?;
+        return Ok(0);
+    }
+    if args[..options_end].iter().any(|arg| arg == "-V" || arg == "--version") {
+        emit(|out| writeln!(out, "{} {}", tool.name(), env!("CARGO_PKG_VERSION")))?;
+        return Ok(0);
+    }
+
+    let invocation = parse(tool, args)?;
+    let paths: Vec<&PathBuf> = invocation.paths.iter().collect();
+    let hooks = Hooks::default();
+    match (invocation.command, paths.as_slice()) {
+        (Command::Diff, &[old, new, patch]) => {
+            let stats = match &invocation.reverse {
+                _ if old.is_dir() && invocation.reverse.is_some() => {
+                    return Err(Failure::Usage("--reverse-patch does not apply to directories".into()));
+                }
+                _ if old.is_dir() => BsdiffRust::diff_directory_files(old, new, patch, &invocation.diff, &hooks)?,
+                Some(reverse) => BsdiffRust::diff_files_with_reverse(old, new, patch, reverse, &invocation.diff, &hooks)?,
+                None => BsdiffRust::diff_files(old, new, patch, &invocation.diff, &hooks)?,
+            };
+            if invocation.stats {
+                emit(|out| writeln!(out, "{}", stats_json(&stats)))?;
+            }
+        }
+        (Command::Patch, &[old, new, patch]) => {
+            let stats = if old.is_dir() {
+                BsdiffRust::patch_directory_files(old, new, patch, &invocation.patch, &hooks)?
+            } else {
+                BsdiffRust::patch_files_streaming(old, new, patch, &invocation.patch, &hooks)?
+            };
+            if invocation.stats {
+                emit(|out| writeln!(out, "{}", stats_json(&stats)))?;
+            }
+        }
+        (Command::Info, &[patch]) => {
+            let info = utils::read_patch_info(patch)?;
+            if invocation.json {
+                emit(|out| writeln!(out, "{}", info_json(&info)))?;
+            } else {
+                emit(|out| print_info(out, &info))?;
+            }
+        }
+        (Command::Verify, &[old, new, patch]) => {
+            if !utils::verify_patch_files(old, new, patch, &hooks)? {
+                eprintln!("{}: {} does not turn {} into {}", tool.name(), patch.display(), old.display(), new.display());
+                return Ok(VERIFY_FAILED);
+            }
+        }
+        (Command::Verify, &[old, patch]) => {
+            let (Some(digest), Some(size)) = (&invocation.digest, invocation.size) else {
+                unreachable!("checked by parse")
+            };
+            let verification = utils::verify_patch_digest_files(old, patch, digest, size, invocation.algorithm, &hooks)?;
+            if !verification.is_valid() {
+                eprintln!(
+                    "{}: {} produces {} bytes with digest {}, expected {} bytes with digest {}",
+                    tool.name(),
+                    patch.display(),
+                    verification.bytes_produced,
+                    verification.actual_digest,
+                    size,
+                    digest.to_ascii_lowercase()
+                );
+                return Ok(VERIFY_FAILED);
+            }
+        }
+        _ => unreachable!("checked by parse"),
+    }
+    Ok(0)
+}
+
+/// Write to stdout. A closed pipe is not an error: the reader, such as
+/// `head`, has everything it wanted.
+fn emit<F: FnOnce(&mut io::StdoutLock) -> io::Result<()>>(write: F) -> Result<(), Failure> {
+    match write(&mut io::stdout().lock()) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(BsdiffError::from(e).into()),
+        _ => Ok(()),
+    }
+}
+
+fn parse(tool: Tool, args: Vec<OsString>) -> Result<Invocation, Failure> {
+    let mut args = args.into_iter().peekable();
+    let command = match args.peek().and_then(|arg| arg.to_str()).and_then(Command::parse) {
+        Some(command) => {
+            args.next();
+            command
+        }
+        None => tool.default_command(),
+    };
+    let mut invocation = Invocation {
+        command,
+        paths: Vec::new(),
+        diff: DiffOptions::default(),
+        reverse: None,
+        patch: PatchOptions::default(),
+        stats: false,
+        json: false,
+        digest: None,
+        size: None,
+        algorithm: DigestAlgorithm::Sha256,
+    };
+
+    let mut options_done = false;
+    while let Some(arg) = args.next() {
+        // Paths are passed through as they are; only options and their
+        // values need to be UTF-8
+        let arg = match arg.into_string() {
+            Ok(arg) if !options_done && arg.starts_with('-') && arg != "-" => arg,
+            path => {
+                invocation.paths.push(path.map_or_else(PathBuf::from, PathBuf::from));
+                continue;
+            }
+        };
+        if arg == "--" {
+            options_done = true;
+            continue;
+        }
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_owned(), Some(value.to_owned())),
+            None => (arg, None),
+        };
+        if inline.is_some() && is_switch(&flag) {
+            return Err(Failure::Usage(format!("{} does not take a value", flag)));
+        }
+        let mut raw_value = || inline.clone().map(OsString::from).or_else(|| args.next());
+        let needs_value = || Failure::Usage(format!("{} needs a value", flag));
+        // Like positional paths, the reverse patch path need not be UTF-8
+        if (command, flag.as_str()) == (Command::Diff, "--reverse-patch") {
+            invocation.reverse = Some(raw_value().ok_or_else(needs_value)?.into());
+            continue;
+        }
+        let mut value = || {
+            match raw_value() {
+                Some(value) => value.into_string().map_err(|value| Failure::Usage(format!("{:?} is not valid UTF-8", value))),
+                None => Err(needs_value()),
+            }
+        };
+
+        let (diff, patch) = (&mut invocation.diff, &mut invocation.patch);
+        match (command, flag.as_str()) {
+            (Command::Diff, "--compression-level") => diff.compression_level = number(&flag, &value()?)?,
+            (Command::Diff, "--compression") => diff.compression = compression(&value()?)?,
+            (Command::Diff, "--format") => diff.format = format(&value()?)?,
+            (Command::Diff, "--no-parallel") => diff.enable_parallel = false,
+            (Command::Diff, "--container") => diff.container = true,
+            (Command::Diff, "--archive") => diff.archive = true,
+            (Command::Diff, "--filter") => diff.filter = filter(&value()?)?,
+            (Command::Diff, "--window-size") => diff.window_size = Some(size(&flag, &value()?)?),
+            (Command::Diff, "--small-match") => diff.small_match = Some(size(&flag, &value()?)? as usize),
+            (Command::Diff, "--buffer-size") => diff.buffer_size = Some(size(&flag, &value()?)? as usize),
+            (Command::Diff, "--parallel-chunk-size") => diff.parallel_chunk_size = Some(size(&flag, &value()?)?),
+            (Command::Diff, "--parallel-jobs") => diff.parallel_jobs = Some(number(&flag, &value()?)?),
+            (Command::Diff, "--threads") => diff.threads = Some(number(&flag, &value()?)?),
+            (Command::Patch, "--max-target-size") => patch.max_target_size = Some(size(&flag, &value()?)?),
+            (Command::Patch, "--max-memory") => patch.max_memory = Some(size(&flag, &value()?)?),
+            (Command::Patch, "--expected-target-size") => patch.expected_target_size = Some(size(&flag, &value()?)?),
+            (Command::Diff | Command::Patch, "--mmap") => (diff.mmap, patch.mmap) = (true, true),
+            (Command::Diff | Command::Patch, "--stats") => invocation.stats = true,
+            (Command::Info, "--json") => invocation.json = true,
+            (Command::Verify, "--digest") => invocation.digest = Some(value()?),
+            (Command::Verify, "--size") => invocation.size = Some(size(&flag, &value()?)?),
+            (Command::Verify, "--algorithm") => invocation.algorithm = algorithm(&value()?)?,
+            _ => return Err(Failure::Usage(format!("unknown option {} for {}", flag, command.name()))),
+        }
+    }
+
+    let expected = match command {
+        Command::Diff | Command::Patch => "<old> <new> <patch>",
+        Command::Info => "<patch>",
+        Command::Verify if invocation.digest.is_some() || invocation.size.is_some() => {
+            if invocation.digest.is_none() || invocation.size.is_none() {
+                return Err(Failure::Usage("--digest and --size must be given together".into()));
+            }
+            "<old> <patch>"
+        }
+        Command::Verify => "<old> <new> <patch>",
+    };
+    if invocation.paths.len() != expected.split(' ').count() {
+        return Err(Failure::Usage(format!("{} expects {}", command.name(), expected)));
+    }
+    Ok(invocation)
+}
+
+/// Whether `flag` is a switch rather than an option with a value.
+fn is_switch(flag: &str) -> bool {
+    matches!(flag, "--no-parallel" | "--container" | "--archive" | "--mmap" | "--stats" | "--json")
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, Failure> {
+    value.parse().map_err(|_| Failure::Usage(format!("{} expects a number, got {:?}", flag, value)))
+}
+
+/// Parse a byte count with an optional K, M or G suffix.
+fn size(flag: &str, value: &str) -> Result<u64, Failure> {
+    let (digits, shift) = match value.as_bytes().last().map(u8::to_ascii_uppercase) {
+        Some(b'K') => (&value[..value.len() - 1], 10),
+        Some(b'M') => (&value[..value.len() - 1], 20),
+        Some(b'G') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| Failure::Usage(format!("{} expects a size in bytes, got {:?}", flag, value)))
+}
+
+fn compression(value: &str) -> Result<PatchCompression, Failure> {
+    match value {
+        "bzip2" => Ok(PatchCompression::Bzip2),
+        "zstd" => Ok(PatchCompression::Zstd),
+        "xz" => Ok(PatchCompression::Xz),
+        "brotli" => Ok(PatchCompression::Brotli),
+        "none" => Ok(PatchCompression::None),
+        _ => Err(Failure::Usage(format!("unknown compression {:?}", value))),
+    }
+}
+
+fn format(value: &str) -> Result<OutputFormat, Failure> {
+    match value {
+        "bsdiff40" => Ok(OutputFormat::Bsdiff40),
+        "endsley" => Ok(OutputFormat::Endsley),
+        "vcdiff" => Ok(OutputFormat::Vcdiff),
+        _ => Err(Failure::Usage(format!("unknown format {:?}", value))),
+    }
+}
+
+fn filter(value: &str) -> Result<ExecutableFilter, Failure> {
+    match value {
+        "none" => Ok(ExecutableFilter::None),
+        "auto" => Ok(ExecutableFilter::Auto),
+        "x86" => Ok(ExecutableFilter::X86),
+        "arm64" => Ok(ExecutableFilter::Arm64),
+        _ => Err(Failure::Usage(format!("unknown filter {:?}", value))),
+    }
+}
+
+fn algorithm(value: &str) -> Result<DigestAlgorithm, Failure> {
+    match value {
+        "sha256" => Ok(DigestAlgorithm::Sha256),
+        "blake3" => Ok(DigestAlgorithm::Blake3),
+        _ => Err(Failure::Usage(format!("unknown digest algorithm {:?}", value))),
+    }
+}
+
+/// Describe an error in terms of the command line rather than the JS option
+/// names it carries.
+fn describe(err: &BsdiffError) -> String {
+    let flag = |name: &str| FLAGS.iter().find(|(option, _)| *option == name).map(|(_, flag)| *flag);
+    let message = match err {
+        BsdiffError::InvalidOption { name, reason } => format!("Invalid option {}: {}", flag(name).unwrap_or(name), reason),
+        other => other.to_string(),
+    };
+    // Option names elsewhere in the message are camelCase, unlike the words
+    // around them
+    let words = message.split(' ').map(|word| {
+        let name = word.trim_end_matches([':', ',']);
+        match flag(name).filter(|_| name.contains(|c: char| c.is_ascii_uppercase())) {
+            Some(flag) => word.replacen(name, flag, 1),
+            None => word.to_owned(),
+        }
+    });
+    words.collect::<Vec<_>>().join(" ")
+}
+
+fn stats_json(stats: &PerformanceStats) -> String {
+    format!(
+        "{{\"elapsedMs\":{},\"oldSize\":{},\"newSize\":{},\"patchSize\":{},\"compressionRatio\":{},\"estimatedPeakMemory\":{},\"heapMemory\":{},\"mappedMemory\":{}}}",
+        stats.elapsed_ms,
+        stats.old_size,
+        stats.new_size,
+        stats.patch_size,
+        json_number(stats.compression_ratio),
+        stats.estimated_peak_memory,
+        stats.heap_memory,
+        stats.mapped_memory
+    )
+}
+
+/// The same fields as `PatchInfoJs` in the Node API.
+fn info_json(info: &PatchInfo) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "null".into());
+    let blocks = info
+        .blocks
+        .iter()
+        .map(|block| {
+            format!(
+                "{{\"name\":{},\"offset\":{},\"length\":{},\"compression\":{}}}",
+                json_string(block.name),
+                block.offset,
+                block.length,
+                json_string(compression_name(block.compression))
+            )
+        })
+        .collect::<Vec<_>>();
+    let container = info.container.as_ref().map(|header| {
+        format!(
+            "{{\"oldSize\":{},\"newSize\":{},\"oldSha256\":{},\"newSha256\":{}}}",
+            header.old_size,
+            header.new_size,
+            json_string(&container::to_hex(&header.old_sha256)),
+            json_string(&container::to_hex(&header.new_sha256))
+        )
+    });
+    let issues = info.issues.iter().map(|issue| json_string(issue)).collect::<Vec<_>>();
+    format!(
+        "{{\"size\":{},\"compressed\":{},\"format\":{},\"targetSize\":{},\"blocks\":[{}],\"container\":{},\"innerFormat\":{},\"issues\":[{}]}}",
+        info.size,
+        info.compressed,
+        json_string(format_name(info.format)),
+        optional(info.target_size.map(|size| size.to_string())),
+        blocks.join(","),
+        optional(container),
+        optional(info.inner_format.map(|format| json_string(format_name(format)))),
+        issues.join(",")
+    )
+}
+
+fn print_info<W: Write>(out: &mut W, info: &PatchInfo) -> io::Result<()> {
+    writeln!(out, "format       {}", format_name(info.format))?;
+    if let Some(inner) = info.inner_format {
+        writeln!(out, "inner format {}", format_name(inner))?;
+    }
+    writeln!(out, "size         {} bytes", info.size)?;
+    match info.target_size {
+        Some(size) => writeln!(out, "target size  {} bytes", size)?,
+        None => writeln!(out, "target size  unknown")?,
+    }
+    writeln!(out, "compressed   {}", if info.compressed { "yes" } else { "no" })?;
+    if let Some(header) = &info.container {
+        writeln!(out, "old file     {} bytes, sha256 {}", header.old_size, container::to_hex(&header.old_sha256))?;
+        writeln!(out, "new file     {} bytes, sha256 {}", header.new_size, container::to_hex(&header.new_sha256))?;
+    }
+    if !info.blocks.is_empty() {
+        writeln!(out, "blocks")?;
+        for block in &info.blocks {
+            writeln!(
+                out,
+                "  {:<12} offset {:<12} length {:<12} {}",
+                block.name,
+                block.offset,
+                block.length,
+                compression_name(block.compression)
+            )?;
+        }
+    }
+    if info.issues.is_empty() {
+        writeln!(out, "issues       none")?;
+    } else {
+        writeln!(out, "issues")?;
+        for issue in &info.issues {
+            writeln!(out, "  {}", issue)?;
+        }
+    }
+    Ok(())
+}
+
+/// Names used for `PatchFormat` by the Node API.
+fn format_name(format: PatchFormat) -> &'static str {
+    match format {
+        PatchFormat::Bsdiff40 => "bsdiff40",
+        PatchFormat::Container => "container",
+        PatchFormat::Windowed => "windowed",
+        PatchFormat::Variant => "variant",
+        PatchFormat::Endsley => "endsley",
+        PatchFormat::Vcdiff => "vcdiff",
+        PatchFormat::Archive => "archive",
+        PatchFormat::Filtered => "filtered",
+        PatchFormat::Directory => "directory",
+        PatchFormat::Gzip => "gzip",
+        PatchFormat::Unknown => "unknown",
+    }
+}
+
+/// Names used for `BlockCompression` by the Node API.
+fn compression_name(compression: BlockCompression) -> &'static str {
+    match compression {
+        BlockCompression::Bzip2 => "bzip2",
+        BlockCompression::Gzip => "gzip",
+        BlockCompression::Zstd => "zstd",
+        BlockCompression::Xz => "xz",
+        BlockCompression::Brotli => "brotli",
+        BlockCompression::None => "none",
+        BlockCompression::Unknown => "unknown",
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// JSON has no NaN or infinity.
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn parsed(tool: Tool, list: &[&str]) -> Invocation {
+        match parse(tool, args(list)) {
+            Ok(invocation) => invocation,
+            Err(Failure::Usage(message)) => panic!("{:?}: {}", list, message),
+            Err(Failure::Error(err)) => panic!("{:?}: {}", list, err),
+        }
+    }
+
+    fn usage_error(tool: Tool, list: &[&str]) -> String {
+        match parse(tool, args(list)) {
+            Err(Failure::Usage(message)) => message,
+            Ok(_) => panic!("{:?} parsed", list),
+            Err(Failure::Error(err)) => panic!("{:?}: {}", list, err),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        // The classic three-path form runs each binary's own command
+        let invocation = parsed(Tool::Bsdiff, &["old", "new", "patch"]);
+        assert_eq!(invocation.command, Command::Diff);
+        assert_eq!(invocation.paths, [PathBuf::from("old"), PathBuf::from("new"), PathBuf::from("patch")]);
+        assert!(invocation.diff.enable_parallel && !invocation.stats);
+        assert_eq!(parsed(Tool::Bspatch, &["old", "new", "patch"]).command, Command::Patch);
+        assert_eq!(parsed(Tool::Bspatch, &["diff", "old", "new", "patch"]).command, Command::Diff);
+        assert_eq!(parsed(Tool::Bsdiff, &["-", "new", "patch"]).paths[0], PathBuf::from("-"));
+
+        // Everything after `--` is a path, even if it looks like an option
+        let invocation = parsed(Tool::Bsdiff, &["--stats", "--", "-old", "--new", "--"]);
+        assert!(invocation.stats);
+        assert_eq!(invocation.paths, [PathBuf::from("-old"), PathBuf::from("--new"), PathBuf::from("--")]);
+
+        // Values inline after `=` or as the next argument
+        let invocation = parsed(
+            Tool::Bsdiff,
+            &["--compression-level=9", "--window-size", "4M", "--format=endsley", "--threads=2", "old", "new", "patch"],
+        );
+        assert_eq!(invocation.diff.compression_level, 9);
+        assert_eq!(invocation.diff.window_size, Some(4 << 20));
+        assert_eq!(invocation.diff.format, OutputFormat::Endsley);
+        assert_eq!(invocation.diff.threads, Some(2));
+        let invocation = parsed(Tool::Bspatch, &["--max-memory=1G", "--expected-target-size=0", "old", "new", "patch"]);
+        assert_eq!(invocation.patch.max_memory, Some(1 << 30));
+        assert_eq!(invocation.patch.expected_target_size, Some(0));
+
+        // Switches take no value, options need one
+        assert_eq!(usage_error(Tool::Bsdiff, &["--stats=yes", "old", "new", "patch"]), "--stats does not take a value");
+        assert_eq!(usage_error(Tool::Bsdiff, &["old", "new", "patch", "--threads"]), "--threads needs a value");
+        assert!(usage_error(Tool::Bsdiff, &["--threads=many", "old", "new", "patch"]).contains("expects a number"));
+        assert!(usage_error(Tool::Bsdiff, &["--window-size=4X", "old", "new", "patch"]).contains("expects a size"));
+        assert!(usage_error(Tool::Bsdiff, &["--format=zip", "old", "new", "patch"]).contains("unknown format"));
+
+        // Options belong to their command
+        assert_eq!(usage_error(Tool::Bsdiff, &["info", "--stats", "patch"]), "unknown option --stats for info");
+        assert_eq!(usage_error(Tool::Bsdiff, &["--max-memory=1", "old", "new", "patch"]), "unknown option --max-memory for diff");
+        assert_eq!(usage_error(Tool::Bsdiff, &["old", "new"]), "diff expects <old> <new> <patch>");
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+        match run(Tool::Bsdiff, args(&["--reverse-patch", "back", dir, dir, "patch"]).into_iter()) {
+            Err(Failure::Usage(message)) => assert_eq!(message, "--reverse-patch does not apply to directories"),
+            _ => panic!("reverse patch of a directory accepted"),
+        }
+
+        // `verify` checks a digest when given one, with the size it goes with
+        let invocation = parsed(Tool::Bsdiff, &["verify", "--digest", "AB", "--size=2", "--algorithm=blake3", "old", "patch"]);
+        assert_eq!((invocation.digest.as_deref(), invocation.size), (Some("AB"), Some(2)));
+        assert_eq!(invocation.algorithm, DigestAlgorithm::Blake3);
+        assert_eq!(parsed(Tool::Bsdiff, &["verify", "old", "new", "patch"]).paths.len(), 3);
+        let message = "--digest and --size must be given together";
+        assert_eq!(usage_error(Tool::Bsdiff, &["verify", "--digest", "AB", "old", "patch"]), message);
+        assert_eq!(usage_error(Tool::Bsdiff, &["verify", "--size=2", "old", "patch"]), message);
+        assert_eq!(usage_error(Tool::Bsdiff, &["verify", "--digest=AB", "--size=2", "old", "new", "patch"]), "verify expects <old> <patch>");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_non_utf8_paths() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let path = OsString::from_vec(b"caf\xe9".to_vec());
+        let list = vec![OsString::from("info"), path.clone()];
+        let invocation = match parse(Tool::Bsdiff, list) {
+            Ok(invocation) => invocation,
+            Err(_) => panic!("non-UTF-8 path refused"),
+        };
+        assert_eq!(invocation.paths, [PathBuf::from(path.clone())]);
+
+        // Including the reverse patch, the one option that is a path
+        let list = vec![OsString::from("--reverse-patch"), path.clone(), "old".into(), "new".into(), "patch".into()];
+        let invocation = match parse(Tool::Bsdiff, list) {
+            Ok(invocation) => invocation,
+            Err(_) => panic!("non-UTF-8 reverse patch path refused"),
+        };
+        assert_eq!(invocation.reverse, Some(PathBuf::from(path.clone())));
+
+        let list = vec![OsString::from("--format"), path, OsString::from("new"), OsString::from("patch")];
+        match parse(Tool::Bsdiff, list) {
+            Err(Failure::Usage(message)) => assert!(message.contains("is not valid UTF-8"), "{}", message),
+            _ => panic!("non-UTF-8 option value accepted"),
+        }
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let io = || io::Error::other("disk on fire");
+        let cases = [
+            (BsdiffError::InvalidOption { name: "threads", reason: "too many".into() }, 2),
+            (BsdiffError::NotFound { what: "Old file", path: "old".into() }, 3),
+            (BsdiffError::Io { path: Some("new".into()), source: io() }, 4),
+            (BsdiffError::Io { path: None, source: io() }, 4),
+            (BsdiffError::CorruptPatch { reason: "truncated".into() }, 5),
+            (BsdiffError::ChecksumMismatch { what: "Old file", expected: "00".into(), actual: "ff".into() }, 6),
+            (BsdiffError::TooLarge { what: "Old file", path: None, size: 2, limit: 1 }, 7),
+            (BsdiffError::LimitExceeded { limit_name: "maxMemory", size: 2, limit: 1 }, 8),
+            (BsdiffError::SizeMismatch { expected: 1, actual: 2 }, 9),
+            (BsdiffError::Cancelled, 130),
+        ];
+        for (err, status) in cases {
+            assert_eq!(exit_code(&err), status, "{:?}", err);
+        }
+        assert_eq!((USAGE, VERIFY_FAILED), (2, 1));
+
+        // Errors name the flag rather than the option it sets
+        let err = BsdiffError::InvalidOption { name: "windowSize", reason: "is too small".into() };
+        assert_eq!(describe(&err), "Invalid option --window-size: is too small");
+        let err = BsdiffError::LimitExceeded { limit_name: "maxMemory", size: 2, limit: 1 };
+        assert!(describe(&err).contains("--max-memory"), "{}", describe(&err));
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("say \"hi\" \\ bye"), r#""say \"hi\" \\ bye""#);
+        assert_eq!(json_string("a\nb\rc\td"), r#""a\nb\rc\td""#);
+        assert_eq!(json_string("\u{0}\u{1}\u{1f}\u{7f}\u{9b}"), r#""\u0000\u0001\u001f\u007f\u009b""#);
+        // Non-ASCII text passes through as UTF-8, and paths that are not
+        // UTF-8 arrive with replacement characters
+        assert_eq!(json_string("café/日本"), "\"café/日本\"");
+        let lossy = String::from_utf8_lossy(b"old/caf\xe9\x01.bin");
+        assert_eq!(json_string(&lossy), "\"old/caf\u{fffd}\\u0001.bin\"");
+
+        assert_eq!(json_number(12.5), "12.5");
+        assert_eq!(json_number(f64::NAN), "null");
+        assert_eq!(json_number(f64::INFINITY), "null");
+
+        let stats = PerformanceStats {
+            elapsed_ms: 3,
+            old_size: 10,
+            new_size: 0,
+            patch_size: 5,
+            compression_ratio: f64::NAN,
+            estimated_peak_memory: 7,
+            heap_memory: 4,
+            mapped_memory: 3,
+        };
+        assert_eq!(
+            stats_json(&stats),
+            "{\"elapsedMs\":3,\"oldSize\":10,\"newSize\":0,\"patchSize\":5,\"compressionRatio\":null,\"estimatedPeakMemory\":7,\"heapMemory\":4,\"mappedMemory\":3}"
+        );
+
+        let info = utils::inspect_patch(&mut io::Cursor::new(b"not a patch\n\"quoted\"")).unwrap();
+        let json = info_json(&info);
+        assert!(json.contains("\"format\":\"unknown\"") && json.contains("\"targetSize\":null"), "{}", json);
+        assert!(json.contains("\"container\":null,\"innerFormat\":null"), "{}", json);
+    }
+}
@@ -0,0 +1,1835 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+
+use crate::bsdiff_rust::{self, BsdiffError, BsdiffRust, DiffOptions, Hooks, PatchOptions};
+use crate::container;
+use crate::utils::{self, verify_patch as verify_patch_util, verify_patch_with_hooks, verify_patch_digest as verify_patch_digest_util, verify_patch_digest_with_hooks, get_patch_info, get_file_size, check_file_access, get_compression_ratio};
+
+// ============================================================
+// Common type conversions and helper functions
+// ============================================================
+
+/// Result of the Rust side of an operation, converted to JS on the main thread.
+type Outcome<T> = std::result::Result<T, BsdiffError>;
+
+/// Convert a `BsdiffError` into a JS `Error` carrying a stable `code` and the
+/// fields of `ErrorDetailsJs`.
+///
+/// Cancellations are named `AbortError`, matching the rejection napi produces
+/// for tasks aborted before they start.
+fn to_js_error(env: &Env, err: BsdiffError) -> Error {
+  let build = || -> Result<Error> {
+    let mut error = env.create_error(Error::from_reason(err.to_string()))?;
+    if matches!(err, BsdiffError::Cancelled) {
+      error.set_named_property("name", "AbortError")?;
+    }
+    let details = ErrorDetailsJs::from(&err);
+    error.set_named_property("code", details.code)?;
+    if let Some(path) = details.path {
+      error.set_named_property("path", path)?;
+    }
+    if let Some(size) = details.size {
+      error.set_named_property("size", size)?;
+    }
+    if let Some(limit) = details.limit {
+      error.set_named_property("limit", limit)?;
+    }
+    if let Some(expected) = details.expected {
+      error.set_named_property("expected", expected)?;
+    }
+    if let Some(actual) = details.actual {
+      error.set_named_property("actual", actual)?;
+    }
+    if let Some(limit_name) = details.limit_name {
+      error.set_named_property("limitName", limit_name)?;
+    }
+    if let Some(expected_size) = details.expected_size {
+      error.set_named_property("expectedSize", expected_size)?;
+    }
+    if let Some(option) = details.option {
+      error.set_named_property("option", option)?;
+    }
+    Ok(Error::from(error.to_unknown()))
+  };
+  build().unwrap_or_else(|e| e)
+}
+
+/// Convert an `Outcome<T>` into `napi::Result<T>`.
+fn into_js<T>(env: &Env, result: Outcome<T>) -> Result<T> {
+  result.map_err(|e| to_js_error(env, e))
+}
+
+/// Outcome of a single `writable.write()`, reported by its completion callback.
+type WriteResult = std::result::Result<(), String>;
+
+/// Progress callback passed from JavaScript.
+type ProgressCallback = ThreadsafeFunction<ProgressJs, (), ProgressJs, Status, false>;
+
+/// Build per-call hooks that forward progress to an optional JS callback and
+/// observe an optional `AbortSignal`.
+fn hooks_from_js(on_progress: Option<ProgressCallback>, signal: Option<&AbortSignal>) -> Hooks {
+  let mut hooks = Hooks::default();
+  if let Some(callback) = on_progress {
+    hooks = hooks.with_progress(move |progress| {
+      // Non-blocking: a busy JS thread should never stall the worker.
+      callback.call(progress.into(), ThreadsafeFunctionCallMode::NonBlocking);
+    });
+  }
+  if let Some(signal) = signal {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let flag = cancelled.clone();
+    signal.on_abort(move || flag.store(true, Ordering::Relaxed));
+    hooks = hooks.with_cancel_flag(cancelled);
+  }
+  hooks
+}
+
+/// Arguments of `writable.write(chunk, callback)`.
+type WriteArgs = FnArgs<(Buffer, OnWritten)>;
+
+/// The callback of `writable.write`, which reports whether the chunk was
+/// written. The JS function is created when the arguments are converted, in
+/// the scope of the call it is passed to.
+struct OnWritten(mpsc::SyncSender<WriteResult>);
+
+impl ToNapiValue for OnWritten {
+  unsafe fn to_napi_value(env: napi::sys::napi_env, val: Self) -> Result<napi::sys::napi_value> {
+    let OnWritten(done) = val;
+    let env = Env::from_raw(env);
+    let on_written = env.create_function_from_closure::<Unknown, (), _>("onWritten", move |cb| {
+      let result = match cb.try_get::<Unknown>(0) {
+        Ok(Either::A(err)) if !matches!(err.get_type()?, ValueType::Null | ValueType::Undefined) => {
+          Err(err.coerce_to_string()?.into_utf8()?.as_str()?.to_owned())
+        }
+        _ => Ok(()),
+      };
+      let _ = done.send(result);
+      Ok(())
+    })?;
+    Ok(on_written.raw())
+  }
+}
+
+/// `std::io::Write` adapter that forwards chunks to a Node.js `Writable`.
+///
+/// Each chunk is handed to `writable.write(chunk, cb)` on the JS thread and the
+/// worker blocks until `cb` fires, so at most one chunk is in flight and the
+/// stream's own backpressure bounds memory use.
+struct JsWritable {
+  write: ThreadsafeFunction<(Vec<u8>, mpsc::SyncSender<WriteResult>), bool, WriteArgs, Status, false>,
+}
+
+impl JsWritable {
+  fn new(writable: Object) -> Result<Self> {
+    let write: Function<WriteArgs, bool> = writable.get_named_property("write")?;
+    let write = write
+      .bind(writable)?
+      .build_threadsafe_function::<(Vec<u8>, mpsc::SyncSender<WriteResult>)>()
+      .build_callback(|ctx| {
+        let (chunk, done) = ctx.value;
+        Ok(FnArgs::from((Buffer::from(chunk), OnWritten(done))))
+      })?;
+    Ok(Self { write })
+  }
+}
+
+impl Write for JsWritable {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    let (done, written) = mpsc::sync_channel(1);
+    let status = self
+      .write
+      .call((buf.to_vec(), done), ThreadsafeFunctionCallMode::Blocking);
+    if status != Status::Ok {
+      return Err(std::io::Error::other(format!("Writable is no longer available: {}", status)));
+    }
+    match written.recv() {
+      Ok(Ok(())) => Ok(buf.len()),
+      Ok(Err(reason)) => Err(std::io::Error::other(reason)),
+      Err(_) => Err(std::io::Error::other("Writable was closed before the chunk was written")),
+    }
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+// ============================================================
+// JS ↔ Rust struct definitions and type conversions
+// ============================================================
+
+/// Patch format exposed to JavaScript.
+#[napi(string_enum)]
+pub enum PatchFormat {
+  /// Classic BSDIFF40.
+  #[napi(value = "bsdiff40")]
+  Bsdiff40,
+  /// BSDIFF40 wrapped in this library's checksummed container.
+  #[napi(value = "container")]
+  Container,
+  /// Windowed sub-patches, as written with `windowSize`.
+  #[napi(value = "windowed")]
+  Windowed,
+  /// BSDIFF40 blocks compressed with zstd, xz, brotli or not at all, as
+  /// written with `compression`.
+  #[napi(value = "variant")]
+  Variant,
+  /// ENDSLEY/BSDIFF43, as written by mendsley/bsdiff.
+  #[napi(value = "endsley")]
+  Endsley,
+  /// VCDIFF (RFC 3284), as written by xdelta3 and open-vcdiff.
+  #[napi(value = "vcdiff")]
+  Vcdiff,
+  /// ZIP entries diffed uncompressed, as written with `archive`.
+  #[napi(value = "archive")]
+  Archive,
+  /// Executable code with converted branch targets, as written with `filter`.
+  #[napi(value = "filtered")]
+  Filtered,
+  /// A directory tree patch, as written by `diffDirectory`.
+  #[napi(value = "directory")]
+  Directory,
+  /// A gzip-compressed file; see `innerFormat`.
+  #[napi(value = "gzip")]
+  Gzip,
+  #[napi(value = "unknown")]
+  Unknown,
+}
+
+impl From<utils::PatchFormat> for PatchFormat {
+  fn from(format: utils::PatchFormat) -> Self {
+    match format {
+      utils::PatchFormat::Bsdiff40 => Self::Bsdiff40,
+      utils::PatchFormat::Container => Self::Container,
+      utils::PatchFormat::Windowed => Self::Windowed,
+      utils::PatchFormat::Variant => Self::Variant,
+      utils::PatchFormat::Endsley => Self::Endsley,
+      utils::PatchFormat::Vcdiff => Self::Vcdiff,
+      utils::PatchFormat::Archive => Self::Archive,
+      utils::PatchFormat::Filtered => Self::Filtered,
+      utils::PatchFormat::Directory => Self::Directory,
+      utils::PatchFormat::Gzip => Self::Gzip,
+      utils::PatchFormat::Unknown => Self::Unknown,
+    }
+  }
+}
+
+/// Compression of a patch block exposed to JavaScript.
+#[napi(string_enum)]
+pub enum BlockCompression {
+  #[napi(value = "bzip2")]
+  Bzip2,
+  #[napi(value = "gzip")]
+  Gzip,
+  #[napi(value = "zstd")]
+  Zstd,
+  #[napi(value = "xz")]
+  Xz,
+  #[napi(value = "brotli")]
+  Brotli,
+  /// The block is stored uncompressed, or is empty.
+  #[napi(value = "none")]
+  None,
+  #[napi(value = "unknown")]
+  Unknown,
+}
+
+impl From<utils::BlockCompression> for BlockCompression {
+  fn from(compression: utils::BlockCompression) -> Self {
+    match compression {
+      utils::BlockCompression::Bzip2 => Self::Bzip2,
+      utils::BlockCompression::Gzip => Self::Gzip,
+      utils::BlockCompression::Zstd => Self::Zstd,
+      utils::BlockCompression::Xz => Self::Xz,
+      utils::BlockCompression::Brotli => Self::Brotli,
+      utils::BlockCompression::None => Self::None,
+      utils::BlockCompression::Unknown => Self::Unknown,
+    }
+  }
+}
+
+/// A block of a patch file exposed to JavaScript.
+#[napi(object)]
+pub struct PatchBlockJs {
+  /// `control`, `diff` or `extra` for BSDIFF40 and each window of a windowed
+  /// patch, `body` for ENDSLEY/BSDIFF43, `file` for each changed or added
+  /// file of a directory patch.
+  pub name: String,
+  /// Offset of the block within the patch file.
+  pub offset: f64,
+  /// Length of the block in bytes.
+  pub length: f64,
+  pub compression: BlockCompression,
+}
+
+/// Container header of a checksummed patch exposed to JavaScript.
+#[napi(object)]
+pub struct PatchContainerJs {
+  pub old_size: f64,
+  pub new_size: f64,
+  /// Hex SHA-256 of the old file.
+  pub old_sha256: String,
+  /// Hex SHA-256 of the new file.
+  pub new_sha256: String,
+}
+
+/// Patch file information exposed to JavaScript.
+#[napi(object)]
+pub struct PatchInfoJs {
+  pub size: f64,
+  pub compressed: bool,
+  pub format: PatchFormat,
+  /// Size of the output declared by the header.
+  pub target_size: Option<f64>,
+  pub blocks: Vec<PatchBlockJs>,
+  pub container: Option<PatchContainerJs>,
+  /// Format of the decompressed data, for gzip-wrapped patches.
+  pub inner_format: Option<PatchFormat>,
+  /// Inconsistencies found in the header. Empty for a well-formed patch.
+  pub issues: Vec<String>,
+}
+
+impl From<utils::PatchInfo> for PatchInfoJs {
+  fn from(info: utils::PatchInfo) -> Self {
+    Self {
+      size: info.size as f64,
+      compressed: info.compressed,
+      format: info.format.into(),
+      target_size: info.target_size.map(|size| size as f64),
+      blocks: info
+        .blocks
+        .into_iter()
+        .map(|block| PatchBlockJs {
+          name: block.name.to_owned(),
+          offset: block.offset as f64,
+          length: block.length as f64,
+          compression: block.compression.into(),
+        })
+        .collect(),
+      container: info.container.map(|header| PatchContainerJs {
+        old_size: header.old_size as f64,
+        new_size: header.new_size as f64,
+        old_sha256: container::to_hex(&header.old_sha256),
+        new_sha256: container::to_hex(&header.new_sha256),
+      }),
+      inner_format: info.inner_format.map(Into::into),
+      issues: info.issues,
+    }
+  }
+}
+
+/// Compression ratio information exposed to JavaScript.
+#[napi(object)]
+pub struct CompressionRatioJs {
+  pub old_size: f64,
+  pub new_size: f64,
+  pub patch_size: f64,
+  pub ratio: f64,
+}
+
+/// Performance statistics exposed to JavaScript.
+#[napi(object)]
+pub struct PerformanceStatsJs {
+  /// Elapsed time in milliseconds.
+  pub elapsed_ms: f64,
+  /// Old file size in bytes.
+  pub old_size: f64,
+  /// New file size in bytes.
+  pub new_size: f64,
+  /// Patch file size in bytes.
+  pub patch_size: f64,
+  /// Compression ratio as a percentage.
+  pub compression_ratio: f64,
+  /// Peak memory held by the operation's buffers in bytes, the sum of
+  /// `heapMemory` and `mappedMemory`. It is worked out from the sizes of the
+  /// buffers each step holds, not measured.
+  pub estimated_peak_memory: f64,
+  /// Part of `estimatedPeakMemory` allocated on the heap.
+  pub heap_memory: f64,
+  /// Part of `estimatedPeakMemory` taken by memory-mapped input files.
+  pub mapped_memory: f64,
+}
+
+impl From<bsdiff_rust::PerformanceStats> for PerformanceStatsJs {
+  fn from(s: bsdiff_rust::PerformanceStats) -> Self {
+    Self {
+      elapsed_ms: s.elapsed_ms as f64,
+      old_size: s.old_size as f64,
+      new_size: s.new_size as f64,
+      patch_size: s.patch_size as f64,
+      compression_ratio: s.compression_ratio,
+      estimated_peak_memory: s.estimated_peak_memory as f64,
+      heap_memory: s.heap_memory as f64,
+      mapped_memory: s.mapped_memory as f64,
+    }
+  }
+}
+
+/// Operation phase exposed to JavaScript.
+#[napi(string_enum)]
+pub enum ProgressPhase {
+  /// Reading input files.
+  #[napi(value = "reading")]
+  Reading,
+  /// Suffix sorting, matching and bzip2 compression, which qbsdiff runs as
+  /// one step reported only when it starts and finishes.
+  #[napi(value = "diffing")]
+  Diffing,
+  /// Converting the patch to another format or compression.
+  #[napi(value = "encoding")]
+  Encoding,
+  /// Applying the patch to the old data.
+  #[napi(value = "patching")]
+  Patching,
+  /// Writing the output file.
+  #[napi(value = "writing")]
+  Writing,
+}
+
+impl From<bsdiff_rust::Phase> for ProgressPhase {
+  fn from(phase: bsdiff_rust::Phase) -> Self {
+    match phase {
+      bsdiff_rust::Phase::Reading => Self::Reading,
+      bsdiff_rust::Phase::Diffing => Self::Diffing,
+      bsdiff_rust::Phase::Encoding => Self::Encoding,
+      bsdiff_rust::Phase::Patching => Self::Patching,
+      bsdiff_rust::Phase::Writing => Self::Writing,
+    }
+  }
+}
+
+/// Progress update exposed to JavaScript.
+#[napi(object)]
+pub struct ProgressJs {
+  pub phase: ProgressPhase,
+  /// Bytes processed so far in this phase.
+  pub bytes_processed: f64,
+  /// Total bytes expected in this phase.
+  pub total_bytes: f64,
+}
+
+impl From<bsdiff_rust::Progress> for ProgressJs {
+  fn from(p: bsdiff_rust::Progress) -> Self {
+    Self {
+      phase: p.phase.into(),
+      bytes_processed: p.bytes_processed as f64,
+      total_bytes: p.total_bytes as f64,
+    }
+  }
+}
+
+/// Compression of the blocks of a generated patch, exposed to JavaScript.
+#[napi(string_enum)]
+pub enum PatchCompression {
+  /// bzip2, written as standard BSDIFF40.
+  #[napi(value = "bzip2")]
+  Bzip2,
+  #[napi(value = "zstd")]
+  Zstd,
+  #[napi(value = "xz")]
+  Xz,
+  #[napi(value = "brotli")]
+  Brotli,
+  /// Uncompressed blocks, for patches that are compressed in transit.
+  #[napi(value = "none")]
+  None,
+}
+
+impl From<PatchCompression> for bsdiff_rust::PatchCompression {
+  fn from(compression: PatchCompression) -> Self {
+    match compression {
+      PatchCompression::Bzip2 => Self::Bzip2,
+      PatchCompression::Zstd => Self::Zstd,
+      PatchCompression::Xz => Self::Xz,
+      PatchCompression::Brotli => Self::Brotli,
+      PatchCompression::None => Self::None,
+    }
+  }
+}
+
+/// Stream format of a generated patch, exposed to JavaScript.
+#[napi(string_enum)]
+pub enum OutputFormat {
+  /// Classic BSDIFF40.
+  #[napi(value = "bsdiff40")]
+  Bsdiff40,
+  /// ENDSLEY/BSDIFF43, as written by mendsley/bsdiff.
+  #[napi(value = "endsley")]
+  Endsley,
+  /// VCDIFF (RFC 3284), as read by xdelta3 and open-vcdiff.
+  #[napi(value = "vcdiff")]
+  Vcdiff,
+}
+
+impl From<OutputFormat> for bsdiff_rust::OutputFormat {
+  fn from(format: OutputFormat) -> Self {
+    match format {
+      OutputFormat::Bsdiff40 => Self::Bsdiff40,
+      OutputFormat::Endsley => Self::Endsley,
+      OutputFormat::Vcdiff => Self::Vcdiff,
+    }
+  }
+}
+
+/// Conversion of branch targets in executable code, exposed to JavaScript.
+#[napi(string_enum)]
+pub enum ExecutableFilter {
+  /// Diff the inputs as they are.
+  #[napi(value = "none")]
+  None,
+  /// x86 or ARM64, as read from the ELF, PE or Mach-O headers of both files.
+  #[napi(value = "auto")]
+  Auto,
+  /// x86 and x86-64 CALL and JMP targets.
+  #[napi(value = "x86")]
+  X86,
+  /// ARM64 BL and ADRP targets.
+  #[napi(value = "arm64")]
+  Arm64,
+}
+
+impl From<ExecutableFilter> for bsdiff_rust::ExecutableFilter {
+  fn from(filter: ExecutableFilter) -> Self {
+    match filter {
+      ExecutableFilter::None => Self::None,
+      ExecutableFilter::Auto => Self::Auto,
+      ExecutableFilter::X86 => Self::X86,
+      ExecutableFilter::Arm64 => Self::Arm64,
+    }
+  }
+}
+
+/// Diff configuration options exposed to JavaScript.
+#[napi(object)]
+pub struct DiffOptionsJs {
+  /// Compression level (1-9, default 6).
+  pub compression_level: Option<f64>,
+  /// Compression of the patch blocks (default bzip2). Anything else produces
+  /// a variant patch, which only this library can apply.
+  pub compression: Option<PatchCompression>,
+  /// Stream format of the patch (default bsdiff40). The endsley format is
+  /// always compressed with bzip2 and vcdiff is not compressed, so neither
+  /// can be combined with `compression`.
+  pub format: Option<OutputFormat>,
+  /// Enable parallel processing (default true).
+  pub enable_parallel: Option<bool>,
+  /// Wrap the patch in a container recording the SHA-256 and size of the old
+  /// and new files, so patching rejects the wrong base file (default false).
+  pub container: Option<bool>,
+  /// Diff the new file in windows of this many bytes, each against a matching
+  /// region of the old file. Required for inputs larger than about 2 GiB.
+  pub window_size: Option<f64>,
+  /// Memory-map the old and new files instead of reading them (default false).
+  pub mmap: Option<bool>,
+  /// Exact matches shorter than this many bytes are encoded as diff data
+  /// instead; zero keeps every match (default 12).
+  pub small_match: Option<f64>,
+  /// Size of the buffer used to compute delta data, at least 128 bytes
+  /// (default 4096).
+  pub buffer_size: Option<f64>,
+  /// Search the new file in parallel chunks of this many bytes, at least
+  /// 256 KiB. Requires `enableParallel`.
+  pub parallel_chunk_size: Option<f64>,
+  /// Search the new file in at most this many parallel jobs. Requires
+  /// `enableParallel`; cannot be combined with `parallelChunkSize`.
+  pub parallel_jobs: Option<f64>,
+  /// Worker threads for this diff's own thread pool, capped by
+  /// `setMaxThreads` (default: that cap, or one per core).
+  pub threads: Option<f64>,
+  /// Diff the entries of ZIP-based inputs (zip, jar, apk, docx) uncompressed,
+  /// deflating them again when patching (default false).
+  pub archive: Option<bool>,
+  /// Convert relative branch targets in executable code before diffing, and
+  /// back when patching (default none). Cannot be combined with `archive`.
+  pub filter: Option<ExecutableFilter>,
+}
+
+impl TryFrom<DiffOptionsJs> for DiffOptions {
+  type Error = BsdiffError;
+
+  fn try_from(js: DiffOptionsJs) -> Outcome<Self> {
+    Ok(Self {
+      compression_level: integer_option("compressionLevel", js.compression_level)?.unwrap_or(6),
+      compression: js.compression.map(Into::into).unwrap_or_default(),
+      format: js.format.map(Into::into).unwrap_or_default(),
+      enable_parallel: js.enable_parallel.unwrap_or(true),
+      container: js.container.unwrap_or(false),
+      window_size: integer_option("windowSize", js.window_size)?,
+      mmap: js.mmap.unwrap_or(false),
+      small_match: integer_option("smallMatch", js.small_match)?,
+      buffer_size: integer_option("bufferSize", js.buffer_size)?,
+      parallel_chunk_size: integer_option("parallelChunkSize", js.parallel_chunk_size)?,
+      parallel_jobs: integer_option("parallelJobs", js.parallel_jobs)?,
+      threads: integer_option("threads", js.threads)?,
+      archive: js.archive.unwrap_or(false),
+      filter: js.filter.map(Into::into).unwrap_or_default(),
+    })
+  }
+}
+
+/// Limits for applying untrusted patches, exposed to JavaScript.
+#[napi(object)]
+pub struct PatchOptionsJs {
+  /// Largest output the patch may produce, in bytes.
+  pub max_target_size: Option<f64>,
+  /// Largest estimated peak memory (inputs plus output buffers), in bytes.
+  pub max_memory: Option<f64>,
+  /// Exact output size the patch must produce, in bytes.
+  pub expected_target_size: Option<f64>,
+  /// Memory-map the old and patch files instead of reading them (default
+  /// false). Mapped files still count towards `maxMemory`.
+  pub mmap: Option<bool>,
+}
+
+impl TryFrom<PatchOptionsJs> for PatchOptions {
+  type Error = BsdiffError;
+
+  fn try_from(js: PatchOptionsJs) -> Outcome<Self> {
+    Ok(Self {
+      max_target_size: integer_option("maxTargetSize", js.max_target_size)?,
+      max_memory: integer_option("maxMemory", js.max_memory)?,
+      expected_target_size: integer_option("expectedTargetSize", js.expected_target_size)?,
+      mmap: js.mmap.unwrap_or(false),
+    })
+  }
+}
+
+/// Convert a numeric JS option to an integer, failing with `InvalidOption`
+/// for negative, fractional, non-finite or unsafe values that a cast would
+/// silently saturate or truncate.
+fn integer_option<T: TryFrom<u64>>(name: &'static str, value: Option<f64>) -> Outcome<Option<T>> {
+  const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+  let Some(n) = value else {
+    return Ok(None);
+  };
+  if !(0.0..=MAX_SAFE_INTEGER).contains(&n) || n.fract() != 0.0 {
+    return Err(BsdiffError::InvalidOption { name, reason: "must be a non-negative safe integer".into() });
+  }
+  T::try_from(n as u64)
+    .map(Some)
+    .map_err(|_| BsdiffError::InvalidOption { name, reason: format!("{} is too large", n) })
+}
+
+/// Convert optional JS diff options, defaulting when they are omitted.
+fn diff_options(options: Option<DiffOptionsJs>) -> Outcome<DiffOptions> {
+  options.map(DiffOptions::try_from).transpose().map(Option::unwrap_or_default)
+}
+
+/// Convert optional JS patch limits, defaulting when they are omitted.
+fn patch_options(options: Option<PatchOptionsJs>) -> Outcome<PatchOptions> {
+  options.map(PatchOptions::try_from).transpose().map(Option::unwrap_or_default)
+}
+
+/// Take the options converted for a task, so that an invalid one rejects
+/// its promise like any other `InvalidOption` error.
+fn take_options<T: Default>(options: &mut Outcome<T>) -> Outcome<T> {
+  std::mem::replace(options, Ok(T::default()))
+}
+
+/// Digest algorithm exposed to JavaScript.
+#[napi(string_enum)]
+pub enum DigestAlgorithm {
+  #[napi(value = "sha256")]
+  Sha256,
+  #[napi(value = "blake3")]
+  Blake3,
+}
+
+impl From<DigestAlgorithm> for utils::DigestAlgorithm {
+  fn from(algorithm: DigestAlgorithm) -> Self {
+    match algorithm {
+      DigestAlgorithm::Sha256 => Self::Sha256,
+      DigestAlgorithm::Blake3 => Self::Blake3,
+    }
+  }
+}
+
+/// Result of verifying patch output against an expected digest, exposed to JavaScript.
+#[napi(object)]
+pub struct DigestVerificationJs {
+  /// Whether both the digest and the size matched.
+  pub valid: bool,
+  /// Whether the output digest equals the expected digest.
+  pub hash_matched: bool,
+  /// Whether the output size equals the expected size.
+  pub size_matched: bool,
+  /// Number of bytes the patch produced.
+  pub bytes_produced: f64,
+  /// Lowercase hex digest of the output.
+  pub actual_digest: String,
+}
+
+impl From<utils::DigestVerification> for DigestVerificationJs {
+  fn from(v: utils::DigestVerification) -> Self {
+    Self {
+      valid: v.is_valid(),
+      hash_matched: v.hash_matched,
+      size_matched: v.size_matched,
+      bytes_produced: v.bytes_produced as f64,
+      actual_digest: v.actual_digest,
+    }
+  }
+}
+
+/// Stable error codes exposed to JavaScript as `err.code`.
+#[napi(string_enum)]
+pub enum ErrorCode {
+  /// An input file does not exist.
+  #[napi(value = "ERR_NOT_FOUND")]
+  NotFound,
+  /// An input exceeds a size limit.
+  #[napi(value = "ERR_TOO_LARGE")]
+  TooLarge,
+  /// The patch is malformed or was not made for this old file.
+  #[napi(value = "ERR_CORRUPT_PATCH")]
+  CorruptPatch,
+  /// Reading or writing failed.
+  #[napi(value = "ERR_IO")]
+  IoError,
+  /// The operation was cancelled through its `AbortSignal`.
+  #[napi(value = "ERR_CANCELLED")]
+  Cancelled,
+  /// Data does not match its expected digest.
+  #[napi(value = "ERR_CHECKSUM_MISMATCH")]
+  ChecksumMismatch,
+  /// A patch needs more than a `PatchOptionsJs` limit allows.
+  #[napi(value = "ERR_LIMIT_EXCEEDED")]
+  LimitExceeded,
+  /// A patch produces a different size than `expectedTargetSize`.
+  #[napi(value = "ERR_SIZE_MISMATCH")]
+  SizeMismatch,
+  /// An option is out of range.
+  #[napi(value = "ERR_INVALID_OPTION")]
+  InvalidOption,
+}
+
+/// Properties set on errors thrown or rejected by this module.
+#[napi(object)]
+pub struct ErrorDetailsJs {
+  pub code: ErrorCode,
+  /// File the error refers to.
+  pub path: Option<String>,
+  /// Actual size in bytes (`ERR_TOO_LARGE`, `ERR_LIMIT_EXCEEDED`, `ERR_SIZE_MISMATCH`).
+  pub size: Option<f64>,
+  /// Size limit in bytes (`ERR_TOO_LARGE`, `ERR_LIMIT_EXCEEDED`).
+  pub limit: Option<f64>,
+  /// Expected digest (`ERR_CHECKSUM_MISMATCH`).
+  pub expected: Option<String>,
+  /// Actual digest (`ERR_CHECKSUM_MISMATCH`).
+  pub actual: Option<String>,
+  /// Option whose limit was exceeded, `maxTargetSize` or `maxMemory` (`ERR_LIMIT_EXCEEDED`).
+  pub limit_name: Option<String>,
+  /// Expected size in bytes (`ERR_SIZE_MISMATCH`).
+  pub expected_size: Option<f64>,
+  /// Name of the rejected option (`ERR_INVALID_OPTION`).
+  pub option: Option<String>,
+}
+
+impl From<&BsdiffError> for ErrorDetailsJs {
+  fn from(err: &BsdiffError) -> Self {
+    let code = match err {
+      BsdiffError::NotFound { .. } => ErrorCode::NotFound,
+      BsdiffError::TooLarge { .. } => ErrorCode::TooLarge,
+      BsdiffError::CorruptPatch { .. } => ErrorCode::CorruptPatch,
+      BsdiffError::Io { .. } => ErrorCode::IoError,
+      BsdiffError::Cancelled => ErrorCode::Cancelled,
+      BsdiffError::ChecksumMismatch { .. } => ErrorCode::ChecksumMismatch,
+      BsdiffError::LimitExceeded { .. } => ErrorCode::LimitExceeded,
+      BsdiffError::SizeMismatch { .. } => ErrorCode::SizeMismatch,
+      BsdiffError::InvalidOption { .. } => ErrorCode::InvalidOption,
+    };
+    let mut details = Self {
+      code,
+      path: err.path().map(str::to_owned),
+      size: None,
+      limit: None,
+      expected: None,
+      actual: None,
+      limit_name: None,
+      expected_size: None,
+      option: None,
+    };
+    match err {
+      BsdiffError::TooLarge { size, limit, .. } => {
+        details.size = Some(*size as f64);
+        details.limit = Some(*limit as f64);
+      }
+      BsdiffError::ChecksumMismatch { expected, actual, .. } => {
+        details.expected = Some(expected.clone());
+        details.actual = Some(actual.clone());
+      }
+      BsdiffError::LimitExceeded { limit_name, size, limit } => {
+        details.limit_name = Some((*limit_name).to_owned());
+        details.size = Some(*size as f64);
+        details.limit = Some(*limit as f64);
+      }
+      BsdiffError::SizeMismatch { expected, actual } => {
+        details.expected_size = Some(*expected as f64);
+        details.size = Some(*actual as f64);
+      }
+      BsdiffError::InvalidOption { name, .. } => {
+        details.option = Some((*name).to_owned());
+      }
+      _ => {}
+    }
+    details
+  }
+}
+
+// ============================================================
+// Synchronous API
+// ============================================================
+
+#[napi]
+pub fn diff_sync(env: Env, old_str: String, new_str: String, patch: String) -> Result<()> {
+  into_js(&env, BsdiffRust::diff(&old_str, &new_str, &patch))
+}
+
+#[napi]
+pub fn patch_sync(env: Env, old_str: String, new_str: String, patch: String) -> Result<()> {
+  into_js(&env, BsdiffRust::patch(&old_str, &new_str, &patch))
+}
+
+/// Generate a patch file and return performance statistics (sync).
+#[napi]
+pub fn diff_with_stats_sync(env: Env, old_str: String, new_str: String, patch: String) -> Result<PerformanceStatsJs> {
+  into_js(&env, BsdiffRust::diff_with_stats(&old_str, &new_str, &patch)).map(Into::into)
+}
+
+/// Apply a patch file and return performance statistics (sync).
+#[napi]
+pub fn patch_with_stats_sync(env: Env, old_str: String, new_str: String, patch: String) -> Result<PerformanceStatsJs> {
+  into_js(&env, BsdiffRust::patch_with_stats(&old_str, &new_str, &patch)).map(Into::into)
+}
+
+/// Generate a patch file with custom options (sync).
+#[napi]
+pub fn diff_with_options_sync(
+  env: Env,
+  old_str: String,
+  new_str: String,
+  patch: String,
+  options: DiffOptionsJs,
+) -> Result<()> {
+  let opts = into_js(&env, DiffOptions::try_from(options))?;
+  into_js(&env, BsdiffRust::diff_with_options(&old_str, &new_str, &patch, &opts))
+}
+
+/// Generate a patch file with custom options and return performance statistics (sync).
+#[napi]
+pub fn diff_with_options_and_stats_sync(
+  env: Env,
+  old_str: String,
+  new_str: String,
+  patch: String,
+  options: DiffOptionsJs,
+) -> Result<PerformanceStatsJs> {
+  let opts = into_js(&env, DiffOptions::try_from(options))?;
+  into_js(&env, BsdiffRust::diff_with_options_and_stats(&old_str, &new_str, &patch, &opts)).map(Into::into)
+}
+
+/// Apply a patch file within the given limits (sync).
+#[napi]
+pub fn patch_with_options_sync(
+  env: Env,
+  old_str: String,
+  new_str: String,
+  patch: String,
+  options: PatchOptionsJs,
+) -> Result<()> {
+  let opts = into_js(&env, PatchOptions::try_from(options))?;
+  into_js(&env, BsdiffRust::patch_with_options(&old_str, &new_str, &patch, &opts))
+}
+
+/// Apply a patch file, streaming the output to disk in bounded chunks (sync).
+#[napi]
+pub fn patch_streaming_sync(
+  env: Env,
+  old_str: String,
+  new_str: String,
+  patch: String,
+  options: Option<PatchOptionsJs>,
+) -> Result<PerformanceStatsJs> {
+  let opts = into_js(&env, patch_options(options))?;
+  into_js(&env, BsdiffRust::patch_streaming(&old_str, &new_str, &patch, &opts, &Hooks::default())).map(Into::into)
+}
+
+/// Generate a patch from in-memory buffers (sync).
+#[napi]
+pub fn diff_buffers_sync(env: Env, old_buf: Buffer, new_buf: Buffer, options: Option<DiffOptionsJs>) -> Result<Buffer> {
+  let opts = into_js(&env, diff_options(options))?;
+  into_js(&env, BsdiffRust::diff_buffers(&old_buf, &new_buf, &opts)).map(Into::into)
+}
+
+/// Apply a patch to an in-memory buffer (sync).
+#[napi]
+pub fn patch_buffers_sync(env: Env, old_buf: Buffer, patch_buf: Buffer, options: Option<PatchOptionsJs>) -> Result<Buffer> {
+  let opts = into_js(&env, patch_options(options))?;
+  into_js(&env, BsdiffRust::patch_buffers(&old_buf, &patch_buf, &opts)).map(Into::into)
+}
+
+/// Compose a chain of patch files into one patch from the base file to the
+/// last version (sync). The chain is applied within the limits in
+/// `patch_options`.
+#[napi]
+pub fn compose_patches_sync(
+  env: Env,
+  base_str: String,
+  patches: Vec<String>,
+  patch: String,
+  options: Option<DiffOptionsJs>,
+  patch_options: Option<PatchOptionsJs>,
+) -> Result<PerformanceStatsJs> {
+  let opts = into_js(&env, diff_options(options))?;
+  let patch_opts = into_js(&env, self::patch_options(patch_options))?;
+  let patches: Vec<&str> = patches.iter().map(String::as_str).collect();
+  into_js(&env, BsdiffRust::compose_patches(&base_str, &patches, &patch, &opts, &patch_opts, &Hooks::default())).map(Into::into)
+}
+
+/// Apply a chain of patch files in sequence, keeping intermediate versions in
+/// memory (sync).
+#[napi]
+pub fn apply_chain_sync(
+  env: Env,
+  old_str: String,
+  patches: Vec<String>,
+  new_str: String,
+  options: Option<PatchOptionsJs>,
+) -> Result<PerformanceStatsJs> {
+  let opts = into_js(&env, patch_options(options))?;
+  let patches: Vec<&str> = patches.iter().map(String::as_str).collect();
+  into_js(&env, BsdiffRust::apply_chain(&old_str, &patches, &new_str, &opts, &Hooks::default())).map(Into::into)
+}
+
+/// Generate a patch file and the reverse (new to old) patch for rolling
+/// back, and return performance statistics (sync).
+#[napi]
+pub fn diff_with_reverse_sync(
+  env: Env,
+  old_str: String,
+  new_str: String,
+  patch: String,
+  reverse_patch: String,
+  options: Option<DiffOptionsJs>,
+) -> Result<PerformanceStatsJs> {
+  let opts = into_js(&env, diff_options(options))?;
+  let stats = BsdiffRust::diff_files_with_reverse(&old_str, &new_str, &patch, &reverse_patch, &opts, &Hooks::default());
+  into_js(&env, stats).map(Into::into)
+}
+
+/// Derive the reverse (new to old) patch of a patch file from the old file,
+/// and write it to `reverse` (sync). The forward patch is applied within the
+/// limits in `patch_options`.
+#[napi]
+pub fn reverse_patch_sync(
+  env: Env,
+  old_str: String,
+  patch: String,
+  reverse: String,
+  options: Option<DiffOptionsJs>,
+  patch_options: Option<PatchOptionsJs>,
+) -> Result<PerformanceStatsJs> {
+  let opts = into_js(&env, diff_options(options))?;
+  let patch_opts = into_js(&env, self::patch_options(patch_options))?;
+  into_js(&env, BsdiffRust::reverse_patch(&old_str, &patch, &reverse, &opts, &patch_opts, &Hooks::default())).map(Into::into)
+}
+
+/// Diff two directory trees into a single patch file (sync).
+#[napi]
+pub fn diff_directory_sync(
+  env: Env,
+  old_dir: String,
+  new_dir: String,
+  patch: String,
+  options: Option<DiffOptionsJs>,
+) -> Result<PerformanceStatsJs> {
+  let opts = into_js(&env, diff_options(options))?;
+  into_js(&env, BsdiffRust::diff_directory(&old_dir, &new_dir, &patch, &opts, &Hooks::default())).map(Into::into)
+}
+
+/// Rebuild the new directory tree of a directory patch into `out_dir`
+/// (sync).
+#[napi]
+pub fn patch_directory_sync(
+  env: Env,
+  old_dir: String,
+  out_dir: String,
+  patch: String,
+  options: Option<PatchOptionsJs>,
+) -> Result<PerformanceStatsJs> {
+  let opts = into_js(&env, patch_options(options))?;
+  into_js(&env, BsdiffRust::patch_directory(&old_dir, &out_dir, &patch, &opts, &Hooks::default())).map(Into::into)
+}
+
+/// Verify patch file integrity.
+#[napi]
+pub fn verify_patch_sync(env: Env, old_str: String, new_str: String, patch: String) -> Result<bool> {
+  into_js(&env, verify_patch_util(&old_str, &new_str, &patch))
+}
+
+/// Verify that a patch produces output with the expected digest and size,
+/// without writing it to disk (sync).
+#[napi]
+pub fn verify_patch_digest_sync(
+  env: Env,
+  old_str: String,
+  patch: String,
+  expected_digest: String,
+  expected_size: f64,
+  algorithm: Option<DigestAlgorithm>,
+) -> Result<DigestVerificationJs> {
+  let algorithm = algorithm.map(Into::into).unwrap_or(utils::DigestAlgorithm::Sha256);
+  into_js(&env, verify_patch_digest_util(&old_str, &patch, &expected_digest, expected_size as u64, algorithm)).map(Into::into)
+}
+
+/// Get patch file information.
+#[napi]
+pub fn get_patch_info_sync(env: Env, patch: String) -> Result<PatchInfoJs> {
+  into_js(&env, get_patch_info(&patch)).map(Into::into)
+}
+
+/// Get file size.
+#[napi]
+pub fn get_file_size_sync(env: Env, file_path: String) -> Result<f64> {
+  into_js(&env, get_file_size(&file_path)).map(|s| s as f64)
+}
+
+/// Check file access permissions.
+#[napi]
+pub fn check_file_access_sync(env: Env, file_path: String) -> Result<()> {
+  into_js(&env, check_file_access(&file_path))
+}
+
+/// Get compression ratio information.
+#[napi]
+pub fn get_compression_ratio_sync(env: Env, old_str: String, new_str: String, patch: String) -> Result<CompressionRatioJs> {
+  let ratio = into_js(&env, get_compression_ratio(&old_str, &new_str, &patch))?;
+  Ok(CompressionRatioJs {
+    old_size: ratio.old_size as f64,
+    new_size: ratio.new_size as f64,
+    patch_size: ratio.patch_size as f64,
+    ratio: ratio.ratio,
+  })
+}
+
+// ============================================================
+// Process-wide settings
+// ============================================================
+
+/// Cap the worker threads of every parallel diff, including those that set
+/// `threads`. Zero removes the cap.
+#[napi]
+pub fn set_max_threads(threads: u32) {
+  BsdiffRust::set_max_threads(threads as usize);
+}
+
+/// Limit how many diffs run their search at the same time; further diffs wait
+/// for a running one to finish. Zero removes the limit.
+#[napi]
+pub fn set_max_concurrent_diffs(limit: u32) {
+  BsdiffRust::set_max_concurrent_diffs(limit as usize);
+}
+
+// ============================================================
+// Async Task definitions
+// ============================================================
+
+pub struct DiffTask {
+  old_str: String,
+  new_str: String,
+  patch: String,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for DiffTask {
+  type Output = Outcome<()>;
+  type JsValue = ();
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = DiffOptions::default();
+    Ok(BsdiffRust::diff_with_hooks(&self.old_str, &self.new_str, &self.patch, &options, &self.hooks).map(drop))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output)
+  }
+}
+
+pub struct PatchTask {
+  old_str: String,
+  new_str: String,
+  patch: String,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for PatchTask {
+  type Output = Outcome<()>;
+  type JsValue = ();
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    Ok(BsdiffRust::patch_with_hooks(&self.old_str, &self.new_str, &self.patch, &PatchOptions::default(), &self.hooks).map(drop))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output)
+  }
+}
+
+pub struct VerifyPatchTask {
+  old_str: String,
+  new_str: String,
+  patch: String,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for VerifyPatchTask {
+  type Output = Outcome<bool>;
+  type JsValue = bool;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    Ok(verify_patch_with_hooks(&self.old_str, &self.new_str, &self.patch, &self.hooks))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output)
+  }
+}
+
+pub struct VerifyPatchDigestTask {
+  old_str: String,
+  patch: String,
+  expected_digest: String,
+  expected_size: u64,
+  algorithm: utils::DigestAlgorithm,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for VerifyPatchDigestTask {
+  type Output = Outcome<utils::DigestVerification>;
+  type JsValue = DigestVerificationJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    Ok(verify_patch_digest_with_hooks(
+      &self.old_str,
+      &self.patch,
+      &self.expected_digest,
+      self.expected_size,
+      self.algorithm,
+      &self.hooks,
+    ))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct DiffWithStatsTask {
+  old_str: String,
+  new_str: String,
+  patch: String,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for DiffWithStatsTask {
+  type Output = Outcome<bsdiff_rust::PerformanceStats>;
+  type JsValue = PerformanceStatsJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = DiffOptions::default();
+    Ok(BsdiffRust::diff_with_hooks(&self.old_str, &self.new_str, &self.patch, &options, &self.hooks))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct PatchWithStatsTask {
+  old_str: String,
+  new_str: String,
+  patch: String,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for PatchWithStatsTask {
+  type Output = Outcome<bsdiff_rust::PerformanceStats>;
+  type JsValue = PerformanceStatsJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    Ok(BsdiffRust::patch_with_hooks(&self.old_str, &self.new_str, &self.patch, &PatchOptions::default(), &self.hooks))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct DiffWithOptionsTask {
+  old_str: String,
+  new_str: String,
+  patch: String,
+  options: Outcome<DiffOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for DiffWithOptionsTask {
+  type Output = Outcome<()>;
+  type JsValue = ();
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = take_options(&mut self.options);
+    Ok(options.and_then(|options| BsdiffRust::diff_with_hooks(&self.old_str, &self.new_str, &self.patch, &options, &self.hooks).map(drop)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output)
+  }
+}
+
+pub struct PatchWithOptionsTask {
+  old_str: String,
+  new_str: String,
+  patch: String,
+  options: Outcome<PatchOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for PatchWithOptionsTask {
+  type Output = Outcome<()>;
+  type JsValue = ();
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = take_options(&mut self.options);
+    Ok(options.and_then(|options| BsdiffRust::patch_with_hooks(&self.old_str, &self.new_str, &self.patch, &options, &self.hooks).map(drop)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output)
+  }
+}
+
+pub struct DiffBuffersTask {
+  old_buf: Buffer,
+  new_buf: Buffer,
+  options: Outcome<DiffOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for DiffBuffersTask {
+  type Output = Outcome<Vec<u8>>;
+  type JsValue = Buffer;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = take_options(&mut self.options);
+    Ok(options.and_then(|options| BsdiffRust::diff_buffers_with_hooks(&self.old_buf, &self.new_buf, &options, &self.hooks)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct PatchBuffersTask {
+  old_buf: Buffer,
+  patch_buf: Buffer,
+  options: Outcome<PatchOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for PatchBuffersTask {
+  type Output = Outcome<Vec<u8>>;
+  type JsValue = Buffer;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = take_options(&mut self.options);
+    Ok(options.and_then(|options| BsdiffRust::patch_buffers_with_hooks(&self.old_buf, &self.patch_buf, &options, &self.hooks)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct PatchStreamingTask {
+  old_str: String,
+  new_str: String,
+  patch: String,
+  options: Outcome<PatchOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for PatchStreamingTask {
+  type Output = Outcome<bsdiff_rust::PerformanceStats>;
+  type JsValue = PerformanceStatsJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = take_options(&mut self.options);
+    Ok(options.and_then(|options| BsdiffRust::patch_streaming(&self.old_str, &self.new_str, &self.patch, &options, &self.hooks)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct PatchToWritableTask {
+  old_str: String,
+  patch: String,
+  writable: Option<JsWritable>,
+  options: Outcome<PatchOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for PatchToWritableTask {
+  type Output = Outcome<bsdiff_rust::PerformanceStats>;
+  type JsValue = PerformanceStatsJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    // Dropping the writer releases the threadsafe function once streaming ends.
+    let writable = self.writable.take().ok_or_else(|| Error::from_reason("Writable already consumed"))?;
+    let options = take_options(&mut self.options);
+    Ok(options.and_then(|options| BsdiffRust::patch_file_to_writer(&self.old_str, &self.patch, writable, &options, &self.hooks)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct ComposePatchesTask {
+  base_str: String,
+  patches: Vec<String>,
+  patch: String,
+  options: Outcome<DiffOptions>,
+  patch_options: Outcome<PatchOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for ComposePatchesTask {
+  type Output = Outcome<bsdiff_rust::PerformanceStats>;
+  type JsValue = PerformanceStatsJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let patches: Vec<&str> = self.patches.iter().map(String::as_str).collect();
+    let (options, patch_options) = (take_options(&mut self.options), take_options(&mut self.patch_options));
+    Ok(options.and_then(|options| BsdiffRust::compose_patches(&self.base_str, &patches, &self.patch, &options, &patch_options?, &self.hooks)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct ApplyChainTask {
+  old_str: String,
+  patches: Vec<String>,
+  new_str: String,
+  options: Outcome<PatchOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for ApplyChainTask {
+  type Output = Outcome<bsdiff_rust::PerformanceStats>;
+  type JsValue = PerformanceStatsJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let patches: Vec<&str> = self.patches.iter().map(String::as_str).collect();
+    let options = take_options(&mut self.options);
+    Ok(options.and_then(|options| BsdiffRust::apply_chain(&self.old_str, &patches, &self.new_str, &options, &self.hooks)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct ReversePatchTask {
+  old_str: String,
+  patch: String,
+  reverse: String,
+  options: Outcome<DiffOptions>,
+  patch_options: Outcome<PatchOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for ReversePatchTask {
+  type Output = Outcome<bsdiff_rust::PerformanceStats>;
+  type JsValue = PerformanceStatsJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let (options, patch_options) = (take_options(&mut self.options), take_options(&mut self.patch_options));
+    Ok(options.and_then(|options| BsdiffRust::reverse_patch(&self.old_str, &self.patch, &self.reverse, &options, &patch_options?, &self.hooks)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct DiffWithReverseTask {
+  old_str: String,
+  new_str: String,
+  patch: String,
+  reverse_patch: String,
+  options: Outcome<DiffOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for DiffWithReverseTask {
+  type Output = Outcome<bsdiff_rust::PerformanceStats>;
+  type JsValue = PerformanceStatsJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = take_options(&mut self.options);
+    Ok(options.and_then(|options| BsdiffRust::diff_files_with_reverse(&self.old_str, &self.new_str, &self.patch, &self.reverse_patch, &options, &self.hooks)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct DiffDirectoryTask {
+  old_dir: String,
+  new_dir: String,
+  patch: String,
+  options: Outcome<DiffOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for DiffDirectoryTask {
+  type Output = Outcome<bsdiff_rust::PerformanceStats>;
+  type JsValue = PerformanceStatsJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = take_options(&mut self.options);
+    Ok(options.and_then(|options| BsdiffRust::diff_directory(&self.old_dir, &self.new_dir, &self.patch, &options, &self.hooks)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+pub struct PatchDirectoryTask {
+  old_dir: String,
+  out_dir: String,
+  patch: String,
+  options: Outcome<PatchOptions>,
+  hooks: Hooks,
+}
+
+#[napi]
+impl Task for PatchDirectoryTask {
+  type Output = Outcome<bsdiff_rust::PerformanceStats>;
+  type JsValue = PerformanceStatsJs;
+
+  fn compute(&mut self) -> Result<Self::Output> {
+    let options = take_options(&mut self.options);
+    Ok(options.and_then(|options| BsdiffRust::patch_directory(&self.old_dir, &self.out_dir, &self.patch, &options, &self.hooks)))
+  }
+
+  fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+    into_js(&env, output).map(Into::into)
+  }
+}
+
+// ============================================================
+// Async API exports
+// ============================================================
+
+#[napi]
+pub fn diff(
+  old_str: String,
+  new_str: String,
+  patch: String,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<DiffTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  Ok(AsyncTask::with_optional_signal(DiffTask {
+    old_str,
+    new_str,
+    patch,
+    hooks,
+  }, signal))
+}
+
+#[napi]
+pub fn patch(
+  old_str: String,
+  new_str: String,
+  patch: String,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<PatchTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  Ok(AsyncTask::with_optional_signal(PatchTask {
+    old_str,
+    new_str,
+    patch,
+    hooks,
+  }, signal))
+}
+
+#[napi]
+pub fn verify_patch(
+  old_str: String,
+  new_str: String,
+  patch: String,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<VerifyPatchTask>> {
+  let hooks = hooks_from_js(None, signal.as_ref());
+  Ok(AsyncTask::with_optional_signal(VerifyPatchTask {
+    old_str,
+    new_str,
+    patch,
+    hooks,
+  }, signal))
+}
+
+/// Verify that a patch produces output with the expected digest and size,
+/// without writing it to disk (async).
+#[napi]
+pub fn verify_patch_digest(
+  old_str: String,
+  patch: String,
+  expected_digest: String,
+  expected_size: f64,
+  algorithm: Option<DigestAlgorithm>,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<VerifyPatchDigestTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  Ok(AsyncTask::with_optional_signal(VerifyPatchDigestTask {
+    old_str,
+    patch,
+    expected_digest,
+    expected_size: expected_size as u64,
+    algorithm: algorithm.map(Into::into).unwrap_or(utils::DigestAlgorithm::Sha256),
+    hooks,
+  }, signal))
+}
+
+/// Generate a patch file and return performance statistics (async).
+#[napi]
+pub fn diff_with_stats(
+  old_str: String,
+  new_str: String,
+  patch: String,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<DiffWithStatsTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  Ok(AsyncTask::with_optional_signal(DiffWithStatsTask {
+    old_str,
+    new_str,
+    patch,
+    hooks,
+  }, signal))
+}
+
+/// Apply a patch file and return performance statistics (async).
+#[napi]
+pub fn patch_with_stats(
+  old_str: String,
+  new_str: String,
+  patch: String,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<PatchWithStatsTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  Ok(AsyncTask::with_optional_signal(PatchWithStatsTask {
+    old_str,
+    new_str,
+    patch,
+    hooks,
+  }, signal))
+}
+
+/// Generate a patch file with custom options (async).
+#[napi]
+pub fn diff_with_options(
+  old_str: String,
+  new_str: String,
+  patch: String,
+  options: DiffOptionsJs,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<DiffWithOptionsTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  let opts = DiffOptions::try_from(options);
+  Ok(AsyncTask::with_optional_signal(DiffWithOptionsTask {
+    old_str,
+    new_str,
+    patch,
+    options: opts,
+    hooks,
+  }, signal))
+}
+
+/// Apply a patch file within the given limits (async).
+#[napi]
+pub fn patch_with_options(
+  old_str: String,
+  new_str: String,
+  patch: String,
+  options: PatchOptionsJs,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<PatchWithOptionsTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  let opts = PatchOptions::try_from(options);
+  Ok(AsyncTask::with_optional_signal(PatchWithOptionsTask {
+    old_str,
+    new_str,
+    patch,
+    options: opts,
+    hooks,
+  }, signal))
+}
+
+/// Apply a patch file, streaming the output to disk in bounded chunks (async).
+#[napi]
+pub fn patch_streaming(
+  old_str: String,
+  new_str: String,
+  patch: String,
+  options: Option<PatchOptionsJs>,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<PatchStreamingTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  let opts = patch_options(options);
+  Ok(AsyncTask::with_optional_signal(PatchStreamingTask {
+    old_str,
+    new_str,
+    patch,
+    options: opts,
+    hooks,
+  }, signal))
+}
+
+/// Apply a patch file, writing the output to a Node.js `Writable` in bounded chunks.
+///
+/// Each chunk waits for its `write` callback before the next is produced. The
+/// stream is not ended; call `writable.end()` once the promise resolves.
+#[napi(ts_args_type = "oldStr: string, patch: string, writable: import('stream').Writable, options?: PatchOptionsJs | undefined | null, signal?: AbortSignal | undefined | null")]
+pub fn patch_to_writable(
+  old_str: String,
+  patch: String,
+  writable: Object,
+  options: Option<PatchOptionsJs>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<PatchToWritableTask>> {
+  let writable = JsWritable::new(writable)?;
+  let hooks = hooks_from_js(None, signal.as_ref());
+  let opts = patch_options(options);
+  Ok(AsyncTask::with_optional_signal(PatchToWritableTask {
+    old_str,
+    patch,
+    writable: Some(writable),
+    options: opts,
+    hooks,
+  }, signal))
+}
+
+/// Generate a patch from in-memory buffers (async).
+#[napi]
+pub fn diff_buffers(
+  old_buf: Buffer,
+  new_buf: Buffer,
+  options: Option<DiffOptionsJs>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<DiffBuffersTask>> {
+  let opts = diff_options(options);
+  let hooks = hooks_from_js(None, signal.as_ref());
+  Ok(AsyncTask::with_optional_signal(DiffBuffersTask {
+    old_buf,
+    new_buf,
+    options: opts,
+    hooks,
+  }, signal))
+}
+
+/// Apply a patch to an in-memory buffer (async).
+#[napi]
+pub fn patch_buffers(
+  old_buf: Buffer,
+  patch_buf: Buffer,
+  options: Option<PatchOptionsJs>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<PatchBuffersTask>> {
+  let opts = patch_options(options);
+  let hooks = hooks_from_js(None, signal.as_ref());
+  Ok(AsyncTask::with_optional_signal(PatchBuffersTask {
+    old_buf,
+    patch_buf,
+    options: opts,
+    hooks,
+  }, signal))
+}
+
+/// Compose a chain of patch files into one patch from the base file to the
+/// last version (async). The chain is applied within the limits in
+/// `patch_options`.
+#[napi]
+pub fn compose_patches(
+  base_str: String,
+  patches: Vec<String>,
+  patch: String,
+  options: Option<DiffOptionsJs>,
+  patch_options: Option<PatchOptionsJs>,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<ComposePatchesTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  let opts = diff_options(options);
+  let patch_opts = self::patch_options(patch_options);
+  Ok(AsyncTask::with_optional_signal(ComposePatchesTask {
+    base_str,
+    patches,
+    patch,
+    options: opts,
+    patch_options: patch_opts,
+    hooks,
+  }, signal))
+}
+
+/// Apply a chain of patch files in sequence, keeping intermediate versions in
+/// memory (async).
+#[napi]
+pub fn apply_chain(
+  old_str: String,
+  patches: Vec<String>,
+  new_str: String,
+  options: Option<PatchOptionsJs>,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<ApplyChainTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  let opts = patch_options(options);
+  Ok(AsyncTask::with_optional_signal(ApplyChainTask {
+    old_str,
+    patches,
+    new_str,
+    options: opts,
+    hooks,
+  }, signal))
+}
+
+/// Generate a patch file and the reverse (new to old) patch for rolling
+/// back, and return performance statistics (async).
+#[napi]
+pub fn diff_with_reverse(
+  old_str: String,
+  new_str: String,
+  patch: String,
+  reverse_patch: String,
+  options: Option<DiffOptionsJs>,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<DiffWithReverseTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  let opts = diff_options(options);
+  Ok(AsyncTask::with_optional_signal(DiffWithReverseTask {
+    old_str,
+    new_str,
+    patch,
+    reverse_patch,
+    options: opts,
+    hooks,
+  }, signal))
+}
+
+/// Derive the reverse (new to old) patch of a patch file from the old file,
+/// and write it to `reverse` (async). The forward patch is applied within the
+/// limits in `patch_options`.
+#[napi]
+pub fn reverse_patch(
+  old_str: String,
+  patch: String,
+  reverse: String,
+  options: Option<DiffOptionsJs>,
+  patch_options: Option<PatchOptionsJs>,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<ReversePatchTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  let opts = diff_options(options);
+  let patch_opts = self::patch_options(patch_options);
+  Ok(AsyncTask::with_optional_signal(ReversePatchTask {
+    old_str,
+    patch,
+    reverse,
+    options: opts,
+    patch_options: patch_opts,
+    hooks,
+  }, signal))
+}
+
+/// Diff two directory trees into a single patch file (async).
+#[napi]
+pub fn diff_directory(
+  old_dir: String,
+  new_dir: String,
+  patch: String,
+  options: Option<DiffOptionsJs>,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<DiffDirectoryTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  let opts = diff_options(options);
+  Ok(AsyncTask::with_optional_signal(DiffDirectoryTask {
+    old_dir,
+    new_dir,
+    patch,
+    options: opts,
+    hooks,
+  }, signal))
+}
+
+/// Rebuild the new directory tree of a directory patch into `out_dir`
+/// (async).
+#[napi]
+pub fn patch_directory(
+  old_dir: String,
+  out_dir: String,
+  patch: String,
+  options: Option<PatchOptionsJs>,
+  on_progress: Option<ProgressCallback>,
+  signal: Option<AbortSignal>,
+) -> Result<AsyncTask<PatchDirectoryTask>> {
+  let hooks = hooks_from_js(on_progress, signal.as_ref());
+  let opts = patch_options(options);
+  Ok(AsyncTask::with_optional_signal(PatchDirectoryTask {
+    old_dir,
+    out_dir,
+    patch,
+    options: opts,
+    hooks,
+  }, signal))
+}
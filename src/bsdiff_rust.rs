@@ -1,8 +1,266 @@
-use std::io::Cursor;
-use std::path::Path;
-use std::time::Instant;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 use qbsdiff::{Bsdiff, Bspatch, ParallelScheme};
 use qbsdiff::bsdiff::MAX_LENGTH;
+use memmap2::Mmap;
+
+use crate::archive::{self, ArchivePatch};
+use crate::container::{self, HashingWriter};
+use crate::endsley;
+use crate::filter::{self, FilteredPatch};
+use crate::tree::{self, TreePatch};
+use crate::utils;
+use crate::variant::{self, VariantPatch};
+use crate::vcdiff;
+use crate::windowed::{self, Window, WindowedPatch};
+
+/// Chunk size used when streaming patch output (matches qbsdiff's copy buffer).
+pub const STREAM_CHUNK_SIZE: usize = qbsdiff::bspatch::BUFFER_SIZE;
+
+/// Chunk size used when reading inputs and writing outputs with progress.
+const IO_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Largest output buffer reserved up front from a patch header. Bigger
+/// targets grow the buffer as data arrives, so a forged size costs nothing
+/// until the patch actually produces that much data.
+const MAX_PREALLOCATION: u64 = 256 * 1024 * 1024;
+
+/// Smallest parallel chunk accepted by `DiffOptions::parallel_chunk_size`.
+/// qbsdiff silently raises smaller chunks to this size to keep matches long.
+const MIN_PARALLEL_CHUNK_SIZE: u64 = 256 * 1024;
+
+/// Smallest delta buffer accepted by `DiffOptions::buffer_size`, below which
+/// qbsdiff silently raises it.
+const MIN_BUFFER_SIZE: usize = 128;
+
+/// Process-wide cap on worker threads per parallel diff; zero means one per core.
+static MAX_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Diffs currently searching, limited by `BsdiffRust::set_max_concurrent_diffs`.
+static DIFF_SLOTS: DiffSlots = DiffSlots::new();
+
+/// Phase of a diff or patch operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading input files.
+    Reading,
+    /// Suffix sorting, matching and bzip2 compression. qbsdiff runs these as
+    /// one call with no progress of its own, so a diff reports this phase
+    /// when it starts and finishes, and after each window or file when it
+    /// diffs in several parts.
+    Diffing,
+    /// Converting qbsdiff's output to another format or compression, counted
+    /// in bytes of the new data. Skipped for bzip2 BSDIFF40 patches.
+    Encoding,
+    /// Applying the patch to the old data.
+    Patching,
+    /// Writing the output file.
+    Writing,
+}
+
+/// A progress update for the current phase.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// Phase the operation is in.
+    pub phase: Phase,
+    /// Bytes processed so far in this phase.
+    pub bytes_processed: u64,
+    /// Total bytes expected in this phase.
+    pub total_bytes: u64,
+}
+
+/// Error returned when an operation is cancelled through its `Hooks`.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Operation was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Whether `err` is a `Cancelled` error, either directly or wrapped in an I/O error.
+pub fn is_cancelled(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.is::<Cancelled>()
+        || err
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+            .is_some_and(|inner| inner.is::<Cancelled>())
+}
+
+/// Error returned by diff and patch operations.
+#[derive(Debug)]
+pub enum BsdiffError {
+    /// An input file does not exist.
+    NotFound { what: &'static str, path: String },
+    /// An input exceeds a size limit.
+    TooLarge { what: &'static str, path: Option<String>, size: u64, limit: u64 },
+    /// The patch is malformed or was not made for this old file.
+    CorruptPatch { reason: String },
+    /// Reading or writing failed.
+    Io { path: Option<String>, source: std::io::Error },
+    /// The operation was cancelled through its `Hooks`.
+    Cancelled,
+    /// Data does not match its expected digest.
+    ChecksumMismatch { what: &'static str, expected: String, actual: String },
+    /// A patch needs more than a limit set in `PatchOptions` allows.
+    LimitExceeded { limit_name: &'static str, size: u64, limit: u64 },
+    /// A patch produces a different amount of data than `PatchOptions::expected_target_size`.
+    SizeMismatch { expected: u64, actual: u64 },
+    /// An option is out of range.
+    InvalidOption { name: &'static str, reason: String },
+}
+
+impl BsdiffError {
+    /// Stable code identifying the kind of error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound { .. } => "ERR_NOT_FOUND",
+            Self::TooLarge { .. } => "ERR_TOO_LARGE",
+            Self::CorruptPatch { .. } => "ERR_CORRUPT_PATCH",
+            Self::Io { .. } => "ERR_IO",
+            Self::Cancelled => "ERR_CANCELLED",
+            Self::ChecksumMismatch { .. } => "ERR_CHECKSUM_MISMATCH",
+            Self::LimitExceeded { .. } => "ERR_LIMIT_EXCEEDED",
+            Self::SizeMismatch { .. } => "ERR_SIZE_MISMATCH",
+            Self::InvalidOption { .. } => "ERR_INVALID_OPTION",
+        }
+    }
+
+    /// File the error refers to, if any.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Self::NotFound { path, .. } => Some(path),
+            Self::TooLarge { path, .. } | Self::Io { path, .. } => path.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Wrap an I/O error raised while accessing `path`.
+    pub fn io(path: impl AsRef<Path>, source: std::io::Error) -> Self {
+        if source.kind() == std::io::ErrorKind::NotFound {
+            return Self::NotFound { what: "File", path: path.as_ref().display().to_string() };
+        }
+        Self::from(source).with_path(path)
+    }
+
+    /// Attach `path` to an I/O error that does not name a file yet.
+    ///
+    /// Paths that are not valid UTF-8 are kept lossily, as the message only.
+    pub fn with_path(self, path: impl AsRef<Path>) -> Self {
+        match self {
+            Self::Io { path: None, source } => Self::Io { path: Some(path.as_ref().display().to_string()), source },
+            other => other,
+        }
+    }
+}
+
+impl std::fmt::Display for BsdiffError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound { what, path } => write!(f, "{} not found: {}", what, path),
+            Self::TooLarge { what, size, limit, .. } => {
+                write!(f, "{} too large: {} bytes (max: {} bytes)", what, size, limit)
+            }
+            Self::CorruptPatch { reason } => write!(f, "Corrupt patch: {}", reason),
+            Self::Io { path: Some(path), source } => write!(f, "{}: {}", source, path),
+            Self::Io { path: None, source } => write!(f, "{}", source),
+            Self::Cancelled => write!(f, "{}", Cancelled),
+            Self::ChecksumMismatch { what, expected, actual } => {
+                write!(f, "{} checksum mismatch: expected {}, got {}", what, expected, actual)
+            }
+            Self::LimitExceeded { limit_name, size, limit } => {
+                write!(f, "Patch exceeds {}: needs {} bytes (limit: {} bytes)", limit_name, size, limit)
+            }
+            Self::SizeMismatch { expected, actual } => {
+                write!(f, "Target size mismatch: expected {} bytes, got {} bytes", expected, actual)
+            }
+            Self::InvalidOption { name, reason } => write!(f, "Invalid option {}: {}", name, reason),
+        }
+    }
+}
+
+impl std::error::Error for BsdiffError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BsdiffError {
+    fn from(source: std::io::Error) -> Self {
+        if is_cancelled(&source) {
+            return Self::Cancelled;
+        }
+        // Writer adapters report limit violations through `io::Error::other`
+        if source.get_ref().is_some_and(|inner| inner.is::<BsdiffError>()) {
+            if let Ok(err) = source.into_inner().unwrap().downcast::<BsdiffError>() {
+                return *err;
+            }
+            unreachable!();
+        }
+        Self::Io { path: None, source }
+    }
+}
+
+impl From<Cancelled> for BsdiffError {
+    fn from(_: Cancelled) -> Self {
+        Self::Cancelled
+    }
+}
+
+/// Per-call hooks for long-running operations.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    on_progress: Option<Arc<dyn Fn(Progress) + Send + Sync>>,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Hooks {
+    /// Report progress to `callback`. It is called from the worker thread.
+    pub fn with_progress<F: Fn(Progress) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Cancel the operation once `flag` is set.
+    ///
+    /// The flag is checked between phases and between I/O chunks. qbsdiff's
+    /// diffing step cannot be interrupted, so a diff stops once it returns.
+    pub fn with_cancel_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    /// Fail with `Cancelled` if cancellation was requested.
+    pub fn check_cancelled(&self) -> std::io::Result<()> {
+        match &self.cancel {
+            Some(flag) if flag.load(Ordering::Relaxed) => Err(std::io::Error::other(Cancelled)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The same cancellation flag, without the progress callback, for steps
+    /// of an operation that reports progress of its own.
+    pub(crate) fn without_progress(&self) -> Hooks {
+        Hooks { on_progress: None, cancel: self.cancel.clone() }
+    }
+
+    pub(crate) fn report(&self, phase: Phase, bytes_processed: u64, total_bytes: u64) {
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(Progress { phase, bytes_processed, total_bytes });
+        }
+    }
+}
 
 /// Performance statistics.
 #[derive(Debug, Clone)]
@@ -17,31 +275,255 @@ pub struct PerformanceStats {
     pub patch_size: u64,
     /// Compression ratio as a percentage.
     pub compression_ratio: f64,
+    /// Peak memory held by the operation's buffers in bytes, the sum of
+    /// `heap_memory` and `mapped_memory`. It is worked out from the sizes of
+    /// the buffers each step holds, not measured, so allocator overhead and
+    /// memory held by dependencies are not included.
+    pub estimated_peak_memory: u64,
+    /// Part of `estimated_peak_memory` allocated on the heap.
+    pub heap_memory: u64,
+    /// Part of `estimated_peak_memory` taken by memory-mapped input files.
+    pub mapped_memory: u64,
+}
+
+/// Compression of the blocks of a generated patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PatchCompression {
+    /// bzip2, written as standard BSDIFF40.
+    #[default]
+    Bzip2,
+    /// zstd, at levels 3 to 19.
+    Zstd,
+    /// xz (LZMA2), at presets 1 to 9.
+    Xz,
+    /// brotli, at qualities 3 to 11.
+    Brotli,
+    /// Uncompressed blocks, for patches that are compressed in transit.
+    None,
+}
+
+/// Stream format of a generated patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Classic BSDIFF40, as written by Colin Percival's bsdiff.
+    #[default]
+    Bsdiff40,
+    /// ENDSLEY/BSDIFF43, as written by mendsley/bsdiff and the `bsdiff` crate.
+    Endsley,
+    /// VCDIFF (RFC 3284), as read by xdelta3 and open-vcdiff.
+    Vcdiff,
+}
+
+/// Conversion of branch targets in executable code before diffing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExecutableFilter {
+    /// Diff the inputs as they are.
+    #[default]
+    None,
+    /// x86 or ARM64, as read from the ELF, PE or Mach-O headers of both
+    /// files; anything else is diffed as it is.
+    Auto,
+    /// x86 and x86-64 CALL and JMP targets.
+    X86,
+    /// ARM64 BL and ADRP targets.
+    Arm64,
 }
 
 /// Diff configuration options.
 #[derive(Debug, Clone)]
 pub struct DiffOptions {
-    /// Compression level (0-9).
+    /// Compression level (1-9).
     pub compression_level: u32,
+    /// Compression of the patch blocks. Anything but bzip2 produces a variant
+    /// patch, which only this library can apply.
+    pub compression: PatchCompression,
+    /// Stream format of the patch. ENDSLEY/BSDIFF43 is always compressed
+    /// with bzip2 and VCDIFF is not compressed, so neither can be combined
+    /// with another `compression`.
+    pub format: OutputFormat,
     /// Whether to enable parallel processing.
     pub enable_parallel: bool,
+    /// Wrap the patch in a container recording the SHA-256 and size of the
+    /// old and new data, so patching can reject the wrong base file.
+    pub container: bool,
+    /// Split the new data into windows of this many bytes and diff each one
+    /// against a matching region of the old data, producing a windowed patch.
+    ///
+    /// Lifts the `MAX_LENGTH` limit on both inputs; each window indexes at
+    /// most `2 * window_size` bytes of the old data.
+    pub window_size: Option<u64>,
+    /// Memory-map the input files instead of reading them onto the heap.
+    pub mmap: bool,
+    /// Exact matches shorter than this many bytes are encoded as diff data
+    /// instead. Lower values find more matches at the cost of time; zero
+    /// keeps every match. Defaults to qbsdiff's `SMALL_MATCH` (12).
+    pub small_match: Option<usize>,
+    /// Size of the buffer used to compute delta data, at least 128 bytes.
+    /// Defaults to qbsdiff's `BUFFER_SIZE` (4096).
+    pub buffer_size: Option<usize>,
+    /// Search the new data in parallel chunks of this many bytes, at least
+    /// 256 KiB. Requires `enable_parallel`.
+    pub parallel_chunk_size: Option<u64>,
+    /// Search the new data in at most this many parallel jobs. Requires
+    /// `enable_parallel`; cannot be combined with `parallel_chunk_size`.
+    pub parallel_jobs: Option<usize>,
+    /// Worker threads for this diff's own thread pool, capped by
+    /// `BsdiffRust::set_max_threads`. Defaults to that cap, or one per core.
+    pub threads: Option<usize>,
+    /// Diff ZIP archives entry by entry: changed entries are inflated and
+    /// diffed uncompressed, then deflated again with the same zlib settings
+    /// when the patch is applied. Entries whose compressed bytes cannot be
+    /// reproduced exactly are diffed as they are, and inputs that are not ZIP
+    /// archives get an ordinary patch.
+    pub archive: bool,
+    /// Convert relative branch targets in the code sections of executables
+    /// to absolute ones before diffing, and back when patching, so code that
+    /// moves costs less. The filter is recorded in the patch.
+    pub filter: ExecutableFilter,
 }
 
 impl Default for DiffOptions {
     fn default() -> Self {
         Self {
             compression_level: 6,
+            compression: PatchCompression::Bzip2,
+            format: OutputFormat::Bsdiff40,
             enable_parallel: true,
+            container: false,
+            window_size: None,
+            mmap: false,
+            small_match: None,
+            buffer_size: None,
+            parallel_chunk_size: None,
+            parallel_jobs: None,
+            threads: None,
+            archive: false,
+            filter: ExecutableFilter::None,
+        }
+    }
+}
+
+impl DiffOptions {
+    /// Fail with `InvalidOption` if a setting is out of range or conflicts
+    /// with another.
+    pub fn validate(&self) -> Result<(), BsdiffError> {
+        let invalid = |name, reason: String| Err(BsdiffError::InvalidOption { name, reason });
+        if !(1..=9).contains(&self.compression_level) {
+            return invalid("compressionLevel", format!("{} is not between 1 and 9", self.compression_level));
+        }
+        match (self.format, self.compression) {
+            (OutputFormat::Bsdiff40, _) | (_, PatchCompression::Bzip2) => {}
+            (OutputFormat::Endsley, _) => {
+                return invalid("compression", "the endsley format is always compressed with bzip2".into());
+            }
+            (OutputFormat::Vcdiff, _) => return invalid("compression", "the vcdiff format is not compressed".into()),
+        }
+        if let Some(buffer_size) = self.buffer_size.filter(|&size| size < MIN_BUFFER_SIZE) {
+            return invalid("bufferSize", format!("{} is smaller than {} bytes", buffer_size, MIN_BUFFER_SIZE));
+        }
+        if let Some(chunk_size) = self.parallel_chunk_size {
+            if !(MIN_PARALLEL_CHUNK_SIZE..=MAX_LENGTH as u64).contains(&chunk_size) {
+                return invalid(
+                    "parallelChunkSize",
+                    format!("{} is not between {} and {} bytes", chunk_size, MIN_PARALLEL_CHUNK_SIZE, MAX_LENGTH),
+                );
+            }
+        }
+        if self.archive && self.filter != ExecutableFilter::None {
+            return invalid("filter", "cannot be combined with archive".into());
+        }
+        if self.parallel_jobs == Some(0) {
+            return invalid("parallelJobs", "must be at least 1".into());
+        }
+        if self.threads == Some(0) {
+            return invalid("threads", "must be at least 1".into());
+        }
+        if self.parallel_chunk_size.is_some() && self.parallel_jobs.is_some() {
+            return invalid("parallelJobs", "cannot be combined with parallelChunkSize".into());
+        }
+        if !self.enable_parallel {
+            if self.parallel_chunk_size.is_some() {
+                return invalid("parallelChunkSize", "requires enableParallel".into());
+            }
+            if self.parallel_jobs.is_some() {
+                return invalid("parallelJobs", "requires enableParallel".into());
+            }
+            if self.threads.is_some() {
+                return invalid("threads", "requires enableParallel".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The qbsdiff parallel scheme selected by these options.
+    fn parallel_scheme(&self) -> ParallelScheme {
+        match (self.enable_parallel, self.parallel_chunk_size, self.parallel_jobs) {
+            (false, _, _) => ParallelScheme::Never,
+            (true, Some(chunk_size), _) => ParallelScheme::ChunkSize(chunk_size as usize),
+            (true, None, Some(jobs)) => ParallelScheme::NumJobs(jobs),
+            (true, None, None) => ParallelScheme::Auto,
+        }
+    }
+}
+
+/// Limits for applying patches from untrusted sources.
+///
+/// The target size recorded in the patch header is checked against these
+/// limits before any output is allocated, and the output is cut off as soon
+/// as it grows past that size.
+#[derive(Debug, Clone, Default)]
+pub struct PatchOptions {
+    /// Largest output the patch may produce, in bytes.
+    pub max_target_size: Option<u64>,
+    /// Largest estimated peak memory (inputs plus output buffers), in bytes.
+    pub max_memory: Option<u64>,
+    /// Exact output size the patch must produce, in bytes.
+    pub expected_target_size: Option<u64>,
+    /// Memory-map the old and patch files instead of reading them onto the
+    /// heap. Mapped files still count towards `max_memory`, since their pages
+    /// become resident as they are read.
+    pub mmap: bool,
+}
+
+impl PatchOptions {
+    /// Check the target size declared by a patch header.
+    fn check_target_size(&self, target_size: u64) -> Result<(), BsdiffError> {
+        if let Some(limit) = self.max_target_size.filter(|&limit| target_size > limit) {
+            return Err(BsdiffError::LimitExceeded { limit_name: "maxTargetSize", size: target_size, limit });
+        }
+        self.check_written(target_size)
+    }
+
+    /// Check an estimate of the memory an operation needs.
+    fn check_memory(&self, estimate: u64) -> Result<(), BsdiffError> {
+        match self.max_memory {
+            Some(limit) if estimate > limit => {
+                Err(BsdiffError::LimitExceeded { limit_name: "maxMemory", size: estimate, limit })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the number of bytes a patch produced.
+    fn check_written(&self, written: u64) -> Result<(), BsdiffError> {
+        match self.expected_target_size {
+            Some(expected) if written != expected => Err(BsdiffError::SizeMismatch { expected, actual: written }),
+            _ => Ok(()),
         }
     }
 }
 
+/// Diff and patch operations.
+///
+/// File-based calls read their inputs, write their output atomically and
+/// return `PerformanceStats`; `_buffers` calls work on slices in memory. The
+/// `_with_hooks` variants report progress and honour cancellation through
+/// `Hooks`.
 pub struct BsdiffRust;
 
 impl BsdiffRust {
     /// Generate a standard BSDIFF40 format patch file.
-    pub fn diff(old_file: &str, new_file: &str, patch_file: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn diff(old_file: &str, new_file: &str, patch_file: &str) -> Result<(), BsdiffError> {
         Self::diff_with_options(old_file, new_file, patch_file, &DiffOptions::default())
     }
 
@@ -51,42 +533,83 @@ impl BsdiffRust {
         new_file: &str, 
         patch_file: &str,
         options: &DiffOptions
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // Validate input files
-        if !Path::new(old_file).exists() {
-            return Err(format!("Old file not found: {}", old_file).into());
-        }
-        if !Path::new(new_file).exists() {
-            return Err(format!("New file not found: {}", new_file).into());
-        }
+    ) -> Result<(), BsdiffError> {
+        Self::diff_with_hooks(old_file, new_file, patch_file, options, &Hooks::default()).map(drop)
+    }
 
-        let old_data = std::fs::read(old_file)?;
-        let new_data = std::fs::read(new_file)?;
+    /// Generate a BSDIFF40 patch from in-memory buffers.
+    ///
+    /// The patch is windowed if `options.window_size` is set and wrapped in a
+    /// container if `options.container` is set.
+    pub fn diff_buffers(
+        old_data: &[u8],
+        new_data: &[u8],
+        options: &DiffOptions
+    ) -> Result<Vec<u8>, BsdiffError> {
+        Self::diff_buffers_with_hooks(old_data, new_data, options, &Hooks::default())
+    }
 
-        // Check file size limit
-        if old_data.len() > MAX_LENGTH {
-            return Err(format!(
-                "Old file too large: {} bytes (max: {} bytes)", 
-                old_data.len(), 
-                MAX_LENGTH
-            ).into());
-        }
+    /// Generate a patch from in-memory buffers, reporting progress through
+    /// `hooks`.
+    pub fn diff_buffers_with_hooks(
+        old_data: &[u8],
+        new_data: &[u8],
+        options: &DiffOptions,
+        hooks: &Hooks
+    ) -> Result<Vec<u8>, BsdiffError> {
+        options.validate()?;
+        let new_size = new_data.len() as u64;
 
-        let parallel_scheme = if options.enable_parallel {
-            ParallelScheme::Auto
-        } else {
-            ParallelScheme::Never
-        };
+        // qbsdiff sorts, matches and compresses in one call, so only its
+        // boundaries, or those of each window, can be reported. Converting its
+        // output reports `Phase::Encoding` as it goes.
+        hooks.check_cancelled()?;
+        let _slot = DIFF_SLOTS.acquire(hooks)?;
+        hooks.report(Phase::Diffing, 0, new_size);
+        let patch_data = in_thread_pool(options, || {
+            let plan = match options.archive {
+                true => archive::Plan::new(old_data, new_data, options.enable_parallel, hooks)?,
+                false => None,
+            };
+            match plan {
+                // Windows report progress over the expanded files, which the
+                // caller never sees
+                Some(plan) => {
+                    let expanded_old = plan.expand_old(old_data)?;
+                    let expanded_new = plan.expand_new(new_data)?;
+                    let patch = diff_payload(&expanded_old, &expanded_new, options, &hooks.without_progress())?;
+                    Ok(plan.encode(old_data, new_data, &patch))
+                }
+                None => match filter::Plan::new(options.filter, old_data, new_data) {
+                    Some(plan) => {
+                        let (filtered_old, filtered_new) = plan.filter(old_data, new_data);
+                        let patch = diff_payload(&filtered_old, &filtered_new, options, hooks)?;
+                        Ok(plan.encode(old_data, new_data, &patch))
+                    }
+                    None => diff_payload(old_data, new_data, options, hooks),
+                },
+            }
+        })?;
+        hooks.report(Phase::Diffing, new_size, new_size);
+        hooks.check_cancelled()?;
 
-        let mut patch_data = Vec::new();
-        Bsdiff::new(&old_data, &new_data)
-            .compression_level(options.compression_level)
-            .parallel_scheme(parallel_scheme)
-            .compare(Cursor::new(&mut patch_data))?;
+        if options.container {
+            return Ok(container::wrap(old_data, new_data, &patch_data));
+        }
+        Ok(patch_data)
+    }
 
-        std::fs::write(patch_file, patch_data)?;
+    /// Cap the worker threads of every parallel diff in this process, including
+    /// those that set `DiffOptions::threads`. Zero removes the cap.
+    pub fn set_max_threads(threads: usize) {
+        MAX_THREADS.store(threads, Ordering::Relaxed);
+    }
 
-        Ok(())
+    /// Limit how many diffs run their search at the same time. Further diffs
+    /// wait, still honouring cancellation, until a running one finishes. Zero
+    /// removes the limit.
+    pub fn set_max_concurrent_diffs(limit: usize) {
+        DIFF_SLOTS.set_limit(limit);
     }
 
     /// Generate a patch file and return performance statistics.
@@ -94,7 +617,7 @@ impl BsdiffRust {
         old_file: &str, 
         new_file: &str, 
         patch_file: &str
-    ) -> Result<PerformanceStats, Box<dyn std::error::Error>> {
+    ) -> Result<PerformanceStats, BsdiffError> {
         Self::diff_with_options_and_stats(old_file, new_file, patch_file, &DiffOptions::default())
     }
 
@@ -104,58 +627,147 @@ impl BsdiffRust {
         new_file: &str, 
         patch_file: &str,
         options: &DiffOptions
-    ) -> Result<PerformanceStats, Box<dyn std::error::Error>> {
-        let start = Instant::now();
+    ) -> Result<PerformanceStats, BsdiffError> {
+        Self::diff_with_hooks(old_file, new_file, patch_file, options, &Hooks::default())
+    }
 
-        // Perform diff
-        Self::diff_with_options(old_file, new_file, patch_file, options)?;
+    /// Generate a patch file with custom options, reporting progress through
+    /// `hooks`, and return performance statistics.
+    pub fn diff_with_hooks(
+        old_file: &str,
+        new_file: &str,
+        patch_file: &str,
+        options: &DiffOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        Self::diff_files(old_file, new_file, patch_file, options, hooks)
+    }
 
-        let elapsed = start.elapsed();
+    /// Generate a patch file from paths of any form, including ones that are
+    /// not valid UTF-8, and return performance statistics.
+    pub fn diff_files(
+        old_file: impl AsRef<Path>,
+        new_file: impl AsRef<Path>,
+        patch_file: impl AsRef<Path>,
+        options: &DiffOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        diff_files_to(old_file.as_ref(), new_file.as_ref(), patch_file.as_ref(), None, options, hooks)
+    }
 
-        // Collect statistics
-        let old_size = std::fs::metadata(old_file)?.len();
-        let new_size = std::fs::metadata(new_file)?.len();
-        let patch_size = std::fs::metadata(patch_file)?.len();
+    /// Generate a patch file and, with the same options, the reverse (new to
+    /// old) patch at `reverse_file`, so an update can be rolled back. Both
+    /// patches are written in full to temporary files before either replaces
+    /// its destination, so a failed diff or write, or cancellation, leaves
+    /// both destinations untouched.
+    pub fn diff_files_with_reverse(
+        old_file: impl AsRef<Path>,
+        new_file: impl AsRef<Path>,
+        patch_file: impl AsRef<Path>,
+        reverse_file: impl AsRef<Path>,
+        options: &DiffOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        let reverse_file = Some(reverse_file.as_ref());
+        diff_files_to(old_file.as_ref(), new_file.as_ref(), patch_file.as_ref(), reverse_file, options, hooks)
+    }
 
-        let compression_ratio = if old_size + new_size > 0 {
-            (patch_size as f64 / (old_size + new_size) as f64) * 100.0
-        } else {
-            0.0
-        };
+    /// Generate a patch from in-memory buffers and write it to `writer`,
+    /// reporting `Phase::Writing` progress through `hooks`.
+    ///
+    /// Returns the number of bytes written.
+    pub fn diff_to_writer<W: Write>(
+        old_data: &[u8],
+        new_data: &[u8],
+        writer: W,
+        options: &DiffOptions,
+        hooks: &Hooks
+    ) -> Result<u64, BsdiffError> {
+        let patch_data = Self::diff_buffers_with_hooks(old_data, new_data, options, hooks)?;
+        let mut writer = ProgressWriter::new(writer, hooks, Phase::Writing, patch_data.len() as u64);
+        for chunk in patch_data.chunks(IO_CHUNK_SIZE) {
+            writer.write_all(chunk)?;
+        }
+        writer.flush()?;
+        Ok(patch_data.len() as u64)
+    }
 
-        Ok(PerformanceStats {
-            elapsed_ms: elapsed.as_millis() as u64,
-            old_size,
-            new_size,
-            patch_size,
-            compression_ratio,
-        })
+    /// Generate a patch from two readers, such as open files, sockets or
+    /// cursors, write it to `writer` and return performance statistics.
+    ///
+    /// Both inputs are read to the end first, since the diff needs all of
+    /// them at once. Their length is unknown up front, so `Phase::Reading`
+    /// is not reported, but cancellation is checked between chunks.
+    pub fn diff_readers<R: Read, S: Read, W: Write>(
+        old: R,
+        new: S,
+        writer: W,
+        options: &DiffOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        options.validate()?;
+
+        let start = Instant::now();
+
+        let old_data = read_stream(old, None, hooks)?;
+        let new_data = read_stream(new, None, hooks)?;
+        let patch_size = Self::diff_to_writer(&old_data, &new_data, writer, options, hooks)?;
+
+        let old_size = old_data.len() as u64;
+        let new_size = new_data.len() as u64;
+        // Both inputs, the u32 suffix array over the indexed old data, and the patch
+        let indexed = options.window_size.map_or(old_size, |w| windowed::region_len(w).min(old_size));
+        let estimated_peak_memory = old_size + new_size + 4 * (indexed + 1) + patch_size;
+
+        Ok(Self::collect_stats(start, old_size, new_size, patch_size, estimated_peak_memory, 0))
     }
 
     /// Apply a standard BSDIFF40 format patch file.
-    pub fn patch(old_file: &str, new_file: &str, patch_file: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Validate input files
-        if !Path::new(old_file).exists() {
-            return Err(format!("Old file not found: {}", old_file).into());
-        }
-        if !Path::new(patch_file).exists() {
-            return Err(format!("Patch file not found: {}", patch_file).into());
-        }
+    ///
+    /// Container patches are accepted too; their checksums are verified
+    /// against the old file before patching and against the output after.
+    pub fn patch(old_file: &str, new_file: &str, patch_file: &str) -> Result<(), BsdiffError> {
+        Self::patch_with_options(old_file, new_file, patch_file, &PatchOptions::default())
+    }
 
-        // Read files
-        let old_data = std::fs::read(old_file)?;
-        let patch_data = std::fs::read(patch_file)?;
+    /// Apply a patch file within the limits set in `options`.
+    pub fn patch_with_options(
+        old_file: &str,
+        new_file: &str,
+        patch_file: &str,
+        options: &PatchOptions
+    ) -> Result<(), BsdiffError> {
+        Self::patch_with_hooks(old_file, new_file, patch_file, options, &Hooks::default()).map(drop)
+    }
 
-        // Apply patch with pre-allocated buffer for better performance
-        let patcher = Bspatch::new(&patch_data)?;
-        // Pre-allocate target size to reduce memory reallocations
-        let mut new_data = Vec::with_capacity(patcher.hint_target_size() as usize);
-        patcher.apply(&old_data, Cursor::new(&mut new_data))?;
+    /// Apply a BSDIFF40 patch to an in-memory buffer within the limits set in `options`.
+    pub fn patch_buffers(
+        old_data: &[u8],
+        patch_data: &[u8],
+        options: &PatchOptions
+    ) -> Result<Vec<u8>, BsdiffError> {
+        Self::patch_buffers_with_hooks(old_data, patch_data, options, &Hooks::default())
+    }
 
-        // Write output file
-        std::fs::write(new_file, new_data)?;
+    /// Apply a BSDIFF40 patch to an in-memory buffer within the limits set in
+    /// `options`, reporting progress through `hooks`.
+    pub fn patch_buffers_with_hooks(
+        old_data: &[u8],
+        patch_data: &[u8],
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<Vec<u8>, BsdiffError> {
+        let target_size = target_size_hint(patch_data)?;
+        options.check_target_size(target_size)?;
+        // Old data, patch and the fully buffered new data
+        options.check_memory((old_data.len() as u64 + patch_data.len() as u64).saturating_add(target_size))?;
 
-        Ok(())
+        // Pre-allocate to reduce reallocations, but never trust the header with more than the cap
+        let mut new_data = Vec::with_capacity(target_size.min(MAX_PREALLOCATION) as usize);
+        let output = ProgressWriter::new(Cursor::new(&mut new_data), hooks, Phase::Patching, target_size);
+        apply_verified(old_data, patch_data, output, None, options)?;
+
+        Ok(new_data)
     }
 
     /// Apply a patch file and return performance statistics.
@@ -163,49 +775,1420 @@ impl BsdiffRust {
         old_file: &str, 
         new_file: &str, 
         patch_file: &str
-    ) -> Result<PerformanceStats, Box<dyn std::error::Error>> {
+    ) -> Result<PerformanceStats, BsdiffError> {
+        Self::patch_with_hooks(old_file, new_file, patch_file, &PatchOptions::default(), &Hooks::default())
+    }
+
+    /// Apply a patch file within the limits set in `options`, reporting
+    /// progress through `hooks`, and return performance statistics.
+    pub fn patch_with_hooks(
+        old_file: &str,
+        new_file: &str,
+        patch_file: &str,
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        Self::patch_files(old_file, new_file, patch_file, options, hooks)
+    }
+
+    /// Apply a patch file given paths of any form, including ones that are
+    /// not valid UTF-8, and return performance statistics.
+    pub fn patch_files(
+        old_file: impl AsRef<Path>,
+        new_file: impl AsRef<Path>,
+        patch_file: impl AsRef<Path>,
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        let (old_file, patch_file) = (old_file.as_ref(), patch_file.as_ref());
+        // Validate input files
+        check_exists("Old file", old_file)?;
+        check_exists("Patch file", patch_file)?;
+
+        let start = Instant::now();
+
+        let [old_data, patch_data] = read_inputs([old_file, patch_file], options.max_memory, options.mmap, hooks)?;
+        let new_data = Self::patch_buffers_with_hooks(&old_data, &patch_data, options, hooks)?;
+
+        write_output(new_file.as_ref(), &new_data, hooks)?;
+
+        let old_size = old_data.len() as u64;
+        let new_size = new_data.len() as u64;
+        let patch_size = patch_data.len() as u64;
+        // Old file, patch and the fully buffered new file
+        let estimated_peak_memory = old_size + patch_size + new_size;
+        let mapped_memory = old_data.mapped_len() + patch_data.mapped_len();
+
+        Ok(Self::collect_stats(start, old_size, new_size, patch_size, estimated_peak_memory, mapped_memory))
+    }
+
+    /// Apply a patch read from `patch` to the old data read from `old`,
+    /// streaming the new data into `writer`, and return performance
+    /// statistics.
+    ///
+    /// Both inputs are read to the end first; `options.max_memory` caps them
+    /// together while they are read. As with `diff_readers`, `Phase::Reading`
+    /// is not reported.
+    pub fn patch_readers<R: Read, S: Read, W: Write>(
+        old: R,
+        patch: S,
+        writer: W,
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        let start = Instant::now();
+
+        let old_data = read_stream(old, options.max_memory, hooks)?;
+        let remaining = options.max_memory.map(|limit| limit - old_data.len() as u64);
+        let patch_data = read_stream(patch, remaining, hooks).map_err(|e| match e {
+            BsdiffError::LimitExceeded { limit_name, size, .. } => {
+                BsdiffError::LimitExceeded { limit_name, size: old_data.len() as u64 + size, limit: options.max_memory.unwrap_or(0) }
+            }
+            other => other,
+        })?;
+        let target_size = target_size_hint(&patch_data)?;
+        let output = ProgressWriter::new(writer, hooks, Phase::Patching, target_size);
+        let new_size = Self::patch_to_writer(&old_data, &patch_data, output, options)?;
+
+        Ok(Self::streaming_stats(start, &old_data, &patch_data, new_size, 0))
+    }
+
+    /// Apply a patch, streaming the new data into `writer` in chunks of
+    /// `STREAM_CHUNK_SIZE` bytes instead of buffering the whole target.
+    ///
+    /// Returns the number of bytes written. The limits in `options` are
+    /// checked before the first byte reaches `writer`. Archive patches still
+    /// rebuild the expanded new file in memory; see `streaming_memory`.
+    pub fn patch_to_writer<W: Write>(
+        old_data: &[u8],
+        patch_data: &[u8],
+        writer: W,
+        options: &PatchOptions
+    ) -> Result<u64, BsdiffError> {
+        options.check_memory(streaming_memory(old_data, patch_data))?;
+        apply_verified(old_data, patch_data, writer, Some(STREAM_CHUNK_SIZE), options)
+    }
+
+    /// Apply a patch file within the limits set in `options`, streaming the
+    /// new data into `writer` and reporting `Phase::Patching` progress through
+    /// `hooks`, and return performance statistics.
+    pub fn patch_file_to_writer<W: Write>(
+        old_file: impl AsRef<Path>,
+        patch_file: impl AsRef<Path>,
+        writer: W,
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        let (old_file, patch_file) = (old_file.as_ref(), patch_file.as_ref());
+        // Validate input files
+        check_exists("Old file", old_file)?;
+        check_exists("Patch file", patch_file)?;
+
+        let start = Instant::now();
+
+        let [old_data, patch_data] = read_inputs([old_file, patch_file], options.max_memory, options.mmap, hooks)?;
+        let target_size = target_size_hint(&patch_data)?;
+        let output = ProgressWriter::new(writer, hooks, Phase::Patching, target_size);
+        let new_size = Self::patch_to_writer(&old_data, &patch_data, output, options)?;
+
+        let mapped_memory = old_data.mapped_len() + patch_data.mapped_len();
+        Ok(Self::streaming_stats(start, &old_data, &patch_data, new_size, mapped_memory))
+    }
+
+    /// Apply a patch file within the limits set in `options`, streaming the
+    /// output straight to disk, and return performance statistics.
+    ///
+    /// Peak memory stays at roughly old + patch + two chunks, independent of
+    /// the size of the new file.
+    pub fn patch_streaming(
+        old_file: &str,
+        new_file: &str,
+        patch_file: &str,
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        Self::patch_files_streaming(old_file, new_file, patch_file, options, hooks)
+    }
+
+    /// Apply a patch file given paths of any form, streaming the output
+    /// straight to disk as `patch_streaming` does.
+    pub fn patch_files_streaming(
+        old_file: impl AsRef<Path>,
+        new_file: impl AsRef<Path>,
+        patch_file: impl AsRef<Path>,
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        let (old_file, new_file, patch_file) = (old_file.as_ref(), new_file.as_ref(), patch_file.as_ref());
+        // Validate input files
+        check_exists("Old file", old_file)?;
+        check_exists("Patch file", patch_file)?;
+
+        let start = Instant::now();
+
+        let [old_data, patch_data] = read_inputs([old_file, patch_file], options.max_memory, options.mmap, hooks)?;
+        let target_size = target_size_hint(&patch_data)?;
+
+        // Dropping the uncommitted output on error never leaves a truncated target behind
+        let mut output = AtomicOutput::create(new_file).map_err(|e| BsdiffError::io(new_file, e))?;
+        let new_size = Self::patch_to_writer(
+            &old_data,
+            &patch_data,
+            ProgressWriter::new(&mut output, hooks, Phase::Writing, target_size),
+            options
+        ).map_err(|e| e.with_path(new_file))?;
+        output.commit().map_err(|e| BsdiffError::io(new_file, e))?;
+
+        let mapped_memory = old_data.mapped_len() + patch_data.mapped_len();
+        Ok(Self::streaming_stats(start, &old_data, &patch_data, new_size, mapped_memory))
+    }
+
+    /// Apply `patches` one after another, starting from `old_data`, and return
+    /// the version the last one produces. Intermediate versions stay in memory.
+    ///
+    /// Every step is checked against the limits in `options`, except
+    /// `expected_target_size`, which applies to the final version only.
+    pub fn apply_chain_buffers(
+        old_data: &[u8],
+        patches: &[&[u8]],
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<Vec<u8>, BsdiffError> {
+        let (last, steps) = patches.split_last().ok_or_else(empty_chain)?;
+        let step_options = PatchOptions { expected_target_size: None, ..options.clone() };
+        let mut current = None;
+        for (index, patch_data) in steps.iter().enumerate() {
+            let base = current.as_deref().unwrap_or(old_data);
+            current = Some(Self::patch_buffers_with_hooks(base, patch_data, &step_options, hooks).map_err(chain_step(index))?);
+        }
+        let base = current.as_deref().unwrap_or(old_data);
+        Self::patch_buffers_with_hooks(base, last, options, hooks).map_err(chain_step(steps.len()))
+    }
+
+    /// Apply a chain of patch files to `old_file` and write the version the
+    /// last one produces to `new_file`, and return performance statistics.
+    ///
+    /// Intermediate versions stay in memory and the last step streams to
+    /// disk; the limits in `options` apply as in `apply_chain_buffers`.
+    pub fn apply_chain(
+        old_file: &str,
+        patch_files: &[&str],
+        new_file: &str,
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        Self::apply_chain_files(old_file, patch_files, new_file, options, hooks)
+    }
+
+    /// Apply a chain of patch files given paths of any form, as `apply_chain`
+    /// does.
+    pub fn apply_chain_files(
+        old_file: impl AsRef<Path>,
+        patch_files: &[impl AsRef<Path>],
+        new_file: impl AsRef<Path>,
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        let (old_file, new_file) = (old_file.as_ref(), new_file.as_ref());
+        // Validate input files
+        check_exists("Old file", old_file)?;
+        for patch_file in patch_files {
+            check_exists("Patch file", patch_file.as_ref())?;
+        }
+        let (last, steps) = patch_files.split_last().ok_or_else(empty_chain)?;
+
         let start = Instant::now();
 
-        // Perform patch
-        Self::patch(old_file, new_file, patch_file)?;
+        let [old_data] = read_inputs([old_file], options.max_memory, options.mmap, hooks)?;
+        let old_size = old_data.len() as u64;
+        let step_options = PatchOptions { expected_target_size: None, ..options.clone() };
+        let mut current = old_data;
+        let mut patch_size = 0;
+        // Peak memory and its mapped part, over all steps
+        let mut peak = (0, 0);
+        for (index, patch_file) in steps.iter().enumerate() {
+            let [patch_data] = read_inputs([patch_file.as_ref()], options.max_memory, options.mmap, hooks)?;
+            let next = Self::patch_buffers_with_hooks(&current, &patch_data, &step_options, hooks)
+                .map_err(chain_step(index))?;
+            patch_size += patch_data.len() as u64;
+            let memory = (current.len() + patch_data.len() + next.len()) as u64;
+            peak = peak.max((memory, current.mapped_len() + patch_data.mapped_len()));
+            current = Input::Heap(next);
+        }
+
+        let [patch_data] = read_inputs([last.as_ref()], options.max_memory, options.mmap, hooks)?;
+        let target_size = target_size_hint(&patch_data).map_err(chain_step(steps.len()))?;
+        // Dropping the uncommitted output on error never leaves a truncated target behind
+        let mut output = AtomicOutput::create(new_file).map_err(|e| BsdiffError::io(new_file, e))?;
+        let new_size = Self::patch_to_writer(
+            &current,
+            &patch_data,
+            ProgressWriter::new(&mut output, hooks, Phase::Writing, target_size),
+            options
+        ).map_err(|e| chain_step(steps.len())(e).with_path(new_file))?;
+        output.commit().map_err(|e| BsdiffError::io(new_file, e))?;
+
+        patch_size += patch_data.len() as u64;
+        let memory = streaming_memory(&current, &patch_data);
+        let (estimated_peak_memory, mapped_memory) = peak.max((memory, current.mapped_len() + patch_data.mapped_len()));
+        Ok(Self::collect_stats(start, old_size, new_size, patch_size, estimated_peak_memory, mapped_memory))
+    }
+
+    /// Compose a chain of patch files into a single patch file from
+    /// `base_file` to the version the last one produces, and return
+    /// performance statistics.
+    ///
+    /// The chain is read and applied in memory within the limits in
+    /// `patch_options`, as `apply_chain_buffers` would, and its result diffed
+    /// against the base with `options`, so the composed patch is as compact as
+    /// a direct diff.
+    pub fn compose_patches(
+        base_file: &str,
+        patch_files: &[&str],
+        patch_file: &str,
+        options: &DiffOptions,
+        patch_options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        Self::compose_patches_files(base_file, patch_files, patch_file, options, patch_options, hooks)
+    }
+
+    /// Compose a chain of patch files given paths of any form, as
+    /// `compose_patches` does.
+    pub fn compose_patches_files(
+        base_file: impl AsRef<Path>,
+        patch_files: &[impl AsRef<Path>],
+        patch_file: impl AsRef<Path>,
+        options: &DiffOptions,
+        patch_options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        let base_file = base_file.as_ref();
+        // Validate input files
+        check_exists("Old file", base_file)?;
+        for patch_file in patch_files {
+            check_exists("Patch file", patch_file.as_ref())?;
+        }
+        options.validate()?;
+
+        let start = Instant::now();
+
+        let [base_data] = read_inputs([base_file], patch_options.max_memory, options.mmap, hooks)?;
+        let patches = patch_files
+            .iter()
+            .map(|path| read_inputs([path.as_ref()], patch_options.max_memory, options.mmap, hooks).map(|[patch_data]| patch_data))
+            .collect::<Result<Vec<_>, _>>()?;
+        let chain = patches.iter().map(|patch_data| &**patch_data).collect::<Vec<_>>();
+        let final_data = Self::apply_chain_buffers(&base_data, &chain, patch_options, hooks)?;
+        let (patch_size, _) = write_patches(&base_data, &final_data, patch_file.as_ref(), None, options, hooks)?;
+
+        let old_size = base_data.len() as u64;
+        let new_size = final_data.len() as u64;
+        let chain_size = chain.iter().map(|patch_data| patch_data.len() as u64).sum::<u64>();
+        // The chain, then as for a diff: both versions, the u32 suffix array
+        // and the composed patch
+        let diff_memory = suffix_array_len(&base_data, &final_data, false, options) + patch_size;
+        let estimated_peak_memory = chain_size + old_size + new_size + diff_memory;
+        let mapped_memory = base_data.mapped_len() + patches.iter().map(Input::mapped_len).sum::<u64>();
+
+        Ok(Self::collect_stats(start, old_size, new_size, patch_size, estimated_peak_memory, mapped_memory))
+    }
+
+    /// Derive the reverse (new to old) patch of `patch_file` from `old_file`,
+    /// and write it to `reverse_file`.
+    ///
+    /// The forward patch is applied in memory, so nothing but the old file
+    /// and the patch that was shipped is needed to prepare a rollback. It is
+    /// read and applied within the limits in `patch_options`, as `patch`
+    /// would, and the reverse patch is generated with `options`.
+    pub fn reverse_patch(
+        old_file: &str,
+        patch_file: &str,
+        reverse_file: &str,
+        options: &DiffOptions,
+        patch_options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        Self::reverse_patch_files(old_file, patch_file, reverse_file, options, patch_options, hooks)
+    }
+
+    /// Derive a reverse patch given paths of any form, as `reverse_patch`
+    /// does.
+    pub fn reverse_patch_files(
+        old_file: impl AsRef<Path>,
+        patch_file: impl AsRef<Path>,
+        reverse_file: impl AsRef<Path>,
+        options: &DiffOptions,
+        patch_options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        let (old_file, patch_file, reverse_file) = (old_file.as_ref(), patch_file.as_ref(), reverse_file.as_ref());
+        // Validate input files
+        check_exists("Old file", old_file)?;
+        check_exists("Patch file", patch_file)?;
+        options.validate()?;
+
+        let start = Instant::now();
+
+        let [old_data, patch_data] = read_inputs([old_file, patch_file], patch_options.max_memory, options.mmap, hooks)?;
+        let new_data = Self::patch_buffers_with_hooks(&old_data, &patch_data, patch_options, hooks)?;
+        let reverse_data = Self::diff_buffers_with_hooks(&new_data, &old_data, options, hooks)?;
+
+        write_output(reverse_file, &reverse_data, hooks)?;
+
+        // Statistics describe the reverse patch: from the new version back to the old one
+        let old_size = new_data.len() as u64;
+        let new_size = old_data.len() as u64;
+        let patch_size = reverse_data.len() as u64;
+        // The old file, the forward patch, the new version, the u32 suffix
+        // array over its indexed part, and the reverse patch
+        let indexed = options.window_size.map_or(old_size, |w| windowed::region_len(w).min(old_size));
+        let estimated_peak_memory = new_size + patch_data.len() as u64 + old_size + 4 * (indexed + 1) + patch_size;
+        let mapped_memory = old_data.mapped_len() + patch_data.mapped_len();
+
+        Ok(Self::collect_stats(start, old_size, new_size, patch_size, estimated_peak_memory, mapped_memory))
+    }
+
+    /// Diff the directory tree at `old_dir` against the one at `new_dir` and
+    /// write a single tree patch to `patch_file`, and return performance
+    /// statistics over the files of both trees.
+    ///
+    /// The patch holds a BSDIFF delta for each changed file, new files whole,
+    /// files that only moved as references to their old path (matched by
+    /// SHA-256), symbolic links, permissions and deletions. Symbolic links are
+    /// not followed, and one pointing outside the tree is refused.
+    pub fn diff_directory(
+        old_dir: &str,
+        new_dir: &str,
+        patch_file: &str,
+        options: &DiffOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        Self::diff_directory_files(old_dir, new_dir, patch_file, options, hooks)
+    }
+
+    /// Diff two directory trees given paths of any form, as `diff_directory`
+    /// does.
+    pub fn diff_directory_files(
+        old_dir: impl AsRef<Path>,
+        new_dir: impl AsRef<Path>,
+        patch_file: impl AsRef<Path>,
+        options: &DiffOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        let (old_dir, new_dir, patch_file) = (old_dir.as_ref(), new_dir.as_ref(), patch_file.as_ref());
+        // Validate input directories
+        check_exists("Old directory", old_dir)?;
+        check_exists("New directory", new_dir)?;
+        options.validate()?;
 
-        let elapsed = start.elapsed();
+        let start = Instant::now();
+
+        // Dropping the uncommitted output on error never leaves a truncated patch behind
+        let mut output = AtomicOutput::create(patch_file).map_err(|e| BsdiffError::io(patch_file, e))?;
+        let (totals, patch_size) = tree::diff(old_dir, new_dir, &mut output, options, hooks)
+            .map_err(|e| e.with_path(patch_file))?;
+        output.commit().map_err(|e| BsdiffError::io(patch_file, e))?;
+
+        Ok(Self::collect_stats(start, totals.old_size, totals.new_size, patch_size, totals.estimated_peak_memory, 0))
+    }
+
+    /// Rebuild the new directory tree of a tree patch from `old_dir` into
+    /// `out_dir`, and return performance statistics.
+    ///
+    /// `out_dir` must not exist or be empty. It is built next to its final
+    /// location and renamed into place once every file has been verified
+    /// against its SHA-256, so a failure leaves it as it was. Entries that
+    /// would escape `out_dir`, and sources outside `old_dir`, are refused.
+    /// The limits in `options` apply to each file, except
+    /// `expected_target_size`.
+    pub fn patch_directory(
+        old_dir: &str,
+        out_dir: &str,
+        patch_file: &str,
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        Self::patch_directory_files(old_dir, out_dir, patch_file, options, hooks)
+    }
+
+    /// Apply a tree patch given paths of any form, as `patch_directory` does.
+    pub fn patch_directory_files(
+        old_dir: impl AsRef<Path>,
+        out_dir: impl AsRef<Path>,
+        patch_file: impl AsRef<Path>,
+        options: &PatchOptions,
+        hooks: &Hooks
+    ) -> Result<PerformanceStats, BsdiffError> {
+        let (old_dir, out_dir, patch_file) = (old_dir.as_ref(), out_dir.as_ref(), patch_file.as_ref());
+        // Validate inputs
+        check_exists("Old directory", old_dir)?;
+        check_exists("Patch file", patch_file)?;
 
-        // Collect statistics
-        let old_size = std::fs::metadata(old_file)?.len();
-        let new_size = std::fs::metadata(new_file)?.len();
-        let patch_size = std::fs::metadata(patch_file)?.len();
+        let start = Instant::now();
+
+        let [patch_data] = read_inputs([patch_file], options.max_memory, options.mmap, hooks)?;
+        if !tree::is_tree(&patch_data) {
+            return Err(BsdiffError::CorruptPatch { reason: "not a directory patch".into() });
+        }
+        let patch = TreePatch::parse(&patch_data)?;
+        let totals = tree::apply(old_dir, out_dir, &patch, options, hooks)?;
+
+        let patch_size = patch_data.len() as u64;
+        // The patch, and the largest old file with its copy and delta buffers
+        let estimated_peak_memory = patch_size + totals.estimated_peak_memory;
+        Ok(Self::collect_stats(start, totals.old_size, totals.new_size, patch_size, estimated_peak_memory, patch_data.mapped_len()))
+    }
+
+    /// Build statistics for a patch streamed with `patch_to_writer`, whose
+    /// memory is estimated by `streaming_memory`.
+    ///
+    /// `mapped_memory` is the part of the inputs that was memory-mapped.
+    pub fn streaming_stats(
+        start: Instant,
+        old_data: &[u8],
+        patch_data: &[u8],
+        new_size: u64,
+        mapped_memory: u64
+    ) -> PerformanceStats {
+        let old_size = old_data.len() as u64;
+        let patch_size = patch_data.len() as u64;
+        let estimated_peak_memory = streaming_memory(old_data, patch_data);
+
+        Self::collect_stats(start, old_size, new_size, patch_size, estimated_peak_memory, mapped_memory)
+    }
 
+    fn collect_stats(
+        start: Instant,
+        old_size: u64,
+        new_size: u64,
+        patch_size: u64,
+        estimated_peak_memory: u64,
+        mapped_memory: u64
+    ) -> PerformanceStats {
         let compression_ratio = if old_size + new_size > 0 {
             (patch_size as f64 / (old_size + new_size) as f64) * 100.0
         } else {
             0.0
         };
 
-        Ok(PerformanceStats {
-            elapsed_ms: elapsed.as_millis() as u64,
+        PerformanceStats {
+            elapsed_ms: start.elapsed().as_millis() as u64,
             old_size,
             new_size,
             patch_size,
             compression_ratio,
-        })
+            estimated_peak_memory,
+            heap_memory: estimated_peak_memory - mapped_memory,
+            mapped_memory,
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::NamedTempFile;
-    
-    #[test]
-    fn test_qbsdiff_diff_patch() {
-        let old_content = b"Hello World! This is the old version with some content.";
-        let new_content = b"Hello World! This is the new version with more content and changes.";
-        
-        let old_file = NamedTempFile::new().unwrap();
-        let new_file = NamedTempFile::new().unwrap();
-        let patch_file = NamedTempFile::new().unwrap();
+fn empty_chain() -> BsdiffError {
+    BsdiffError::InvalidOption { name: "patches", reason: "at least one patch is required".into() }
+}
+
+/// Name the position of a failing patch in a chain in `CorruptPatch` errors.
+fn chain_step(index: usize) -> impl Fn(BsdiffError) -> BsdiffError {
+    move |err| match err {
+        BsdiffError::CorruptPatch { reason } => {
+            BsdiffError::CorruptPatch { reason: format!("patch {} of the chain: {}", index + 1, reason) }
+        }
+        other => other,
+    }
+}
+
+/// Fail with `NotFound` if `path` does not exist.
+fn check_exists(what: &'static str, path: &Path) -> Result<(), BsdiffError> {
+    if !path.exists() {
+        return Err(BsdiffError::NotFound { what, path: path.display().to_string() });
+    }
+    Ok(())
+}
+
+/// Fail with `TooLarge` if qbsdiff cannot index `data` in one piece.
+fn check_indexable(what: &'static str, data: &[u8]) -> Result<(), BsdiffError> {
+    if data.len() > MAX_LENGTH {
+        return Err(BsdiffError::TooLarge {
+            what,
+            path: None,
+            size: data.len() as u64,
+            limit: MAX_LENGTH as u64,
+        });
+    }
+    Ok(())
+}
+
+/// Diff one pair of inputs into a BSDIFF40, variant, ENDSLEY/BSDIFF43, VCDIFF
+/// or windowed patch.
+fn diff_payload(old_data: &[u8], new_data: &[u8], options: &DiffOptions, hooks: &Hooks) -> Result<Vec<u8>, BsdiffError> {
+    match options.window_size {
+        Some(window_size) => diff_windowed(old_data, new_data, options, window_size, hooks),
+        None => {
+            check_indexable("Old file", old_data)?;
+            check_indexable("New file", new_data)?;
+            compare(old_data, new_data, options, hooks)
+        }
+    }
+}
+
+/// Run qbsdiff on one pair of inputs, recompressing its blocks or encoding
+/// them in another format if the options ask for it.
+fn compare(old_data: &[u8], new_data: &[u8], options: &DiffOptions, hooks: &Hooks) -> Result<Vec<u8>, BsdiffError> {
+    // qbsdiff always writes BSDIFF40 with bzip2; use the fastest level when
+    // the blocks are recompressed anyway
+    let bzip2_level = match (options.format, options.compression) {
+        (OutputFormat::Bsdiff40, PatchCompression::Bzip2) => options.compression_level,
+        _ => 1,
+    };
+    let mut bsdiff = Bsdiff::new(old_data, new_data)
+        .compression_level(bzip2_level)
+        .parallel_scheme(options.parallel_scheme());
+    if let Some(small_match) = options.small_match {
+        bsdiff = bsdiff.small_match(small_match);
+    }
+    if let Some(buffer_size) = options.buffer_size {
+        bsdiff = bsdiff.buffer_size(buffer_size);
+    }
+
+    let mut patch_data = Vec::new();
+    bsdiff.compare(Cursor::new(&mut patch_data))?;
+    if (options.format, options.compression) == (OutputFormat::Bsdiff40, PatchCompression::Bzip2) {
+        return Ok(patch_data);
+    }
+    let mut progress = ProgressCounter::new(hooks, Phase::Encoding, new_data.len() as u64);
+    let patch_data = match options.compression {
+        PatchCompression::Bzip2 => match options.format {
+            OutputFormat::Bsdiff40 => unreachable!("returned above"),
+            OutputFormat::Endsley => endsley::encode(&patch_data, options.compression_level, &mut progress)?,
+            OutputFormat::Vcdiff => vcdiff::encode(&patch_data, old_data, new_data, &mut progress)?,
+        },
+        compression => variant::transcode(&patch_data, compression, options.compression_level, &mut progress)?,
+    };
+    progress.finish();
+    Ok(patch_data)
+}
+
+/// Worker threads for a diff that asks for `requested` under the
+/// process-wide cap `max`, or zero for one per core.
+fn effective_threads(requested: Option<usize>, max: usize) -> usize {
+    match (requested, max) {
+        (Some(threads), 0) => threads,
+        (Some(threads), max) => threads.min(max),
+        (None, max) => max,
+    }
+}
+
+/// Run `op` in a thread pool of its own, sized by `options`, so qbsdiff's
+/// parallel search does not compete for the global rayon pool or with other
+/// diffs. Dropping the pool when `op` returns stops its threads.
+fn in_thread_pool<T: Send>(
+    options: &DiffOptions,
+    op: impl FnOnce() -> Result<T, BsdiffError> + Send
+) -> Result<T, BsdiffError> {
+    if !options.enable_parallel {
+        return op();
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(effective_threads(options.threads, MAX_THREADS.load(Ordering::Relaxed)))
+        .thread_name(|i| format!("bsdiff-worker-{}", i))
+        .build()
+        .map_err(std::io::Error::other)?;
+    pool.install(op)
+}
+
+/// Counting semaphore behind `BsdiffRust::set_max_concurrent_diffs`.
+struct DiffSlots {
+    /// Running diffs and the limit, zero for none.
+    state: Mutex<(usize, usize)>,
+    freed: Condvar,
+}
+
+/// A running diff's slot, released on drop.
+struct DiffSlot(&'static DiffSlots);
+
+impl DiffSlots {
+    const fn new() -> Self {
+        Self { state: Mutex::new((0, 0)), freed: Condvar::new() }
+    }
+
+    fn set_limit(&self, limit: usize) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).1 = limit;
+        self.freed.notify_all();
+    }
+
+    /// Wait for a free slot, checking for cancellation while waiting.
+    fn acquire(&'static self, hooks: &Hooks) -> Result<DiffSlot, BsdiffError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        while state.1 != 0 && state.0 >= state.1 {
+            hooks.check_cancelled()?;
+            state = self.freed.wait_timeout(state, Duration::from_millis(50)).unwrap_or_else(|e| e.into_inner()).0;
+        }
+        state.0 += 1;
+        Ok(DiffSlot(self))
+    }
+}
+
+impl Drop for DiffSlot {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner()).0 -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Diff `new_data` in windows of `window_size` bytes, each against the region
+/// of `old_data` it matches best, reporting `Phase::Diffing` per window.
+fn diff_windowed(
+    old_data: &[u8],
+    new_data: &[u8],
+    options: &DiffOptions,
+    window_size: u64,
+    hooks: &Hooks
+) -> Result<Vec<u8>, BsdiffError> {
+    let max_window_size = MAX_LENGTH as u64 / 2;
+    if !(windowed::MIN_WINDOW_SIZE..=max_window_size).contains(&window_size) {
+        return Err(BsdiffError::InvalidOption {
+            name: "windowSize",
+            reason: format!("{} is not between {} and {} bytes", window_size, windowed::MIN_WINDOW_SIZE, max_window_size),
+        });
+    }
+
+    let matcher = windowed::RegionMatcher::new(old_data, new_data, window_size);
+    let mut parts = Vec::new();
+    for (i, chunk) in new_data.chunks(window_size as usize).enumerate() {
+        hooks.check_cancelled()?;
+        let start = i as u64 * window_size;
+        let region = matcher.region(start, chunk.len() as u64);
+        let patch = compare(&old_data[region.clone()], chunk, options, &hooks.without_progress())?;
+        parts.push((region, chunk.len() as u64, patch));
+        hooks.report(Phase::Diffing, start + chunk.len() as u64, new_data.len() as u64);
+    }
+
+    let windows = parts
+        .iter()
+        .map(|(region, new_length, patch)| Window {
+            old_offset: region.start as u64,
+            old_length: region.len() as u64,
+            new_length: *new_length,
+            patch,
+        })
+        .collect();
+    let patch = WindowedPatch {
+        old_size: old_data.len() as u64,
+        new_size: new_data.len() as u64,
+        windows,
+    };
+    Ok(patch.encode())
+}
+
+/// Parse a patch header, reporting failures as `CorruptPatch`.
+///
+/// The header's lengths are validated first: qbsdiff adds them up unchecked,
+/// so a negative length would overflow inside `Bspatch::new`.
+fn parse_patch(patch_data: &[u8]) -> Result<Bspatch<'_>, BsdiffError> {
+    if patch_data.starts_with(utils::BSDIFF40_MAGIC) && patch_data.len() >= utils::BSDIFF40_HEADER_LEN as usize {
+        let corrupt = |reason: String| Err(BsdiffError::CorruptPatch { reason });
+        let control_length = utils::decode_offtin(&patch_data[8..16]);
+        let diff_length = utils::decode_offtin(&patch_data[16..24]);
+        let target_size = utils::decode_offtin(&patch_data[24..32]);
+        if control_length < 0 || diff_length < 0 {
+            return corrupt(format!("negative block length (control {}, diff {})", control_length, diff_length));
+        }
+        if target_size < 0 {
+            return corrupt(format!("negative target size {}", target_size));
+        }
+        let body = (patch_data.len() as u64) - utils::BSDIFF40_HEADER_LEN;
+        if (control_length as u64).checked_add(diff_length as u64).is_none_or(|n| n > body) {
+            return corrupt(format!(
+                "control and diff blocks ({} + {} bytes) extend past the end of the patch",
+                control_length, diff_length
+            ));
+        }
+    }
+    Bspatch::new(patch_data).map_err(|e| BsdiffError::CorruptPatch { reason: e.to_string() })
+}
+
+/// Size of the output a patch produces, as recorded in its header.
+fn target_size_hint(patch_data: &[u8]) -> Result<u64, BsdiffError> {
+    let (_, payload) = container::split(patch_data)?;
+    if windowed::is_windowed(payload) {
+        return Ok(WindowedPatch::parse(payload)?.new_size);
+    }
+    if variant::is_variant(payload) {
+        return Ok(VariantPatch::parse(payload)?.new_size);
+    }
+    if endsley::is_endsley(payload) {
+        return endsley::target_size(payload);
+    }
+    if vcdiff::is_vcdiff(payload) {
+        return vcdiff::target_size(payload);
+    }
+    if archive::is_archive(payload) {
+        return Ok(ArchivePatch::parse(payload)?.new_size);
+    }
+    if filter::is_filtered(payload) {
+        return Ok(FilteredPatch::parse(payload)?.new_size);
+    }
+    if tree::is_tree(payload) {
+        return Err(BsdiffError::CorruptPatch { reason: "directory patch; apply it with patchDirectory".into() });
+    }
+    Ok(parse_patch(payload)?.hint_target_size())
+}
+
+/// Memory held while `patch_to_writer` applies `patch_data`: the old data and
+/// patch, plus the copy buffer and delta buffer of one chunk each. Archive
+/// patches hold the expanded old and new files instead, since the new
+/// entries can only be deflated once the inner patch has produced them, and
+/// filtered patches hold the filtered old and new files, since branch targets
+/// can only be restored once the inner patch has produced them.
+pub(crate) fn streaming_memory(old_data: &[u8], patch_data: &[u8]) -> u64 {
+    let inputs = old_data.len() as u64 + patch_data.len() as u64;
+    let payload = container::split(patch_data).map_or(patch_data, |(_, payload)| payload);
+    if archive::is_archive(payload) {
+        if let Ok(patch) = ArchivePatch::parse(payload) {
+            return inputs
+                .saturating_add(patch.expanded_old_size().unwrap_or(u64::MAX))
+                .saturating_add(patch.expanded_new_size().unwrap_or(u64::MAX));
+        }
+    } else if filter::is_filtered(payload) {
+        if let Ok(patch) = FilteredPatch::parse(payload) {
+            return (inputs + old_data.len() as u64).saturating_add(patch.new_size);
+        }
+    }
+    inputs + 2 * STREAM_CHUNK_SIZE as u64
+}
+
+/// Apply a BSDIFF40, ENDSLEY/BSDIFF43, VCDIFF, variant, windowed, archive,
+/// filtered or container patch, optionally in chunks of `chunk_size` bytes.
+///
+/// The target size in the header is checked against `options` before anything
+/// is written, and the output may never grow past it. A container's old
+/// checksum is checked before anything is written and its new checksum once
+/// the whole output has passed through `writer`.
+fn apply_verified<W: Write>(
+    old_data: &[u8],
+    patch_data: &[u8],
+    writer: W,
+    chunk_size: Option<usize>,
+    options: &PatchOptions
+) -> Result<u64, BsdiffError> {
+    let (header, payload) = container::split(patch_data)?;
+    let target_size = target_size_hint(payload)?;
+    options.check_target_size(target_size)?;
+
+    if let Some(header) = &header {
+        header.verify_old(old_data)?;
+    }
+
+    let mut writer = HashingWriter::new(SizeLimitWriter { inner: writer, written: 0, limit: target_size }, header.is_some());
+    let written = if archive::is_archive(payload) {
+        let patch = ArchivePatch::parse(payload)?;
+        patch.check_old(old_data)?;
+        let (expanded_old_size, expanded_new_size) = (
+            patch.expanded_old_size().unwrap_or(u64::MAX),
+            patch.expanded_new_size().unwrap_or(u64::MAX),
+        );
+        // Old data and patch, plus the expanded old and new files
+        options.check_memory(
+            (old_data.len() as u64 + patch_data.len() as u64)
+                .saturating_add(expanded_old_size)
+                .saturating_add(expanded_new_size)
+        )?;
+        check_inner_size(patch.patch, expanded_new_size)?;
+        let expanded_old = patch.expand_old(old_data)?;
+        let expanded_new = apply_inner(patch.patch, &expanded_old, expanded_new_size, chunk_size)?;
+        patch.write_new(&expanded_new, ChunkedWriter { inner: &mut writer, chunk_size: chunk_size.unwrap_or(STREAM_CHUNK_SIZE) })?
+    } else if filter::is_filtered(payload) {
+        let patch = FilteredPatch::parse(payload)?;
+        patch.check_old(old_data)?;
+        // Old data and patch, plus the filtered old and new files
+        options.check_memory((2 * old_data.len() as u64 + patch_data.len() as u64).saturating_add(patch.new_size))?;
+        check_inner_size(patch.patch, patch.new_size)?;
+        let mut new_data = apply_inner(patch.patch, &patch.filter_old(old_data), patch.new_size, chunk_size)?;
+        patch.unfilter_new(&mut new_data);
+        ChunkedWriter { inner: &mut writer, chunk_size: chunk_size.unwrap_or(STREAM_CHUNK_SIZE) }.write_all(&new_data)?;
+        new_data.len() as u64
+    } else {
+        apply_unwrapped(payload, old_data, &mut writer, chunk_size)?
+    };
+    options.check_written(written)?;
+    if let (Some(header), Some(digest)) = (&header, writer.finalize()) {
+        header.verify_new(&digest, written)?;
+    }
+    Ok(written)
+}
+
+/// Fail with `CorruptPatch` unless the patch wrapped by an archive or filtered
+/// patch produces `expected` bytes, as its wrapper recorded.
+fn check_inner_size(patch_data: &[u8], expected: u64) -> Result<(), BsdiffError> {
+    let inner_size = target_size_hint(patch_data)?;
+    if inner_size != expected {
+        return Err(BsdiffError::CorruptPatch {
+            reason: format!("inner patch produces {} bytes, expected {}", inner_size, expected),
+        });
+    }
+    Ok(())
+}
+
+/// Apply the patch wrapped by an archive or filtered patch into memory,
+/// checking that it produces exactly `expected` bytes.
+fn apply_inner(patch_data: &[u8], old_data: &[u8], expected: u64, chunk_size: Option<usize>) -> Result<Vec<u8>, BsdiffError> {
+    let mut new_data = Vec::with_capacity(expected.min(MAX_PREALLOCATION) as usize);
+    let limited = SizeLimitWriter { inner: &mut new_data, written: 0, limit: expected };
+    let produced = apply_unwrapped(patch_data, old_data, limited, chunk_size)?;
+    if produced != expected {
+        return Err(BsdiffError::CorruptPatch {
+            reason: format!("inner patch produced {} bytes, expected {}", produced, expected),
+        });
+    }
+    Ok(new_data)
+}
+
+/// Apply a windowed patch, or any patch `apply_payload` takes, optionally in
+/// chunks of `chunk_size` bytes.
+fn apply_unwrapped<W: Write>(
+    payload: &[u8],
+    old_data: &[u8],
+    mut writer: W,
+    chunk_size: Option<usize>
+) -> Result<u64, BsdiffError> {
+    if !windowed::is_windowed(payload) {
+        return apply_payload(payload, old_data, writer, chunk_size);
+    }
+    let patch = WindowedPatch::parse(payload)?;
+    patch.check_old(old_data)?;
+    let mut written = 0;
+    for window in &patch.windows {
+        let produced = apply_payload(window.patch, window.old_region(old_data)?, &mut writer, chunk_size)?;
+        if produced != window.new_length {
+            return Err(BsdiffError::CorruptPatch {
+                reason: format!("window produced {} bytes, expected {}", produced, window.new_length),
+            });
+        }
+        written += produced;
+    }
+    Ok(written)
+}
+
+/// Apply a BSDIFF40, ENDSLEY/BSDIFF43, VCDIFF or variant patch, optionally in
+/// chunks of `chunk_size` bytes. VCDIFF output is written a window at a time.
+fn apply_payload<W: Write>(
+    payload: &[u8],
+    old_data: &[u8],
+    writer: W,
+    chunk_size: Option<usize>
+) -> Result<u64, BsdiffError> {
+    if variant::is_variant(payload) {
+        let patch = VariantPatch::parse(payload)?;
+        return patch.apply(old_data, writer, chunk_size.unwrap_or(STREAM_CHUNK_SIZE));
+    }
+    if endsley::is_endsley(payload) {
+        return endsley::apply(payload, old_data, writer, chunk_size.unwrap_or(STREAM_CHUNK_SIZE));
+    }
+    if vcdiff::is_vcdiff(payload) {
+        return vcdiff::apply(payload, old_data, writer);
+    }
+    let patcher = parse_patch(payload)?;
+    let patcher = match chunk_size {
+        Some(chunk_size) => patcher.buffer_size(chunk_size).delta_min(chunk_size),
+        None => patcher,
+    };
+    apply_patch(patcher, old_data, writer)
+}
+
+/// Apply a patch, telling errors raised by `writer` apart from a corrupt patch.
+fn apply_patch<W: Write>(patcher: Bspatch<'_>, old_data: &[u8], writer: W) -> Result<u64, BsdiffError> {
+    let mut writer = FailureTracker { inner: writer, failed: false };
+    patcher.apply(old_data, &mut writer).map_err(|e| {
+        if writer.failed {
+            BsdiffError::from(e)
+        } else {
+            BsdiffError::CorruptPatch { reason: e.to_string() }
+        }
+    })
+}
+
+/// Writer adapter that records whether the inner writer failed.
+struct FailureTracker<W: Write> {
+    inner: W,
+    failed: bool,
+}
+
+impl<W: Write> Write for FailureTracker<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.failed |= result.is_err();
+        result
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let result = self.inner.flush();
+        self.failed |= result.is_err();
+        result
+    }
+}
+
+/// Writer adapter that fails once more than `limit` bytes pass through it.
+///
+/// qbsdiff writes whatever the control block asks for, regardless of the
+/// target size recorded in the header.
+struct SizeLimitWriter<W: Write> {
+    inner: W,
+    written: u64,
+    limit: u64,
+}
+
+impl<W: Write> Write for SizeLimitWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.written + buf.len() as u64 > self.limit {
+            return Err(std::io::Error::other(BsdiffError::CorruptPatch {
+                reason: format!("output exceeds the declared target size of {} bytes", self.limit),
+            }));
+        }
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer adapter that hands at most `chunk_size` bytes at a time to the
+/// inner writer, so output rebuilt in memory still reaches it in bounded
+/// pieces.
+struct ChunkedWriter<W: Write> {
+    inner: W,
+    chunk_size: usize,
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(&buf[..buf.len().min(self.chunk_size)])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Contents of an input file, read onto the heap or memory-mapped read-only.
+enum Input {
+    Heap(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Input {
+    /// Bytes of this input that are mapped rather than on the heap.
+    fn mapped_len(&self) -> u64 {
+        match self {
+            Self::Heap(_) => 0,
+            Self::Mapped(map) => map.len() as u64,
+        }
+    }
+}
+
+impl Deref for Input {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Heap(data) => data,
+            Self::Mapped(map) => map,
+        }
+    }
+}
+
+/// Read or, if `mmap` is set, map input files, reporting combined
+/// `Phase::Reading` progress.
+///
+/// Fails with `LimitExceeded` before reading if the files together are larger
+/// than `max_memory`.
+fn read_inputs<const N: usize>(
+    paths: [&Path; N],
+    max_memory: Option<u64>,
+    mmap: bool,
+    hooks: &Hooks
+) -> Result<[Input; N], BsdiffError> {
+    let mut files = Vec::with_capacity(N);
+    let mut total = 0;
+    for path in paths {
+        let file = File::open(path).map_err(|e| BsdiffError::io(path, e))?;
+        let len = file.metadata().map_err(|e| BsdiffError::io(path, e))?.len();
+        total += len;
+        files.push((path, file, len));
+    }
+    if let Some(limit) = max_memory.filter(|&limit| total > limit) {
+        return Err(BsdiffError::LimitExceeded { limit_name: "maxMemory", size: total, limit });
+    }
+
+    let mut done = 0;
+    let inputs = files.into_iter().map(|(path, mut file, len)| {
+        let read = |e| BsdiffError::io(path, e);
+        hooks.check_cancelled()?;
+        // Empty files cannot be mapped on every platform
+        if mmap && len > 0 {
+            // SAFETY: the map is read-only and dropped when the operation ends.
+            // Like any memory-mapped file, its contents change if another
+            // process modifies the file while it is mapped.
+            let map = unsafe { Mmap::map(&file) }.map_err(read)?;
+            done += map.len() as u64;
+            hooks.report(Phase::Reading, done, total);
+            return Ok(Input::Mapped(map));
+        }
+
+        let mut data = Vec::with_capacity(len as usize);
+        loop {
+            hooks.check_cancelled()?;
+            let n = (&mut file).take(IO_CHUNK_SIZE as u64).read_to_end(&mut data).map_err(read)?;
+            if n == 0 {
+                break;
+            }
+            done += n as u64;
+            hooks.report(Phase::Reading, done, total);
+        }
+        Ok(Input::Heap(data))
+    }).collect::<Result<Vec<_>, BsdiffError>>()?;
+
+    Ok(inputs.try_into().unwrap_or_else(|_| unreachable!()))
+}
+
+/// Read `reader` to the end in chunks, checking for cancellation between them.
+///
+/// Fails with `LimitExceeded` as soon as more than `max_memory` bytes arrive.
+fn read_stream<R: Read>(reader: R, max_memory: Option<u64>, hooks: &Hooks) -> Result<Vec<u8>, BsdiffError> {
+    // One byte past the limit is enough to tell that it was exceeded
+    let mut reader = reader.take(max_memory.map_or(u64::MAX, |limit| limit.saturating_add(1)));
+    let mut data = Vec::new();
+    loop {
+        hooks.check_cancelled()?;
+        if (&mut reader).take(IO_CHUNK_SIZE as u64).read_to_end(&mut data)? == 0 {
+            break;
+        }
+    }
+    if let Some(limit) = max_memory.filter(|&limit| data.len() as u64 > limit) {
+        return Err(BsdiffError::LimitExceeded { limit_name: "maxMemory", size: data.len() as u64, limit });
+    }
+    Ok(data)
+}
+
+/// Diff two files into `patch_file`, and into `reverse_file` the other way
+/// round if given.
+fn diff_files_to(
+    old_file: &Path,
+    new_file: &Path,
+    patch_file: &Path,
+    reverse_file: Option<&Path>,
+    options: &DiffOptions,
+    hooks: &Hooks
+) -> Result<PerformanceStats, BsdiffError> {
+    // Validate input files
+    check_exists("Old file", old_file)?;
+    check_exists("New file", new_file)?;
+    options.validate()?;
+
+    let start = Instant::now();
+
+    let [old_data, new_data] = read_inputs([old_file, new_file], None, options.mmap, hooks)?;
+    let old_size = old_data.len() as u64;
+    let new_size = new_data.len() as u64;
+
+    let (patch_size, reverse_size) = write_patches(&old_data, &new_data, patch_file, reverse_file, options, hooks)?;
+
+    // Both inputs, the u32 suffix array over the larger indexed input,
+    // and the patches
+    let indexed = suffix_array_len(&old_data, &new_data, reverse_file.is_some(), options);
+    let estimated_peak_memory = old_size + new_size + indexed + patch_size + reverse_size;
+    let mapped_memory = old_data.mapped_len() + new_data.mapped_len();
+
+    Ok(BsdiffRust::collect_stats(start, old_size, new_size, patch_size, estimated_peak_memory, mapped_memory))
+}
+
+/// Diff `old_data` against `new_data` and write the patch to `patch_file`,
+/// along with the reverse patch if `reverse_file` is given.
+///
+/// Both diffs run and both outputs are staged before either is committed.
+/// Returns the size of the patch and of the reverse patch, which is zero when
+/// there is none.
+fn write_patches(
+    old_data: &[u8],
+    new_data: &[u8],
+    patch_file: &Path,
+    reverse_file: Option<&Path>,
+    options: &DiffOptions,
+    hooks: &Hooks
+) -> Result<(u64, u64), BsdiffError> {
+    if reverse_file == Some(patch_file) {
+        return Err(BsdiffError::InvalidOption {
+            name: "reversePatch",
+            reason: "must not be the path of the forward patch".into(),
+        });
+    }
+    let patch_data = BsdiffRust::diff_buffers_with_hooks(old_data, new_data, options, hooks)?;
+    let reverse = match reverse_file {
+        Some(reverse_file) => Some((reverse_file, BsdiffRust::diff_buffers_with_hooks(new_data, old_data, options, hooks)?)),
+        None => None,
+    };
+
+    let output = stage_output(patch_file, &patch_data, hooks)?;
+    let reverse_output = match &reverse {
+        Some((reverse_file, reverse_data)) => Some((reverse_file, stage_output(reverse_file, reverse_data, hooks)?)),
+        None => None,
+    };
+    hooks.check_cancelled()?;
+
+    output.commit().map_err(|e| BsdiffError::io(patch_file, e))?;
+    if let Some((reverse_file, reverse_output)) = reverse_output {
+        reverse_output.commit().map_err(|e| BsdiffError::io(reverse_file, e))?;
+    }
+    Ok((patch_data.len() as u64, reverse.map_or(0, |(_, reverse_data)| reverse_data.len() as u64)))
+}
+
+/// Size of the u32 suffix array a diff of `old_data` against `new_data`
+/// builds, and of the reverse diff when there is one. The two run one after
+/// the other, so only the larger counts.
+fn suffix_array_len(old_data: &[u8], new_data: &[u8], reverse: bool, options: &DiffOptions) -> u64 {
+    let indexed = |len: usize| {
+        let len = len as u64;
+        options.window_size.map_or(len, |w| windowed::region_len(w).min(len))
+    };
+    let mut indexed_len = indexed(old_data.len());
+    if reverse {
+        indexed_len = indexed_len.max(indexed(new_data.len()));
+    }
+    4 * (indexed_len + 1)
+}
+
+/// Atomically write an output file in chunks, reporting `Phase::Writing` progress.
+///
+/// The destination is left untouched if writing fails or is cancelled part-way.
+fn write_output(path: &Path, data: &[u8], hooks: &Hooks) -> Result<(), BsdiffError> {
+    stage_output(path, data, hooks)?.commit().map_err(|e| BsdiffError::io(path, e))
+}
+
+/// Write an output file's temporary file in chunks, reporting
+/// `Phase::Writing` progress, and return it uncommitted.
+fn stage_output(path: &Path, data: &[u8], hooks: &Hooks) -> Result<AtomicOutput, BsdiffError> {
+    let write = || {
+        let mut output = AtomicOutput::create(path)?;
+        let mut writer = ProgressWriter::new(&mut output, hooks, Phase::Writing, data.len() as u64);
+        for chunk in data.chunks(IO_CHUNK_SIZE) {
+            writer.write_all(chunk)?;
+        }
+        Ok(output)
+    };
+    write().map_err(|e| BsdiffError::io(path, e))
+}
+
+/// Crash-safe output file.
+///
+/// Data goes to a temporary file next to the destination. `commit` fsyncs it,
+/// renames it over the destination and fsyncs the directory, so a crash leaves
+/// either the old file or the complete new one. Dropping an uncommitted output
+/// deletes the temporary file.
+struct AtomicOutput {
+    file: tempfile::NamedTempFile,
+    path: PathBuf,
+}
+
+impl AtomicOutput {
+    fn create(path: &Path) -> std::io::Result<Self> {
+        let path = path.to_path_buf();
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+
+        let prefix = format!(".{}.", name);
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(&prefix).suffix(".tmp");
+        // Keep the permissions of a file being replaced; otherwise use the
+        // same default mode as `File::create` instead of tempfile's 0600.
+        match std::fs::metadata(&path) {
+            Ok(metadata) => {
+                builder.permissions(metadata.permissions());
+            }
+            #[cfg(unix)]
+            Err(_) => {
+                use std::os::unix::fs::PermissionsExt;
+                builder.permissions(std::fs::Permissions::from_mode(0o666));
+            }
+            #[cfg(not(unix))]
+            Err(_) => {}
+        }
+
+        Ok(Self { file: builder.tempfile_in(&dir)?, path })
+    }
+
+    fn commit(mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.file.as_file().sync_all()?;
+        self.file.persist(&self.path).map_err(|e| e.error)?;
+        sync_parent_dir(&self.path)
+    }
+}
+
+impl Write for AtomicOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Persist a rename by fsyncing the directory that contains `path`.
+#[cfg(unix)]
+pub(crate) fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened for fsync on this platform; the rename itself
+/// is already durable once `MoveFileEx` returns.
+#[cfg(not(unix))]
+pub(crate) fn sync_parent_dir(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Writer adapter that reports the bytes passing through it.
+struct ProgressWriter<'h, W: Write> {
+    inner: W,
+    hooks: &'h Hooks,
+    phase: Phase,
+    written: u64,
+    total: u64,
+}
+
+impl<'h, W: Write> ProgressWriter<'h, W> {
+    fn new(inner: W, hooks: &'h Hooks, phase: Phase, total: u64) -> Self {
+        Self { inner, hooks, phase, written: 0, total }
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hooks.check_cancelled()?;
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        self.hooks.report(self.phase, self.written, self.total);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Counts progress through a step that has no reader or writer of its own,
+/// reporting at most once per `IO_CHUNK_SIZE` bytes.
+pub(crate) struct ProgressCounter<'h> {
+    hooks: &'h Hooks,
+    phase: Phase,
+    done: u64,
+    reported: u64,
+    total: u64,
+}
+
+impl<'h> ProgressCounter<'h> {
+    fn new(hooks: &'h Hooks, phase: Phase, total: u64) -> Self {
+        hooks.report(phase, 0, total);
+        Self { hooks, phase, done: 0, reported: 0, total }
+    }
+
+    /// Count `bytes` more, failing if the operation was cancelled.
+    pub(crate) fn advance(&mut self, bytes: u64) -> std::io::Result<()> {
+        self.done += bytes;
+        if self.done - self.reported >= IO_CHUNK_SIZE as u64 {
+            self.hooks.check_cancelled()?;
+            self.hooks.report(self.phase, self.done, self.total);
+            self.reported = self.done;
+        }
+        Ok(())
+    }
+
+    fn finish(&self) {
+        if self.reported != self.total {
+            self.hooks.report(self.phase, self.total, self.total);
+        }
+    }
+}
+
+/// Reader adapter that counts the bytes passing through it.
+pub(crate) struct ProgressReader<'c, 'h, R: Read> {
+    pub(crate) inner: R,
+    pub(crate) progress: &'c mut ProgressCounter<'h>,
+}
+
+impl<R: Read> Read for ProgressReader<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.advance(n as u64)?;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::NamedTempFile;
+    
+    /// Deterministic xorshift64 generator for fuzz corpora and incompressible
+    /// data.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    /// Encode `x` as a BSDIFF40 offtin: sign and magnitude, little-endian.
+    fn encode_offtin(x: i64) -> [u8; 8] {
+        let magnitude = x.unsigned_abs();
+        (if x < 0 { magnitude | 1 << 63 } else { magnitude }).to_le_bytes()
+    }
+
+    /// Writer that keeps the output and the largest single write it saw
+    struct ChunkRecorder {
+        data: Vec<u8>,
+        max_write: usize,
+    }
+
+    impl Write for ChunkRecorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.max_write = self.max_write.max(buf.len());
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_qbsdiff_diff_patch() {
+        let old_content = b"Hello World! This is the old version with some content.";
+        let new_content = b"Hello World! This is the new version with more content and changes.";
+        
+        let old_file = NamedTempFile::new().unwrap();
+        let new_file = NamedTempFile::new().unwrap();
+        let patch_file = NamedTempFile::new().unwrap();
         
         fs::write(&old_file, old_content).unwrap();
         fs::write(&new_file, new_content).unwrap();
@@ -275,6 +2258,7 @@ mod tests {
         let options = DiffOptions {
             compression_level: 9,
             enable_parallel: false,
+            ..DiffOptions::default()
         };
         
         BsdiffRust::diff_with_options(
@@ -409,4 +2393,1869 @@ mod tests {
         );
         assert!(result.is_err(), "Corrupted patch should produce an error");
     }
+
+    #[test]
+    fn test_error_kinds() {
+        let old_content = b"Typed errors: the old release artifact.".repeat(64);
+        let new_content = b"Typed errors: the new release artifact.".repeat(64);
+        let old_file = NamedTempFile::new().unwrap();
+        fs::write(&old_file, &old_content).unwrap();
+        let old_path = old_file.path().to_str().unwrap();
+
+        let err = BsdiffRust::diff("/nonexistent/old.bin", old_path, "/nonexistent/patch.bin").unwrap_err();
+        assert_eq!(err.code(), "ERR_NOT_FOUND");
+        assert_eq!(err.path(), Some("/nonexistent/old.bin"));
+
+        // Bad magic and a truncated body are both corrupt patches
+        let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &DiffOptions::default()).unwrap();
+        for bad in [&b"garbage"[..], &patch_data[..patch_data.len() / 2]] {
+            let err = BsdiffRust::patch_buffers(&old_content, bad, &PatchOptions::default()).unwrap_err();
+            assert!(matches!(err, BsdiffError::CorruptPatch { .. }), "Expected CorruptPatch, got: {}", err);
+        }
+
+        // Write failures are I/O errors naming the output, not corrupt patches
+        let patch_file = NamedTempFile::new().unwrap();
+        fs::write(&patch_file, &patch_data).unwrap();
+        let output = "/nonexistent-dir/new.bin";
+        let err = BsdiffRust::patch_streaming(old_path, output, patch_file.path().to_str().unwrap(), &PatchOptions::default(), &Hooks::default())
+            .unwrap_err();
+        assert!(matches!(err, BsdiffError::NotFound { .. } | BsdiffError::Io { .. }), "Got: {}", err);
+        assert_eq!(err.path(), Some(output));
+
+        let err = BsdiffRust::patch_to_writer(&old_content, &patch_data, FailingWriter, &PatchOptions::default()).unwrap_err();
+        assert_eq!(err.code(), "ERR_IO");
+    }
+
+    /// Writer whose every write fails.
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_diff_patch_buffers() {
+        let old_content = b"In-memory buffers: the old release artifact.";
+        let new_content = b"In-memory buffers: the new release artifact, slightly longer.";
+
+        let patch_data = BsdiffRust::diff_buffers(old_content, new_content, &DiffOptions::default()).unwrap();
+        assert_eq!(&patch_data[0..8], b"BSDIFF40", "Patch should have BSDIFF40 header");
+
+        let generated_content = BsdiffRust::patch_buffers(old_content, &patch_data, &PatchOptions::default()).unwrap();
+        assert_eq!(generated_content, new_content, "Patched buffer should match new buffer");
+
+        // Buffer patches must be interchangeable with file patches
+        let old_file = NamedTempFile::new().unwrap();
+        let patch_file = NamedTempFile::new().unwrap();
+        let generated_file = NamedTempFile::new().unwrap();
+        fs::write(&old_file, old_content).unwrap();
+        fs::write(&patch_file, &patch_data).unwrap();
+        BsdiffRust::patch(
+            old_file.path().to_str().unwrap(),
+            generated_file.path().to_str().unwrap(),
+            patch_file.path().to_str().unwrap(),
+        ).unwrap();
+        assert_eq!(fs::read(generated_file.path()).unwrap(), new_content);
+
+        assert!(BsdiffRust::patch_buffers(old_content, b"not a patch", &PatchOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_patch_streaming() {
+        // Larger than several chunks so the output is written incrementally
+        let old_content: Vec<u8> = (0..STREAM_CHUNK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content[1000..1100].fill(0xAB);
+        new_content.extend_from_slice(b"appended tail");
+
+        let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &DiffOptions::default()).unwrap();
+
+        // Streaming into a writer records every chunk write
+        let mut recorder = ChunkRecorder { data: Vec::new(), max_write: 0 };
+        let written = BsdiffRust::patch_to_writer(&old_content, &patch_data, &mut recorder, &PatchOptions::default()).unwrap();
+        assert_eq!(written, new_content.len() as u64);
+        assert_eq!(recorder.data, new_content);
+        assert!(recorder.max_write <= STREAM_CHUNK_SIZE, "Writes should be bounded by the chunk size");
+
+        // Streaming to disk
+        let old_file = NamedTempFile::new().unwrap();
+        let patch_file = NamedTempFile::new().unwrap();
+        let generated_file = NamedTempFile::new().unwrap();
+        fs::write(&old_file, &old_content).unwrap();
+        fs::write(&patch_file, &patch_data).unwrap();
+
+        let stats = BsdiffRust::patch_streaming(
+            old_file.path().to_str().unwrap(),
+            generated_file.path().to_str().unwrap(),
+            patch_file.path().to_str().unwrap(),
+            &PatchOptions::default(),
+            &Hooks::default(),
+        ).unwrap();
+        assert_eq!(fs::read(generated_file.path()).unwrap(), new_content);
+        assert_eq!(stats.new_size, new_content.len() as u64);
+        assert!(stats.estimated_peak_memory < stats.old_size + stats.patch_size + stats.new_size);
+    }
+
+    #[test]
+    fn test_progress_hooks() {
+        use std::sync::Mutex;
+
+        let old_content: Vec<u8> = (0..IO_CHUNK_SIZE * 2).map(|i| (i % 253) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content[4096..4200].fill(0x5A);
+
+        let old_file = NamedTempFile::new().unwrap();
+        let new_file = NamedTempFile::new().unwrap();
+        let patch_file = NamedTempFile::new().unwrap();
+        let generated_file = NamedTempFile::new().unwrap();
+        fs::write(&old_file, &old_content).unwrap();
+        fs::write(&new_file, &new_content).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let hooks = Hooks::default().with_progress(move |p| sink.lock().unwrap().push(p));
+
+        BsdiffRust::diff_with_hooks(
+            old_file.path().to_str().unwrap(),
+            new_file.path().to_str().unwrap(),
+            patch_file.path().to_str().unwrap(),
+            &DiffOptions::default(),
+            &hooks,
+        ).unwrap();
+
+        let diff_events = std::mem::take(&mut *events.lock().unwrap());
+        let phases: Vec<Phase> = diff_events.iter().map(|p| p.phase).collect();
+        assert_eq!(phases.first(), Some(&Phase::Reading));
+        assert!(phases.contains(&Phase::Diffing));
+        assert_eq!(phases.last(), Some(&Phase::Writing));
+        let last_read = diff_events.iter().rfind(|p| p.phase == Phase::Reading).unwrap();
+        assert_eq!(last_read.bytes_processed, (old_content.len() + new_content.len()) as u64);
+        assert_eq!(last_read.bytes_processed, last_read.total_bytes);
+        assert!(!phases.contains(&Phase::Encoding), "bzip2 BSDIFF40 is written as qbsdiff made it");
+
+        // Converting to another format reports progress part way through
+        for options in [
+            DiffOptions { format: OutputFormat::Endsley, ..DiffOptions::default() },
+            DiffOptions { format: OutputFormat::Vcdiff, ..DiffOptions::default() },
+            DiffOptions { compression: PatchCompression::Zstd, ..DiffOptions::default() },
+        ] {
+            BsdiffRust::diff_buffers_with_hooks(&old_content, &new_content, &options, &hooks).unwrap();
+            let encoded = std::mem::take(&mut *events.lock().unwrap())
+                .into_iter()
+                .filter(|p| p.phase == Phase::Encoding)
+                .map(|p| (p.bytes_processed, p.total_bytes))
+                .collect::<Vec<_>>();
+            let total = new_content.len() as u64;
+            assert_eq!(encoded.first(), Some(&(0, total)), "{:?}", options.format);
+            assert_eq!(encoded.last(), Some(&(total, total)), "{:?}", options.format);
+            assert!(encoded.iter().any(|&(done, _)| 0 < done && done < total), "{:?}: {:?}", options.compression, encoded);
+            assert!(encoded.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        }
+
+        let stats = BsdiffRust::patch_with_hooks(
+            old_file.path().to_str().unwrap(),
+            generated_file.path().to_str().unwrap(),
+            patch_file.path().to_str().unwrap(),
+            &PatchOptions::default(),
+            &hooks,
+        ).unwrap();
+        assert_eq!(fs::read(generated_file.path()).unwrap(), new_content);
+
+        let patch_events = std::mem::take(&mut *events.lock().unwrap());
+        let patching: Vec<&Progress> = patch_events.iter().filter(|p| p.phase == Phase::Patching).collect();
+        assert!(patching.len() > 1, "Patching progress should be reported per chunk");
+        assert!(patching.windows(2).all(|w| w[0].bytes_processed <= w[1].bytes_processed));
+        assert_eq!(patching.last().unwrap().bytes_processed, stats.new_size);
+        assert_eq!(patch_events.last().unwrap().phase, Phase::Writing);
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let old_content: Vec<u8> = (0..IO_CHUNK_SIZE * 2).map(|i| (i % 241) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content.truncate(IO_CHUNK_SIZE * 3 / 2);
+
+        let old_file = NamedTempFile::new().unwrap();
+        let new_file = NamedTempFile::new().unwrap();
+        let patch_file = NamedTempFile::new().unwrap();
+        fs::write(&old_file, &old_content).unwrap();
+        fs::write(&new_file, &new_content).unwrap();
+        BsdiffRust::diff(
+            old_file.path().to_str().unwrap(),
+            new_file.path().to_str().unwrap(),
+            patch_file.path().to_str().unwrap(),
+        ).unwrap();
+
+        // Already cancelled: fails while reading, before any output exists
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.bin");
+        let flag = Arc::new(AtomicBool::new(true));
+        let hooks = Hooks::default().with_cancel_flag(flag.clone());
+        let err = BsdiffRust::diff_with_hooks(
+            old_file.path().to_str().unwrap(),
+            new_file.path().to_str().unwrap(),
+            output.to_str().unwrap(),
+            &DiffOptions::default(),
+            &hooks,
+        ).unwrap_err();
+        assert!(matches!(err, BsdiffError::Cancelled), "Expected a Cancelled error, got: {}", err);
+        assert!(!output.exists(), "Cancelled diff should not create output");
+
+        // Cancelled mid-patch: the partially streamed target is removed
+        flag.store(false, Ordering::Relaxed);
+        let trigger = flag.clone();
+        let hooks = Hooks::default()
+            .with_cancel_flag(flag.clone())
+            .with_progress(move |p| {
+                if p.phase == Phase::Writing && p.bytes_processed > 0 {
+                    trigger.store(true, Ordering::Relaxed);
+                }
+            });
+        for patch_fn in [BsdiffRust::patch_streaming, BsdiffRust::patch_with_hooks] {
+            flag.store(false, Ordering::Relaxed);
+            let err = patch_fn(
+                old_file.path().to_str().unwrap(),
+                output.to_str().unwrap(),
+                patch_file.path().to_str().unwrap(),
+                &PatchOptions::default(),
+                &hooks,
+            ).unwrap_err();
+            assert!(matches!(err, BsdiffError::Cancelled), "Expected a Cancelled error, got: {}", err);
+            assert!(!output.exists(), "Cancelled patch should not leave partial output");
+        }
+
+        // Other errors are not mistaken for cancellation
+        let err = BsdiffRust::patch_buffers(&old_content, b"garbage", &PatchOptions::default()).unwrap_err();
+        assert!(!matches!(err, BsdiffError::Cancelled));
+    }
+
+    #[test]
+    fn test_atomic_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app.bin");
+        fs::write(&target, b"previous release").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&target, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        // A failed write leaves the existing file and no temporary files behind
+        let flag = Arc::new(AtomicBool::new(false));
+        let trigger = flag.clone();
+        let hooks = Hooks::default()
+            .with_cancel_flag(flag)
+            .with_progress(move |_| trigger.store(true, Ordering::Relaxed));
+        let data = vec![7u8; IO_CHUNK_SIZE * 2];
+        assert!(write_output(&target, &data, &hooks).is_err());
+        assert_eq!(fs::read(&target).unwrap(), b"previous release");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "Temporary file should be cleaned up");
+
+        // A successful write replaces the file and keeps its permissions
+        write_output(&target, &data, &Hooks::default()).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), data);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o777, 0o755);
+        }
+    }
+
+    #[test]
+    fn test_container_patch() {
+        let old_content = b"Container: the old release artifact.".repeat(32);
+        let new_content = b"Container: the new release artifact, with changes.".repeat(32);
+        let options = DiffOptions { container: true, ..DiffOptions::default() };
+
+        let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap();
+        assert_eq!(&patch_data[0..8], container::MAGIC);
+        assert_eq!(&patch_data[container::HEADER_LEN..container::HEADER_LEN + 8], b"BSDIFF40");
+        assert_eq!(BsdiffRust::patch_buffers(&old_content, &patch_data, &PatchOptions::default()).unwrap(), new_content);
+
+        // The wrong base is rejected before anything is written
+        let mut wrong_base = old_content.clone();
+        wrong_base[0] ^= 1;
+        let mut sink = Vec::new();
+        let err = BsdiffRust::patch_to_writer(&wrong_base, &patch_data, &mut sink, &PatchOptions::default()).unwrap_err();
+        assert!(matches!(err, BsdiffError::ChecksumMismatch { what: "Old file", .. }), "Got: {}", err);
+        assert!(sink.is_empty());
+
+        // A tampered new checksum is caught after patching, and the streamed
+        // target is never committed
+        let mut tampered = patch_data.clone();
+        tampered[64] ^= 1;
+        let dir = tempfile::tempdir().unwrap();
+        let old_file = dir.path().join("old.bin");
+        let patch_file = dir.path().join("patch.bin");
+        let output = dir.path().join("new.bin");
+        fs::write(&old_file, &old_content).unwrap();
+        fs::write(&patch_file, &tampered).unwrap();
+        let err = BsdiffRust::patch_streaming(
+            old_file.to_str().unwrap(),
+            output.to_str().unwrap(),
+            patch_file.to_str().unwrap(),
+            &PatchOptions::default(),
+            &Hooks::default(),
+        ).unwrap_err();
+        assert!(matches!(err, BsdiffError::ChecksumMismatch { what: "New file", .. }), "Got: {}", err);
+        assert!(!output.exists());
+
+        // Headerless BSDIFF40 patches are still accepted
+        let raw = BsdiffRust::diff_buffers(&old_content, &new_content, &DiffOptions::default()).unwrap();
+        assert_eq!(&raw[0..8], b"BSDIFF40");
+        assert_eq!(BsdiffRust::patch_buffers(&old_content, &raw, &PatchOptions::default()).unwrap(), new_content);
+
+        // A truncated container header is a corrupt patch
+        let err = BsdiffRust::patch_buffers(&old_content, &patch_data[..40], &PatchOptions::default()).unwrap_err();
+        assert!(matches!(err, BsdiffError::CorruptPatch { .. }), "Got: {}", err);
+    }
+
+    #[test]
+    fn test_verify_patch_digest() {
+        use crate::utils::{verify_patch_digest, DigestAlgorithm};
+        use sha2::{Digest, Sha256};
+
+        let old_content = b"Digest verification: the old release artifact.".repeat(32);
+        let new_content = b"Digest verification: the new release artifact, changed.".repeat(32);
+        let dir = tempfile::tempdir().unwrap();
+        let old_file = dir.path().join("old.bin");
+        let patch_file = dir.path().join("patch.bin");
+        fs::write(&old_file, &old_content).unwrap();
+        fs::write(&patch_file, BsdiffRust::diff_buffers(&old_content, &new_content, &DiffOptions::default()).unwrap()).unwrap();
+        let (old_path, patch_path) = (old_file.to_str().unwrap(), patch_file.to_str().unwrap());
+        let size = new_content.len() as u64;
+
+        let sha256 = container::to_hex(&Sha256::digest(&new_content));
+        let result = verify_patch_digest(old_path, patch_path, &sha256.to_uppercase(), size, DigestAlgorithm::Sha256).unwrap();
+        assert!(result.is_valid());
+        assert_eq!(result.bytes_produced, size);
+        assert_eq!(result.actual_digest, sha256);
+
+        let blake3 = blake3::hash(&new_content).to_hex().to_string();
+        let result = verify_patch_digest(old_path, patch_path, &blake3, size, DigestAlgorithm::Blake3).unwrap();
+        assert!(result.is_valid());
+
+        // Mismatches are reported per field, not as errors
+        let result = verify_patch_digest(old_path, patch_path, &sha256, size + 1, DigestAlgorithm::Sha256).unwrap();
+        assert!(result.hash_matched && !result.size_matched);
+        let result = verify_patch_digest(old_path, patch_path, &blake3, size, DigestAlgorithm::Sha256).unwrap();
+        assert!(!result.hash_matched && result.size_matched);
+
+        // Nothing but the inputs is left on disk
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_patch_info() {
+        use crate::utils::{get_patch_info, inspect_patch, BlockCompression, PatchFormat};
+
+        let old_content = b"Patch info: the old release artifact.".repeat(32);
+        let new_content = b"Patch info: the new release artifact, with changes.".repeat(32);
+        let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &DiffOptions::default()).unwrap();
+        let inspect = |data: &[u8]| inspect_patch(&mut Cursor::new(data)).unwrap();
+
+        let patch_file = NamedTempFile::new().unwrap();
+        fs::write(&patch_file, &patch_data).unwrap();
+        let info = get_patch_info(patch_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(info.format, PatchFormat::Bsdiff40);
+        assert!(info.compressed);
+        assert!(info.issues.is_empty(), "Unexpected issues: {:?}", info.issues);
+        assert_eq!(info.target_size, Some(new_content.len() as u64));
+        let names: Vec<_> = info.blocks.iter().map(|b| b.name).collect();
+        assert_eq!(names, ["control", "diff", "extra"]);
+        assert_eq!(32 + info.blocks.iter().map(|b| b.length).sum::<u64>(), patch_data.len() as u64);
+        assert!(info.blocks.iter().all(|b| b.compression == BlockCompression::Bzip2));
+
+        let options = DiffOptions { container: true, ..DiffOptions::default() };
+        let info = inspect(&BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap());
+        assert_eq!(info.format, PatchFormat::Container);
+        assert_eq!(info.blocks[0].offset, container::HEADER_LEN as u64 + 32);
+        assert_eq!(info.container.unwrap().old_size, old_content.len() as u64);
+
+        let mut endsley = b"ENDSLEY/BSDIFF43".to_vec();
+        endsley.extend_from_slice(&1234u64.to_le_bytes());
+        endsley.extend_from_slice(b"BZh91AY&SY");
+        let info = inspect(&endsley);
+        assert_eq!(info.format, PatchFormat::Endsley);
+        assert_eq!(info.target_size, Some(1234));
+        assert_eq!(info.blocks[0].compression, BlockCompression::Bzip2);
+
+        assert_eq!(inspect(&[0xD6, 0xC3, 0xC4, 0x00, 0x00]).format, PatchFormat::Vcdiff);
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&patch_data).unwrap();
+        let info = inspect(&gzip.finish().unwrap());
+        assert_eq!(info.format, PatchFormat::Gzip);
+        assert_eq!(info.inner_format, Some(PatchFormat::Bsdiff40));
+
+        let info = inspect(b"PK\x03\x04 not a patch");
+        assert_eq!(info.format, PatchFormat::Unknown);
+        assert!(!info.issues.is_empty());
+
+        // Inconsistent headers are reported rather than failing
+        let mut hostile = patch_data.clone();
+        hostile[8..16].copy_from_slice(&(u64::MAX >> 1).to_le_bytes());
+        assert!(inspect(&hostile).issues[0].contains("extend past the end"));
+        hostile[15] |= 0x80;
+        assert!(inspect(&hostile).issues[0].contains("negative block length"));
+        assert!(inspect(&patch_data[..20]).issues[0].contains("truncated header"));
+    }
+
+    #[test]
+    fn test_patch_limits() {
+        let old_content: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut new_content = old_content.repeat(16);
+        new_content[1000..1100].fill(0xAB);
+        let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &DiffOptions::default()).unwrap();
+        let target_size = new_content.len() as u64;
+
+        // maxTargetSize is checked before anything is written
+        let options = PatchOptions { max_target_size: Some(target_size - 1), ..PatchOptions::default() };
+        let mut sink = Vec::new();
+        let err = BsdiffRust::patch_to_writer(&old_content, &patch_data, &mut sink, &options).unwrap_err();
+        assert!(matches!(err, BsdiffError::LimitExceeded { limit_name: "maxTargetSize", .. }), "Got: {}", err);
+        assert_eq!(err.code(), "ERR_LIMIT_EXCEEDED");
+        assert!(sink.is_empty());
+        let options = PatchOptions { max_target_size: Some(target_size), ..PatchOptions::default() };
+        assert_eq!(BsdiffRust::patch_buffers(&old_content, &patch_data, &options).unwrap(), new_content);
+
+        // Buffering the target needs more memory than streaming it
+        let streaming_memory = (old_content.len() + patch_data.len() + 2 * STREAM_CHUNK_SIZE) as u64;
+        let options = PatchOptions { max_memory: Some(streaming_memory), ..PatchOptions::default() };
+        let err = BsdiffRust::patch_buffers(&old_content, &patch_data, &options).unwrap_err();
+        assert!(matches!(err, BsdiffError::LimitExceeded { limit_name: "maxMemory", .. }), "Got: {}", err);
+        let mut sink = Vec::new();
+        BsdiffRust::patch_to_writer(&old_content, &patch_data, &mut sink, &options).unwrap();
+        assert_eq!(sink, new_content);
+
+        // Inputs larger than maxMemory are rejected before they are read
+        let dir = tempfile::tempdir().unwrap();
+        let old_file = dir.path().join("old.bin");
+        let patch_file = dir.path().join("patch.bin");
+        let output = dir.path().join("new.bin");
+        fs::write(&old_file, &old_content).unwrap();
+        fs::write(&patch_file, &patch_data).unwrap();
+        let options = PatchOptions { max_memory: Some(old_content.len() as u64), ..PatchOptions::default() };
+        let err = BsdiffRust::patch_streaming(
+            old_file.to_str().unwrap(),
+            output.to_str().unwrap(),
+            patch_file.to_str().unwrap(),
+            &options,
+            &Hooks::default(),
+        ).unwrap_err();
+        assert!(matches!(err, BsdiffError::LimitExceeded { limit_name: "maxMemory", .. }), "Got: {}", err);
+        assert!(!output.exists());
+
+        // expectedTargetSize must match the header and the output exactly
+        let options = PatchOptions { expected_target_size: Some(target_size + 1), ..PatchOptions::default() };
+        let err = BsdiffRust::patch_buffers(&old_content, &patch_data, &options).unwrap_err();
+        assert!(matches!(err, BsdiffError::SizeMismatch { expected, actual } if expected == target_size + 1 && actual == target_size));
+        assert_eq!(err.code(), "ERR_SIZE_MISMATCH");
+        let options = PatchOptions { expected_target_size: Some(target_size), ..PatchOptions::default() };
+        BsdiffRust::patch_with_options(
+            old_file.to_str().unwrap(),
+            output.to_str().unwrap(),
+            patch_file.to_str().unwrap(),
+            &options,
+        ).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), new_content);
+
+        // A header that understates the output is cut off once it is exceeded
+        let mut understated = patch_data.clone();
+        understated[24..32].copy_from_slice(&1024u64.to_le_bytes());
+        let mut sink = Vec::new();
+        let err = BsdiffRust::patch_to_writer(&old_content, &understated, &mut sink, &PatchOptions::default()).unwrap_err();
+        assert!(matches!(err, BsdiffError::CorruptPatch { .. }), "Got: {}", err);
+        assert!(sink.len() <= 1024);
+    }
+
+    #[test]
+    fn test_hostile_headers() {
+        fn header(control: i64, diff: i64, target: i64) -> Vec<u8> {
+            let mut data = b"BSDIFF40".to_vec();
+            for field in [control, diff, target] {
+                data.extend_from_slice(&encode_offtin(field));
+            }
+            data
+        }
+        fn with_body(mut data: Vec<u8>, body: &[u8]) -> Vec<u8> {
+            data.extend_from_slice(body);
+            data
+        }
+
+        let old_content = b"Hostile headers: the old release artifact.".repeat(64);
+        let new_content = b"Hostile headers: the new release artifact, with changes.".repeat(64);
+        let valid = BsdiffRust::diff_buffers(&old_content, &new_content, &DiffOptions::default()).unwrap();
+        let container = BsdiffRust::diff_buffers(
+            &old_content,
+            &new_content,
+            &DiffOptions { container: true, ..DiffOptions::default() },
+        ).unwrap();
+        let bz_empty = {
+            let mut data = Vec::new();
+            Bsdiff::new(b"", b"").compare(Cursor::new(&mut data)).unwrap();
+            data[32..].to_vec()
+        };
+
+        let mut huge_container = container.clone();
+        huge_container[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut corpus: Vec<(&str, Vec<u8>)> = vec![
+            ("empty", Vec::new()),
+            ("magic only", b"BSDIFF40".to_vec()),
+            ("truncated header", valid[..20].to_vec()),
+            ("header only", header(0, 0, 0)),
+            ("huge target size", with_body(header(0, 0, i64::MAX), &bz_empty)),
+            ("negative zero target size", with_body(header(0, 0, i64::MIN), &bz_empty)),
+            ("negative target size", header(0, 0, -1)),
+            ("negative control length", header(-1, 0, 16)),
+            ("negative diff length", header(0, i64::MIN + 1, 16)),
+            ("overflowing block lengths", header(i64::MAX, i64::MAX, 16)),
+            ("blocks past end of file", with_body(header(1 << 20, 0, 16), &bz_empty)),
+            ("garbage blocks", with_body(header(4, 4, 1 << 40), b"BZh9garbageBZh9")),
+            ("container without payload", container[..container::HEADER_LEN].to_vec()),
+            ("container with huge new size", huge_container),
+            ("container with future version", container.iter().enumerate().map(|(i, &b)| if i == 8 { 2 } else { b }).collect()),
+            ("windowed header only", windowed::WindowedPatch { old_size: 0, new_size: 1 << 40, windows: Vec::new() }.encode()),
+            ("windowed table past end", {
+                let mut data = windowed::WindowedPatch { old_size: 0, new_size: 0, windows: Vec::new() }.encode();
+                data[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+                data
+            }),
+            ("windowed region outside old file", windowed::WindowedPatch {
+                old_size: old_content.len() as u64,
+                new_size: new_content.len() as u64,
+                windows: vec![Window { old_offset: u64::MAX, old_length: 2, new_length: new_content.len() as u64, patch: &valid }],
+            }.encode()),
+        ];
+        for (offset, value) in [(8, i64::MAX), (16, i64::MAX), (24, i64::MAX), (24, 1), (8, -8), (16, 1 << 62)] {
+            let mut data = valid.clone();
+            data[offset..offset + 8].copy_from_slice(&encode_offtin(value));
+            corpus.push(("tampered valid header", data));
+        }
+
+        // Deterministic mutations of a real patch, biased towards the header
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..256 {
+            let mut data = valid.clone();
+            for _ in 0..1 + rng.next() % 4 {
+                let offset = if rng.next().is_multiple_of(2) { 8 + rng.next() as usize % 24 } else { rng.next() as usize % data.len() };
+                data[offset] = rng.next() as u8;
+            }
+            if rng.next().is_multiple_of(8) {
+                data.truncate(rng.next() as usize % data.len());
+            }
+            corpus.push(("mutated", data));
+        }
+
+        let limit = 1 << 20;
+        let options = PatchOptions { max_target_size: Some(limit), max_memory: Some(4 * limit), ..PatchOptions::default() };
+        for (name, data) in &corpus {
+            // Any failure must be reported as an error, never a panic or an abort
+            match BsdiffRust::patch_buffers(&old_content, data, &options) {
+                Ok(output) => assert!(output.len() as u64 <= limit, "{}: produced {} bytes", name, output.len()),
+                Err(err) => assert!(
+                    matches!(err, BsdiffError::CorruptPatch { .. } | BsdiffError::LimitExceeded { .. } | BsdiffError::ChecksumMismatch { .. }),
+                    "{}: unexpected error {}",
+                    name,
+                    err
+                ),
+            }
+            let written = BsdiffRust::patch_to_writer(&old_content, data, std::io::sink(), &options).unwrap_or(0);
+            assert!(written <= limit, "{}: streamed {} bytes", name, written);
+            // Without limits, forged sizes still never get allocated up front
+            let _ = BsdiffRust::patch_buffers(&old_content, data, &PatchOptions::default());
+        }
+    }
+
+    #[test]
+    fn test_windowed_patch() {
+        use crate::utils::{inspect_patch, PatchFormat};
+
+        let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+        // Incompressible data, so only a well-matched old region keeps windows small
+        let old_content = rng.bytes(320 * 1024);
+        let mut new_content = old_content[..20 * 1024].to_vec();
+        new_content.extend_from_slice(&rng.bytes(48 * 1024));
+        new_content.extend_from_slice(&old_content[20 * 1024..]);
+        new_content[200 * 1024..200 * 1024 + 64].fill(0);
+
+        let options = DiffOptions { window_size: Some(16 * 1024), ..DiffOptions::default() };
+        let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap();
+        assert_eq!(&patch_data[0..8], windowed::MAGIC);
+        assert_eq!(BsdiffRust::patch_buffers(&old_content, &patch_data, &PatchOptions::default()).unwrap(), new_content);
+        let mut sink = Vec::new();
+        BsdiffRust::patch_to_writer(&old_content, &patch_data, &mut sink, &PatchOptions::default()).unwrap();
+        assert_eq!(sink, new_content);
+
+        // Windows after the insertion are matched against the shifted old data
+        let whole = BsdiffRust::diff_buffers(&old_content, &new_content, &DiffOptions::default()).unwrap();
+        assert!(patch_data.len() < whole.len() * 3 / 2, "windowed {} vs whole {}", patch_data.len(), whole.len());
+
+        let info = inspect_patch(&mut Cursor::new(&patch_data)).unwrap();
+        assert_eq!(info.format, PatchFormat::Windowed);
+        assert_eq!(info.target_size, Some(new_content.len() as u64));
+        assert_eq!(info.blocks.len(), 3 * new_content.len().div_ceil(16 * 1024));
+        assert!(info.issues.is_empty(), "Unexpected issues: {:?}", info.issues);
+
+        // Windowed patches can be wrapped in a container too
+        let options = DiffOptions { container: true, ..options };
+        let wrapped = BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap();
+        assert_eq!(&wrapped[container::HEADER_LEN..container::HEADER_LEN + 8], windowed::MAGIC);
+        assert_eq!(BsdiffRust::patch_buffers(&old_content, &wrapped, &PatchOptions::default()).unwrap(), new_content);
+        assert!(inspect_patch(&mut Cursor::new(&wrapped)).unwrap().issues.is_empty());
+
+        // Limits apply to the whole output
+        let limits = PatchOptions { max_target_size: Some(new_content.len() as u64 - 1), ..PatchOptions::default() };
+        let err = BsdiffRust::patch_buffers(&old_content, &patch_data, &limits).unwrap_err();
+        assert!(matches!(err, BsdiffError::LimitExceeded { .. }), "Got: {}", err);
+
+        // The old file must have the size the patch was made for
+        let err = BsdiffRust::patch_buffers(&old_content[1..], &patch_data, &PatchOptions::default()).unwrap_err();
+        assert!(matches!(err, BsdiffError::CorruptPatch { .. }), "Got: {}", err);
+        let err = BsdiffRust::patch_buffers(&old_content, &patch_data[..patch_data.len() - 1], &PatchOptions::default()).unwrap_err();
+        assert!(matches!(err, BsdiffError::CorruptPatch { .. }), "Got: {}", err);
+
+        let options = DiffOptions { window_size: Some(windowed::MIN_WINDOW_SIZE - 1), ..DiffOptions::default() };
+        let err = BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap_err();
+        assert!(matches!(err, BsdiffError::InvalidOption { name: "windowSize", .. }), "Got: {}", err);
+        assert_eq!(err.code(), "ERR_INVALID_OPTION");
+
+        // Empty inputs produce an empty window table
+        let options = DiffOptions { window_size: Some(windowed::MIN_WINDOW_SIZE), ..DiffOptions::default() };
+        let empty = BsdiffRust::diff_buffers(b"", b"", &options).unwrap();
+        assert_eq!(empty.len(), windowed::HEADER_LEN);
+        assert!(BsdiffRust::patch_buffers(b"", &empty, &PatchOptions::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mmap_inputs() {
+        let old_content: Vec<u8> = (0..STREAM_CHUNK_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content[5000..5100].fill(0xCD);
+
+        let old_file = NamedTempFile::new().unwrap();
+        let new_file = NamedTempFile::new().unwrap();
+        let patch_file = NamedTempFile::new().unwrap();
+        let generated_file = NamedTempFile::new().unwrap();
+        fs::write(&old_file, &old_content).unwrap();
+        fs::write(&new_file, &new_content).unwrap();
+        let path = |f: &NamedTempFile| f.path().to_str().unwrap().to_string();
+
+        let options = DiffOptions { mmap: true, ..DiffOptions::default() };
+        let stats = BsdiffRust::diff_with_hooks(
+            &path(&old_file), &path(&new_file), &path(&patch_file), &options, &Hooks::default()
+        ).unwrap();
+        assert_eq!(stats.mapped_memory, stats.old_size + stats.new_size);
+        assert_eq!(stats.heap_memory + stats.mapped_memory, stats.estimated_peak_memory);
+        // Mapping must not change the patch
+        let heap_patch = BsdiffRust::diff_buffers(&old_content, &new_content, &DiffOptions::default()).unwrap();
+        assert_eq!(fs::read(patch_file.path()).unwrap(), heap_patch);
+
+        let options = PatchOptions { mmap: true, ..PatchOptions::default() };
+        let stats = BsdiffRust::patch_with_hooks(
+            &path(&old_file), &path(&generated_file), &path(&patch_file), &options, &Hooks::default()
+        ).unwrap();
+        assert_eq!(fs::read(generated_file.path()).unwrap(), new_content);
+        assert_eq!(stats.mapped_memory, stats.old_size + stats.patch_size);
+        assert_eq!(stats.heap_memory, stats.new_size);
+
+        let stats = BsdiffRust::patch_streaming(
+            &path(&old_file), &path(&generated_file), &path(&patch_file), &options, &Hooks::default()
+        ).unwrap();
+        assert_eq!(fs::read(generated_file.path()).unwrap(), new_content);
+        assert_eq!(stats.heap_memory, 2 * STREAM_CHUNK_SIZE as u64);
+
+        // Mapped inputs still count towards maxMemory
+        let limited = PatchOptions { max_memory: Some(stats.old_size), ..options };
+        let result = BsdiffRust::patch_streaming(
+            &path(&old_file), &path(&generated_file), &path(&patch_file), &limited, &Hooks::default()
+        );
+        assert!(matches!(result, Err(BsdiffError::LimitExceeded { limit_name: "maxMemory", .. })));
+
+        // Empty files are read rather than mapped
+        let empty = NamedTempFile::new().unwrap();
+        let stats = BsdiffRust::diff_with_hooks(
+            &path(&empty), &path(&empty), &path(&patch_file), &DiffOptions { mmap: true, ..DiffOptions::default() }, &Hooks::default()
+        ).unwrap();
+        assert_eq!(stats.mapped_memory, 0);
+    }
+
+    #[test]
+    fn test_tuning_options() {
+        // Large enough for several parallel chunks of the minimum size
+        let old_content: Vec<u8> = (0..1536 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+        let mut new_content = old_content.clone();
+        for offset in (0..new_content.len()).step_by(100_000) {
+            new_content[offset..offset + 20].fill(0x5A);
+        }
+        new_content.extend_from_slice(b"tuned tail");
+
+        let settings = [
+            DiffOptions { small_match: Some(0), ..DiffOptions::default() },
+            DiffOptions { small_match: Some(64), ..DiffOptions::default() },
+            DiffOptions { buffer_size: Some(MIN_BUFFER_SIZE), ..DiffOptions::default() },
+            DiffOptions { buffer_size: Some(1024 * 1024), ..DiffOptions::default() },
+            DiffOptions { parallel_chunk_size: Some(MIN_PARALLEL_CHUNK_SIZE), ..DiffOptions::default() },
+            DiffOptions { parallel_jobs: Some(1), ..DiffOptions::default() },
+            DiffOptions { parallel_jobs: Some(4), compression_level: 1, ..DiffOptions::default() },
+            DiffOptions { enable_parallel: false, compression_level: 9, ..DiffOptions::default() },
+            DiffOptions { parallel_jobs: Some(3), window_size: Some(512 * 1024), ..DiffOptions::default() },
+        ];
+        for options in &settings {
+            let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, options).unwrap();
+            let patched = BsdiffRust::patch_buffers(&old_content, &patch_data, &PatchOptions::default()).unwrap();
+            assert!(patched == new_content, "Patch should apply with {:?}", options);
+        }
+
+        let invalid = [
+            (DiffOptions { compression_level: 10, ..DiffOptions::default() }, "compressionLevel"),
+            (DiffOptions { compression_level: 0, ..DiffOptions::default() }, "compressionLevel"),
+            (DiffOptions { buffer_size: Some(MIN_BUFFER_SIZE - 1), ..DiffOptions::default() }, "bufferSize"),
+            (DiffOptions { parallel_chunk_size: Some(4096), ..DiffOptions::default() }, "parallelChunkSize"),
+            (DiffOptions { parallel_jobs: Some(0), ..DiffOptions::default() }, "parallelJobs"),
+            (
+                DiffOptions { parallel_chunk_size: Some(MIN_PARALLEL_CHUNK_SIZE), parallel_jobs: Some(2), ..DiffOptions::default() },
+                "parallelJobs",
+            ),
+            (DiffOptions { enable_parallel: false, parallel_jobs: Some(2), ..DiffOptions::default() }, "parallelJobs"),
+        ];
+        for (options, option_name) in &invalid {
+            match BsdiffRust::diff_buffers(b"old", b"new", options) {
+                Err(BsdiffError::InvalidOption { name, .. }) => assert_eq!(name, *option_name),
+                other => panic!("Expected InvalidOption for {:?}, got {:?}", options, other.map(|p| p.len())),
+            }
+        }
+
+        // Options are checked before the inputs are read
+        let result = BsdiffRust::diff_with_options(
+            "Cargo.toml", "Cargo.toml", "/nonexistent/dir/patch", &invalid[0].0
+        );
+        assert!(matches!(result, Err(BsdiffError::InvalidOption { .. })));
+    }
+
+    #[test]
+    fn test_thread_pools() {
+        use std::sync::Mutex;
+
+        let old_content: Vec<u8> = (0..64 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content[30_000..30_100].fill(0x11);
+
+        // Windowed diffs report progress from inside the pool
+        let pool_sizes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = pool_sizes.clone();
+        let hooks = Hooks::default().with_progress(move |_| {
+            let worker = std::thread::current().name().is_some_and(|name| name.starts_with("bsdiff-worker-"));
+            recorded.lock().unwrap().push((worker, rayon::current_num_threads()));
+        });
+        let options = DiffOptions { threads: Some(2), window_size: Some(16 * 1024), ..DiffOptions::default() };
+        let patch_data = BsdiffRust::diff_buffers_with_hooks(&old_content, &new_content, &options, &hooks).unwrap();
+        assert_eq!(BsdiffRust::patch_buffers(&old_content, &patch_data, &PatchOptions::default()).unwrap(), new_content);
+        assert!(pool_sizes.lock().unwrap().contains(&(true, 2)), "Windows should be diffed in a 2-thread pool");
+
+        // The process-wide cap wins over a larger per-call count
+        assert_eq!(effective_threads(Some(4), 0), 4);
+        assert_eq!(effective_threads(Some(4), 1), 1);
+        assert_eq!(effective_threads(Some(2), 8), 2);
+        assert_eq!(effective_threads(None, 3), 3);
+        assert_eq!(effective_threads(None, 0), 0);
+
+        for options in [
+            DiffOptions { threads: Some(0), ..DiffOptions::default() },
+            DiffOptions { threads: Some(2), enable_parallel: false, ..DiffOptions::default() },
+        ] {
+            let result = BsdiffRust::diff_buffers(b"old", b"new", &options);
+            assert!(matches!(result, Err(BsdiffError::InvalidOption { name: "threads", .. })));
+        }
+
+        // A full limiter makes further diffs wait until a slot frees up or
+        // they are cancelled
+        static SLOTS: DiffSlots = DiffSlots::new();
+        SLOTS.set_limit(1);
+        let slot = SLOTS.acquire(&Hooks::default()).unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let hooks = Hooks::default().with_cancel_flag(cancel.clone());
+            std::thread::spawn(move || SLOTS.acquire(&hooks).map(drop))
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!waiter.is_finished(), "Second diff should wait for a slot");
+        cancel.store(true, Ordering::Relaxed);
+        assert!(matches!(waiter.join().unwrap(), Err(BsdiffError::Cancelled)));
+
+        let waiter = std::thread::spawn(|| SLOTS.acquire(&Hooks::default()).map(drop));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!waiter.is_finished());
+        drop(slot);
+        waiter.join().unwrap().unwrap();
+        assert_eq!(SLOTS.state.lock().unwrap().0, 0);
+    }
+
+    #[test]
+    fn test_compression_backends() {
+        use crate::utils::{inspect_patch, BlockCompression, PatchFormat};
+        use crate::variant::VariantPatch;
+
+        let old_content: Vec<u8> = (0..200 * 1024u32).map(|i| (i / 7 % 251) as u8).collect();
+        let mut new_content = old_content.clone();
+        for offset in (0..new_content.len()).step_by(10_000) {
+            new_content[offset..offset + 16].copy_from_slice(b"compressed patch");
+        }
+        new_content.extend_from_slice(&[0x42; 3000]);
+
+        let mut sizes = Vec::new();
+        for compression in [
+            PatchCompression::Bzip2,
+            PatchCompression::Zstd,
+            PatchCompression::Xz,
+            PatchCompression::Brotli,
+            PatchCompression::None,
+        ] {
+            let options = DiffOptions { compression, ..DiffOptions::default() };
+            let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap();
+            assert_eq!(BsdiffRust::patch_buffers(&old_content, &patch_data, &PatchOptions::default()).unwrap(), new_content);
+            let mut sink = Vec::new();
+            BsdiffRust::patch_to_writer(&old_content, &patch_data, &mut sink, &PatchOptions::default()).unwrap();
+            assert_eq!(sink, new_content, "{:?}", compression);
+
+            let info = inspect_patch(&mut Cursor::new(&patch_data)).unwrap();
+            assert!(info.issues.is_empty(), "{:?}: {:?}", compression, info.issues);
+            assert_eq!(info.target_size, Some(new_content.len() as u64));
+            assert_eq!(info.blocks.len(), 3);
+            if compression == PatchCompression::Bzip2 {
+                assert_eq!(&patch_data[0..8], utils::BSDIFF40_MAGIC);
+                assert_eq!(info.format, PatchFormat::Bsdiff40);
+            } else {
+                assert_eq!(&patch_data[0..8], variant::MAGIC);
+                assert_eq!(info.format, PatchFormat::Variant);
+                assert!(info.blocks.iter().all(|b| b.compression == BlockCompression::declared(compression)));
+            }
+            sizes.push(patch_data.len());
+        }
+        assert!(sizes[..4].iter().all(|&size| size < sizes[4]), "Compressed patches should beat raw blocks: {:?}", sizes);
+
+        // Variant payloads inside windowed patches and containers
+        let options = DiffOptions {
+            compression: PatchCompression::Zstd,
+            window_size: Some(64 * 1024),
+            container: true,
+            ..DiffOptions::default()
+        };
+        let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap();
+        assert_eq!(BsdiffRust::patch_buffers(&old_content, &patch_data, &PatchOptions::default()).unwrap(), new_content);
+        let info = inspect_patch(&mut Cursor::new(&patch_data)).unwrap();
+        assert!(info.issues.is_empty(), "{:?}", info.issues);
+        assert!(info.blocks.iter().all(|b| b.compression == BlockCompression::Zstd));
+
+        // Hostile variant patches fail cleanly
+        fn control(entries: &[(i64, i64, i64)]) -> Vec<u8> {
+            entries.iter().flat_map(|&(add, copy, seek)| [encode_offtin(add), encode_offtin(copy), encode_offtin(seek)].concat()).collect()
+        }
+        let raw = |control: &[u8], diff: &[u8], extra: &[u8], new_size: u64| {
+            VariantPatch { compression: PatchCompression::None, new_size, control, diff, extra }.encode()
+        };
+        let old = b"old data";
+        assert_eq!(
+            BsdiffRust::patch_buffers(old, &raw(&control(&[(3, 2, 3), (2, 0, 0)]), &[0, 0, 0, 1, 1], b"XY", 7), &PatchOptions::default()).unwrap(),
+            b"oldXYub"
+        );
+        let valid = BsdiffRust::diff_buffers(
+            &old_content, &new_content, &DiffOptions { compression: PatchCompression::Zstd, ..DiffOptions::default() }
+        ).unwrap();
+        let mut corpus = vec![
+            raw(&control(&[(0, 8, 0)]), b"", b"too much", 4),
+            raw(&control(&[(9, 0, 0)]), &[0; 9], b"", 9),
+            raw(&control(&[(-1, 0, 0)]), b"", b"", 0),
+            raw(&control(&[(0, 0, -1), (1, 0, 0)]), &[0], b"", 1),
+            raw(&control(&[(0, 4, 0)]), b"", b"ab", 4),
+            raw(&control(&[(0, 2, 0)]), b"", b"ab", 4),
+            raw(&control(&[(0, 2, 0)])[..20], b"", b"ab", 2),
+            valid[..variant::HEADER_LEN].to_vec(),
+            valid.iter().enumerate().map(|(i, &b)| if i == 9 { 200 } else { b }).collect(),
+        ];
+        let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+        for _ in 0..128 {
+            let mut data = valid.clone();
+            let seed = rng.next();
+            let offset = if seed.is_multiple_of(2) { 16 + (seed >> 8) as usize % 32 } else { (seed >> 8) as usize % data.len() };
+            data[offset] ^= (seed >> 40) as u8 | 1;
+            corpus.push(data);
+        }
+        let options = PatchOptions { max_target_size: Some(1 << 20), ..PatchOptions::default() };
+        for data in &corpus {
+            match BsdiffRust::patch_buffers(&old_content, data, &options) {
+                Ok(output) => assert!(output.len() <= 1 << 20),
+                Err(err) => assert!(
+                    matches!(err, BsdiffError::CorruptPatch { .. } | BsdiffError::LimitExceeded { .. }),
+                    "unexpected error {}",
+                    err
+                ),
+            }
+        }
+        for data in &corpus[..7] {
+            assert!(matches!(BsdiffRust::patch_buffers(old, data, &options), Err(BsdiffError::CorruptPatch { .. })));
+        }
+    }
+
+    #[test]
+    fn test_endsley_golden_vectors() {
+        use bzip2::read::BzDecoder;
+        use crate::utils::{inspect_patch, PatchFormat};
+
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/resources/bsdiff43");
+        let read = |case: &str, suffix: &str| fs::read(dir.join(format!("{}.{}", case, suffix))).unwrap();
+        let body = |patch: &[u8]| {
+            let mut body = Vec::new();
+            BzDecoder::new(&patch[utils::ENDSLEY_HEADER_LEN as usize..]).read_to_end(&mut body).unwrap();
+            body
+        };
+        let options = DiffOptions {
+            format: OutputFormat::Endsley,
+            compression_level: 9,
+            enable_parallel: false,
+            ..DiffOptions::default()
+        };
+
+        for case in ["empty", "identical", "text", "binary", "grow", "reorder"] {
+            let (old_content, new_content) = (read(case, "old"), read(case, "new"));
+
+            // Patches from the bsdiff crate apply, buffered and streaming
+            let reference = read(case, "ref.patch");
+            assert_eq!(BsdiffRust::patch_buffers(&old_content, &reference, &PatchOptions::default()).unwrap(), new_content, "{}", case);
+            let mut sink = Vec::new();
+            BsdiffRust::patch_to_writer(&old_content, &reference, &mut sink, &PatchOptions::default()).unwrap();
+            assert_eq!(sink, new_content, "{}", case);
+
+            // Patches we write match the checked-in ones, which the bsdiff
+            // crate's patcher accepts (see the README next to them)
+            let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap();
+            let golden = read(case, "lib.patch");
+            assert_eq!(&patch_data[..utils::ENDSLEY_HEADER_LEN as usize], &golden[..utils::ENDSLEY_HEADER_LEN as usize], "{}", case);
+            assert_eq!(body(&patch_data), body(&golden), "{}", case);
+            assert_eq!(BsdiffRust::patch_buffers(&old_content, &patch_data, &PatchOptions::default()).unwrap(), new_content);
+
+            let info = inspect_patch(&mut Cursor::new(&patch_data)).unwrap();
+            assert_eq!(info.format, PatchFormat::Endsley);
+            assert!(info.issues.is_empty(), "{}: {:?}", case, info.issues);
+            assert_eq!(info.target_size, Some(new_content.len() as u64));
+        }
+
+        // Inside windowed patches and containers
+        let (old_content, new_content) = (read("binary", "old").repeat(4), read("binary", "new").repeat(4));
+        let options = DiffOptions { window_size: Some(4096), container: true, ..options };
+        let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap();
+        assert_eq!(BsdiffRust::patch_buffers(&old_content, &patch_data, &PatchOptions::default()).unwrap(), new_content);
+        let info = inspect_patch(&mut Cursor::new(&patch_data)).unwrap();
+        assert!(info.issues.is_empty(), "{:?}", info.issues);
+
+        let options = DiffOptions { compression: PatchCompression::Zstd, ..options };
+        assert!(matches!(
+            BsdiffRust::diff_buffers(&old_content, &new_content, &options),
+            Err(BsdiffError::InvalidOption { name: "compression", .. })
+        ));
+
+        // Hostile patches fail cleanly
+        let raw = |new_size: i64, entries: &[(i64, i64, i64, &[u8])]| {
+            let mut body = Vec::new();
+            for &(add, copy, seek, data) in entries {
+                body.extend([&encode_offtin(add)[..], &encode_offtin(copy), &encode_offtin(seek), data].concat());
+            }
+            let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+            encoder.write_all(&body).unwrap();
+            [&utils::ENDSLEY_MAGIC[..], &encode_offtin(new_size), &encoder.finish().unwrap()].concat()
+        };
+        let old = b"old data";
+        // Diff bytes outside the old data are added to zero, as in mendsley's bspatch
+        let patch_data = raw(11, &[(3, 2, -10, b"\0\0\0XY"), (2, 0, 15, &[7, 7]), (2, 0, -11, &[1, 1]), (2, 0, 0, &[0, 1])]);
+        assert_eq!(BsdiffRust::patch_buffers(old, &patch_data, &PatchOptions::default()).unwrap(), b"oldXY\x07\x07\x01\x01le");
+        let valid = raw(4, &[(0, 4, 0, b"data")]);
+        let corpus = [
+            raw(-1, &[]),
+            raw(4, &[(0, 8, 0, b"too much")]),
+            raw(4, &[(-1, 0, 0, b"")]),
+            raw(4, &[(0, 2, 0, b"ab")]),
+            raw(4, &[(0, 4, 0, b"ab")]),
+            raw(4, &[(0, 0, i64::MAX, b""), (1, 0, i64::MAX, &[0]), (3, 0, 0, &[0; 3])]),
+            valid[..20].to_vec(),
+            valid[..valid.len() / 2].to_vec(),
+            [&valid[..24], b"not bzip2"].concat(),
+        ];
+        for (i, data) in corpus.iter().enumerate() {
+            let result = BsdiffRust::patch_buffers(old, data, &PatchOptions::default());
+            assert!(matches!(result, Err(BsdiffError::CorruptPatch { .. })), "{}: {:?}", i, result);
+            let mut sink = Vec::new();
+            assert!(matches!(
+                BsdiffRust::patch_to_writer(old, data, &mut sink, &PatchOptions::default()),
+                Err(BsdiffError::CorruptPatch { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_vcdiff() {
+        use crate::utils::{inspect_patch, PatchFormat};
+        use crate::vcdiff::write_varint;
+
+        let options = DiffOptions { format: OutputFormat::Vcdiff, ..DiffOptions::default() };
+        let old_content: Vec<u8> = (0..100 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content[5000..5016].copy_from_slice(b"VCDIFF RFC  3284");
+        new_content.splice(40_000..40_000, [0x55; 300]);
+        new_content.drain(70_000..71_000);
+        for (old, new) in [
+            (&old_content[..], &new_content[..]),
+            (b"hello world", b"hello there world"),
+            (b"", b"only new data"),
+            (b"only old data", b""),
+            (b"", b""),
+        ] {
+            let patch_data = BsdiffRust::diff_buffers(old, new, &options).unwrap();
+            assert_eq!(&patch_data[..4], &[0xD6, 0xC3, 0xC4, 0x00]);
+            assert_eq!(BsdiffRust::patch_buffers(old, &patch_data, &PatchOptions::default()).unwrap(), new);
+            let mut sink = Vec::new();
+            BsdiffRust::patch_to_writer(old, &patch_data, &mut sink, &PatchOptions::default()).unwrap();
+            assert_eq!(sink, new);
+
+            let info = inspect_patch(&mut Cursor::new(&patch_data)).unwrap();
+            assert_eq!(info.format, PatchFormat::Vcdiff);
+            assert!(info.issues.is_empty(), "{:?}", info.issues);
+            assert_eq!(info.target_size, Some(new.len() as u64));
+        }
+        let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap();
+        assert!(patch_data.len() < 1024, "Matches should be copied: {} bytes", patch_data.len());
+
+        // Inside windowed patches and containers
+        let windowed = DiffOptions { window_size: Some(32 * 1024), container: true, ..options.clone() };
+        let patch_data = BsdiffRust::diff_buffers(&old_content, &new_content, &windowed).unwrap();
+        assert_eq!(BsdiffRust::patch_buffers(&old_content, &patch_data, &PatchOptions::default()).unwrap(), new_content);
+        assert!(inspect_patch(&mut Cursor::new(&patch_data)).unwrap().issues.is_empty());
+
+        let zstd = DiffOptions { compression: PatchCompression::Zstd, ..options.clone() };
+        assert!(matches!(
+            BsdiffRust::diff_buffers(b"old", b"new", &zstd),
+            Err(BsdiffError::InvalidOption { name: "compression", .. })
+        ));
+
+        // Deltas using features this encoder never writes: an xdelta3
+        // application header and checksums, paired instructions, the near and
+        // same address caches, runs and overlapping copies from the target
+        let old = b"0123456789abcdefghij";
+        let window = |indicator: u8, source: Option<(u64, u64)>, target_len: usize, sections: [&[u8]; 3], checksum: &[u8]| {
+            let mut delta = Vec::new();
+            write_varint(&mut delta, target_len as u64);
+            delta.push(0);
+            for section in sections {
+                write_varint(&mut delta, section.len() as u64);
+            }
+            delta.extend_from_slice(checksum);
+            delta.extend(sections.concat());
+            let mut window = vec![indicator];
+            if let Some((len, pos)) = source {
+                write_varint(&mut window, len);
+                write_varint(&mut window, pos);
+            }
+            write_varint(&mut window, delta.len() as u64);
+            window.extend(delta);
+            window
+        };
+        let expected = b"XYZcdef!ghijghij-----------abcd.tail";
+        let sections: [&[u8]; 3] = [b"XYZ!-.", &[4, 20, 187, 116, 0, 5, 35, 6, 247], &[2, 4, 6, 2, 0]];
+        let adler = adler2::adler32_slice(&expected[..32]);
+        let tail = window(0, None, 4, [b"tail", &[5], &[]], &[]);
+
+        let xdelta3 = [
+            &[0xD6, 0xC3, 0xC4, 0x00, 0x04, 9][..],
+            b"old//new/",
+            &window(0x05, Some((10, 10)), 32, sections, &adler.to_be_bytes()),
+            &tail,
+        ].concat();
+        // open-vcdiff writes its checksum, which starts from 0, as a varint
+        let mut checksum = Vec::new();
+        let mut sdch_adler = adler2::Adler32::from_checksum(0);
+        sdch_adler.write_slice(&expected[..32]);
+        write_varint(&mut checksum, sdch_adler.checksum() as u64);
+        let open_vcdiff = [&[0xD6, 0xC3, 0xC4, b'S', 0x00][..], &window(0x05, Some((10, 10)), 32, sections, &checksum), &tail].concat();
+        for patch_data in [&xdelta3, &open_vcdiff] {
+            assert_eq!(BsdiffRust::patch_buffers(old, patch_data, &PatchOptions::default()).unwrap(), expected);
+            let info = inspect_patch(&mut Cursor::new(patch_data)).unwrap();
+            assert!(info.issues.is_empty(), "{:?}", info.issues);
+            assert_eq!(info.target_size, Some(expected.len() as u64));
+            assert_eq!(info.blocks.len(), 6);
+        }
+
+        // Deltas from xdelta3 and open-vcdiff (see the README next to them)
+        let inputs = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/resources/bsdiff43");
+        let deltas = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/resources/vcdiff");
+        for case in ["empty", "identical", "text", "binary", "grow", "reorder"] {
+            let old = fs::read(inputs.join(format!("{}.old", case))).unwrap();
+            let new = fs::read(inputs.join(format!("{}.new", case))).unwrap();
+            for tool in ["xdelta3", "open-vcdiff", "open-vcdiff-interleaved"] {
+                let patch_data = fs::read(deltas.join(format!("{}.{}.vcdiff", case, tool))).unwrap();
+                assert_eq!(BsdiffRust::patch_buffers(&old, &patch_data, &PatchOptions::default()).unwrap(), new, "{} {}", case, tool);
+                let info = inspect_patch(&mut Cursor::new(&patch_data)).unwrap();
+                assert_eq!(info.format, PatchFormat::Vcdiff);
+                assert!(info.issues.is_empty(), "{} {}: {:?}", case, tool, info.issues);
+                assert_eq!(info.target_size, Some(new.len() as u64));
+            }
+        }
+        let djw = fs::read(deltas.join("text.xdelta3-djw.vcdiff")).unwrap();
+        let result = BsdiffRust::patch_buffers(&fs::read(inputs.join("text.old")).unwrap(), &djw, &PatchOptions::default());
+        assert!(matches!(result, Err(BsdiffError::CorruptPatch { .. })), "{:?}", result);
+
+        // Hostile deltas fail cleanly
+        let header = [0xD6, 0xC3, 0xC4, 0x00, 0x00];
+        let with_window = |window: Vec<u8>| [&header[..], &window].concat();
+        let corpus = [
+            header[..4].to_vec(),
+            [&header[..], &[0x00, 0x80][..]].concat(),
+            with_window(window(0x01, Some((10, 15)), 4, [b"", &[20], &[0]], &[])),
+            with_window(window(0x02, Some((4, 0)), 4, [b"", &[20], &[0]], &[])),
+            with_window(window(0, None, 4, [b"", &[20], &[0]], &[])),
+            with_window(window(0, None, 4, [b"abcdef", &[7], &[]], &[])),
+            with_window(window(0, None, 4, [b"abc", &[5], &[]], &[])),
+            with_window(window(0, None, 4, [b"ab", &[3, 3], &[]], &[])),
+            with_window(window(0, None, 2, [b"ab", &[3], &[1]], &[])),
+            with_window(window(0, None, 1 << 27, [b"a", &[0, 0x80, 0x80, 0x80, 0x40], &[]], &[])),
+            with_window(window(0x04, None, 2, [b"ab", &[3], &[]], &[0, 0, 0, 0])),
+            [&[0xD6, 0xC3, 0xC4, 0x00, 0x01, 0x02][..], &window(0, None, 2, [b"ab", &[3], &[]], &[])].concat(),
+            [&[0xD6, 0xC3, 0xC4, 0x00, 0x04, 0x7F][..], b"short"].concat(),
+        ];
+        for (i, data) in corpus.iter().enumerate() {
+            let result = BsdiffRust::patch_buffers(old, data, &PatchOptions::default());
+            assert!(
+                matches!(result, Err(BsdiffError::CorruptPatch { .. } | BsdiffError::ChecksumMismatch { .. })),
+                "{}: {:?}",
+                i,
+                result
+            );
+        }
+
+        let valid = BsdiffRust::diff_buffers(&old_content, &new_content, &options).unwrap();
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+        let options = PatchOptions { max_target_size: Some(1 << 20), ..PatchOptions::default() };
+        for _ in 0..256 {
+            let mut data = valid.clone();
+            let seed = rng.next();
+            let offset = (seed >> 8) as usize % data.len();
+            data[offset] ^= (seed >> 40) as u8 | 1;
+            match BsdiffRust::patch_buffers(&old_content, &data, &options) {
+                Ok(output) => assert!(output.len() <= 1 << 20),
+                Err(err) => assert!(
+                    matches!(err, BsdiffError::CorruptPatch { .. } | BsdiffError::LimitExceeded { .. }),
+                    "unexpected error {}",
+                    err
+                ),
+            }
+            inspect_patch(&mut Cursor::new(&data)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_patch_chains() {
+        let v1: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut v2 = v1.clone();
+        v2[500..600].fill(0x11);
+        v2.extend_from_slice(b"second release");
+        let mut v3 = v2[1000..].to_vec();
+        v3[3000..3050].fill(0x22);
+        v3.extend_from_slice(b"third release");
+
+        let options = DiffOptions::default();
+        let patch12 = BsdiffRust::diff_buffers(&v1, &v2, &options).unwrap();
+        let patch23 = BsdiffRust::diff_buffers(&v2, &v3, &options).unwrap();
+        let hooks = Hooks::default();
+
+        // Applying the chain in memory
+        let chain = [patch12.as_slice(), patch23.as_slice()];
+        assert_eq!(BsdiffRust::apply_chain_buffers(&v1, &chain, &PatchOptions::default(), &hooks).unwrap(), v3);
+        let err = BsdiffRust::apply_chain_buffers(&v1, &[], &PatchOptions::default(), &hooks).unwrap_err();
+        assert!(matches!(err, BsdiffError::InvalidOption { name: "patches", .. }));
+
+        // The expected size applies to the final version only
+        let expect = |size: usize| PatchOptions { expected_target_size: Some(size as u64), ..Default::default() };
+        assert_eq!(BsdiffRust::apply_chain_buffers(&v1, &chain, &expect(v3.len()), &hooks).unwrap(), v3);
+        let err = BsdiffRust::apply_chain_buffers(&v1, &chain, &expect(v2.len()), &hooks).unwrap_err();
+        assert!(matches!(err, BsdiffError::SizeMismatch { .. }), "{:?}", err);
+
+        // A corrupt patch is named by its position in the chain
+        let mut corrupt = patch23.clone();
+        corrupt.truncate(corrupt.len() / 2);
+        let err = BsdiffRust::apply_chain_buffers(&v1, &[&patch12, &corrupt], &PatchOptions::default(), &hooks).unwrap_err();
+        match err {
+            BsdiffError::CorruptPatch { reason } => assert!(reason.starts_with("patch 2 of the chain: "), "{}", reason),
+            other => panic!("expected CorruptPatch, got {:?}", other),
+        }
+
+        // Files
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        fs::write(path("v1"), &v1).unwrap();
+        fs::write(path("1-2.patch"), &patch12).unwrap();
+        fs::write(path("2-3.patch"), &patch23).unwrap();
+        let patch_files = [path("1-2.patch"), path("2-3.patch")];
+        let patch_files: Vec<&str> = patch_files.iter().map(String::as_str).collect();
+
+        let stats = BsdiffRust::apply_chain(&path("v1"), &patch_files, &path("v3"), &PatchOptions::default(), &hooks).unwrap();
+        assert_eq!(fs::read(path("v3")).unwrap(), v3);
+        assert_eq!(stats.old_size, v1.len() as u64);
+        assert_eq!(stats.new_size, v3.len() as u64);
+        assert_eq!(stats.patch_size, (patch12.len() + patch23.len()) as u64);
+
+        // The composed patch goes straight from the first to the last version
+        let stats = BsdiffRust::compose_patches(&path("v1"), &patch_files, &path("1-3.patch"), &options, &PatchOptions::default(), &hooks).unwrap();
+        let composed = fs::read(path("1-3.patch")).unwrap();
+        assert!(composed.starts_with(b"BSDIFF40"));
+        assert_eq!(stats.patch_size, composed.len() as u64);
+        assert_eq!(BsdiffRust::patch_buffers(&v1, &composed, &PatchOptions::default()).unwrap(), v3);
+
+        // Both take paths of any form
+        let patch_paths = [dir.path().join("1-2.patch"), dir.path().join("2-3.patch")];
+        BsdiffRust::apply_chain_files(dir.path().join("v1"), &patch_paths, dir.path().join("v3c"), &PatchOptions::default(), &hooks).unwrap();
+        assert_eq!(fs::read(path("v3c")).unwrap(), v3);
+        BsdiffRust::compose_patches_files(dir.path().join("v1"), &patch_paths, dir.path().join("1-3c.patch"), &options, &PatchOptions::default(), &hooks).unwrap();
+        assert_eq!(fs::read(path("1-3c.patch")).unwrap(), composed);
+
+        // Other formats compose too, and a composed patch can join a chain
+        let endsley = DiffOptions { format: OutputFormat::Endsley, ..Default::default() };
+        BsdiffRust::compose_patches(&path("v1"), &patch_files[..1], &path("1-2.endsley"), &endsley, &PatchOptions::default(), &hooks).unwrap();
+        BsdiffRust::apply_chain(&path("v1"), &[&path("1-2.endsley"), patch_files[1]], &path("v3b"), &PatchOptions::default(), &hooks).unwrap();
+        assert_eq!(fs::read(path("v3b")).unwrap(), v3);
+
+        // Nothing is written when the chain is broken or a patch is missing
+        assert!(BsdiffRust::apply_chain(&path("v1"), &[], &path("none"), &PatchOptions::default(), &hooks).is_err());
+        let err = BsdiffRust::apply_chain(&path("v1"), &[patch_files[0], &path("missing")], &path("none"), &PatchOptions::default(), &hooks).unwrap_err();
+        assert!(matches!(err, BsdiffError::NotFound { .. }));
+        fs::write(path("corrupt.patch"), &corrupt).unwrap();
+        let err = BsdiffRust::compose_patches(&path("v1"), &[patch_files[0], &path("corrupt.patch")], &path("none"), &options, &PatchOptions::default(), &hooks).unwrap_err();
+        assert!(matches!(err, BsdiffError::CorruptPatch { .. }));
+        // or when a step exceeds the patch limits
+        for (patch_options, limit) in [
+            (PatchOptions { max_target_size: Some(v3.len() as u64 - 1), ..PatchOptions::default() }, "maxTargetSize"),
+            (PatchOptions { max_memory: Some(v1.len() as u64 - 1), ..PatchOptions::default() }, "maxMemory"),
+        ] {
+            let err = BsdiffRust::compose_patches(&path("v1"), &patch_files, &path("none"), &options, &patch_options, &hooks).unwrap_err();
+            assert!(matches!(err, BsdiffError::LimitExceeded { limit_name, .. } if limit_name == limit), "{:?}", err);
+        }
+        assert!(!dir.path().join("none").exists());
+    }
+
+    #[test]
+    fn test_reverse_patch() {
+        let old_content: Vec<u8> = (0..30_000u32).map(|i| (i * 13 % 251) as u8).collect();
+        let mut new_content = old_content[2000..].to_vec();
+        new_content[4000..4100].fill(0x33);
+        new_content.extend_from_slice(b"new release");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        fs::write(path("old"), &old_content).unwrap();
+        fs::write(path("new"), &new_content).unwrap();
+        let hooks = Hooks::default();
+
+        // The forward and reverse patches are written together
+        let options = DiffOptions::default();
+        let stats = BsdiffRust::diff_files_with_reverse(path("old"), path("new"), path("update.patch"), path("rollback.patch"), &options, &hooks).unwrap();
+        let forward = fs::read(path("update.patch")).unwrap();
+        let rollback = fs::read(path("rollback.patch")).unwrap();
+        assert_eq!(stats.patch_size, forward.len() as u64);
+        assert_eq!(BsdiffRust::patch_buffers(&old_content, &forward, &PatchOptions::default()).unwrap(), new_content);
+        assert_eq!(BsdiffRust::patch_buffers(&new_content, &rollback, &PatchOptions::default()).unwrap(), old_content);
+
+        // The same path for both is rejected before anything is written
+        let err = BsdiffRust::diff_files_with_reverse(path("old"), path("new"), path("same.patch"), path("same.patch"), &options, &hooks).unwrap_err();
+        assert!(matches!(err, BsdiffError::InvalidOption { name: "reversePatch", .. }));
+        assert!(!dir.path().join("same.patch").exists());
+
+        // So is the forward patch when the reverse patch cannot be written
+        let unwritable = dir.path().join("missing").join("rollback.patch");
+        let err = BsdiffRust::diff_files_with_reverse(path("old"), path("new"), path("lone.patch"), &unwritable, &options, &hooks).unwrap_err();
+        assert!(matches!(err, BsdiffError::NotFound { .. }), "{:?}", err);
+        assert!(!dir.path().join("lone.patch").exists());
+
+        // The rollback patch can be derived from the old file and the forward patch alone
+        let container = DiffOptions { container: true, ..Default::default() };
+        let patch_options = PatchOptions::default();
+        let stats = BsdiffRust::reverse_patch(&path("old"), &path("update.patch"), &path("derived.patch"), &container, &patch_options, &hooks).unwrap();
+        let derived = fs::read(path("derived.patch")).unwrap();
+        assert_eq!(stats.old_size, new_content.len() as u64);
+        assert_eq!(stats.new_size, old_content.len() as u64);
+        assert_eq!(stats.patch_size, derived.len() as u64);
+        assert_eq!(BsdiffRust::patch_buffers(&new_content, &derived, &PatchOptions::default()).unwrap(), old_content);
+        // A container rollback refuses to run on anything but the new version
+        let err = BsdiffRust::patch_buffers(&old_content, &derived, &PatchOptions::default()).unwrap_err();
+        assert!(matches!(err, BsdiffError::ChecksumMismatch { .. }), "{:?}", err);
+
+        // A forward patch that does not apply to the old file yields no rollback
+        fs::write(path("other"), b"not the old release").unwrap();
+        let (other, update) = (dir.path().join("other"), dir.path().join("update.patch"));
+        assert!(BsdiffRust::reverse_patch_files(&other, &update, dir.path().join("bad.patch"), &options, &patch_options, &hooks).is_err());
+        assert!(!dir.path().join("bad.patch").exists());
+
+        // The forward step honours the patch limits
+        for (patch_options, limit) in [
+            (PatchOptions { max_target_size: Some(1000), ..PatchOptions::default() }, "maxTargetSize"),
+            (PatchOptions { max_memory: Some(1000), ..PatchOptions::default() }, "maxMemory"),
+        ] {
+            let err = BsdiffRust::reverse_patch(&path("old"), &path("update.patch"), &path("bad.patch"), &options, &patch_options, &hooks).unwrap_err();
+            assert!(matches!(err, BsdiffError::LimitExceeded { limit_name, .. } if limit_name == limit), "{:?}", err);
+        }
+        assert!(!dir.path().join("bad.patch").exists());
+    }
+
+    #[test]
+    fn test_readers_and_paths() {
+        use sha2::{Digest, Sha256};
+
+        let old_content: Vec<u8> = (0..40_000u32).map(|i| (i * 7 % 253) as u8).collect();
+        let mut new_content = old_content.clone();
+        new_content[10_000..10_200].fill(0x5a);
+        new_content.extend_from_slice(b"appended");
+        let hooks = Hooks::default();
+
+        // Readers and writers: cursors stand in for sockets and open handles
+        let mut patch_data = Vec::new();
+        let stats = BsdiffRust::diff_readers(Cursor::new(&old_content), Cursor::new(&new_content), &mut patch_data, &DiffOptions::default(), &hooks).unwrap();
+        assert_eq!(stats.patch_size, patch_data.len() as u64);
+        assert_eq!(stats.mapped_memory, 0);
+        let mut written = Vec::new();
+        let size = BsdiffRust::diff_to_writer(&old_content, &new_content, &mut written, &DiffOptions::default(), &hooks).unwrap();
+        assert_eq!(size, written.len() as u64);
+        assert_eq!(written, patch_data);
+
+        let mut patched = Vec::new();
+        let stats = BsdiffRust::patch_readers(&old_content[..], &patch_data[..], &mut patched, &PatchOptions::default(), &hooks).unwrap();
+        assert_eq!(patched, new_content);
+        assert_eq!(stats.new_size, new_content.len() as u64);
+        assert!(utils::verify_patch_readers(&old_content[..], &new_content[..], &patch_data[..], &hooks).unwrap());
+        assert!(!utils::verify_patch_readers(&old_content[..], &old_content[..], &patch_data[..], &hooks).unwrap());
+        let digest = container::to_hex(&Sha256::digest(&new_content));
+        let verification = utils::verify_patch_digest_readers(
+            &old_content[..], &patch_data[..], &digest, new_content.len() as u64, utils::DigestAlgorithm::Sha256, &hooks
+        ).unwrap();
+        assert!(verification.is_valid());
+
+        // maxMemory caps both readers together while they are read
+        let limit = old_content.len() as u64 + patch_data.len() as u64 - 1;
+        let options = PatchOptions { max_memory: Some(limit), ..Default::default() };
+        let err = BsdiffRust::patch_readers(&old_content[..], &patch_data[..], Vec::new(), &options, &hooks).unwrap_err();
+        assert!(matches!(err, BsdiffError::LimitExceeded { limit_name: "maxMemory", limit: l, .. } if l == limit), "{:?}", err);
+
+        // Path variants take any AsRef<Path>, including paths that are not UTF-8
+        let dir = tempfile::tempdir().unwrap();
+        #[cfg(unix)]
+        let old_file = {
+            use std::os::unix::ffi::OsStrExt;
+            dir.path().join(std::ffi::OsStr::from_bytes(b"old-\xff"))
+        };
+        #[cfg(not(unix))]
+        let old_file = dir.path().join("old");
+        let (new_file, patch_file, out_file) = (dir.path().join("new"), dir.path().join("patch"), dir.path().join("out"));
+        fs::write(&old_file, &old_content).unwrap();
+        fs::write(&new_file, &new_content).unwrap();
+
+        let stats = BsdiffRust::diff_files(&old_file, &new_file, &patch_file, &DiffOptions::default(), &hooks).unwrap();
+        assert_eq!(utils::file_size(&patch_file).unwrap(), stats.patch_size);
+        BsdiffRust::patch_files(&old_file, &out_file, &patch_file, &PatchOptions::default(), &hooks).unwrap();
+        assert_eq!(fs::read(&out_file).unwrap(), new_content);
+        fs::remove_file(&out_file).unwrap();
+        BsdiffRust::patch_files_streaming(&old_file, &out_file, &patch_file, &PatchOptions::default(), &hooks).unwrap();
+        assert_eq!(fs::read(&out_file).unwrap(), new_content);
+        assert!(utils::verify_patch_files(&old_file, &new_file, &patch_file, &hooks).unwrap());
+        assert_eq!(utils::read_patch_info(&patch_file).unwrap().target_size, Some(new_content.len() as u64));
+        utils::check_access(&old_file).unwrap();
+        assert_eq!(utils::compression_ratio(&old_file, &new_file, &patch_file).unwrap().patch_size, stats.patch_size);
+
+        // Errors still name the path, lossily where it is not UTF-8
+        let missing = old_file.with_extension("missing");
+        match BsdiffRust::patch_files(&missing, &out_file, &patch_file, &PatchOptions::default(), &hooks).unwrap_err() {
+            BsdiffError::NotFound { path, .. } => assert_eq!(path, missing.display().to_string()),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_directory_patch() {
+        use crate::tree::{Change, Entry};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (old_dir, new_dir, out_dir) = (dir.path().join("old"), dir.path().join("new"), dir.path().join("out"));
+        let write = |root: &Path, name: &str, data: &[u8]| {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        };
+        let library: Vec<u8> = (0..20_000u32).map(|i| (i * 31 % 241) as u8).collect();
+        let mut library_v2 = library.clone();
+        library_v2[5000..5100].fill(0);
+        let assets = b"moved without changes".repeat(50);
+        write(&old_dir, "lib/core.bin", &library);
+        write(&old_dir, "lib/unchanged.txt", b"same in both");
+        write(&old_dir, "assets.dat", &assets);
+        write(&old_dir, "obsolete/readme.txt", b"removed in the new release");
+        write(&old_dir, "run.sh", b"#!/bin/sh\nexec app\n");
+        write(&new_dir, "lib/core.bin", &library_v2);
+        write(&new_dir, "lib/unchanged.txt", b"same in both");
+        write(&new_dir, "data/assets.dat", &assets);
+        write(&new_dir, "run.sh", b"#!/bin/sh\nexec app\n");
+        write(&new_dir, "notes/new.txt", b"added in the new release");
+        fs::create_dir(new_dir.join("empty")).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(new_dir.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+            std::os::unix::fs::symlink("../lib/core.bin", new_dir.join("data/core.bin")).unwrap();
+        }
+        let hooks = Hooks::default();
+
+        let stats = BsdiffRust::diff_directory_files(&old_dir, &new_dir, dir.path().join("tree.patch"), &DiffOptions::default(), &hooks).unwrap();
+        let patch_data = fs::read(dir.path().join("tree.patch")).unwrap();
+        assert_eq!(stats.patch_size, patch_data.len() as u64);
+        assert!(stats.patch_size < stats.new_size / 4, "{:?}", stats);
+
+        // One entry of each kind, the moved file as a copy of its old path
+        let patch = TreePatch::parse(&patch_data).unwrap();
+        let change = |path: &[u8]| patch.entries.iter().find(|e| e.path == path).map(|e| e.change.clone()).unwrap();
+        assert!(matches!(change(b"lib/core.bin"), Change::Patch { source: b"lib/core.bin", .. }));
+        assert!(matches!(change(b"lib/unchanged.txt"), Change::Copy { source: b"lib/unchanged.txt", .. }));
+        assert!(matches!(change(b"data/assets.dat"), Change::Copy { source: b"assets.dat", .. }));
+        assert!(matches!(change(b"notes/new.txt"), Change::Add { .. }));
+        assert_eq!(change(b"obsolete/readme.txt"), Change::Delete);
+        assert_eq!(change(b"empty"), Change::Directory);
+        let info = utils::read_patch_info(dir.path().join("tree.patch")).unwrap();
+        assert_eq!(info.format, utils::PatchFormat::Directory);
+        assert_eq!(info.target_size, Some(stats.new_size));
+        assert!(info.issues.is_empty(), "{:?}", info.issues);
+
+        // The new tree is rebuilt exactly, permissions and links included
+        let stats = BsdiffRust::patch_directory_files(&old_dir, &out_dir, dir.path().join("tree.patch"), &PatchOptions::default(), &hooks).unwrap();
+        assert_eq!(fs::read(out_dir.join("lib/core.bin")).unwrap(), library_v2);
+        assert_eq!(fs::read(out_dir.join("data/assets.dat")).unwrap(), assets);
+        assert_eq!(fs::read(out_dir.join("notes/new.txt")).unwrap(), b"added in the new release");
+        assert!(out_dir.join("empty").is_dir());
+        assert!(!out_dir.join("obsolete").exists() && !out_dir.join("assets.dat").exists());
+        assert_eq!(stats.new_size, library_v2.len() as u64 + assets.len() as u64 + 12 + 19 + 24);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(out_dir.join("run.sh")).unwrap().permissions().mode() & 0o777, 0o755);
+            assert_eq!(fs::read_link(out_dir.join("data/core.bin")).unwrap(), Path::new("../lib/core.bin"));
+        }
+        // Only into an empty directory
+        let err = BsdiffRust::patch_directory_files(&old_dir, &out_dir, dir.path().join("tree.patch"), &PatchOptions::default(), &hooks).unwrap_err();
+        assert!(matches!(err, BsdiffError::Io { .. }), "{:?}", err);
+
+        // A different old tree fails before the output appears
+        fs::write(old_dir.join("lib/core.bin"), b"another build").unwrap();
+        let elsewhere = dir.path().join("elsewhere");
+        let err = BsdiffRust::patch_directory_files(&old_dir, &elsewhere, dir.path().join("tree.patch"), &PatchOptions::default(), &hooks).unwrap_err();
+        assert!(matches!(err, BsdiffError::ChecksumMismatch { what: "Old file", .. }), "{:?}", err);
+        assert!(!elsewhere.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 4, "Staging directory should be cleaned up");
+
+        // Paths escaping the output directory are refused
+        let hostile = |entries: &[Entry<'_>]| {
+            let mut data = TreePatch::header(entries.len() as u64).to_vec();
+            for entry in entries {
+                entry.encode(&mut data);
+            }
+            fs::write(dir.path().join("hostile.patch"), data).unwrap();
+            BsdiffRust::patch_directory_files(&old_dir, &elsewhere, dir.path().join("hostile.patch"), &PatchOptions::default(), &hooks)
+                .unwrap_err()
+        };
+        let add = Change::Add { size: 0, sha256: [0; 32], patch: b"" };
+        for path in [&b"../escaped"[..], b"/etc/passwd", b"a/../../escaped", b"", b"./x"] {
+            let err = hostile(&[Entry { path, mode: 0o644, change: add.clone() }]);
+            assert!(matches!(err, BsdiffError::CorruptPatch { .. }), "{:?}: {:?}", String::from_utf8_lossy(path), err);
+        }
+        // Including through a symbolic link, or one pointing out of the tree
+        let link = Entry { path: b"link", mode: 0, change: Change::Symlink { target: b".." } };
+        let below = Entry { path: b"link/escaped", mode: 0o644, change: add.clone() };
+        assert!(matches!(hostile(&[link.clone(), below]), BsdiffError::CorruptPatch { .. }));
+        assert!(matches!(hostile(&[link]), BsdiffError::CorruptPatch { .. }));
+        // Or one that only escapes by resolving through another link
+        let chained = [
+            Entry { path: b"d", mode: 0o755, change: Change::Directory },
+            Entry { path: b"d/l", mode: 0, change: Change::Symlink { target: b".." } },
+            Entry { path: b"x", mode: 0, change: Change::Symlink { target: b"d/l/.." } },
+        ];
+        assert!(matches!(hostile(&chained), BsdiffError::CorruptPatch { .. }));
+        assert!(matches!(hostile(&[chained[2].clone(), chained[0].clone(), chained[1].clone()]), BsdiffError::CorruptPatch { .. }));
+        let copy = Entry { path: b"copy", mode: 0o644, change: Change::Copy { source: b"../tree.patch", size: 0, sha256: [0; 32] } };
+        assert!(matches!(hostile(&[copy]), BsdiffError::CorruptPatch { .. }));
+        assert!(!elsewhere.exists() && !dir.path().join("escaped").exists());
+
+        // Single-file APIs refuse directory patches
+        let err = BsdiffRust::patch_buffers(&library, &patch_data, &PatchOptions::default()).unwrap_err();
+        assert!(matches!(err, BsdiffError::CorruptPatch { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_deflate() {
+        use std::io::Read;
+        use flate2::read::DeflateDecoder;
+        use sha2::{Digest, Sha256};
+        use crate::deflate::{self, Params, Strategy};
+
+        // Text over three windows long, incompressible noise that zlib stores,
+        // both in one stream, and nothing at all
+        let text: Vec<u8> = (0..5000u32).flat_map(|i| format!("line {}: value {} of {}\n", i, i * 7 % 13, i % 97).into_bytes()).collect();
+        let noise = XorShift(0x2545_F491_4F6C_DD1D).bytes(40_000);
+        let mixed = [&text[..20_000], &noise[..20_000], &text[..20_000]].concat();
+        let empty = Vec::new();
+
+        // The encoder matches zlib byte for byte at every level, memory level
+        // and strategy `find_params` tries (digests from zlib 1.2.13)
+        for (data, level, mem_level, strategy, len, digest) in [
+            (&text, 1, 8, Strategy::Default, 30090, "939bcf226d38ca85f78e1f2b931bf0a597edc78e3583042c0fa59139492dae73"),
+            (&text, 2, 8, Strategy::Default, 30271, "af6f2e160f96a37e3e01732331b897867f1e880396d269055c53e987f1720673"),
+            (&text, 3, 8, Strategy::Default, 27431, "7758431d6a3fb66b1a5a920ecbaa873d91fb0086faa64454787e20dc8f38fe7c"),
+            (&text, 4, 8, Strategy::Default, 25911, "61d7133ba39465df0ef5b8d066bbabbcdf3d0906049fcb49bcf6b8d9e35d7804"),
+            (&text, 5, 8, Strategy::Default, 25742, "9b3c03a5974e7e3b18b1ec8fadc955bb859c75db6e12ad1836202698fd3c1d28"),
+            (&text, 6, 8, Strategy::Default, 24518, "2b3855ab85df017a8622b8c52d88ba77fb6fd9e597ca1ee5dba62a0d7e21fc59"),
+            (&text, 7, 8, Strategy::Default, 24404, "be2fe5f18ba5e61e2bf922f53c2bead781a50bced1ab19b8fc0761fe7ccc8db8"),
+            (&text, 8, 8, Strategy::Default, 24423, "d90b540dc3113ca8375f6d6535d188dd4daefdca59eceeb21c3233600d1f7014"),
+            (&text, 9, 8, Strategy::Default, 24416, "dbcc4413b045181b805ca296b2c89047438f371b9ee3f4b9e0ec0ac25fcba66d"),
+            (&text, 4, 8, Strategy::Filtered, 26312, "0cd08cb9a105eccf9c7d732a968eb6821bb61b6650b60ff061292a405560b59c"),
+            (&text, 5, 8, Strategy::Filtered, 26160, "412966d721c5e90b92e562878504144fa12dca4aa74ec8c356f69aec6dfeffab"),
+            (&text, 6, 8, Strategy::Filtered, 24553, "6fead38e439cf51227fabad3ae17f5bc0d81be77218fb18482c98443eb22cfe7"),
+            (&text, 7, 8, Strategy::Filtered, 24434, "25b2b8831d3f9b1a17e413b3dda3c8856ad59364be690b473d0babb27b7b942d"),
+            (&text, 8, 8, Strategy::Filtered, 24454, "d6c6faeb920a1b3d4bc9bcabd230a7263c52cebfc8805022ebfb337e41c4616d"),
+            (&text, 9, 8, Strategy::Filtered, 24451, "a481692c203bb60971bc5829acc84a2430ac397351cb628f829443fb01c7f3d8"),
+            (&text, 1, 9, Strategy::Default, 30077, "89ce1525f94e9a9db565baac093184404e930aaa83819bded50ef3a91290ed9e"),
+            (&text, 4, 9, Strategy::Default, 25953, "04862349bd9d7449339bc82764c8d0fb62c296bf25040a229134d4a2af389649"),
+            (&text, 6, 9, Strategy::Default, 24518, "2b3855ab85df017a8622b8c52d88ba77fb6fd9e597ca1ee5dba62a0d7e21fc59"),
+            (&text, 9, 9, Strategy::Default, 24416, "dbcc4413b045181b805ca296b2c89047438f371b9ee3f4b9e0ec0ac25fcba66d"),
+            (&text, 6, 9, Strategy::Filtered, 24553, "6fead38e439cf51227fabad3ae17f5bc0d81be77218fb18482c98443eb22cfe7"),
+            (&text, 9, 9, Strategy::Filtered, 24451, "a481692c203bb60971bc5829acc84a2430ac397351cb628f829443fb01c7f3d8"),
+            (&noise, 1, 8, Strategy::Default, 40015, "4c02d7af104ee0cc88124eca54079e845b187d213148201e30b8343d036126e6"),
+            (&noise, 6, 8, Strategy::Default, 40015, "a9c4aba05d6b646e4dee0313942294d5124db70c93a31e2b98357f4a9e5d3317"),
+            (&noise, 9, 8, Strategy::Default, 40015, "a9c4aba05d6b646e4dee0313942294d5124db70c93a31e2b98357f4a9e5d3317"),
+            (&mixed, 1, 8, Strategy::Default, 32302, "1c498557fa0a7e02ee1fc01b335289e47bb4813cb929f1fe6305fbdec34ac43e"),
+            (&mixed, 6, 8, Strategy::Default, 30477, "df8bcb59d67114e2f9a639caeaa8046b44fcd6c1e84e2d4dbe71c348e84a3967"),
+            (&mixed, 9, 9, Strategy::Filtered, 30134, "4f53005ecfed3948d1428fb9db882a10c44f8751caaa418def4bc968c2683a06"),
+            (&empty, 1, 8, Strategy::Default, 2, "9b4fb24edd6d1d8830e272398263cdbf026b97392cc35387b991dc0248a628f9"),
+            (&empty, 6, 8, Strategy::Default, 2, "9b4fb24edd6d1d8830e272398263cdbf026b97392cc35387b991dc0248a628f9"),
+        ] {
+            let params = Params { level, mem_level, strategy };
+            let compressed = deflate::deflate(data, params);
+            assert_eq!((compressed.len(), container::to_hex(&Sha256::digest(&compressed))), (len, digest.to_string()), "{:?}", params);
+            let mut inflated = Vec::new();
+            DeflateDecoder::new(&compressed[..]).read_to_end(&mut inflated).unwrap();
+            assert!(inflated == *data, "{:?}", params);
+            assert_eq!(deflate::find_params(data, &compressed).map(|found| deflate::deflate(data, found)), Some(compressed));
+        }
+
+        // Noise is stored, and a stream from another encoder is not reproduced
+        assert_eq!(deflate::deflate(&noise, Params { level: 6, mem_level: 8, strategy: Strategy::Default })[0] >> 1 & 3, 0);
+        let foreign = [&[1, 0x10, 0, 0xEF, 0xFF][..], &text[..16]].concat();
+        assert_eq!(deflate::find_params(&text[..16], &foreign), None);
+
+        // Settings round-trip through their stored bytes, and nothing else decodes
+        let params = Params { level: 9, mem_level: 9, strategy: Strategy::Filtered };
+        assert_eq!(Params::from_bytes(params.to_bytes()), Some(params));
+        for bytes in [[0, 8, 0], [10, 8, 0], [6, 0, 0], [6, 10, 0], [6, 8, 2]] {
+            assert_eq!(Params::from_bytes(bytes), None, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_archive_patch() {
+        use crate::deflate::{self, Params, Strategy};
+        use crate::utils::{inspect_patch, PatchFormat, BSDIFF40_MAGIC};
+
+        let text: Vec<u8> = (0..5000u32).flat_map(|i| format!("line {}: value {} of {}\n", i, i * 7 % 13, i % 97).into_bytes()).collect();
+
+        // A minimal ZIP writer: (name, method, data, uncompressed length)
+        let zip = |entries: &[(&str, u16, Vec<u8>, usize)]| {
+            let (mut data, mut central) = (Vec::new(), Vec::new());
+            for (name, method, body, len) in entries {
+                let offset = data.len() as u32;
+                let fields = |data: &mut Vec<u8>| {
+                    data.extend_from_slice(&[20, 0, 0, 0]);
+                    data.extend_from_slice(&method.to_le_bytes());
+                    data.extend_from_slice(&[0; 8]);
+                    data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+                    data.extend_from_slice(&(*len as u32).to_le_bytes());
+                    data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                    data.extend_from_slice(&[0; 2]);
+                };
+                data.extend_from_slice(b"PK\x03\x04");
+                fields(&mut data);
+                data.extend_from_slice(name.as_bytes());
+                data.extend_from_slice(body);
+                central.extend_from_slice(b"PK\x01\x02\x14\x00");
+                fields(&mut central);
+                central.extend_from_slice(&[0; 10]);
+                central.extend_from_slice(&offset.to_le_bytes());
+                central.extend_from_slice(name.as_bytes());
+            }
+            let (cd_offset, cd_len) = (data.len() as u32, central.len() as u32);
+            data.extend_from_slice(&central);
+            data.extend_from_slice(b"PK\x05\x06\0\0\0\0");
+            data.extend_from_slice(&[entries.len() as u8, 0, entries.len() as u8, 0]);
+            data.extend_from_slice(&cd_len.to_le_bytes());
+            data.extend_from_slice(&cd_offset.to_le_bytes());
+            data.extend_from_slice(&[0; 2]);
+            data
+        };
+        let deflated = |data: &[u8], level| (deflate::deflate(data, Params { level, mem_level: 8, strategy: Strategy::Default }), data.len());
+        // A single stored deflate block, which zlib never writes for such data
+        let foreign = |data: &[u8]| {
+            let len = data.len() as u16;
+            let mut block = vec![1];
+            block.extend_from_slice(&len.to_le_bytes());
+            block.extend_from_slice(&(!len).to_le_bytes());
+            block.extend_from_slice(data);
+            (block, data.len())
+        };
+        let mut text_v2 = text.clone();
+        text_v2.splice(30_000..30_000, b"an inserted line\n".iter().copied());
+        text_v2[60_000..60_010].copy_from_slice(b"0123456789");
+        let code: Vec<u8> = (0..40_000u32).map(|i| b"fn main() { let x = 42; }\n"[(i % 26) as usize] ^ (i / 4000) as u8).collect();
+        let mut code_v2 = code.clone();
+        code_v2[20_000..20_004].copy_from_slice(b"XXXX");
+        let entry = |name, (body, len): (Vec<u8>, usize), method| (name, method, body, len);
+        let old = zip(&[
+            entry("text.txt", deflated(&text, 6), 8),
+            entry("code.bin", deflated(&code, 1), 8),
+            entry("stored.txt", (text[..5000].to_vec(), 5000), 0),
+            entry("foreign.dat", foreign(&text[..20_000]), 8),
+        ]);
+        let new = zip(&[
+            entry("text.txt", deflated(&text_v2, 6), 8),
+            entry("code.bin", deflated(&code_v2, 1), 8),
+            entry("stored.txt", (text_v2[..5000].to_vec(), 5000), 0),
+            entry("foreign.dat", foreign(&text_v2[..20_000]), 8),
+            entry("added.txt", deflated(b"a new entry", 6), 8),
+        ]);
+
+        // Reproducible entries are expanded, with the settings they were deflated with
+        let options = DiffOptions { archive: true, ..DiffOptions::default() };
+        let patch_data = BsdiffRust::diff_buffers(&old, &new, &options).unwrap();
+        assert!(patch_data.starts_with(archive::MAGIC));
+        let patch = ArchivePatch::parse(&patch_data).unwrap();
+        assert_eq!((patch.old_size, patch.new_size), (old.len() as u64, new.len() as u64));
+        assert_eq!(patch.old_entries.len(), 2);
+        let levels: Vec<u8> = patch.new_entries.iter().map(|(_, params)| params.level).collect();
+        assert_eq!(levels, [6, 1]);
+        assert_eq!(BsdiffRust::patch_buffers(&old, &patch_data, &PatchOptions::default()).unwrap(), new);
+        let raw = BsdiffRust::diff_buffers(&old, &new, &DiffOptions::default()).unwrap();
+        assert!(patch_data.len() < raw.len() / 4, "{} vs {} bytes", patch_data.len(), raw.len());
+
+        let info = inspect_patch(&mut std::io::Cursor::new(&patch_data)).unwrap();
+        assert_eq!((info.format, info.target_size), (PatchFormat::Archive, Some(new.len() as u64)));
+        assert!(!info.blocks.is_empty() && info.issues.is_empty(), "{:?}", info);
+
+        // Combined with a container, windows and another codec
+        for options in [
+            DiffOptions { archive: true, container: true, ..DiffOptions::default() },
+            DiffOptions { archive: true, window_size: Some(64 * 1024), compression: PatchCompression::Zstd, ..DiffOptions::default() },
+        ] {
+            let patch_data = BsdiffRust::diff_buffers(&old, &new, &options).unwrap();
+            assert_eq!(BsdiffRust::patch_buffers(&old, &patch_data, &PatchOptions::default()).unwrap(), new, "{:?}", options);
+            let info = inspect_patch(&mut std::io::Cursor::new(&patch_data)).unwrap();
+            assert_eq!(info.target_size, Some(new.len() as u64));
+            assert!(info.issues.is_empty(), "{:?}", info.issues);
+        }
+
+        // Anything but a pair of ZIP archives gets an ordinary patch
+        let plain = BsdiffRust::diff_buffers(&text, &text_v2, &options).unwrap();
+        assert!(plain.starts_with(BSDIFF40_MAGIC));
+        let half_zip = BsdiffRust::diff_buffers(&text, &new, &options).unwrap();
+        assert!(half_zip.starts_with(BSDIFF40_MAGIC));
+        assert_eq!(BsdiffRust::patch_buffers(&text, &half_zip, &PatchOptions::default()).unwrap(), new);
+
+        // The wrong base file, hostile tables and settings, and memory limits
+        let expect_corrupt = |old_data: &[u8], patch_data: &[u8]| {
+            let err = BsdiffRust::patch_buffers(old_data, patch_data, &PatchOptions::default()).unwrap_err();
+            assert!(matches!(err, BsdiffError::CorruptPatch { .. }), "{:?}", err);
+        };
+        expect_corrupt(&new, &patch_data);
+        let new_table = archive::HEADER_LEN + 2 * archive::OLD_ENTRY_LEN;
+        for (offset, bytes) in [
+            (32, &u64::MAX.to_le_bytes()[..]),
+            (new_table + 24, &[0][..]),
+            (new_table + 26, &[7][..]),
+            (new_table + 8, &u64::MAX.to_le_bytes()[..]),
+            (new_table + 16, &u64::MAX.to_le_bytes()[..]),
+            (archive::HEADER_LEN, &(old.len() as u64).to_le_bytes()[..]),
+        ] {
+            let mut hostile = patch_data.clone();
+            hostile[offset..offset + bytes.len()].copy_from_slice(bytes);
+            expect_corrupt(&old, &hostile);
+        }
+        let limits = PatchOptions { max_memory: Some((old.len() + new.len() + patch_data.len()) as u64), ..PatchOptions::default() };
+        let err = BsdiffRust::patch_buffers(&old, &patch_data, &limits).unwrap_err();
+        assert!(matches!(err, BsdiffError::LimitExceeded { limit_name: "maxMemory", .. }), "{:?}", err);
+
+        // Streaming rebuilds the expanded files in memory, and the statistics
+        // and limits say so; the output still arrives in bounded chunks
+        let expanded = patch.expanded_old_size().unwrap() + patch.expanded_new_size().unwrap();
+        let memory = (old.len() + patch_data.len()) as u64 + expanded;
+        let mut recorder = ChunkRecorder { data: Vec::new(), max_write: 0 };
+        let written = BsdiffRust::patch_to_writer(&old, &patch_data, &mut recorder, &PatchOptions::default()).unwrap();
+        assert_eq!(recorder.data, new);
+        assert!(recorder.max_write <= STREAM_CHUNK_SIZE);
+        let stats = BsdiffRust::streaming_stats(Instant::now(), &old, &patch_data, written, 0);
+        assert_eq!(stats.estimated_peak_memory, memory);
+        let limits = PatchOptions { max_memory: Some(memory - 1), ..PatchOptions::default() };
+        let err = BsdiffRust::patch_to_writer(&old, &patch_data, Vec::new(), &limits).unwrap_err();
+        assert!(matches!(err, BsdiffError::LimitExceeded { limit_name: "maxMemory", .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_executable_filter() {
+        use crate::filter::{self, Arch, FilteredPatch};
+        use crate::utils::{inspect_patch, PatchFormat, BSDIFF40_MAGIC};
+
+        // Known conversions, and exact inverses on data full of candidates
+        let mut call = [0xE8, 0, 0, 0, 0, 0xE8, 0xF6, 0xFF, 0xFF, 0xFF];
+        Arch::X86.apply(&mut call, &[0..5, 5..10], true);
+        assert_eq!(call, [0xE8, 5, 0, 0, 0, 0xE8, 0, 0, 0, 0]);
+        let mut branch = vec![0; 0x1000];
+        branch.extend_from_slice(&[0x00, 0x00, 0x00, 0x94, 0x00, 0x00, 0x00, 0x90]);
+        Arch::Arm64.apply(&mut branch, &[0x1000..0x1004, 0x1004..0x1008], true);
+        assert_eq!(u32::from_le_bytes(branch[0x1000..0x1004].try_into().unwrap()), 0x9400_0400);
+        assert_eq!(u32::from_le_bytes(branch[0x1004..].try_into().unwrap()), 0x9000_0000 | 1 << 29);
+        let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+        let noise: Vec<u8> = (0..0x40000)
+            .map(|_| {
+                let seed = rng.next();
+                [0xE8, 0xE9, 0x00, 0xFF, 0x94, 0x90, seed as u8, (seed >> 8) as u8][(seed >> 24) as usize % 8]
+            })
+            .collect();
+        for arch in [Arch::X86, Arch::Arm64] {
+            for regions in [vec![0..0x20000, 0x20000..0x40000], vec![3..1000, 1000..0x3FFFF], vec![0x1234..0x1300, 0x2000..0x2100]] {
+                let mut data = noise.clone();
+                arch.apply(&mut data, &regions, true);
+                assert!(data != noise, "{:?} {:?}", arch, regions);
+                arch.apply(&mut data, &regions, false);
+                assert!(data == noise, "{:?} {:?}", arch, regions);
+            }
+        }
+
+        // A 64-bit x86 ELF whose calls all target a stub table before .text,
+        // where the new build inserts code at the start of .text
+        let elf = |prologue: usize| {
+            let mut data = vec![0; 0x200];
+            data[..6].copy_from_slice(b"\x7fELF\x02\x01");
+            data[18] = 0x3E;
+            data[0x3A] = 64;
+            data[0x3C] = 2;
+            data.resize(0x200 + prologue, 0x90);
+            for k in 0..6000usize {
+                let pos = data.len() as i64;
+                let target = 0x100 + (k * 7 % 32) as i64 * 16;
+                data.push(0xE8);
+                data.extend_from_slice(&((target - pos - 5) as i32).to_le_bytes());
+                data.extend_from_slice(&[0x48, 0x89, 0xC7]);
+            }
+            let (shoff, text_len) = (data.len() as u64, data.len() as u64 - 0x200);
+            data[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+            data.extend_from_slice(&[0; 64]);
+            let mut text = [0u8; 64];
+            text[4] = 1;
+            text[8] = 6;
+            text[24..32].copy_from_slice(&0x200u64.to_le_bytes());
+            text[32..40].copy_from_slice(&text_len.to_le_bytes());
+            data.extend_from_slice(&text);
+            data
+        };
+        let (old, new) = (elf(0), elf(48));
+        let plan = filter::Plan::new(ExecutableFilter::Auto, &old, &new).unwrap();
+        let (old_code, new_code) = (0x200..old.len() as u64 - 128, 0x200..new.len() as u64 - 128);
+        assert_eq!((plan.arch, plan.old_regions, plan.new_regions), (Arch::X86, vec![old_code], vec![new_code]));
+
+        let options = DiffOptions { filter: ExecutableFilter::Auto, ..DiffOptions::default() };
+        let patch_data = BsdiffRust::diff_buffers(&old, &new, &options).unwrap();
+        assert!(patch_data.starts_with(filter::MAGIC));
+        assert_eq!(BsdiffRust::patch_buffers(&old, &patch_data, &PatchOptions::default()).unwrap(), new);
+        let raw = BsdiffRust::diff_buffers(&old, &new, &DiffOptions::default()).unwrap();
+        assert!(patch_data.len() < raw.len(), "{} vs {} bytes", patch_data.len(), raw.len());
+        let info = inspect_patch(&mut std::io::Cursor::new(&patch_data)).unwrap();
+        assert_eq!((info.format, info.target_size), (PatchFormat::Filtered, Some(new.len() as u64)));
+        assert!(!info.blocks.is_empty() && info.issues.is_empty(), "{:?}", info);
+
+        // Combined with a container and windows, and in reverse
+        for options in [
+            DiffOptions { filter: ExecutableFilter::X86, container: true, ..DiffOptions::default() },
+            DiffOptions { filter: ExecutableFilter::Auto, window_size: Some(16 * 1024), ..DiffOptions::default() },
+        ] {
+            let patch_data = BsdiffRust::diff_buffers(&old, &new, &options).unwrap();
+            assert_eq!(BsdiffRust::patch_buffers(&old, &patch_data, &PatchOptions::default()).unwrap(), new, "{:?}", options);
+            let reverse = BsdiffRust::diff_buffers(&new, &old, &options).unwrap();
+            assert_eq!(BsdiffRust::patch_buffers(&new, &reverse, &PatchOptions::default()).unwrap(), old, "{:?}", options);
+        }
+
+        // PE and Mach-O code sections, and the architecture they name
+        let mut pe = vec![0; 0x400];
+        pe[..2].copy_from_slice(b"MZ");
+        pe[0x3C] = 0x40;
+        pe[0x40..0x44].copy_from_slice(b"PE\0\0");
+        pe[0x44..0x46].copy_from_slice(&0xAA64u16.to_le_bytes());
+        pe[0x46] = 1;
+        pe[0x58 + 16..0x58 + 24].copy_from_slice(&[0x00, 0x01, 0, 0, 0x00, 0x02, 0, 0]);
+        pe[0x58 + 36..0x58 + 40].copy_from_slice(&0x6000_0020u32.to_le_bytes());
+        let mut mach_o = vec![0; 0x400];
+        mach_o[..8].copy_from_slice(&[0xCF, 0xFA, 0xED, 0xFE, 0x07, 0, 0, 0x01]);
+        mach_o[16] = 1;
+        mach_o[32] = 0x19;
+        mach_o[36] = 152;
+        mach_o[96] = 1;
+        mach_o[104 + 40] = 0x80;
+        mach_o[104 + 48..104 + 50].copy_from_slice(&0x300u16.to_le_bytes());
+        mach_o[104 + 64..104 + 68].copy_from_slice(&0x8000_0400u32.to_le_bytes());
+        for (data, arch, code) in [(&pe, Arch::Arm64, 0x200..0x300), (&mach_o, Arch::X86, 0x300..0x380)] {
+            let plan = filter::Plan::new(ExecutableFilter::Auto, data, data).unwrap();
+            assert_eq!((plan.arch, plan.new_regions), (arch, vec![code]));
+        }
+
+        // Other files: ordinary patches with auto, the whole file otherwise
+        assert!(filter::Plan::new(ExecutableFilter::Auto, &old, &pe).is_none());
+        assert!(filter::Plan::new(ExecutableFilter::None, &old, &new).is_none());
+        let plain = BsdiffRust::diff_buffers(&noise, &noise[1..], &options).unwrap();
+        assert!(plain.starts_with(BSDIFF40_MAGIC));
+        let options = DiffOptions { filter: ExecutableFilter::Arm64, ..DiffOptions::default() };
+        let whole = BsdiffRust::diff_buffers(&noise, &noise[1..], &options).unwrap();
+        let whole_file = 0..noise.len() as u64;
+        assert_eq!(FilteredPatch::parse(&whole).unwrap().old_regions, [whole_file]);
+        assert_eq!(BsdiffRust::patch_buffers(&noise, &whole, &PatchOptions::default()).unwrap(), &noise[1..]);
+
+        // Streaming holds the filtered old and new files, and the statistics
+        // and limits say so; the output still arrives in bounded chunks
+        let memory = (2 * noise.len() + whole.len() + noise.len() - 1) as u64;
+        let mut recorder = ChunkRecorder { data: Vec::new(), max_write: 0 };
+        let written = BsdiffRust::patch_to_writer(&noise, &whole, &mut recorder, &PatchOptions::default()).unwrap();
+        assert_eq!(recorder.data, &noise[1..]);
+        assert!(recorder.max_write <= STREAM_CHUNK_SIZE);
+        let stats = BsdiffRust::streaming_stats(Instant::now(), &noise, &whole, written, 0);
+        assert_eq!(stats.estimated_peak_memory, memory);
+        let limits = PatchOptions { max_memory: Some(memory - 1), ..PatchOptions::default() };
+        let err = BsdiffRust::patch_to_writer(&noise, &whole, Vec::new(), &limits).unwrap_err();
+        assert!(matches!(err, BsdiffError::LimitExceeded { limit_name: "maxMemory", .. }), "{:?}", err);
+        let options = DiffOptions { filter: ExecutableFilter::Auto, archive: true, ..DiffOptions::default() };
+        let err = BsdiffRust::diff_buffers(&old, &new, &options).unwrap_err();
+        assert!(matches!(err, BsdiffError::InvalidOption { name: "filter", .. }), "{:?}", err);
+
+        // Hostile headers
+        for (offset, bytes) in [
+            (9, &[3][..]),
+            (32, &u64::MAX.to_le_bytes()[..]),
+            (filter::HEADER_LEN + 8, &u64::MAX.to_le_bytes()[..]),
+            (filter::HEADER_LEN, &(old.len() as u64).to_le_bytes()[..]),
+            (filter::HEADER_LEN + filter::REGION_LEN + 8, &(new.len() as u64).to_le_bytes()[..]),
+        ] {
+            let mut hostile = patch_data.clone();
+            hostile[offset..offset + bytes.len()].copy_from_slice(bytes);
+            let err = BsdiffRust::patch_buffers(&old, &hostile, &PatchOptions::default()).unwrap_err();
+            assert!(matches!(err, BsdiffError::CorruptPatch { .. }), "{:?}", err);
+        }
+    }
 }
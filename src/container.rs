@@ -0,0 +1,172 @@
+//! Checksummed container patches, as written with `DiffOptions::container`.
+
+use std::io::Write;
+use sha2::{Digest, Sha256};
+
+use crate::bsdiff_rust::BsdiffError;
+
+/// Magic bytes that open a container patch.
+pub const MAGIC: &[u8; 8] = b"BSDIFFRC";
+
+/// Container format version written by this library.
+pub const VERSION: u8 = 1;
+
+/// Size of the container header in bytes.
+///
+/// Layout (integers little-endian):
+///
+/// | Offset | Size | Field                         |
+/// | ------ | ---- | ----------------------------- |
+/// | 0      | 8    | `MAGIC`                       |
+/// | 8      | 1    | version                       |
+/// | 9      | 7    | reserved, zero                |
+/// | 16     | 8    | old size                      |
+/// | 24     | 8    | new size                      |
+/// | 32     | 32   | SHA-256 of the old data       |
+/// | 64     | 32   | SHA-256 of the new data       |
+///
+/// The BSDIFF40 payload follows the header.
+pub const HEADER_LEN: usize = 96;
+
+/// Container header recording which old data a patch was made for and what
+/// it produces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Size of the old data in bytes.
+    pub old_size: u64,
+    /// Size of the new data in bytes.
+    pub new_size: u64,
+    /// SHA-256 of the old data.
+    pub old_sha256: [u8; 32],
+    /// SHA-256 of the new data.
+    pub new_sha256: [u8; 32],
+}
+
+impl Header {
+    /// Describe a patch from `old_data` to `new_data`.
+    pub fn new(old_data: &[u8], new_data: &[u8]) -> Self {
+        Self {
+            old_size: old_data.len() as u64,
+            new_size: new_data.len() as u64,
+            old_sha256: Sha256::digest(old_data).into(),
+            new_sha256: Sha256::digest(new_data).into(),
+        }
+    }
+
+    /// Parse a header from the first `HEADER_LEN` bytes of a container patch.
+    pub fn parse(data: &[u8]) -> Result<Self, BsdiffError> {
+        if !is_container(data) {
+            return Err(BsdiffError::CorruptPatch { reason: "missing container magic".into() });
+        }
+        if data.len() < HEADER_LEN {
+            return Err(BsdiffError::CorruptPatch { reason: "truncated container header".into() });
+        }
+        if data[8] != VERSION {
+            return Err(BsdiffError::CorruptPatch {
+                reason: format!("unsupported container version {}", data[8]),
+            });
+        }
+
+        let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        Ok(Self {
+            old_size: u64_at(16),
+            new_size: u64_at(24),
+            old_sha256: data[32..64].try_into().unwrap(),
+            new_sha256: data[64..96].try_into().unwrap(),
+        })
+    }
+
+    /// Serialize the header, ready to prepend to a BSDIFF40 payload.
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8] = VERSION;
+        header[16..24].copy_from_slice(&self.old_size.to_le_bytes());
+        header[24..32].copy_from_slice(&self.new_size.to_le_bytes());
+        header[32..64].copy_from_slice(&self.old_sha256);
+        header[64..96].copy_from_slice(&self.new_sha256);
+        header
+    }
+
+    /// Fail with `ChecksumMismatch` unless `old_data` is the data this patch was made for.
+    pub fn verify_old(&self, old_data: &[u8]) -> Result<(), BsdiffError> {
+        let actual: [u8; 32] = Sha256::digest(old_data).into();
+        check("Old file", &self.old_sha256, self.old_size, &actual, old_data.len() as u64)
+    }
+
+    /// Fail with `ChecksumMismatch` unless the output matches the recorded new data.
+    pub fn verify_new(&self, digest: &[u8; 32], size: u64) -> Result<(), BsdiffError> {
+        check("New file", &self.new_sha256, self.new_size, digest, size)
+    }
+}
+
+fn check(what: &'static str, expected: &[u8; 32], expected_size: u64, actual: &[u8; 32], actual_size: u64) -> Result<(), BsdiffError> {
+    if expected == actual && expected_size == actual_size {
+        return Ok(());
+    }
+    Err(BsdiffError::ChecksumMismatch {
+        what,
+        expected: to_hex(expected),
+        actual: to_hex(actual),
+    })
+}
+
+/// Whether `patch_data` starts with the container magic.
+pub fn is_container(patch_data: &[u8]) -> bool {
+    patch_data.starts_with(MAGIC)
+}
+
+/// Wrap a BSDIFF40 payload in a container describing `old_data` and `new_data`.
+pub(crate) fn wrap(old_data: &[u8], new_data: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut patch_data = Vec::with_capacity(HEADER_LEN + payload.len());
+    patch_data.extend_from_slice(&Header::new(old_data, new_data).encode());
+    patch_data.extend_from_slice(payload);
+    patch_data
+}
+
+/// Split a patch into its container header, if any, and the BSDIFF40 payload.
+///
+/// Headerless BSDIFF40 patches are returned unchanged.
+pub(crate) fn split(patch_data: &[u8]) -> Result<(Option<Header>, &[u8]), BsdiffError> {
+    if !is_container(patch_data) {
+        return Ok((None, patch_data));
+    }
+    let header = Header::parse(patch_data)?;
+    Ok((Some(header), &patch_data[HEADER_LEN..]))
+}
+
+/// Writer adapter that hashes the bytes passing through it when enabled.
+pub(crate) struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Option<Sha256>,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, enabled: bool) -> Self {
+        Self { inner, hasher: enabled.then(Sha256::new) }
+    }
+
+    /// SHA-256 of everything written, or `None` if hashing was disabled.
+    pub fn finalize(self) -> Option<[u8; 32]> {
+        self.hasher.map(|hasher| hasher.finalize().into())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Lowercase hex encoding of a digest.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,1041 @@
+//! A raw deflate encoder that reproduces zlib's output bit for bit.
+//!
+//! Archive patches recompress ZIP entries when they are applied, and must get
+//! back the exact bytes the archive held. Most ZIP writers use zlib, whose
+//! streams differ from those of the Rust backends (miniz_oxide, zlib-rs), so
+//! this module follows zlib 1.2.12's `deflate_fast`, `deflate_slow` and tree
+//! construction step by step. Only what ZIP entries use is supported: raw
+//! streams with a 32 KiB window, levels 1-9 and the default and filtered
+//! strategies.
+
+use std::sync::OnceLock;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const W_SIZE: usize = 1 << 15;
+const W_MASK: usize = W_SIZE - 1;
+const WINDOW_SIZE: usize = 2 * W_SIZE;
+/// Bytes of lookahead needed to find a match of any length.
+const MIN_LOOKAHEAD: usize = MAX_MATCH + MIN_MATCH + 1;
+const MAX_DIST: usize = W_SIZE - MIN_LOOKAHEAD;
+/// Matches of length 3 are dropped if they are farther than this.
+const TOO_FAR: usize = 4096;
+/// Bytes zeroed past the end of the input, so matches never read stale data.
+const WIN_INIT: usize = MAX_MATCH;
+const NIL: u16 = 0;
+
+const LENGTH_CODES: usize = 29;
+const LITERALS: usize = 256;
+const L_CODES: usize = LITERALS + 1 + LENGTH_CODES;
+const D_CODES: usize = 30;
+const BL_CODES: usize = 19;
+const HEAP_SIZE: usize = 2 * L_CODES + 1;
+const MAX_BITS: usize = 15;
+const MAX_BL_BITS: usize = 7;
+const END_BLOCK: usize = 256;
+const REP_3_6: usize = 16;
+const REPZ_3_10: usize = 17;
+const REPZ_11_138: usize = 18;
+
+const EXTRA_LBITS: [u8; LENGTH_CODES] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const EXTRA_DBITS: [u8; D_CODES] =
+    [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+const EXTRA_BLBITS: [u8; BL_CODES] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 3, 7];
+/// Order in which the bit length code lengths are sent.
+const BL_ORDER: [usize; BL_CODES] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// zlib's `configuration_table` for levels 1-9: good length, max lazy, nice
+/// length and max chain, and whether the level uses lazy matching.
+const CONFIGURATIONS: [(usize, usize, usize, usize, bool); 9] = [
+    (4, 4, 8, 4, false),
+    (4, 5, 16, 8, false),
+    (4, 6, 32, 32, false),
+    (4, 4, 16, 16, true),
+    (8, 16, 32, 32, true),
+    (8, 16, 128, 128, true),
+    (8, 32, 128, 256, true),
+    (32, 128, 258, 1024, true),
+    (32, 258, 258, 4096, true),
+];
+
+/// zlib compression strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Default,
+    /// Prefer literals over short matches, as zlib's `Z_FILTERED`.
+    Filtered,
+}
+
+/// The zlib settings a deflate stream was produced with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    /// Compression level, 1-9.
+    pub level: u8,
+    /// Memory level, 1-9; zlib's default is 8.
+    pub mem_level: u8,
+    pub strategy: Strategy,
+}
+
+impl Params {
+    /// Decode settings stored as level, memory level and strategy bytes.
+    pub fn from_bytes(bytes: [u8; 3]) -> Option<Self> {
+        let strategy = match bytes[2] {
+            0 => Strategy::Default,
+            1 => Strategy::Filtered,
+            _ => return None,
+        };
+        let valid = (1..=9).contains(&bytes[0]) && (1..=9).contains(&bytes[1]);
+        valid.then_some(Self { level: bytes[0], mem_level: bytes[1], strategy })
+    }
+
+    pub fn to_bytes(self) -> [u8; 3] {
+        [self.level, self.mem_level, self.strategy as u8]
+    }
+}
+
+/// Compress `data` into a raw deflate stream, as zlib's `deflate` with a
+/// window of 15 bits and the settings in `params` would.
+pub fn deflate(data: &[u8], params: Params) -> Vec<u8> {
+    let mut deflater = Deflater::new(data, params, None);
+    deflater.run();
+    deflater.out.bytes
+}
+
+/// Find the settings that reproduce `compressed` from `data`, trying the
+/// common ones first. Each attempt stops at the first block that differs.
+pub fn find_params(data: &[u8], compressed: &[u8]) -> Option<Params> {
+    // Level 6 is zlib's default and 9 the usual "best"; fast levels ignore
+    // the strategy, and memory levels other than 8 are rare
+    const LEVELS: [u8; 9] = [6, 9, 1, 5, 4, 7, 8, 2, 3];
+    let mut candidates = Vec::new();
+    for mem_level in [8, 9] {
+        for strategy in [Strategy::Default, Strategy::Filtered] {
+            for level in LEVELS {
+                if strategy == Strategy::Filtered && level <= 3 {
+                    continue;
+                }
+                candidates.push(Params { level, mem_level, strategy });
+            }
+        }
+    }
+    candidates.into_iter().find(|&params| {
+        let mut deflater = Deflater::new(data, params, Some(compressed));
+        deflater.run() && deflater.out.bytes.len() == compressed.len()
+    })
+}
+
+/// Code tables shared by every stream, built once as zlib's `tr_static_init`.
+struct Tables {
+    /// Length code of each match length minus `MIN_MATCH`.
+    length_code: [u8; 256],
+    base_length: [u16; LENGTH_CODES],
+    /// Distance code of distances 0-255, then of distances 256+ by 128s.
+    dist_code: [u8; 512],
+    base_dist: [u16; D_CODES],
+    static_ltree: [Node; L_CODES + 2],
+    static_dtree: [Node; D_CODES],
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let mut tables = Tables {
+            length_code: [0; 256],
+            base_length: [0; LENGTH_CODES],
+            dist_code: [0; 512],
+            base_dist: [0; D_CODES],
+            static_ltree: [Node::default(); L_CODES + 2],
+            static_dtree: [Node::default(); D_CODES],
+        };
+        let mut length = 0;
+        for (code, &extra) in EXTRA_LBITS[..LENGTH_CODES - 1].iter().enumerate() {
+            tables.base_length[code] = length as u16;
+            for _ in 0..1 << extra {
+                tables.length_code[length] = code as u8;
+                length += 1;
+            }
+        }
+        // Length 258 has a code of its own, overwriting the last entry of code 27
+        tables.length_code[length - 1] = (LENGTH_CODES - 1) as u8;
+
+        let mut dist = 0;
+        for (code, &extra) in EXTRA_DBITS[..16].iter().enumerate() {
+            tables.base_dist[code] = dist as u16;
+            for _ in 0..1 << extra {
+                tables.dist_code[dist] = code as u8;
+                dist += 1;
+            }
+        }
+        dist >>= 7;
+        for (code, &extra) in EXTRA_DBITS.iter().enumerate().skip(16) {
+            tables.base_dist[code] = (dist << 7) as u16;
+            for _ in 0..1 << (extra - 7) {
+                tables.dist_code[256 + dist] = code as u8;
+                dist += 1;
+            }
+        }
+
+        let mut bl_count = [0u16; MAX_BITS + 1];
+        for (n, node) in tables.static_ltree.iter_mut().enumerate() {
+            node.len = match n {
+                0..=143 => 8,
+                144..=255 => 9,
+                256..=279 => 7,
+                _ => 8,
+            };
+            bl_count[node.len as usize] += 1;
+        }
+        gen_codes(&mut tables.static_ltree, L_CODES + 1, &bl_count);
+        for (n, node) in tables.static_dtree.iter_mut().enumerate() {
+            *node = Node { len: 5, code: bi_reverse(n as u16, 5), ..Node::default() };
+        }
+        tables
+    })
+}
+
+fn d_code(dist: usize) -> usize {
+    let tables = tables();
+    if dist < 256 {
+        tables.dist_code[dist] as usize
+    } else {
+        tables.dist_code[256 + (dist >> 7)] as usize
+    }
+}
+
+fn bi_reverse(code: u16, len: u8) -> u16 {
+    code.reverse_bits() >> (16 - len as u32)
+}
+
+/// A Huffman tree node. zlib overlays `freq` with `code` and `dad` with
+/// `len`; they are kept apart here, but each is only read while zlib's
+/// overlay would still hold it.
+#[derive(Debug, Clone, Copy, Default)]
+struct Node {
+    freq: u16,
+    code: u16,
+    dad: u16,
+    len: u16,
+}
+
+/// The fixed parameters of one of the three trees.
+struct StaticDesc {
+    static_tree: Option<&'static [Node]>,
+    extra_bits: &'static [u8],
+    extra_base: usize,
+    elems: usize,
+    max_length: usize,
+}
+
+fn l_desc() -> StaticDesc {
+    StaticDesc {
+        static_tree: Some(&tables().static_ltree),
+        extra_bits: &EXTRA_LBITS,
+        extra_base: LITERALS + 1,
+        elems: L_CODES,
+        max_length: MAX_BITS,
+    }
+}
+
+fn d_desc() -> StaticDesc {
+    StaticDesc {
+        static_tree: Some(&tables().static_dtree),
+        extra_bits: &EXTRA_DBITS,
+        extra_base: 0,
+        elems: D_CODES,
+        max_length: MAX_BITS,
+    }
+}
+
+const BL_DESC: StaticDesc =
+    StaticDesc { static_tree: None, extra_bits: &EXTRA_BLBITS, extra_base: 0, elems: BL_CODES, max_length: MAX_BL_BITS };
+
+/// Assign codes to the leaves of a tree from the count of codes of each length.
+fn gen_codes(tree: &mut [Node], max_code: usize, bl_count: &[u16; MAX_BITS + 1]) {
+    let mut next_code = [0u16; MAX_BITS + 1];
+    let mut code = 0u16;
+    for bits in 1..=MAX_BITS {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+    for node in &mut tree[..=max_code] {
+        let len = node.len as usize;
+        if len != 0 {
+            node.code = bi_reverse(next_code[len], len as u8);
+            next_code[len] += 1;
+        }
+    }
+}
+
+/// State of the tree builder: the heap, depths and the running bit counts of
+/// the current block.
+struct Builder {
+    heap: [usize; HEAP_SIZE],
+    heap_len: usize,
+    heap_max: usize,
+    depth: [u8; HEAP_SIZE],
+    bl_count: [u16; MAX_BITS + 1],
+    /// Bits of the block with the dynamic trees, wrapping like zlib's `ulg`.
+    opt_len: u64,
+    /// Bits of the block with the static trees.
+    static_len: u64,
+}
+
+impl Builder {
+    fn smaller(&self, tree: &[Node], n: usize, m: usize) -> bool {
+        tree[n].freq < tree[m].freq || (tree[n].freq == tree[m].freq && self.depth[n] <= self.depth[m])
+    }
+
+    /// Restore the heap property by moving node `k` down.
+    fn pqdownheap(&mut self, tree: &[Node], mut k: usize) {
+        let v = self.heap[k];
+        let mut j = k << 1;
+        while j <= self.heap_len {
+            if j < self.heap_len && self.smaller(tree, self.heap[j + 1], self.heap[j]) {
+                j += 1;
+            }
+            if self.smaller(tree, v, self.heap[j]) {
+                break;
+            }
+            self.heap[k] = self.heap[j];
+            k = j;
+            j <<= 1;
+        }
+        self.heap[k] = v;
+    }
+
+    /// Build a Huffman tree from the frequencies in `tree`, set the lengths
+    /// and codes of its leaves and return its largest code with a non-zero
+    /// frequency.
+    fn build_tree(&mut self, tree: &mut [Node], desc: &StaticDesc) -> usize {
+        let elems = desc.elems;
+        let mut max_code: isize = -1;
+        self.heap_len = 0;
+        self.heap_max = HEAP_SIZE;
+        for (n, node) in tree.iter_mut().enumerate().take(elems) {
+            if node.freq != 0 {
+                self.heap_len += 1;
+                self.heap[self.heap_len] = n;
+                max_code = n as isize;
+                self.depth[n] = 0;
+            } else {
+                node.len = 0;
+            }
+        }
+
+        // The format needs at least one distance code, and at least two codes
+        // of any kind; force the missing ones
+        while self.heap_len < 2 {
+            let node = if max_code < 2 {
+                max_code += 1;
+                max_code as usize
+            } else {
+                0
+            };
+            self.heap_len += 1;
+            self.heap[self.heap_len] = node;
+            tree[node].freq = 1;
+            self.depth[node] = 0;
+            self.opt_len = self.opt_len.wrapping_sub(1);
+            if let Some(stree) = desc.static_tree {
+                self.static_len = self.static_len.wrapping_sub(stree[node].len as u64);
+            }
+        }
+        let max_code = max_code as usize;
+
+        for n in (1..=self.heap_len / 2).rev() {
+            self.pqdownheap(tree, n);
+        }
+
+        // Combine the two least frequent nodes until one is left
+        let mut node = elems;
+        loop {
+            let n = self.heap[1];
+            self.heap[1] = self.heap[self.heap_len];
+            self.heap_len -= 1;
+            self.pqdownheap(tree, 1);
+            let m = self.heap[1];
+
+            self.heap_max -= 1;
+            self.heap[self.heap_max] = n;
+            self.heap_max -= 1;
+            self.heap[self.heap_max] = m;
+
+            tree[node].freq = tree[n].freq.wrapping_add(tree[m].freq);
+            self.depth[node] = self.depth[n].max(self.depth[m]) + 1;
+            tree[n].dad = node as u16;
+            tree[m].dad = node as u16;
+            self.heap[1] = node;
+            node += 1;
+            self.pqdownheap(tree, 1);
+            if self.heap_len < 2 {
+                break;
+            }
+        }
+        self.heap_max -= 1;
+        self.heap[self.heap_max] = self.heap[1];
+
+        self.gen_bitlen(tree, max_code, desc);
+        gen_codes(tree, max_code, &self.bl_count);
+        max_code
+    }
+
+    /// Set the code lengths of a freshly built tree, limiting them to the
+    /// tree's maximum length, and update `opt_len` and `static_len`.
+    fn gen_bitlen(&mut self, tree: &mut [Node], max_code: usize, desc: &StaticDesc) {
+        let max_length = desc.max_length;
+        self.bl_count = [0; MAX_BITS + 1];
+        let mut overflow = 0;
+
+        tree[self.heap[self.heap_max]].len = 0;
+        let mut h = self.heap_max + 1;
+        while h < HEAP_SIZE {
+            let n = self.heap[h];
+            h += 1;
+            let mut bits = tree[tree[n].dad as usize].len as usize + 1;
+            if bits > max_length {
+                bits = max_length;
+                overflow += 1;
+            }
+            tree[n].len = bits as u16;
+            if n > max_code {
+                continue;
+            }
+            self.bl_count[bits] += 1;
+            let xbits = if n >= desc.extra_base { desc.extra_bits[n - desc.extra_base] as u64 } else { 0 };
+            let f = tree[n].freq as u64;
+            self.opt_len = self.opt_len.wrapping_add(f * (bits as u64 + xbits));
+            if let Some(stree) = desc.static_tree {
+                self.static_len = self.static_len.wrapping_add(f * (stree[n].len as u64 + xbits));
+            }
+        }
+        if overflow == 0 {
+            return;
+        }
+
+        // Move overflowing leaves up, splitting shorter codes to make room
+        loop {
+            let mut bits = max_length - 1;
+            while self.bl_count[bits] == 0 {
+                bits -= 1;
+            }
+            self.bl_count[bits] -= 1;
+            self.bl_count[bits + 1] += 2;
+            self.bl_count[max_length] -= 1;
+            overflow -= 2;
+            if overflow <= 0 {
+                break;
+            }
+        }
+
+        // Reassign the lengths, walking the leaves from the least frequent
+        let mut h = HEAP_SIZE;
+        for bits in (1..=max_length).rev() {
+            let mut n = self.bl_count[bits];
+            while n != 0 {
+                h -= 1;
+                let m = self.heap[h];
+                if m > max_code {
+                    continue;
+                }
+                if tree[m].len as usize != bits {
+                    let delta = (bits as u64).wrapping_sub(tree[m].len as u64).wrapping_mul(tree[m].freq as u64);
+                    self.opt_len = self.opt_len.wrapping_add(delta);
+                    tree[m].len = bits as u16;
+                }
+                n -= 1;
+            }
+        }
+    }
+}
+
+/// Writes bits least significant first, as deflate streams are packed.
+struct BitWriter {
+    bytes: Vec<u8>,
+    buf: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn send_bits(&mut self, value: u64, len: u32) {
+        self.buf |= value << self.count;
+        self.count += len;
+        while self.count >= 8 {
+            self.bytes.push(self.buf as u8);
+            self.buf >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn send_code(&mut self, code: usize, tree: &[Node]) {
+        self.send_bits(tree[code].code as u64, tree[code].len as u32);
+    }
+
+    /// Pad to a byte boundary with zero bits.
+    fn windup(&mut self) {
+        if self.count > 0 {
+            self.bytes.push(self.buf as u8);
+        }
+        self.buf = 0;
+        self.count = 0;
+    }
+}
+
+/// One deflate stream in progress, with the fields of zlib's
+/// `deflate_state` that raw streams use.
+struct Deflater<'a> {
+    input: &'a [u8],
+    next_in: usize,
+    /// Stop at the first block that does not match these bytes.
+    expected: Option<&'a [u8]>,
+    diverged: bool,
+
+    good_match: usize,
+    max_lazy: usize,
+    nice_match: usize,
+    max_chain: usize,
+    lazy: bool,
+    strategy: Strategy,
+
+    window: Vec<u8>,
+    prev: Vec<u16>,
+    head: Vec<u16>,
+    hash_mask: usize,
+    hash_shift: usize,
+    ins_h: usize,
+    high_water: usize,
+
+    strstart: usize,
+    lookahead: usize,
+    block_start: isize,
+    match_start: usize,
+    match_length: usize,
+    prev_length: usize,
+    prev_match: usize,
+    match_available: bool,
+
+    dyn_ltree: [Node; HEAP_SIZE],
+    dyn_dtree: [Node; 2 * D_CODES + 1],
+    bl_tree: [Node; 2 * BL_CODES + 1],
+    builder: Builder,
+    /// Symbols of the current block as (distance, literal or length - 3);
+    /// a distance of zero marks a literal.
+    symbols: Vec<(u16, u8)>,
+    /// Symbols per block, one less than zlib's `lit_bufsize`.
+    max_symbols: usize,
+    out: BitWriter,
+}
+
+impl<'a> Deflater<'a> {
+    fn new(input: &'a [u8], params: Params, expected: Option<&'a [u8]>) -> Self {
+        let (good_match, max_lazy, nice_match, max_chain, lazy) = CONFIGURATIONS[params.level as usize - 1];
+        let hash_bits = params.mem_level as usize + 7;
+        let lit_bufsize = 1 << (params.mem_level as usize + 6);
+        let mut deflater = Self {
+            input,
+            next_in: 0,
+            expected,
+            diverged: false,
+            good_match,
+            max_lazy,
+            nice_match,
+            max_chain,
+            lazy,
+            strategy: params.strategy,
+            window: vec![0; WINDOW_SIZE],
+            prev: vec![NIL; W_SIZE],
+            head: vec![NIL; 1 << hash_bits],
+            hash_mask: (1 << hash_bits) - 1,
+            hash_shift: hash_bits.div_ceil(MIN_MATCH),
+            ins_h: 0,
+            high_water: 0,
+            strstart: 0,
+            lookahead: 0,
+            block_start: 0,
+            match_start: 0,
+            match_length: MIN_MATCH - 1,
+            prev_length: MIN_MATCH - 1,
+            prev_match: 0,
+            match_available: false,
+            dyn_ltree: [Node::default(); HEAP_SIZE],
+            dyn_dtree: [Node::default(); 2 * D_CODES + 1],
+            bl_tree: [Node::default(); 2 * BL_CODES + 1],
+            builder: Builder {
+                heap: [0; HEAP_SIZE],
+                heap_len: 0,
+                heap_max: 0,
+                depth: [0; HEAP_SIZE],
+                bl_count: [0; MAX_BITS + 1],
+                opt_len: 0,
+                static_len: 0,
+            },
+            symbols: Vec::with_capacity(lit_bufsize - 1),
+            max_symbols: lit_bufsize - 1,
+            out: BitWriter { bytes: Vec::new(), buf: 0, count: 0 },
+        };
+        deflater.init_block();
+        deflater
+    }
+
+    /// Compress the whole input. Returns false if it stopped early because
+    /// the output diverged from the expected bytes.
+    fn run(&mut self) -> bool {
+        if self.lazy {
+            self.deflate_slow()
+        } else {
+            self.deflate_fast()
+        }
+    }
+
+    fn update_hash(&mut self, c: u8) {
+        self.ins_h = ((self.ins_h << self.hash_shift) ^ c as usize) & self.hash_mask;
+    }
+
+    /// Insert the string at `pos` into the hash table and return the previous
+    /// head of its chain.
+    fn insert_string(&mut self, pos: usize) -> usize {
+        self.update_hash(self.window[pos + MIN_MATCH - 1]);
+        let head = self.head[self.ins_h];
+        self.prev[pos & W_MASK] = head;
+        self.head[self.ins_h] = pos as u16;
+        head as usize
+    }
+
+    /// Read more input into the window, sliding it down by `W_SIZE` once the
+    /// current position passes the upper half.
+    fn fill_window(&mut self) {
+        loop {
+            let mut more = WINDOW_SIZE - self.lookahead - self.strstart;
+            if self.strstart >= W_SIZE + MAX_DIST {
+                self.window.copy_within(W_SIZE..2 * W_SIZE - more, 0);
+                self.match_start = self.match_start.wrapping_sub(W_SIZE);
+                self.strstart -= W_SIZE;
+                self.block_start -= W_SIZE as isize;
+                self.slide_hash();
+                more += W_SIZE;
+            }
+            if self.next_in == self.input.len() {
+                break;
+            }
+
+            let n = more.min(self.input.len() - self.next_in);
+            let at = self.strstart + self.lookahead;
+            self.window[at..at + n].copy_from_slice(&self.input[self.next_in..self.next_in + n]);
+            self.next_in += n;
+            self.lookahead += n;
+            if self.lookahead >= MIN_MATCH {
+                self.ins_h = self.window[self.strstart] as usize;
+                self.update_hash(self.window[self.strstart + 1]);
+            }
+            if self.lookahead >= MIN_LOOKAHEAD || self.next_in == self.input.len() {
+                break;
+            }
+        }
+
+        // Zero the bytes after the input that matches may compare against
+        if self.high_water < WINDOW_SIZE {
+            let curr = self.strstart + self.lookahead;
+            if self.high_water < curr {
+                let init = (WINDOW_SIZE - curr).min(WIN_INIT);
+                self.window[curr..curr + init].fill(0);
+                self.high_water = curr + init;
+            } else if self.high_water < curr + WIN_INIT {
+                let init = (curr + WIN_INIT - self.high_water).min(WINDOW_SIZE - self.high_water);
+                self.window[self.high_water..self.high_water + init].fill(0);
+                self.high_water += init;
+            }
+        }
+    }
+
+    fn slide_hash(&mut self) {
+        let slide = |m: &mut u16| *m = if *m as usize >= W_SIZE { *m - W_SIZE as u16 } else { NIL };
+        self.head.iter_mut().for_each(slide);
+        self.prev.iter_mut().for_each(slide);
+    }
+
+    /// Find the longest match for the string at `strstart` along the hash
+    /// chain starting at `cur_match`, as zlib's portable `longest_match`.
+    fn longest_match(&mut self, mut cur_match: usize) -> usize {
+        let mut chain_length = self.max_chain;
+        let scan = self.strstart;
+        let mut best_len = self.prev_length;
+        let mut nice_match = self.nice_match;
+        let limit = self.strstart.saturating_sub(MAX_DIST);
+        let window = &self.window;
+        let mut scan_end1 = window[scan + best_len - 1];
+        let mut scan_end = window[scan + best_len];
+
+        if self.prev_length >= self.good_match {
+            chain_length >>= 2;
+        }
+        nice_match = nice_match.min(self.lookahead);
+
+        loop {
+            let m = cur_match;
+            let candidate = window[m + best_len] == scan_end
+                && window[m + best_len - 1] == scan_end1
+                && window[m] == window[scan]
+                && window[m + 1] == window[scan + 1];
+            if candidate {
+                // The third byte matches whenever the hash does, so zlib
+                // starts comparing at the fourth
+                let mut len = 3;
+                while len < MAX_MATCH && window[scan + len] == window[m + len] {
+                    len += 1;
+                }
+                if len > best_len {
+                    self.match_start = cur_match;
+                    best_len = len;
+                    if len >= nice_match {
+                        break;
+                    }
+                    scan_end1 = window[scan + best_len - 1];
+                    scan_end = window[scan + best_len];
+                }
+            }
+            cur_match = self.prev[cur_match & W_MASK] as usize;
+            chain_length -= 1;
+            if cur_match <= limit || chain_length == 0 {
+                break;
+            }
+        }
+        best_len.min(self.lookahead)
+    }
+
+    /// Levels 1-3: take every match found, without lazy evaluation.
+    fn deflate_fast(&mut self) -> bool {
+        loop {
+            if self.lookahead < MIN_LOOKAHEAD {
+                self.fill_window();
+                if self.lookahead == 0 {
+                    break;
+                }
+            }
+
+            let mut hash_head = NIL as usize;
+            if self.lookahead >= MIN_MATCH {
+                hash_head = self.insert_string(self.strstart);
+            }
+            if hash_head != NIL as usize && self.strstart - hash_head <= MAX_DIST {
+                self.match_length = self.longest_match(hash_head);
+            }
+
+            let flush = if self.match_length >= MIN_MATCH {
+                let flush = self.tally_dist(self.strstart - self.match_start, self.match_length - MIN_MATCH);
+                self.lookahead -= self.match_length;
+                if self.match_length <= self.max_lazy && self.lookahead >= MIN_MATCH {
+                    // Insert the strings inside the match, for short matches only
+                    self.match_length -= 1;
+                    loop {
+                        self.strstart += 1;
+                        self.insert_string(self.strstart);
+                        self.match_length -= 1;
+                        if self.match_length == 0 {
+                            break;
+                        }
+                    }
+                    self.strstart += 1;
+                } else {
+                    self.strstart += self.match_length;
+                    self.match_length = 0;
+                    self.ins_h = self.window[self.strstart] as usize;
+                    self.update_hash(self.window[self.strstart + 1]);
+                }
+                flush
+            } else {
+                let flush = self.tally_lit(self.window[self.strstart]);
+                self.lookahead -= 1;
+                self.strstart += 1;
+                flush
+            };
+            if flush && !self.flush_block(false) {
+                return false;
+            }
+        }
+        self.flush_block(true)
+    }
+
+    /// Levels 4-9: emit a match only if the next position has no longer one.
+    fn deflate_slow(&mut self) -> bool {
+        loop {
+            if self.lookahead < MIN_LOOKAHEAD {
+                self.fill_window();
+                if self.lookahead == 0 {
+                    break;
+                }
+            }
+
+            let mut hash_head = NIL as usize;
+            if self.lookahead >= MIN_MATCH {
+                hash_head = self.insert_string(self.strstart);
+            }
+
+            self.prev_length = self.match_length;
+            self.prev_match = self.match_start;
+            self.match_length = MIN_MATCH - 1;
+            if hash_head != NIL as usize && self.prev_length < self.max_lazy && self.strstart - hash_head <= MAX_DIST {
+                self.match_length = self.longest_match(hash_head);
+                let too_far = self.match_length == MIN_MATCH && self.strstart - self.match_start > TOO_FAR;
+                if self.match_length <= 5 && (self.strategy == Strategy::Filtered || too_far) {
+                    self.match_length = MIN_MATCH - 1;
+                }
+            }
+
+            if self.prev_length >= MIN_MATCH && self.match_length <= self.prev_length {
+                let max_insert = self.strstart + self.lookahead - MIN_MATCH;
+                let flush = self.tally_dist(self.strstart - 1 - self.prev_match, self.prev_length - MIN_MATCH);
+                self.lookahead -= self.prev_length - 1;
+                self.prev_length -= 2;
+                loop {
+                    self.strstart += 1;
+                    if self.strstart <= max_insert {
+                        self.insert_string(self.strstart);
+                    }
+                    self.prev_length -= 1;
+                    if self.prev_length == 0 {
+                        break;
+                    }
+                }
+                self.match_available = false;
+                self.match_length = MIN_MATCH - 1;
+                self.strstart += 1;
+                if flush && !self.flush_block(false) {
+                    return false;
+                }
+            } else if self.match_available {
+                let flush = self.tally_lit(self.window[self.strstart - 1]);
+                if flush && !self.flush_block(false) {
+                    return false;
+                }
+                self.strstart += 1;
+                self.lookahead -= 1;
+            } else {
+                self.match_available = true;
+                self.strstart += 1;
+                self.lookahead -= 1;
+            }
+        }
+        if self.match_available {
+            self.tally_lit(self.window[self.strstart - 1]);
+            self.match_available = false;
+        }
+        self.flush_block(true)
+    }
+
+    fn init_block(&mut self) {
+        self.dyn_ltree[..L_CODES].iter_mut().for_each(|node| node.freq = 0);
+        self.dyn_dtree[..D_CODES].iter_mut().for_each(|node| node.freq = 0);
+        self.bl_tree[..BL_CODES].iter_mut().for_each(|node| node.freq = 0);
+        self.dyn_ltree[END_BLOCK].freq = 1;
+        self.builder.opt_len = 0;
+        self.builder.static_len = 0;
+        self.symbols.clear();
+    }
+
+    /// Record a literal; returns whether the block is full.
+    fn tally_lit(&mut self, c: u8) -> bool {
+        self.symbols.push((0, c));
+        self.dyn_ltree[c as usize].freq += 1;
+        self.symbols.len() == self.max_symbols
+    }
+
+    /// Record a match; returns whether the block is full.
+    fn tally_dist(&mut self, dist: usize, len: usize) -> bool {
+        self.symbols.push((dist as u16, len as u8));
+        self.dyn_ltree[tables().length_code[len] as usize + LITERALS + 1].freq += 1;
+        self.dyn_dtree[d_code(dist - 1)].freq += 1;
+        self.symbols.len() == self.max_symbols
+    }
+
+    /// Emit the current block in the cheapest of the stored, static and
+    /// dynamic forms, as zlib's `_tr_flush_block`. Returns false if the
+    /// output no longer matches the expected bytes.
+    fn flush_block(&mut self, last: bool) -> bool {
+        let stored_len = (self.strstart as isize - self.block_start) as usize;
+        let stored = (self.block_start >= 0).then_some(self.block_start as usize);
+
+        let (l_desc, d_desc) = (l_desc(), d_desc());
+        let l_max_code = self.builder.build_tree(&mut self.dyn_ltree, &l_desc);
+        let d_max_code = self.builder.build_tree(&mut self.dyn_dtree, &d_desc);
+        let max_blindex = self.build_bl_tree(l_max_code, d_max_code);
+
+        let mut opt_lenb = (self.builder.opt_len.wrapping_add(3 + 7)) >> 3;
+        let static_lenb = (self.builder.static_len.wrapping_add(3 + 7)) >> 3;
+        if static_lenb <= opt_lenb {
+            opt_lenb = static_lenb;
+        }
+
+        match stored {
+            Some(start) if stored_len as u64 + 4 <= opt_lenb => {
+                self.out.send_bits(last as u64, 3);
+                self.out.windup();
+                self.out.bytes.extend_from_slice(&(stored_len as u16).to_le_bytes());
+                self.out.bytes.extend_from_slice(&(!(stored_len as u16)).to_le_bytes());
+                self.out.bytes.extend_from_slice(&self.window[start..start + stored_len]);
+            }
+            _ if static_lenb == opt_lenb => {
+                self.out.send_bits((1 << 1) + last as u64, 3);
+                let tables = tables();
+                self.compress_block(&tables.static_ltree, &tables.static_dtree);
+            }
+            _ => {
+                self.out.send_bits((2 << 1) + last as u64, 3);
+                self.send_all_trees(l_max_code + 1, d_max_code + 1, max_blindex + 1);
+                let (ltree, dtree) = (self.dyn_ltree, self.dyn_dtree);
+                self.compress_block(&ltree, &dtree);
+            }
+        }
+        self.init_block();
+        if last {
+            self.out.windup();
+        }
+        self.block_start = self.strstart as isize;
+
+        if let Some(expected) = self.expected {
+            let written = &self.out.bytes;
+            if written.len() > expected.len() || expected[..written.len()] != written[..] {
+                self.diverged = true;
+            }
+        }
+        !self.diverged
+    }
+
+    /// Count the code lengths of both trees, as `send_tree` will send them,
+    /// build the bit length tree and return the index in `BL_ORDER` of its
+    /// last code to send.
+    fn build_bl_tree(&mut self, l_max_code: usize, d_max_code: usize) -> usize {
+        scan_tree(&mut self.dyn_ltree, l_max_code, &mut self.bl_tree);
+        scan_tree(&mut self.dyn_dtree, d_max_code, &mut self.bl_tree);
+        self.builder.build_tree(&mut self.bl_tree, &BL_DESC);
+
+        let mut max_blindex = BL_CODES - 1;
+        while max_blindex >= 3 && self.bl_tree[BL_ORDER[max_blindex]].len == 0 {
+            max_blindex -= 1;
+        }
+        self.builder.opt_len = self.builder.opt_len.wrapping_add(3 * (max_blindex as u64 + 1) + 5 + 5 + 4);
+        max_blindex
+    }
+
+    fn send_all_trees(&mut self, lcodes: usize, dcodes: usize, blcodes: usize) {
+        self.out.send_bits((lcodes - 257) as u64, 5);
+        self.out.send_bits((dcodes - 1) as u64, 5);
+        self.out.send_bits((blcodes - 4) as u64, 4);
+        for &code in &BL_ORDER[..blcodes] {
+            self.out.send_bits(self.bl_tree[code].len as u64, 3);
+        }
+        send_tree(&mut self.out, &mut self.dyn_ltree, lcodes - 1, &self.bl_tree);
+        send_tree(&mut self.out, &mut self.dyn_dtree, dcodes - 1, &self.bl_tree);
+    }
+
+    fn compress_block(&mut self, ltree: &[Node], dtree: &[Node]) {
+        let tables = tables();
+        for &(dist, lc) in &self.symbols {
+            if dist == 0 {
+                self.out.send_code(lc as usize, ltree);
+                continue;
+            }
+            let code = tables.length_code[lc as usize] as usize;
+            self.out.send_code(code + LITERALS + 1, ltree);
+            let extra = EXTRA_LBITS[code] as u32;
+            if extra != 0 {
+                self.out.send_bits((lc as u16 - tables.base_length[code]) as u64, extra);
+            }
+            let dist = dist as usize - 1;
+            let code = d_code(dist);
+            self.out.send_code(code, dtree);
+            let extra = EXTRA_DBITS[code] as u32;
+            if extra != 0 {
+                self.out.send_bits((dist - tables.base_dist[code] as usize) as u64, extra);
+            }
+        }
+        self.out.send_code(END_BLOCK, ltree);
+    }
+}
+
+/// One run of equal code lengths, as `scan_tree` and `send_tree` code it.
+enum Run {
+    /// `count` lengths sent one by one.
+    Lengths { len: usize, count: usize },
+    /// `count` repeats of `len` with `REP_3_6`, sending `len` itself first
+    /// unless it repeats the previous run.
+    Repeat { len: usize, count: usize, send_len: bool },
+    /// `count` zero lengths with `REPZ_3_10` or `REPZ_11_138`.
+    Zeros { count: usize },
+}
+
+/// Split the code lengths of `tree` into runs. Like zlib, this leaves a
+/// guard length of 0xffff after `max_code`.
+fn length_runs(tree: &mut [Node], max_code: usize) -> Vec<Run> {
+    let mut runs = Vec::new();
+    let mut prevlen = usize::MAX;
+    let mut nextlen = tree[0].len as usize;
+    let mut count = 0;
+    let (mut max_count, mut min_count) = if nextlen == 0 { (138, 3) } else { (7, 4) };
+    tree[max_code + 1].len = 0xffff;
+    for n in 0..=max_code {
+        let curlen = nextlen;
+        nextlen = tree[n + 1].len as usize;
+        count += 1;
+        if count < max_count && curlen == nextlen {
+            continue;
+        }
+        runs.push(if count < min_count {
+            Run::Lengths { len: curlen, count }
+        } else if curlen != 0 {
+            Run::Repeat { len: curlen, count, send_len: curlen != prevlen }
+        } else {
+            Run::Zeros { count }
+        });
+        count = 0;
+        prevlen = curlen;
+        (max_count, min_count) = if nextlen == 0 {
+            (138, 3)
+        } else if curlen == nextlen {
+            (6, 3)
+        } else {
+            (7, 4)
+        };
+    }
+    runs
+}
+
+/// Count the bit length codes `send_tree` will use for `tree`.
+fn scan_tree(tree: &mut [Node], max_code: usize, bl_tree: &mut [Node]) {
+    for run in length_runs(tree, max_code) {
+        match run {
+            Run::Lengths { len, count } => bl_tree[len].freq += count as u16,
+            Run::Repeat { len, send_len, .. } => {
+                if send_len {
+                    bl_tree[len].freq += 1;
+                }
+                bl_tree[REP_3_6].freq += 1;
+            }
+            Run::Zeros { count } if count <= 10 => bl_tree[REPZ_3_10].freq += 1,
+            Run::Zeros { .. } => bl_tree[REPZ_11_138].freq += 1,
+        }
+    }
+}
+
+/// Send the code lengths of `tree` with the bit length codes of `bl_tree`.
+fn send_tree(out: &mut BitWriter, tree: &mut [Node], max_code: usize, bl_tree: &[Node]) {
+    for run in length_runs(tree, max_code) {
+        match run {
+            Run::Lengths { len, count } => (0..count).for_each(|_| out.send_code(len, bl_tree)),
+            Run::Repeat { len, mut count, send_len } => {
+                if send_len {
+                    out.send_code(len, bl_tree);
+                    count -= 1;
+                }
+                out.send_code(REP_3_6, bl_tree);
+                out.send_bits(count as u64 - 3, 2);
+            }
+            Run::Zeros { count } if count <= 10 => {
+                out.send_code(REPZ_3_10, bl_tree);
+                out.send_bits(count as u64 - 3, 3);
+            }
+            Run::Zeros { count } => {
+                out.send_code(REPZ_11_138, bl_tree);
+                out.send_bits(count as u64 - 11, 7);
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::io::{self, Read, Write};
+use bzip2::read::BzDecoder;
+use bzip2::write::BzEncoder;
+use bzip2::Compression;
+
+use crate::bsdiff_rust::{BsdiffError, ProgressCounter};
+use crate::utils::{self, ENDSLEY_HEADER_LEN, ENDSLEY_MAGIC};
+use crate::variant::{self, ChunkWriter, CONTROL_LEN};
+
+// An ENDSLEY/BSDIFF43 patch, as written by mendsley/bsdiff, is the magic and
+// the new size followed by a single bzip2 stream. For each control entry the
+// stream holds the entry itself, then its diff bytes, then its extra bytes.
+
+/// Whether `patch_data` starts with the ENDSLEY/BSDIFF43 magic.
+pub fn is_endsley(patch_data: &[u8]) -> bool {
+    patch_data.starts_with(ENDSLEY_MAGIC)
+}
+
+/// Size of the output declared by the header.
+pub fn target_size(patch_data: &[u8]) -> Result<u64, BsdiffError> {
+    let corrupt = |reason: String| BsdiffError::CorruptPatch { reason };
+    if !is_endsley(patch_data) {
+        return Err(corrupt("missing ENDSLEY/BSDIFF43 magic".into()));
+    }
+    if (patch_data.len() as u64) < ENDSLEY_HEADER_LEN {
+        return Err(corrupt("truncated ENDSLEY/BSDIFF43 header".into()));
+    }
+    let new_size = utils::decode_offtin(&patch_data[16..24]);
+    if new_size < 0 {
+        return Err(corrupt(format!("negative target size {}", new_size)));
+    }
+    Ok(new_size as u64)
+}
+
+/// Rewrite a BSDIFF40 patch from qbsdiff as ENDSLEY/BSDIFF43, compressing
+/// the interleaved stream at bzip2 `level`.
+pub fn encode(bsdiff40: &[u8], level: u32, progress: &mut ProgressCounter<'_>) -> Result<Vec<u8>, BsdiffError> {
+    let header_len = utils::BSDIFF40_HEADER_LEN as usize;
+    let control_length = utils::decode_offtin(&bsdiff40[8..16]) as usize;
+    let diff_length = utils::decode_offtin(&bsdiff40[16..24]) as usize;
+    let (control, rest) = bsdiff40[header_len..].split_at(control_length);
+    let (diff, extra) = rest.split_at(diff_length);
+    let mut control = BzDecoder::new(control);
+    let mut diff = BzDecoder::new(diff);
+    let mut extra = BzDecoder::new(extra);
+
+    let mut data = ENDSLEY_MAGIC.to_vec();
+    data.extend_from_slice(&bsdiff40[24..32]);
+    let mut body = BzEncoder::new(data, Compression::new(level));
+    let mut entry = [0u8; CONTROL_LEN];
+    while variant::read_entry(&mut control, &mut entry)? {
+        body.write_all(&entry)?;
+        for (block, length) in [(&mut diff, &entry[0..8]), (&mut extra, &entry[8..16])] {
+            let length = utils::decode_offtin(length) as u64;
+            if io::copy(&mut block.take(length), &mut body)? != length {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            progress.advance(length)?;
+        }
+    }
+    Ok(body.finish()?)
+}
+
+/// Apply an ENDSLEY/BSDIFF43 patch to `old_data`, writing the output in
+/// chunks of `chunk_size` bytes.
+///
+/// Like mendsley's bspatch, diff bytes that fall outside the old data are
+/// added to zero. Errors from `writer` are passed through; anything wrong
+/// with the patch itself is a `CorruptPatch`.
+pub fn apply<W: Write>(patch_data: &[u8], old_data: &[u8], writer: W, chunk_size: usize) -> Result<u64, BsdiffError> {
+    let corrupt = |reason: String| BsdiffError::CorruptPatch { reason };
+    let stream_error = |e: io::Error| BsdiffError::CorruptPatch { reason: format!("patch body: {}", e) };
+    let new_size = target_size(patch_data)?;
+    let mut body = BzDecoder::new(&patch_data[ENDSLEY_HEADER_LEN as usize..]);
+
+    let mut output = ChunkWriter::new(writer, chunk_size);
+    let mut entry = [0u8; CONTROL_LEN];
+    let old_len = old_data.len() as i64;
+    let mut old_pos = 0i64;
+    let mut written = 0u64;
+    while written < new_size {
+        if !variant::read_entry(&mut body, &mut entry).map_err(stream_error)? {
+            return Err(corrupt(format!("patch ends after {} of {} bytes", written, new_size)));
+        }
+        let add = utils::decode_offtin(&entry[0..8]);
+        let copy = utils::decode_offtin(&entry[8..16]);
+        let seek = utils::decode_offtin(&entry[16..24]);
+        if add < 0 || copy < 0 {
+            return Err(corrupt(format!("negative control lengths (add {}, copy {})", add, copy)));
+        }
+        if written.checked_add(add as u64).and_then(|n| n.checked_add(copy as u64)).is_none_or(|n| n > new_size) {
+            return Err(corrupt(format!("control entry produces more than the declared {} bytes", new_size)));
+        }
+
+        let mut pos = old_pos;
+        output.extend_with(add as u64, |chunk| {
+            body.read_exact(chunk).map_err(stream_error)?;
+            let end = pos.saturating_add(chunk.len() as i64);
+            let (old_start, old_end) = (pos.clamp(0, old_len), end.clamp(0, old_len));
+            if old_start < old_end {
+                let skip = (old_start - pos) as usize;
+                for (byte, old) in chunk[skip..].iter_mut().zip(&old_data[old_start as usize..old_end as usize]) {
+                    *byte = byte.wrapping_add(*old);
+                }
+            }
+            pos = end;
+            Ok(())
+        })?;
+        output.extend_with(copy as u64, |chunk| body.read_exact(chunk).map_err(stream_error))?;
+
+        written += (add + copy) as u64;
+        old_pos = old_pos
+            .checked_add(add)
+            .and_then(|pos| pos.checked_add(seek))
+            .ok_or_else(|| corrupt(format!("seek of {} overflows", seek)))?;
+    }
+    output.finish()?;
+    Ok(written)
+}
@@ -0,0 +1,498 @@
+//! Executable filters, as applied with `DiffOptions::filter`.
+//!
+//! Code that moves shifts the relative targets of every call and jump across
+//! it, so two builds of a binary differ in thousands of scattered bytes. Like
+//! xz's BCJ filters, a filtered patch turns those relative targets into
+//! absolute ones before diffing, which leaves most of them unchanged, and
+//! turns them back after patching. Only the code sections found in the ELF,
+//! PE or Mach-O headers are filtered.
+
+use std::ops::Range;
+
+use crate::bsdiff_rust::{BsdiffError, ExecutableFilter};
+
+/// Magic bytes that open a filtered patch.
+pub const MAGIC: &[u8; 8] = b"BSDIFFRF";
+
+/// Filtered format version written by this library.
+pub const VERSION: u8 = 1;
+
+/// Size of the filtered header in bytes.
+///
+/// Layout (integers little-endian):
+///
+/// | Offset | Size | Field                         |
+/// | ------ | ---- | ----------------------------- |
+/// | 0      | 8    | `MAGIC`                       |
+/// | 8      | 1    | version                       |
+/// | 9      | 1    | filter, 1 x86 or 2 ARM64      |
+/// | 10     | 6    | reserved, zero                |
+/// | 16     | 8    | old size                      |
+/// | 24     | 8    | new size                      |
+/// | 32     | 8    | old region count              |
+/// | 40     | 8    | new region count              |
+///
+/// The filtered regions of the old file follow, `REGION_LEN` bytes each,
+/// then those of the new file, both in offset order. The rest is a BSDIFF40,
+/// variant, ENDSLEY/BSDIFF43, VCDIFF or windowed patch from the filtered old
+/// file to the filtered new file.
+pub const HEADER_LEN: usize = 48;
+
+/// Size of a region: offset and length, each a little-endian u64.
+pub const REGION_LEN: usize = 16;
+
+/// Instruction set whose branch targets a filter converts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    /// x86 and x86-64 CALL and JMP (E8/E9) with 32-bit displacements.
+    X86 = 1,
+    /// ARM64 BL and ADRP.
+    Arm64 = 2,
+}
+
+impl Arch {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Self::X86),
+            2 => Some(Self::Arm64),
+            _ => None,
+        }
+    }
+
+    /// Convert the relative targets in `regions` of `data` to absolute ones,
+    /// or back when `encode` is false.
+    pub fn apply(self, data: &mut [u8], regions: &[Range<u64>], encode: bool) {
+        for region in regions {
+            let code = &mut data[region.start as usize..region.end as usize];
+            // Positions wrap like the 32-bit offsets of xz's filters
+            match self {
+                Self::X86 => x86(code, region.start as u32, encode),
+                Self::Arm64 => arm64(code, region.start as u32, encode),
+            }
+        }
+    }
+}
+
+/// A parsed filtered patch.
+#[derive(Debug, Clone)]
+pub struct FilteredPatch<'p> {
+    pub arch: Arch,
+    pub old_size: u64,
+    pub new_size: u64,
+    /// Regions of the old file to filter before patching.
+    pub old_regions: Vec<Range<u64>>,
+    /// Regions of the patched file to convert back.
+    pub new_regions: Vec<Range<u64>>,
+    /// Patch from the filtered old file to the filtered new file.
+    pub patch: &'p [u8],
+}
+
+impl<'p> FilteredPatch<'p> {
+    /// Parse a filtered patch, checking that its regions are in order, do
+    /// not overlap and lie inside the files they belong to.
+    pub fn parse(data: &'p [u8]) -> Result<Self, BsdiffError> {
+        let corrupt = |reason: String| BsdiffError::CorruptPatch { reason };
+        if !is_filtered(data) {
+            return Err(corrupt("missing filtered magic".into()));
+        }
+        if data.len() < HEADER_LEN {
+            return Err(corrupt("truncated filtered header".into()));
+        }
+        if data[8] != VERSION {
+            return Err(corrupt(format!("unsupported filtered version {}", data[8])));
+        }
+        let arch = Arch::from_byte(data[9]).ok_or_else(|| corrupt(format!("unknown filter {}", data[9])))?;
+
+        let u64_at = |data: &[u8], offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let (old_size, new_size) = (u64_at(data, 16), u64_at(data, 24));
+        let (old_count, new_count) = (u64_at(data, 32), u64_at(data, 40));
+        let tables_len = old_count
+            .checked_add(new_count)
+            .and_then(|count| count.checked_mul(REGION_LEN as u64))
+            .filter(|&len| len <= (data.len() - HEADER_LEN) as u64)
+            .ok_or_else(|| corrupt("filtered region tables extend past the end of the patch".into()))?;
+        let (tables, patch) = data[HEADER_LEN..].split_at(tables_len as usize);
+        let mut regions = tables.chunks_exact(REGION_LEN).map(|entry| (u64_at(entry, 0), u64_at(entry, 8)));
+        let old_regions = check_regions("old", regions.by_ref().take(old_count as usize), old_size)?;
+        let new_regions = check_regions("new", regions, new_size)?;
+        Ok(Self { arch, old_size, new_size, old_regions, new_regions, patch })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let regions = self.old_regions.iter().chain(&self.new_regions);
+        let mut data = Vec::with_capacity(HEADER_LEN + regions.clone().count() * REGION_LEN + self.patch.len());
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.push(self.arch as u8);
+        data.extend_from_slice(&[0; 6]);
+        for field in [self.old_size, self.new_size, self.old_regions.len() as u64, self.new_regions.len() as u64] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        for region in regions {
+            data.extend_from_slice(&region.start.to_le_bytes());
+            data.extend_from_slice(&(region.end - region.start).to_le_bytes());
+        }
+        data.extend_from_slice(self.patch);
+        data
+    }
+
+    /// Fail with `CorruptPatch` unless `old_data` has the size this patch was made for.
+    pub fn check_old(&self, old_data: &[u8]) -> Result<(), BsdiffError> {
+        if old_data.len() as u64 != self.old_size {
+            return Err(BsdiffError::CorruptPatch {
+                reason: format!("patch was made for an old file of {} bytes, got {}", self.old_size, old_data.len()),
+            });
+        }
+        Ok(())
+    }
+
+    /// A copy of `old_data` with its listed regions filtered.
+    pub fn filter_old(&self, old_data: &[u8]) -> Vec<u8> {
+        let mut filtered = old_data.to_vec();
+        self.arch.apply(&mut filtered, &self.old_regions, true);
+        filtered
+    }
+
+    /// Convert the listed regions of the patched file back in place.
+    pub fn unfilter_new(&self, new_data: &mut [u8]) {
+        self.arch.apply(new_data, &self.new_regions, false);
+    }
+}
+
+/// Whether `patch_data` starts with the filtered magic.
+pub fn is_filtered(patch_data: &[u8]) -> bool {
+    patch_data.starts_with(MAGIC)
+}
+
+fn check_regions(which: &str, regions: impl Iterator<Item = (u64, u64)>, size: u64) -> Result<Vec<Range<u64>>, BsdiffError> {
+    let mut checked: Vec<Range<u64>> = Vec::new();
+    for (offset, len) in regions {
+        let end = offset.checked_add(len).filter(|&end| end <= size);
+        let Some(end) = end.filter(|_| checked.last().is_none_or(|last| offset >= last.end)) else {
+            return Err(BsdiffError::CorruptPatch {
+                reason: format!(
+                    "{} region {}+{} overlaps another or lies outside the {} file of {} bytes",
+                    which, offset, len, which, size
+                ),
+            });
+        };
+        checked.push(offset..end);
+    }
+    Ok(checked)
+}
+
+/// The filter and regions chosen for a pair of inputs.
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub arch: Arch,
+    pub old_regions: Vec<Range<u64>>,
+    pub new_regions: Vec<Range<u64>>,
+}
+
+impl Plan {
+    /// Choose the filter and the regions to filter in each input.
+    ///
+    /// `Auto` filters only ELF, PE and Mach-O files of the same supported
+    /// architecture. An explicit filter is used on any input, filtering the
+    /// whole of those whose code sections are not found. Returns `None` if
+    /// there is nothing to filter.
+    pub fn new(filter: ExecutableFilter, old_data: &[u8], new_data: &[u8]) -> Option<Self> {
+        let (old_exe, new_exe) = (Executable::parse(old_data), Executable::parse(new_data));
+        let whole = |data: &[u8], exe: Option<Executable>| exe.map_or_else(|| std::iter::once(0..data.len() as u64).collect(), |exe| exe.code);
+        let plan = match filter {
+            ExecutableFilter::None => return None,
+            ExecutableFilter::Auto => {
+                let (old_exe, new_exe) = old_exe.zip(new_exe)?;
+                if old_exe.arch != new_exe.arch {
+                    return None;
+                }
+                Self { arch: new_exe.arch?, old_regions: old_exe.code, new_regions: new_exe.code }
+            }
+            ExecutableFilter::X86 | ExecutableFilter::Arm64 => Self {
+                arch: if filter == ExecutableFilter::X86 { Arch::X86 } else { Arch::Arm64 },
+                old_regions: whole(old_data, old_exe),
+                new_regions: whole(new_data, new_exe),
+            },
+        };
+        (!plan.old_regions.is_empty() || !plan.new_regions.is_empty()).then_some(plan)
+    }
+
+    /// Copies of both inputs with their regions filtered.
+    pub fn filter(&self, old_data: &[u8], new_data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let (mut old, mut new) = (old_data.to_vec(), new_data.to_vec());
+        self.arch.apply(&mut old, &self.old_regions, true);
+        self.arch.apply(&mut new, &self.new_regions, true);
+        (old, new)
+    }
+
+    /// Wrap `patch`, made from the filtered old file to the filtered new one.
+    pub fn encode(self, old_data: &[u8], new_data: &[u8], patch: &[u8]) -> Vec<u8> {
+        FilteredPatch {
+            arch: self.arch,
+            old_size: old_data.len() as u64,
+            new_size: new_data.len() as u64,
+            old_regions: self.old_regions,
+            new_regions: self.new_regions,
+            patch,
+        }
+        .encode()
+    }
+}
+
+/// xz's x86 BCJ filter over `data`, which starts at position `start`.
+///
+/// An E8 or E9 byte is taken for a CALL or JMP when its displacement has a
+/// high byte of 0x00 or 0xFF, that is when it lands within 16 MiB. The mask
+/// of recent rejected candidates makes the conversion reversible where
+/// candidates overlap.
+fn x86(data: &mut [u8], start: u32, encode: bool) {
+    const ALLOWED: [bool; 8] = [true, true, true, false, true, false, false, false];
+    const BIT_NUMBER: [u32; 8] = [0, 1, 2, 2, 3, 3, 3, 3];
+    let ms_byte = |b: u8| b == 0 || b == 0xFF;
+    if data.len() < 5 {
+        return;
+    }
+
+    let (mut prev_mask, mut prev_pos) = (0u32, start.wrapping_sub(5));
+    let mut pos = 0;
+    while pos <= data.len() - 5 {
+        if data[pos] != 0xE8 && data[pos] != 0xE9 {
+            pos += 1;
+            continue;
+        }
+        let now = start.wrapping_add(pos as u32);
+        let offset = now.wrapping_sub(prev_pos);
+        prev_pos = now;
+        if offset > 5 {
+            prev_mask = 0;
+        } else {
+            for _ in 0..offset {
+                prev_mask = (prev_mask & 0x77) << 1;
+            }
+        }
+
+        let b = data[pos + 4];
+        if ms_byte(b) && ALLOWED[(prev_mask >> 1) as usize & 7] && prev_mask >> 1 < 0x10 {
+            let mut src = u32::from_le_bytes(data[pos + 1..pos + 5].try_into().unwrap());
+            let mut dest;
+            loop {
+                let next = now.wrapping_add(5);
+                dest = if encode { src.wrapping_add(next) } else { src.wrapping_sub(next) };
+                if prev_mask == 0 {
+                    break;
+                }
+                let i = BIT_NUMBER[(prev_mask >> 1) as usize & 7];
+                if !ms_byte((dest >> (24 - i * 8)) as u8) {
+                    break;
+                }
+                src = dest ^ ((1 << (32 - i * 8)) - 1);
+            }
+            // The high byte repeats bit 24, so it stays 0x00 or 0xFF
+            data[pos + 1..pos + 4].copy_from_slice(&dest.to_le_bytes()[..3]);
+            data[pos + 4] = !((dest >> 24) & 1).wrapping_sub(1) as u8;
+            pos += 5;
+            prev_mask = 0;
+        } else {
+            pos += 1;
+            prev_mask |= 1;
+            if ms_byte(b) {
+                prev_mask |= 0x10;
+            }
+        }
+    }
+}
+
+/// xz's ARM64 filter over `data`, which starts at position `start`: the
+/// 26-bit word offsets of BL and the 4 KiB page offsets of ADRP within
+/// +/-512 MiB.
+fn arm64(data: &mut [u8], start: u32, encode: bool) {
+    for (i, word) in data.chunks_exact_mut(4).enumerate() {
+        let pc = start.wrapping_add(i as u32 * 4);
+        let mut instr = u32::from_le_bytes(word.try_into().unwrap());
+        if instr >> 26 == 0x25 {
+            let pc = if encode { pc >> 2 } else { 0u32.wrapping_sub(pc >> 2) };
+            instr = 0x9400_0000 | (instr.wrapping_add(pc) & 0x03FF_FFFF);
+        } else if instr & 0x9F00_0000 == 0x9000_0000 {
+            let src = ((instr >> 29) & 3) | ((instr >> 3) & 0x001F_FFFC);
+            if src.wrapping_add(0x0002_0000) & 0x001C_0000 != 0 {
+                continue;
+            }
+            let pc = if encode { pc >> 12 } else { 0u32.wrapping_sub(pc >> 12) };
+            let dest = src.wrapping_add(pc);
+            instr &= 0x9000_001F;
+            instr |= (dest & 3) << 29;
+            instr |= (dest & 0x0003_FFFC) << 3;
+            instr |= 0u32.wrapping_sub(dest & 0x0002_0000) & 0x00E0_0000;
+        } else {
+            continue;
+        }
+        word.copy_from_slice(&instr.to_le_bytes());
+    }
+}
+
+/// The architecture and code sections of an ELF, PE or Mach-O file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Executable {
+    /// `None` for architectures without a filter.
+    arch: Option<Arch>,
+    /// File regions holding code, sorted and disjoint.
+    code: Vec<Range<u64>>,
+}
+
+impl Executable {
+    /// Read the headers of a little-endian ELF, PE or 64-bit Mach-O file, or
+    /// `None` if `data` is none of these or its headers are malformed.
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (arch, mut code) = if data.starts_with(b"\x7fELF") {
+            elf(data)?
+        } else if data.starts_with(b"MZ") {
+            pe(data)?
+        } else if data.starts_with(&[0xCF, 0xFA, 0xED, 0xFE]) {
+            mach_o(data)?
+        } else {
+            return None;
+        };
+
+        // Clamp to the file and merge sections that overlap
+        let len = data.len() as u64;
+        code.retain_mut(|region| {
+            *region = region.start.min(len)..region.end.min(len);
+            !region.is_empty()
+        });
+        code.sort_by_key(|region| region.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(code.len());
+        for region in code {
+            match merged.last_mut() {
+                Some(last) if region.start <= last.end => last.end = last.end.max(region.end),
+                _ => merged.push(region),
+            }
+        }
+        Some(Self { arch, code: merged })
+    }
+}
+
+type Sections = (Option<Arch>, Vec<Range<u64>>);
+
+fn le(data: &[u8], offset: u64, size: usize) -> Option<u64> {
+    let offset = usize::try_from(offset).ok()?;
+    let bytes = data.get(offset..offset.checked_add(size)?)?;
+    Some(bytes.iter().rev().fold(0, |value, &b| value << 8 | b as u64))
+}
+
+fn region(offset: u64, size: u64) -> Option<Range<u64>> {
+    Some(offset..offset.checked_add(size)?)
+}
+
+/// Executable sections of an ELF file, or its executable segments if it has
+/// no section headers.
+fn elf(data: &[u8]) -> Option<Sections> {
+    const SHT_NOBITS: u64 = 8;
+    const SHF_EXECINSTR: u64 = 4;
+    const PT_LOAD: u64 = 1;
+    const PF_X: u64 = 1;
+    // Only little-endian files, class 1 (32-bit) or 2 (64-bit)
+    let wide = match (data.get(4), data.get(5)) {
+        (Some(1), Some(1)) => false,
+        (Some(2), Some(1)) => true,
+        _ => return None,
+    };
+    let arch = match le(data, 18, 2)? {
+        3 | 0x3E => Some(Arch::X86),
+        0xB7 => Some(Arch::Arm64),
+        _ => None,
+    };
+    let word = if wide { 8 } else { 4 };
+
+    let (shoff, shentsize, shnum) = if wide {
+        (le(data, 0x28, 8)?, le(data, 0x3A, 2)?, le(data, 0x3C, 2)?)
+    } else {
+        (le(data, 0x20, 4)?, le(data, 0x2E, 2)?, le(data, 0x30, 2)?)
+    };
+    let mut code = Vec::new();
+    for i in 0..shnum {
+        let header = shoff.checked_add(i * shentsize)?;
+        let (flags, offset) = (le(data, header + 8, word)?, le(data, header + 8 + 2 * word as u64, word)?);
+        let size = le(data, header + 8 + 3 * word as u64, word)?;
+        if le(data, header + 4, 4)? != SHT_NOBITS && flags & SHF_EXECINSTR != 0 {
+            code.push(region(offset, size)?);
+        }
+    }
+    if shnum > 0 {
+        return Some((arch, code));
+    }
+
+    let (phoff, phentsize, phnum) = if wide {
+        (le(data, 0x20, 8)?, le(data, 0x36, 2)?, le(data, 0x38, 2)?)
+    } else {
+        (le(data, 0x1C, 4)?, le(data, 0x2A, 2)?, le(data, 0x2C, 2)?)
+    };
+    for i in 0..phnum {
+        let header = phoff.checked_add(i * phentsize)?;
+        let (flags, offset, size) = if wide {
+            (le(data, header + 4, 4)?, le(data, header + 8, 8)?, le(data, header + 0x20, 8)?)
+        } else {
+            (le(data, header + 24, 4)?, le(data, header + 4, 4)?, le(data, header + 16, 4)?)
+        };
+        if le(data, header, 4)? == PT_LOAD && flags & PF_X != 0 {
+            code.push(region(offset, size)?);
+        }
+    }
+    Some((arch, code))
+}
+
+/// Code sections of a PE (Windows) file.
+fn pe(data: &[u8]) -> Option<Sections> {
+    const IMAGE_SCN_CNT_CODE: u64 = 0x20;
+    const IMAGE_SCN_MEM_EXECUTE: u64 = 0x2000_0000;
+    let header = le(data, 0x3C, 4)?;
+    if le(data, header, 4)? != u32::from_le_bytes(*b"PE\0\0") as u64 {
+        return None;
+    }
+    let arch = match le(data, header + 4, 2)? {
+        0x14C | 0x8664 => Some(Arch::X86),
+        0xAA64 => Some(Arch::Arm64),
+        _ => None,
+    };
+    let table = header + 24 + le(data, header + 20, 2)?;
+    let mut code = Vec::new();
+    for i in 0..le(data, header + 6, 2)? {
+        let section = table + i * 40;
+        let (size, offset) = (le(data, section + 16, 4)?, le(data, section + 20, 4)?);
+        if le(data, section + 36, 4)? & (IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE) != 0 {
+            code.push(region(offset, size)?);
+        }
+    }
+    Some((arch, code))
+}
+
+/// Instruction sections of a 64-bit Mach-O (macOS, iOS) file.
+fn mach_o(data: &[u8]) -> Option<Sections> {
+    const LC_SEGMENT_64: u64 = 0x19;
+    const S_ATTR_PURE_INSTRUCTIONS: u64 = 0x8000_0000;
+    const S_ATTR_SOME_INSTRUCTIONS: u64 = 0x400;
+    let arch = match le(data, 4, 4)? {
+        0x0100_0007 => Some(Arch::X86),
+        0x0100_000C => Some(Arch::Arm64),
+        _ => None,
+    };
+    let mut code = Vec::new();
+    let mut command = 32;
+    for _ in 0..le(data, 16, 4)? {
+        let size = le(data, command + 4, 4)?;
+        if size < 8 {
+            return None;
+        }
+        if le(data, command, 4)? == LC_SEGMENT_64 {
+            for i in 0..le(data, command + 64, 4)? {
+                let section = command + 72 + i * 80;
+                let (size, offset) = (le(data, section + 40, 8)?, le(data, section + 48, 4)?);
+                let flags = le(data, section + 64, 4)?;
+                if offset != 0 && flags & (S_ATTR_PURE_INSTRUCTIONS | S_ATTR_SOME_INSTRUCTIONS) != 0 {
+                    code.push(region(offset, size)?);
+                }
+            }
+        }
+        command += size;
+    }
+    Some((arch, code))
+}
@@ -0,0 +1,741 @@
+//! Directory tree patches, as written by `BsdiffRust::diff_directory`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use sha2::{Digest, Sha256};
+
+use crate::bsdiff_rust::{self, BsdiffError, BsdiffRust, DiffOptions, Hooks, PatchOptions, Phase};
+use crate::container::{self, HashingWriter};
+
+/// Magic bytes that open a tree patch.
+pub const MAGIC: &[u8; 8] = b"BSDIFFRD";
+
+/// Tree format version written by this library.
+pub const VERSION: u8 = 1;
+
+/// Size of the tree header in bytes.
+///
+/// Layout (integers little-endian):
+///
+/// | Offset | Size | Field                         |
+/// | ------ | ---- | ----------------------------- |
+/// | 0      | 8    | `MAGIC`                       |
+/// | 8      | 1    | version                       |
+/// | 9      | 7    | reserved, zero                |
+/// | 16     | 8    | entry count                   |
+///
+/// The entries follow in path order, parents before their children. Each
+/// starts with its kind (u8), mode (u32) and path (u32 length and bytes),
+/// followed by the fields of its kind:
+///
+/// | Kind          | Fields                                                    |
+/// | ------------- | --------------------------------------------------------- |
+/// | 0 `Directory` |                                                           |
+/// | 1 `Copy`      | source, size, SHA-256                                     |
+/// | 2 `Patch`     | source, source SHA-256, size, SHA-256, patch              |
+/// | 3 `Add`       | size, SHA-256, patch                                      |
+/// | 4 `Symlink`   | target                                                    |
+/// | 5 `Delete`    |                                                           |
+///
+/// Sources and targets are a u32 length and bytes, sizes a u64, digests 32
+/// bytes and patches a u64 length and bytes.
+pub const HEADER_LEN: usize = 24;
+
+/// Entry kinds, as listed in the table above.
+pub const DIRECTORY: u8 = 0;
+pub const COPY: u8 = 1;
+pub const PATCH: u8 = 2;
+pub const ADD: u8 = 3;
+pub const SYMLINK: u8 = 4;
+pub const DELETE: u8 = 5;
+
+/// One entry of a tree patch. Paths are relative to the tree root, with
+/// components separated by `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'p> {
+    pub path: &'p [u8],
+    /// Unix permission bits; zero for symbolic links and deletions.
+    pub mode: u32,
+    pub change: Change<'p>,
+}
+
+/// What an entry puts at its path in the new tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<'p> {
+    /// A directory.
+    Directory,
+    /// A file identical to the old file at `source`: unchanged when `source`
+    /// is the entry's own path, renamed or copied otherwise.
+    Copy { source: &'p [u8], size: u64, sha256: [u8; 32] },
+    /// A file produced by applying `patch` to the old file at `source`.
+    Patch { source: &'p [u8], source_sha256: [u8; 32], size: u64, sha256: [u8; 32], patch: &'p [u8] },
+    /// A new file, stored whole as a patch against empty data so that it is
+    /// compressed like the deltas.
+    Add { size: u64, sha256: [u8; 32], patch: &'p [u8] },
+    /// A symbolic link to `target`.
+    Symlink { target: &'p [u8] },
+    /// A path of the old tree that the new tree no longer has.
+    Delete,
+}
+
+impl Entry<'_> {
+    /// Append the encoded entry to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let kind = match self.change {
+            Change::Directory => DIRECTORY,
+            Change::Copy { .. } => COPY,
+            Change::Patch { .. } => PATCH,
+            Change::Add { .. } => ADD,
+            Change::Symlink { .. } => SYMLINK,
+            Change::Delete => DELETE,
+        };
+        let short = |out: &mut Vec<u8>, bytes: &[u8]| {
+            out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            out.extend_from_slice(bytes);
+        };
+        let long = |out: &mut Vec<u8>, bytes: &[u8]| {
+            out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            out.extend_from_slice(bytes);
+        };
+
+        out.push(kind);
+        out.extend_from_slice(&self.mode.to_le_bytes());
+        short(out, self.path);
+        match &self.change {
+            Change::Directory | Change::Delete => {}
+            Change::Copy { source, size, sha256 } => {
+                short(out, source);
+                out.extend_from_slice(&size.to_le_bytes());
+                out.extend_from_slice(sha256);
+            }
+            Change::Patch { source, source_sha256, size, sha256, patch } => {
+                short(out, source);
+                out.extend_from_slice(source_sha256);
+                out.extend_from_slice(&size.to_le_bytes());
+                out.extend_from_slice(sha256);
+                long(out, patch);
+            }
+            Change::Add { size, sha256, patch } => {
+                out.extend_from_slice(&size.to_le_bytes());
+                out.extend_from_slice(sha256);
+                long(out, patch);
+            }
+            Change::Symlink { target } => short(out, target),
+        }
+    }
+}
+
+/// A parsed tree patch.
+#[derive(Debug, Clone)]
+pub struct TreePatch<'p> {
+    pub entries: Vec<Entry<'p>>,
+}
+
+impl<'p> TreePatch<'p> {
+    /// Parse a tree patch, checking that every entry lies inside the tree
+    /// and below a directory entry, and that no path appears twice.
+    pub fn parse(data: &'p [u8]) -> Result<Self, BsdiffError> {
+        if !is_tree(data) {
+            return Err(corrupt("missing tree magic".into()));
+        }
+        if data.len() < HEADER_LEN {
+            return Err(corrupt("truncated tree header".into()));
+        }
+        if data[8] != VERSION {
+            return Err(corrupt(format!("unsupported tree version {}", data[8])));
+        }
+
+        let count = u64::from_le_bytes(data[16..24].try_into().unwrap());
+        let mut fields = Fields(&data[HEADER_LEN..]);
+        // Never trust the count with an allocation; every entry takes at least 9 bytes
+        let mut entries = Vec::with_capacity(count.min(fields.0.len() as u64 / 9) as usize);
+        // Whether each path seen so far is a directory
+        let mut seen = HashMap::new();
+        for _ in 0..count {
+            let kind = fields.u8()?;
+            let mode = fields.u32()?;
+            let path = fields.short()?;
+            let change = match kind {
+                DIRECTORY => Change::Directory,
+                COPY => Change::Copy { source: fields.short()?, size: fields.u64()?, sha256: fields.sha256()? },
+                PATCH => Change::Patch {
+                    source: fields.short()?,
+                    source_sha256: fields.sha256()?,
+                    size: fields.u64()?,
+                    sha256: fields.sha256()?,
+                    patch: fields.long()?,
+                },
+                ADD => Change::Add { size: fields.u64()?, sha256: fields.sha256()?, patch: fields.long()? },
+                SYMLINK => Change::Symlink { target: fields.short()? },
+                DELETE => Change::Delete,
+                kind => return Err(corrupt(format!("unknown tree entry kind {}", kind))),
+            };
+
+            check_path(path)?;
+            if let Change::Copy { source, .. } | Change::Patch { source, .. } = change {
+                check_path(source)?;
+            }
+            if change != Change::Delete {
+                if let Some(parent) = parent(path) {
+                    if seen.get(parent) != Some(&true) {
+                        return Err(corrupt(format!("entry {} is not below a directory entry", lossy(path))));
+                    }
+                }
+                if seen.insert(path, change == Change::Directory).is_some() {
+                    return Err(corrupt(format!("entry {} appears twice", lossy(path))));
+                }
+            }
+            entries.push(Entry { path, mode, change });
+        }
+        if !fields.0.is_empty() {
+            return Err(corrupt(format!("{} trailing bytes after the last entry", fields.0.len())));
+        }
+        // Links may name links listed after them, so check targets once all are known
+        let links = entries.iter()
+            .filter(|entry| matches!(entry.change, Change::Symlink { .. }))
+            .map(|entry| entry.path)
+            .collect::<HashSet<_>>();
+        for entry in &entries {
+            if let Change::Symlink { target } = entry.change {
+                check_link_target(entry.path, target, |path| links.contains(path))
+                    .map_err(|reason| corrupt(format!("symbolic link {} {}", lossy(entry.path), reason)))?;
+            }
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Serialize the header of a tree patch with `count` entries.
+    pub fn header(count: u64) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[..8].copy_from_slice(MAGIC);
+        header[8] = VERSION;
+        header[16..].copy_from_slice(&count.to_le_bytes());
+        header
+    }
+}
+
+/// Whether `patch_data` starts with the tree magic.
+pub fn is_tree(patch_data: &[u8]) -> bool {
+    patch_data.starts_with(MAGIC)
+}
+
+/// Little-endian fields read from the front of a tree patch.
+struct Fields<'p>(&'p [u8]);
+
+impl<'p> Fields<'p> {
+    fn take(&mut self, len: u64) -> Result<&'p [u8], BsdiffError> {
+        if len > self.0.len() as u64 {
+            return Err(corrupt("tree entry extends past the end of the patch".into()));
+        }
+        let (field, rest) = self.0.split_at(len as usize);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> Result<u8, BsdiffError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, BsdiffError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, BsdiffError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn sha256(&mut self) -> Result<[u8; 32], BsdiffError> {
+        Ok(self.take(32)?.try_into().unwrap())
+    }
+
+    /// Bytes preceded by a u32 length.
+    fn short(&mut self) -> Result<&'p [u8], BsdiffError> {
+        let len = self.u32()?;
+        self.take(len as u64)
+    }
+
+    /// Bytes preceded by a u64 length.
+    fn long(&mut self) -> Result<&'p [u8], BsdiffError> {
+        let len = self.u64()?;
+        self.take(len)
+    }
+}
+
+/// Total sizes of the files a tree operation read and wrote, and its
+/// estimated peak memory.
+pub struct Totals {
+    pub old_size: u64,
+    pub new_size: u64,
+    pub estimated_peak_memory: u64,
+}
+
+/// Diff the tree at `old_dir` against the tree at `new_dir` and write the
+/// tree patch to `writer`. Returns the totals and the size of the patch.
+///
+/// Files are diffed one at a time with `options`, reporting `Phase::Diffing`
+/// progress over the bytes of the new tree. `options.container` does not
+/// apply: every file is checksummed anyway.
+pub fn diff<W: Write>(
+    old_dir: &Path,
+    new_dir: &Path,
+    mut writer: W,
+    options: &DiffOptions,
+    hooks: &Hooks
+) -> Result<(Totals, u64), BsdiffError> {
+    let options = DiffOptions { container: false, ..options.clone() };
+    let file_hooks = hooks.without_progress();
+    let old_tree = scan(old_dir)?;
+    let new_tree = scan(new_dir)?;
+
+    // Old files by content, for renames and copies; the first path in order wins
+    let mut old_digests = HashMap::new();
+    let mut old_by_digest = HashMap::new();
+    let mut old_size = 0;
+    for (path, node) in &old_tree {
+        if let Node::File { .. } = node {
+            hooks.check_cancelled()?;
+            let (size, digest) = digest_file(&join(old_dir, path))?;
+            old_size += size;
+            old_digests.insert(path.as_slice(), digest);
+            old_by_digest.entry(digest).or_insert(path.as_slice());
+        }
+    }
+
+    let deleted = old_tree.keys().filter(|path| !new_tree.contains_key(*path)).collect::<Vec<_>>();
+    writer.write_all(&TreePatch::header((new_tree.len() + deleted.len()) as u64))?;
+    let mut patch_size = HEADER_LEN as u64;
+
+    let new_size = new_tree.values().map(|node| match node {
+        Node::File { size, .. } => *size,
+        _ => 0,
+    }).sum::<u64>();
+    let mut done = 0;
+    let mut estimated_peak_memory = 0;
+    hooks.report(Phase::Diffing, 0, new_size);
+    let mut encoded = Vec::new();
+    for (path, node) in &new_tree {
+        hooks.check_cancelled()?;
+        let mut write = |entry: Entry<'_>| {
+            encoded.clear();
+            entry.encode(&mut encoded);
+            patch_size += encoded.len() as u64;
+            writer.write_all(&encoded)
+        };
+        match node {
+            Node::Directory { mode } => write(Entry { path, mode: *mode, change: Change::Directory })?,
+            Node::Symlink { target } => write(Entry { path, mode: 0, change: Change::Symlink { target } })?,
+            Node::File { mode, .. } => {
+                let file = join(new_dir, path);
+                let new_data = fs::read(&file).map_err(|e| BsdiffError::io(&file, e))?;
+                let size = new_data.len() as u64;
+                let sha256: [u8; 32] = Sha256::digest(&new_data).into();
+                let unchanged = old_digests.get(path.as_slice()) == Some(&sha256);
+                let patch;
+                let change = match old_by_digest.get(&sha256) {
+                    _ if unchanged => Change::Copy { source: path, size, sha256 },
+                    Some(&source) => Change::Copy { source, size, sha256 },
+                    None => {
+                        let source = old_digests.get_key_value(path.as_slice());
+                        let old_data = match source {
+                            Some((&source, _)) => {
+                                let file = join(old_dir, source);
+                                fs::read(&file).map_err(|e| BsdiffError::io(&file, e))?
+                            }
+                            None => Vec::new(),
+                        };
+                        patch = BsdiffRust::diff_buffers_with_hooks(&old_data, &new_data, &options, &file_hooks)?;
+                        // Both versions, the u32 suffix array and the encoded patch twice
+                        estimated_peak_memory = estimated_peak_memory.max(5 * old_data.len() as u64 + 4 + size + 2 * patch.len() as u64);
+                        match source {
+                            Some((&source, &source_sha256)) => {
+                                Change::Patch { source, source_sha256, size, sha256, patch: &patch }
+                            }
+                            None => Change::Add { size, sha256, patch: &patch },
+                        }
+                    }
+                };
+                estimated_peak_memory = estimated_peak_memory.max(size);
+                write(Entry { path, mode: *mode, change })?;
+                done += size;
+                hooks.report(Phase::Diffing, done, new_size);
+            }
+        }
+    }
+    for path in deleted {
+        encoded.clear();
+        Entry { path, mode: 0, change: Change::Delete }.encode(&mut encoded);
+        patch_size += encoded.len() as u64;
+        writer.write_all(&encoded)?;
+    }
+    writer.flush()?;
+
+    Ok((Totals { old_size, new_size, estimated_peak_memory }, patch_size))
+}
+
+/// Rebuild the new tree of `patch` from the tree at `old_dir` into `out_dir`,
+/// which must not exist yet or be empty.
+///
+/// The tree is built in a temporary directory next to `out_dir` and renamed
+/// into place once every file has been written and verified, so a failure
+/// leaves `out_dir` as it was. Each file is checked against the limits in
+/// `options`, except `expected_target_size`, and `Phase::Patching` progress is
+/// reported over the bytes of the new tree.
+pub fn apply(
+    old_dir: &Path,
+    out_dir: &Path,
+    patch: &TreePatch<'_>,
+    options: &PatchOptions,
+    hooks: &Hooks
+) -> Result<Totals, BsdiffError> {
+    match fs::read_dir(out_dir).map(|mut entries| entries.next().is_none()) {
+        Ok(true) => {}
+        Ok(false) => {
+            let source = std::io::Error::new(std::io::ErrorKind::AlreadyExists, "output directory is not empty");
+            return Err(BsdiffError::io(out_dir, source));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(BsdiffError::io(out_dir, e)),
+    }
+    let old_root = old_dir.canonicalize().map_err(|e| BsdiffError::io(old_dir, e))?;
+    let parent = match out_dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = out_dir.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let staging = tempfile::Builder::new()
+        .prefix(&format!(".{}.", name))
+        .suffix(".tmp")
+        .tempdir_in(parent)
+        .map_err(|e| BsdiffError::io(parent, e))?;
+
+    let file_options = PatchOptions { expected_target_size: None, ..options.clone() };
+    let new_size = patch.entries.iter().map(|entry| match entry.change {
+        Change::Copy { size, .. } | Change::Patch { size, .. } | Change::Add { size, .. } => size,
+        _ => 0,
+    }).sum::<u64>();
+    let mut old_size = 0;
+    let mut done = 0;
+    let mut estimated_peak_memory = 0;
+    hooks.report(Phase::Patching, 0, new_size);
+    for entry in &patch.entries {
+        hooks.check_cancelled()?;
+        let path = join(staging.path(), entry.path);
+        match &entry.change {
+            Change::Directory => fs::create_dir(&path).map_err(|e| BsdiffError::io(&path, e))?,
+            Change::Symlink { target } => symlink(target, &path)?,
+            Change::Delete => {}
+            Change::Copy { source, size, sha256 } => {
+                let old_data = read_source(&old_root, source, sha256)?;
+                old_size += old_data.len() as u64;
+                estimated_peak_memory = estimated_peak_memory.max(old_data.len() as u64);
+                write_file(&path, entry.mode, *size, sha256, |out| {
+                    out.write_all(&old_data)?;
+                    Ok(old_data.len() as u64)
+                })?;
+                done += size;
+            }
+            Change::Patch { source, source_sha256, size, sha256, patch } => {
+                let old_data = read_source(&old_root, source, source_sha256)?;
+                old_size += old_data.len() as u64;
+                estimated_peak_memory = estimated_peak_memory.max(bsdiff_rust::streaming_memory(&old_data, patch));
+                write_file(&path, entry.mode, *size, sha256, |out| {
+                    BsdiffRust::patch_to_writer(&old_data, patch, out, &file_options)
+                })?;
+                done += size;
+            }
+            Change::Add { size, sha256, patch } => {
+                estimated_peak_memory = estimated_peak_memory.max(bsdiff_rust::streaming_memory(&[], patch));
+                write_file(&path, entry.mode, *size, sha256, |out| {
+                    BsdiffRust::patch_to_writer(&[], patch, out, &file_options)
+                })?;
+                done += size;
+            }
+        }
+        hooks.report(Phase::Patching, done, new_size);
+    }
+    // Deepest first, so that read-only directories are filled before they are locked
+    for entry in patch.entries.iter().rev().filter(|entry| entry.change == Change::Directory) {
+        set_mode(&join(staging.path(), entry.path), entry.mode)?;
+    }
+
+    hooks.check_cancelled()?;
+    if out_dir.exists() {
+        fs::remove_dir(out_dir).map_err(|e| BsdiffError::io(out_dir, e))?;
+    }
+    let staged = staging.keep();
+    if let Err(e) = fs::rename(&staged, out_dir) {
+        let _ = fs::remove_dir_all(&staged);
+        return Err(BsdiffError::io(out_dir, e));
+    }
+    bsdiff_rust::sync_parent_dir(out_dir).map_err(|e| BsdiffError::io(out_dir, e))?;
+
+    Ok(Totals { old_size, new_size, estimated_peak_memory })
+}
+
+/// A node of a directory tree.
+enum Node {
+    Directory { mode: u32 },
+    File { mode: u32, size: u64 },
+    Symlink { target: Vec<u8> },
+}
+
+/// Walk the tree at `root` without following symbolic links, keyed by path
+/// relative to `root`. Parents sort before their children.
+fn scan(root: &Path) -> Result<BTreeMap<Vec<u8>, Node>, BsdiffError> {
+    let metadata = fs::metadata(root).map_err(|e| BsdiffError::io(root, e))?;
+    if !metadata.is_dir() {
+        let source = std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a directory");
+        return Err(BsdiffError::io(root, source));
+    }
+
+    let mut nodes = BTreeMap::new();
+    let mut pending = vec![(root.to_path_buf(), Vec::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        for item in fs::read_dir(&dir).map_err(|e| BsdiffError::io(&dir, e))? {
+            let item = item.map_err(|e| BsdiffError::io(&dir, e))?;
+            let file = item.path();
+            let mut path = prefix.clone();
+            if !path.is_empty() {
+                path.push(b'/');
+            }
+            path.extend_from_slice(&name_bytes(&file)?);
+
+            let metadata = fs::symlink_metadata(&file).map_err(|e| BsdiffError::io(&file, e))?;
+            let node = if metadata.is_dir() {
+                pending.push((file, path.clone()));
+                Node::Directory { mode: mode_of(&metadata) }
+            } else if metadata.is_file() {
+                Node::File { mode: mode_of(&metadata), size: metadata.len() }
+            } else if metadata.file_type().is_symlink() {
+                let target = fs::read_link(&file).map_err(|e| BsdiffError::io(&file, e))?;
+                let target = os_bytes(target.as_os_str()).ok_or_else(|| not_portable(&file))?;
+                Node::Symlink { target }
+            } else {
+                let source = std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file, directory or symbolic link");
+                return Err(BsdiffError::io(&file, source));
+            };
+            nodes.insert(path, node);
+        }
+    }
+
+    // Refuse what `TreePatch::parse` would refuse when applying the patch
+    let is_link = |path: &[u8]| matches!(nodes.get(path), Some(Node::Symlink { .. }));
+    for (path, node) in &nodes {
+        if let Node::Symlink { target } = node {
+            if let Err(reason) = check_link_target(path, target, is_link) {
+                let source = std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("symbolic link {}", reason));
+                return Err(BsdiffError::io(join(root, path), source));
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+/// Size and SHA-256 of a file, streamed rather than read whole.
+fn digest_file(path: &Path) -> Result<(u64, [u8; 32]), BsdiffError> {
+    let mut file = File::open(path).map_err(|e| BsdiffError::io(path, e))?;
+    let mut hasher = HashingWriter::new(std::io::sink(), true);
+    let size = std::io::copy(&mut file, &mut hasher).map_err(|e| BsdiffError::io(path, e))?;
+    Ok((size, hasher.finalize().unwrap_or_default()))
+}
+
+/// Read the old file at `source`, refusing one that lies outside `old_root`
+/// through a symbolic link, and check it against `sha256`.
+fn read_source(old_root: &Path, source: &[u8], sha256: &[u8; 32]) -> Result<Vec<u8>, BsdiffError> {
+    let path = join(old_root, source);
+    let resolved = path.canonicalize().map_err(|e| BsdiffError::io(&path, e))?;
+    if !resolved.starts_with(old_root) {
+        return Err(corrupt(format!("source {} escapes the old directory", lossy(source))));
+    }
+    let data = fs::read(&resolved).map_err(|e| BsdiffError::io(&path, e))?;
+    let actual: [u8; 32] = Sha256::digest(&data).into();
+    if &actual != sha256 {
+        return Err(BsdiffError::ChecksumMismatch {
+            what: "Old file",
+            expected: container::to_hex(sha256),
+            actual: container::to_hex(&actual),
+        });
+    }
+    Ok(data)
+}
+
+/// Create the file at `path` with the data `produce` writes, check it against
+/// `size` and `sha256`, and give it `mode`.
+fn write_file<F>(path: &Path, mode: u32, size: u64, sha256: &[u8; 32], produce: F) -> Result<(), BsdiffError>
+where
+    F: FnOnce(&mut HashingWriter<BufWriter<&File>>) -> Result<u64, BsdiffError>,
+{
+    let file = File::create(path).map_err(|e| BsdiffError::io(path, e))?;
+    let mut out = HashingWriter::new(BufWriter::new(&file), true);
+    let written = produce(&mut out).map_err(|e| e.with_path(path))?;
+    out.flush().map_err(|e| BsdiffError::io(path, e))?;
+    if written != size {
+        return Err(BsdiffError::SizeMismatch { expected: size, actual: written });
+    }
+    let actual = out.finalize().unwrap_or_default();
+    if &actual != sha256 {
+        return Err(BsdiffError::ChecksumMismatch {
+            what: "New file",
+            expected: container::to_hex(sha256),
+            actual: container::to_hex(&actual),
+        });
+    }
+    file.sync_all().map_err(|e| BsdiffError::io(path, e))?;
+    set_mode(path, mode)
+}
+
+/// Fail with `CorruptPatch` unless `path` is a relative path of normal
+/// components, which cannot escape the directory it is joined to.
+fn check_path(path: &[u8]) -> Result<(), BsdiffError> {
+    let escapes = || corrupt(format!("entry path {} escapes the directory", lossy(path)));
+    if path.is_empty() {
+        return Err(escapes());
+    }
+    for component in path.split(|&b| b == b'/') {
+        if component.contains(&0) {
+            return Err(escapes());
+        }
+        // Rules out empty, `.` and `..` components, and on Windows drive
+        // prefixes and backslashes
+        let component = os_path(component).ok_or_else(escapes)?;
+        if !matches!(component.components().collect::<Vec<_>>()[..], [Component::Normal(_)]) {
+            return Err(escapes());
+        }
+    }
+    Ok(())
+}
+
+/// Check that a symbolic link at `path` pointing to `target` resolves inside
+/// the tree. The target is walked lexically, so it must not step through any
+/// path `is_link` reports as another link: `d/l/..` is only `d` when `d/l`
+/// is a directory.
+fn check_link_target(path: &[u8], target: &[u8], is_link: impl Fn(&[u8]) -> bool) -> Result<(), &'static str> {
+    if target.is_empty() || target.starts_with(b"/") || target.contains(&0) {
+        return Err("must point to a relative path");
+    }
+    // The walk starts in the link's parent, which parsing ensured is a directory
+    let mut walked = parent(path).map(<[u8]>::to_vec).unwrap_or_default();
+    for component in target.split(|&b| b == b'/') {
+        if matches!(component, b"" | b".") {
+            continue;
+        }
+        if is_link(&walked) {
+            return Err("points through another symbolic link");
+        }
+        if component == b".." {
+            if walked.is_empty() {
+                return Err("points outside the directory");
+            }
+            walked.truncate(parent(&walked).map_or(0, <[u8]>::len));
+        } else {
+            if !walked.is_empty() {
+                walked.push(b'/');
+            }
+            walked.extend_from_slice(component);
+        }
+    }
+    Ok(())
+}
+
+/// Parent of a `/`-separated path, or `None` at the top level.
+fn parent(path: &[u8]) -> Option<&[u8]> {
+    path.iter().rposition(|&b| b == b'/').map(|end| &path[..end])
+}
+
+/// `root` joined with a `/`-separated path that passed `check_path`.
+fn join(root: &Path, path: &[u8]) -> PathBuf {
+    let mut joined = root.to_path_buf();
+    for component in path.split(|&b| b == b'/') {
+        joined.push(os_path(component).unwrap_or_else(|| unreachable!("checked by check_path")));
+    }
+    joined
+}
+
+fn corrupt(reason: String) -> BsdiffError {
+    BsdiffError::CorruptPatch { reason }
+}
+
+fn lossy(path: &[u8]) -> String {
+    String::from_utf8_lossy(path).into_owned()
+}
+
+fn not_portable(path: &Path) -> BsdiffError {
+    let source = std::io::Error::new(std::io::ErrorKind::InvalidData, "name is not valid UTF-8");
+    BsdiffError::io(path, source)
+}
+
+/// The file name of `path` as stored in a tree patch.
+fn name_bytes(path: &Path) -> Result<Vec<u8>, BsdiffError> {
+    path.file_name().and_then(os_bytes).ok_or_else(|| not_portable(path))
+}
+
+// Names are stored as raw bytes on Unix, so any file name round-trips, and
+// as UTF-8 elsewhere
+
+#[cfg(unix)]
+fn os_bytes(name: &std::ffi::OsStr) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(name.as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn os_bytes(name: &std::ffi::OsStr) -> Option<Vec<u8>> {
+    name.to_str().map(|name| name.replace('\\', "/").into_bytes())
+}
+
+#[cfg(unix)]
+fn os_path(bytes: &[u8]) -> Option<&Path> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(Path::new(std::ffi::OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn os_path(bytes: &[u8]) -> Option<&Path> {
+    std::str::from_utf8(bytes).ok().map(Path::new)
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, false) => 0o644,
+        (false, true) => 0o444,
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), BsdiffError> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|e| BsdiffError::io(path, e))
+}
+
+/// Only the read-only flag exists on this platform.
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<(), BsdiffError> {
+    let mut permissions = fs::metadata(path).map_err(|e| BsdiffError::io(path, e))?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions).map_err(|e| BsdiffError::io(path, e))
+}
+
+#[cfg(unix)]
+fn symlink(target: &[u8], path: &Path) -> Result<(), BsdiffError> {
+    let target = os_path(target).unwrap_or_else(|| unreachable!("raw bytes on Unix"));
+    std::os::unix::fs::symlink(target, path).map_err(|e| BsdiffError::io(path, e))
+}
+
+/// Creating symbolic links needs extra privileges on Windows, and which kind
+/// to create depends on a target that may not exist yet.
+#[cfg(not(unix))]
+fn symlink(_target: &[u8], path: &Path) -> Result<(), BsdiffError> {
+    let source = std::io::Error::new(std::io::ErrorKind::Unsupported, "symbolic links are not supported on this platform");
+    Err(BsdiffError::io(path, source))
+}
@@ -1,80 +1,975 @@
+//! Helpers to inspect, verify and measure patch files.
+
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+
+use crate::bsdiff_rust::{BsdiffError, BsdiffRust, Hooks, PatchOptions};
+use crate::bsdiff_rust::PatchCompression;
+use crate::archive;
+use crate::container;
+use crate::filter;
+use crate::tree;
+use crate::variant;
+use crate::vcdiff;
+use crate::windowed;
+
+/// Magic bytes of a BSDIFF40 patch.
+pub const BSDIFF40_MAGIC: &[u8; 8] = b"BSDIFF40";
+
+/// Magic bytes of an ENDSLEY/BSDIFF43 patch.
+pub const ENDSLEY_MAGIC: &[u8; 16] = b"ENDSLEY/BSDIFF43";
+
+/// Magic bytes of a VCDIFF (RFC 3284) delta, as produced by xdelta3.
+pub const VCDIFF_MAGIC: &[u8; 3] = &[0xD6, 0xC3, 0xC4];
+
+const GZIP_MAGIC: &[u8; 2] = &[0x1F, 0x8B];
+
+/// Size of the BSDIFF40 header: magic plus control, diff and target sizes.
+pub(crate) const BSDIFF40_HEADER_LEN: u64 = 32;
+
+/// Size of the ENDSLEY/BSDIFF43 header: magic plus target size.
+pub(crate) const ENDSLEY_HEADER_LEN: u64 = 24;
+
+/// Patch format, detected from its magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    /// Classic BSDIFF40.
+    Bsdiff40,
+    /// BSDIFF40 wrapped in this library's checksummed container.
+    Container,
+    /// BSDIFF40 or variant sub-patches for windows of a large file.
+    Windowed,
+    /// BSDIFF40 blocks compressed with another codec than bzip2.
+    Variant,
+    /// ENDSLEY/BSDIFF43, as written by mendsley/bsdiff.
+    Endsley,
+    /// VCDIFF (RFC 3284), as written by xdelta3 and open-vcdiff.
+    Vcdiff,
+    /// ZIP entries diffed uncompressed, as written with `DiffOptions::archive`.
+    Archive,
+    /// Executable code with converted branch targets, as written with
+    /// `DiffOptions::filter`.
+    Filtered,
+    /// A directory tree patch, as written by `BsdiffRust::diff_directory`.
+    Directory,
+    /// A gzip-compressed file; see `PatchInfo::inner_format`.
+    Gzip,
+    /// None of the above.
+    Unknown,
+}
+
+impl PatchFormat {
+    /// Detect the format from the first bytes of a patch.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(BSDIFF40_MAGIC) {
+            Self::Bsdiff40
+        } else if container::is_container(data) {
+            Self::Container
+        } else if windowed::is_windowed(data) {
+            Self::Windowed
+        } else if variant::is_variant(data) {
+            Self::Variant
+        } else if data.starts_with(ENDSLEY_MAGIC) {
+            Self::Endsley
+        } else if data.starts_with(VCDIFF_MAGIC) {
+            Self::Vcdiff
+        } else if archive::is_archive(data) {
+            Self::Archive
+        } else if filter::is_filtered(data) {
+            Self::Filtered
+        } else if tree::is_tree(data) {
+            Self::Directory
+        } else if data.starts_with(GZIP_MAGIC) {
+            Self::Gzip
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+/// Compression of a patch block, detected from its magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockCompression {
+    /// bzip2, as in BSDIFF40 patches.
+    Bzip2,
+    /// gzip.
+    Gzip,
+    /// zstd.
+    Zstd,
+    /// xz.
+    Xz,
+    /// brotli, which has no magic bytes and is only known from a variant header.
+    Brotli,
+    /// The block is stored uncompressed, or is empty.
+    None,
+    /// Data that is none of the above.
+    Unknown,
+}
+
+impl BlockCompression {
+    /// Detect the compression from the first bytes of a block.
+    pub fn detect(block: &[u8]) -> Self {
+        match block {
+            [] => Self::None,
+            [b'B', b'Z', b'h', b'1'..=b'9', ..] => Self::Bzip2,
+            [0x1F, 0x8B, ..] => Self::Gzip,
+            [0x28, 0xB5, 0x2F, 0xFD, ..] => Self::Zstd,
+            [0xFD, b'7', b'z', b'X', b'Z', 0x00, ..] => Self::Xz,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Compression declared for the blocks of a variant patch.
+    ///
+    /// Brotli streams and raw data have no magic bytes to detect.
+    pub fn declared(compression: PatchCompression) -> Self {
+        match compression {
+            PatchCompression::Bzip2 => Self::Bzip2,
+            PatchCompression::Zstd => Self::Zstd,
+            PatchCompression::Xz => Self::Xz,
+            PatchCompression::Brotli => Self::Brotli,
+            PatchCompression::None => Self::None,
+        }
+    }
+
+    /// Whether the block is compressed with a known codec.
+    pub fn is_compressed(self) -> bool {
+        !matches!(self, Self::None | Self::Unknown)
+    }
+}
+
+/// A block of a patch file.
+#[derive(Debug, Clone)]
+pub struct PatchBlock {
+    /// `control`, `diff` or `extra` for BSDIFF40, variant and each window of a
+    /// windowed patch, `body` for ENDSLEY/BSDIFF43, and `data`, `instructions`
+    /// or `addresses` for each VCDIFF window, and `file` for the patch of
+    /// each changed or added file of a directory patch.
+    pub name: &'static str,
+    /// Offset of the block within the patch file.
+    pub offset: u64,
+    /// Length of the block in bytes.
+    pub length: u64,
+    /// Compression of the block.
+    pub compression: BlockCompression,
+}
 
 /// Patch file information.
 #[derive(Debug, Clone)]
 pub struct PatchInfo {
+    /// Size of the patch file in bytes.
     pub size: u64,
+    /// Whether the patch data is compressed with a known codec.
     pub compressed: bool,
+    /// Format detected from the magic bytes.
+    pub format: PatchFormat,
+    /// Size of the output declared by the header.
+    pub target_size: Option<u64>,
+    /// Blocks found in the patch, in file order.
+    pub blocks: Vec<PatchBlock>,
+    /// Container header, for container patches.
+    pub container: Option<container::Header>,
+    /// Format of the decompressed data, for gzip-wrapped patches.
+    pub inner_format: Option<PatchFormat>,
+    /// Inconsistencies found in the header. Empty for a well-formed patch.
+    pub issues: Vec<String>,
 }
 
 /// Compression ratio information.
 #[derive(Debug, Clone)]
 pub struct CompressionRatio {
+    /// Size of the old file in bytes.
     pub old_size: u64,
+    /// Size of the new file in bytes.
     pub new_size: u64,
+    /// Size of the patch file in bytes.
     pub patch_size: u64,
-    pub ratio: f64, // percentage
+    /// Patch size as a percentage of the old and new sizes combined.
+    pub ratio: f64,
 }
 
 /// Verify patch file integrity.
-pub fn verify_patch(old_file: &str, new_file: &str, patch_file: &str) -> Result<bool, Box<dyn std::error::Error>> {
-    let new_data = std::fs::read(new_file)?;
+pub fn verify_patch(old_file: &str, new_file: &str, patch_file: &str) -> Result<bool, BsdiffError> {
+    verify_patch_with_hooks(old_file, new_file, patch_file, &Hooks::default())
+}
+
+/// Verify patch file integrity, reporting progress through `hooks`.
+pub fn verify_patch_with_hooks(
+    old_file: &str,
+    new_file: &str,
+    patch_file: &str,
+    hooks: &Hooks,
+) -> Result<bool, BsdiffError> {
+    verify_patch_files(old_file, new_file, patch_file, hooks)
+}
+
+/// Verify patch file integrity given paths of any form, including ones that
+/// are not valid UTF-8.
+pub fn verify_patch_files(
+    old_file: impl AsRef<Path>,
+    new_file: impl AsRef<Path>,
+    patch_file: impl AsRef<Path>,
+    hooks: &Hooks,
+) -> Result<bool, BsdiffError> {
+    let new_file = new_file.as_ref();
+    let new_data = std::fs::read(new_file).map_err(|e| BsdiffError::io(new_file, e))?;
     
     // Create a temporary file to apply the patch
     let temp_file = tempfile::NamedTempFile::new()?;
-    let temp_path = temp_file.path().to_str().ok_or("Invalid temp path")?;
     
-    // Apply the patch using BsdiffRust::patch
-    crate::bsdiff_rust::BsdiffRust::patch(old_file, temp_path, patch_file)?;
+    // Apply the patch using BsdiffRust::patch_files
+    BsdiffRust::patch_files(old_file, temp_file.path(), patch_file, &PatchOptions::default(), hooks)?;
     
     // Read the generated data and compare
-    let patched_data = std::fs::read(temp_path)?;
+    let patched_data = std::fs::read(temp_file.path()).map_err(|e| BsdiffError::io(temp_file.path(), e))?;
     
     Ok(patched_data == new_data)
 }
 
+/// Verify that the patch read from `patch` turns the data read from `old`
+/// into the data read from `new`.
+pub fn verify_patch_readers<R: Read, S: Read, T: Read>(
+    old: R,
+    mut new: S,
+    patch: T,
+    hooks: &Hooks,
+) -> Result<bool, BsdiffError> {
+    let mut new_data = Vec::new();
+    new.read_to_end(&mut new_data)?;
+
+    let mut patched_data = Vec::new();
+    BsdiffRust::patch_readers(old, patch, &mut patched_data, &PatchOptions::default(), hooks)?;
+
+    Ok(patched_data == new_data)
+}
+
+/// Digest algorithm used to verify patch output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    /// SHA-256.
+    Sha256,
+    /// BLAKE3, much faster on large outputs.
+    Blake3,
+}
+
+/// Result of verifying patch output against an expected digest and size.
+#[derive(Debug, Clone)]
+pub struct DigestVerification {
+    /// Whether the output digest equals the expected digest.
+    pub hash_matched: bool,
+    /// Whether the output size equals the expected size.
+    pub size_matched: bool,
+    /// Number of bytes the patch produced.
+    pub bytes_produced: u64,
+    /// Lowercase hex digest of the output.
+    pub actual_digest: String,
+}
+
+impl DigestVerification {
+    /// Whether both the digest and the size matched.
+    pub fn is_valid(&self) -> bool {
+        self.hash_matched && self.size_matched
+    }
+}
+
+/// Verify that applying `patch_file` to `old_file` produces data with the
+/// expected digest and size.
+///
+/// The output is streamed through the hasher and never written to disk.
+/// `expected_digest` is hex and compared case-insensitively.
+pub fn verify_patch_digest(
+    old_file: &str,
+    patch_file: &str,
+    expected_digest: &str,
+    expected_size: u64,
+    algorithm: DigestAlgorithm,
+) -> Result<DigestVerification, BsdiffError> {
+    verify_patch_digest_with_hooks(old_file, patch_file, expected_digest, expected_size, algorithm, &Hooks::default())
+}
+
+/// Verify patch output against an expected digest and size, reporting
+/// progress through `hooks`.
+pub fn verify_patch_digest_with_hooks(
+    old_file: &str,
+    patch_file: &str,
+    expected_digest: &str,
+    expected_size: u64,
+    algorithm: DigestAlgorithm,
+    hooks: &Hooks,
+) -> Result<DigestVerification, BsdiffError> {
+    verify_patch_digest_files(old_file, patch_file, expected_digest, expected_size, algorithm, hooks)
+}
+
+/// Verify patch output against an expected digest and size, given paths of
+/// any form.
+pub fn verify_patch_digest_files(
+    old_file: impl AsRef<Path>,
+    patch_file: impl AsRef<Path>,
+    expected_digest: &str,
+    expected_size: u64,
+    algorithm: DigestAlgorithm,
+    hooks: &Hooks,
+) -> Result<DigestVerification, BsdiffError> {
+    let mut sink = DigestSink::new(algorithm);
+    let stats = BsdiffRust::patch_file_to_writer(old_file, patch_file, &mut sink, &PatchOptions::default(), hooks)?;
+    Ok(sink.verification(expected_digest, expected_size, stats.new_size))
+}
+
+/// Verify the output of the patch read from `patch`, applied to the data
+/// read from `old`, against an expected digest and size.
+pub fn verify_patch_digest_readers<R: Read, S: Read>(
+    old: R,
+    patch: S,
+    expected_digest: &str,
+    expected_size: u64,
+    algorithm: DigestAlgorithm,
+    hooks: &Hooks,
+) -> Result<DigestVerification, BsdiffError> {
+    let mut sink = DigestSink::new(algorithm);
+    let stats = BsdiffRust::patch_readers(old, patch, &mut sink, &PatchOptions::default(), hooks)?;
+    Ok(sink.verification(expected_digest, expected_size, stats.new_size))
+}
+
+/// Writer that hashes everything written to it and discards the data.
+enum DigestSink {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl DigestSink {
+    fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            DigestAlgorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// Lowercase hex digest of everything written.
+    fn finalize(self) -> String {
+        match self {
+            Self::Sha256(hasher) => container::to_hex(&hasher.finalize()),
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+
+    /// Compare the digest of everything written, `bytes_produced` bytes,
+    /// with the expected digest and size.
+    fn verification(self, expected_digest: &str, expected_size: u64, bytes_produced: u64) -> DigestVerification {
+        let actual_digest = self.finalize();
+        DigestVerification {
+            hash_matched: actual_digest.eq_ignore_ascii_case(expected_digest.trim()),
+            size_matched: bytes_produced == expected_size,
+            bytes_produced,
+            actual_digest,
+        }
+    }
+}
+
+impl Write for DigestSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Sha256(hasher) => hasher.update(buf),
+            Self::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 /// Get patch file information.
-pub fn get_patch_info(patch_file: &str) -> Result<PatchInfo, Box<dyn std::error::Error>> {
-    let metadata = std::fs::metadata(patch_file)?;
-    
-    // Check if the file is in BSDIFF40 format
-    let mut file = File::open(patch_file)?;
-    let mut header = [0u8; 8];
-    file.read_exact(&mut header).ok();
-    let is_bsdiff40 = &header == b"BSDIFF40";
-    
-    Ok(PatchInfo {
-        size: metadata.len(),
-        compressed: is_bsdiff40, // BSDIFF40 format uses bzip2 compression
+///
+/// Only the header and the first bytes of each block are read; the patch is
+/// never decompressed or applied.
+pub fn get_patch_info(patch_file: &str) -> Result<PatchInfo, BsdiffError> {
+    read_patch_info(patch_file)
+}
+
+/// Get patch file information given a path of any form.
+pub fn read_patch_info(patch_file: impl AsRef<Path>) -> Result<PatchInfo, BsdiffError> {
+    let patch_file = patch_file.as_ref();
+    let mut file = File::open(patch_file).map_err(|e| BsdiffError::io(patch_file, e))?;
+    inspect_patch(&mut file).map_err(|e| BsdiffError::io(patch_file, e))
+}
+
+/// Inspect the header of a patch read from `reader`.
+pub fn inspect_patch<R: Read + Seek>(reader: &mut R) -> std::io::Result<PatchInfo> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    let header = read_at(reader, 0, container::HEADER_LEN + BSDIFF40_HEADER_LEN as usize)?;
+
+    let mut info = PatchInfo {
+        size,
+        compressed: false,
+        format: PatchFormat::detect(&header),
+        target_size: None,
+        blocks: Vec::new(),
+        container: None,
+        inner_format: None,
+        issues: Vec::new(),
+    };
+
+    match info.format {
+        PatchFormat::Bsdiff40 => inspect_bsdiff40(reader, &header, 0, &mut info)?,
+        PatchFormat::Windowed => inspect_windowed(reader, 0, &mut info)?,
+        PatchFormat::Variant => inspect_variant(reader, 0, &mut info)?,
+        PatchFormat::Container => match container::Header::parse(&header) {
+            Ok(container_header) => {
+                let payload = &header[container::HEADER_LEN..];
+                if payload.starts_with(BSDIFF40_MAGIC) {
+                    inspect_bsdiff40(reader, payload, container::HEADER_LEN as u64, &mut info)?;
+                } else if windowed::is_windowed(payload) {
+                    inspect_windowed(reader, container::HEADER_LEN as u64, &mut info)?;
+                } else if variant::is_variant(payload) {
+                    inspect_variant(reader, container::HEADER_LEN as u64, &mut info)?;
+                } else if payload.starts_with(ENDSLEY_MAGIC) {
+                    inspect_endsley(reader, payload, container::HEADER_LEN as u64, &mut info)?;
+                } else if vcdiff::is_vcdiff(payload) {
+                    inspect_vcdiff(reader, container::HEADER_LEN as u64, &mut info)?;
+                } else if archive::is_archive(payload) {
+                    inspect_archive(reader, container::HEADER_LEN as u64, &mut info)?;
+                } else if filter::is_filtered(payload) {
+                    inspect_filtered(reader, container::HEADER_LEN as u64, &mut info)?;
+                } else {
+                    info.issues.push(
+                        "container payload is not BSDIFF40, ENDSLEY/BSDIFF43, VCDIFF, variant, windowed, archive or filtered".into(),
+                    );
+                }
+                if info.target_size.is_some_and(|t| t != container_header.new_size) {
+                    info.issues.push(format!(
+                        "container new size {} does not match payload target size {}",
+                        container_header.new_size,
+                        info.target_size.unwrap_or_default()
+                    ));
+                }
+                info.container = Some(container_header);
+            }
+            Err(e) => info.issues.push(e.to_string()),
+        },
+        PatchFormat::Endsley => inspect_endsley(reader, &header, 0, &mut info)?,
+        PatchFormat::Gzip => {
+            // Decompress just enough to identify the wrapped format
+            reader.seek(SeekFrom::Start(0))?;
+            let mut inner = Vec::new();
+            match GzDecoder::new(&mut *reader).take(ENDSLEY_MAGIC.len() as u64).read_to_end(&mut inner) {
+                Ok(_) => info.inner_format = Some(PatchFormat::detect(&inner)),
+                Err(e) => info.issues.push(format!("gzip stream is corrupt: {}", e)),
+            }
+            info.compressed = true;
+        }
+        PatchFormat::Vcdiff => inspect_vcdiff(reader, 0, &mut info)?,
+        PatchFormat::Archive => inspect_archive(reader, 0, &mut info)?,
+        PatchFormat::Filtered => inspect_filtered(reader, 0, &mut info)?,
+        PatchFormat::Directory => inspect_tree(reader, &mut info)?,
+        PatchFormat::Unknown => info.issues.push("unrecognized magic bytes".into()),
+    }
+
+    info.compressed |= info.blocks.iter().any(|block| block.compression.is_compressed());
+    Ok(info)
+}
+
+/// Walk the entries of a directory patch and describe the patch of every
+/// changed or added file. The target size is the size of all new files.
+fn inspect_tree<R: Read + Seek>(reader: &mut R, info: &mut PatchInfo) -> std::io::Result<()> {
+    let header = read_at(reader, 0, tree::HEADER_LEN)?;
+    if header.len() < tree::HEADER_LEN {
+        info.issues.push(truncated(info.size, tree::HEADER_LEN as u64));
+        return Ok(());
+    }
+    if header[8] != tree::VERSION {
+        info.issues.push(format!("unsupported tree version {}", header[8]));
+        return Ok(());
+    }
+    let count = u64::from_le_bytes(header[16..24].try_into().unwrap());
+
+    reader.seek(SeekFrom::Start(tree::HEADER_LEN as u64))?;
+    let mut target_size = 0u64;
+    for index in 0..count {
+        match inspect_tree_entry(reader, info) {
+            Ok(size) => target_size = target_size.saturating_add(size),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                info.issues.push(format!("tree entry {} extends past the end of the file", index + 1));
+                return Ok(());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                info.issues.push(format!("tree entry {}: {}", index + 1, e));
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    info.target_size = Some(target_size);
+    let end = reader.stream_position()?;
+    if end < info.size {
+        info.issues.push(format!("{} trailing bytes after the last tree entry", info.size - end));
+    }
+    Ok(())
+}
+
+/// Read one directory patch entry, skipping over its file patch, and return
+/// the size of the file it produces.
+fn inspect_tree_entry<R: Read + Seek>(reader: &mut R, info: &mut PatchInfo) -> std::io::Result<u64> {
+    let kind = read_le(reader, 1)? as u8;
+    // Mode, then the path
+    read_le(reader, 4)?;
+    skip_short(reader, info.size)?;
+    let size = match kind {
+        tree::DIRECTORY | tree::DELETE => 0,
+        tree::SYMLINK => {
+            skip_short(reader, info.size)?;
+            0
+        }
+        tree::COPY => {
+            skip_short(reader, info.size)?;
+            let size = read_le(reader, 8)?;
+            skip(reader, 32, info.size)?;
+            size
+        }
+        tree::PATCH | tree::ADD => {
+            if kind == tree::PATCH {
+                // Source and its digest
+                skip_short(reader, info.size)?;
+                skip(reader, 32, info.size)?;
+            }
+            let size = read_le(reader, 8)?;
+            skip(reader, 32, info.size)?;
+            let length = read_le(reader, 8)?;
+            let offset = skip(reader, length, info.size)?;
+            // File patches are BSDIFF40 unless other diff options were used
+            let head = read_at(reader, offset, (BSDIFF40_HEADER_LEN as usize + 6).min(length as usize))?;
+            let block = match head.get(BSDIFF40_HEADER_LEN as usize..) {
+                Some(control) if head.starts_with(BSDIFF40_MAGIC) => control,
+                _ => &head,
+            };
+            info.blocks.push(PatchBlock { name: "file", offset, length, compression: BlockCompression::detect(block) });
+            reader.seek(SeekFrom::Start(offset + length))?;
+            size
+        }
+        kind => {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unknown tree entry kind {}", kind)));
+        }
+    };
+    Ok(size)
+}
+
+/// Read a little-endian integer of `len` bytes at the current position.
+fn read_le<R: Read>(reader: &mut R, len: usize) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes[..len])?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Skip `len` bytes of a file of `size` bytes, and return where they start.
+fn skip<R: Seek>(reader: &mut R, len: u64, size: u64) -> std::io::Result<u64> {
+    let start = reader.stream_position()?;
+    if start.checked_add(len).is_none_or(|end| end > size) {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    reader.seek(SeekFrom::Start(start + len))?;
+    Ok(start)
+}
+
+/// Skip bytes preceded by a u32 length.
+fn skip_short<R: Read + Seek>(reader: &mut R, size: u64) -> std::io::Result<()> {
+    let len = read_le(reader, 4)?;
+    skip(reader, len, size).map(drop)
+}
+
+/// Parse a windowed patch that starts at `base` and describe the blocks of
+/// every sub-patch.
+fn inspect_windowed<R: Read + Seek>(reader: &mut R, base: u64, info: &mut PatchInfo) -> std::io::Result<()> {
+    let header = read_at(reader, base, windowed::HEADER_LEN)?;
+    if header.len() < windowed::HEADER_LEN {
+        info.issues.push(truncated(info.size, base + windowed::HEADER_LEN as u64));
+        return Ok(());
+    }
+    if header[8] != windowed::VERSION {
+        info.issues.push(format!("unsupported windowed version {}", header[8]));
+        return Ok(());
+    }
+    let u64_at = |data: &[u8], offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    let (old_size, new_size, count) = (u64_at(&header, 16), u64_at(&header, 24), u64_at(&header, 32));
+    info.target_size = Some(new_size);
+
+    let table_start = base + windowed::HEADER_LEN as u64;
+    let Some(table_end) = count
+        .checked_mul(windowed::ENTRY_LEN as u64)
+        .and_then(|len| len.checked_add(table_start))
+        .filter(|&end| end <= info.size)
+    else {
+        info.issues.push(format!("window table of {} entries extends past the end of the file", count));
+        return Ok(());
+    };
+
+    let table = read_at(reader, table_start, (table_end - table_start) as usize)?;
+    let mut offset = table_end;
+    let mut produced = 0u64;
+    for (i, entry) in table.chunks_exact(windowed::ENTRY_LEN).enumerate() {
+        let (old_offset, old_length, new_length, length) =
+            (u64_at(entry, 0), u64_at(entry, 8), u64_at(entry, 16), u64_at(entry, 24));
+        if old_offset.checked_add(old_length).is_none_or(|end| end > old_size) {
+            info.issues.push(format!("window {}: region {}+{} lies outside the old file", i, old_offset, old_length));
+        }
+        produced = produced.saturating_add(new_length);
+
+        let Some(end) = offset.checked_add(length).filter(|&end| end <= info.size) else {
+            info.issues.push(format!("window {}: sub-patch extends past the end of the file", i));
+            return Ok(());
+        };
+        // Inspect the sub-patch as if it were a file of its own
+        let mut window = PatchInfo { size: end, target_size: None, blocks: Vec::new(), issues: Vec::new(), ..info.clone() };
+        let sub_header = read_at(reader, offset, BSDIFF40_HEADER_LEN as usize)?;
+        if sub_header.starts_with(BSDIFF40_MAGIC) {
+            inspect_bsdiff40(reader, &sub_header, offset, &mut window)?;
+        } else if variant::is_variant(&sub_header) {
+            inspect_variant(reader, offset, &mut window)?;
+        } else if sub_header.starts_with(ENDSLEY_MAGIC) {
+            inspect_endsley(reader, &sub_header, offset, &mut window)?;
+        } else if vcdiff::is_vcdiff(&sub_header) {
+            inspect_vcdiff(reader, offset, &mut window)?;
+        } else {
+            window.issues.push("sub-patch is not BSDIFF40, ENDSLEY/BSDIFF43, VCDIFF or variant".into());
+        }
+        if window.target_size.is_some_and(|t| t != new_length) {
+            window.issues.push(format!("target size {} does not match window length {}", window.target_size.unwrap_or_default(), new_length));
+        }
+        info.blocks.extend(window.blocks);
+        info.issues.extend(window.issues.into_iter().map(|issue| format!("window {}: {}", i, issue)));
+        offset = end;
+    }
+    if produced != new_size {
+        info.issues.push(format!("windows produce {} bytes but the header declares {}", produced, new_size));
+    }
+    if offset != info.size {
+        info.issues.push(format!("{} trailing bytes after the last window", info.size - offset));
+    }
+    Ok(())
+}
+
+/// Parse an archive patch that starts at `base` and describe the blocks of
+/// its inner patch. The target size is the size of the new archive.
+fn inspect_archive<R: Read + Seek>(reader: &mut R, base: u64, info: &mut PatchInfo) -> std::io::Result<()> {
+    let header = read_at(reader, base, archive::HEADER_LEN)?;
+    if header.len() < archive::HEADER_LEN {
+        info.issues.push(truncated(info.size, base + archive::HEADER_LEN as u64));
+        return Ok(());
+    }
+    if header[8] != archive::VERSION {
+        info.issues.push(format!("unsupported archive version {}", header[8]));
+        return Ok(());
+    }
+    let u64_at = |data: &[u8], offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    let (new_size, old_count, new_count) = (u64_at(&header, 24), u64_at(&header, 32), u64_at(&header, 40));
+    info.target_size = Some(new_size);
+
+    let tables_start = base + archive::HEADER_LEN as u64;
+    let Some(tables_end) = old_count
+        .checked_mul(archive::OLD_ENTRY_LEN as u64)
+        .zip(new_count.checked_mul(archive::NEW_ENTRY_LEN as u64))
+        .and_then(|(old, new)| old.checked_add(new))
+        .and_then(|len| len.checked_add(tables_start))
+        .filter(|&end| end <= info.size)
+    else {
+        info.issues.push(format!("entry tables of {} and {} entries extend past the end of the file", old_count, new_count));
+        return Ok(());
+    };
+
+    // The inner patch produces the new archive with its listed entries inflated
+    let new_table_start = tables_start + old_count * archive::OLD_ENTRY_LEN as u64;
+    let new_table = read_at(reader, new_table_start, (tables_end - new_table_start) as usize)?;
+    let expanded_size = new_table.chunks_exact(archive::NEW_ENTRY_LEN).try_fold(new_size, |size, entry| {
+        size.checked_sub(u64_at(entry, 8))?.checked_add(u64_at(entry, 16))
+    });
+    let Some(expanded_size) = expanded_size else {
+        info.issues.push("new entries do not fit the new file".into());
+        return Ok(());
+    };
+    inspect_inner(reader, tables_end, expanded_size, info)
+}
+
+/// Parse a filtered patch that starts at `base` and describe the blocks of
+/// its inner patch.
+fn inspect_filtered<R: Read + Seek>(reader: &mut R, base: u64, info: &mut PatchInfo) -> std::io::Result<()> {
+    let header = read_at(reader, base, filter::HEADER_LEN)?;
+    if header.len() < filter::HEADER_LEN {
+        info.issues.push(truncated(info.size, base + filter::HEADER_LEN as u64));
+        return Ok(());
+    }
+    if header[8] != filter::VERSION {
+        info.issues.push(format!("unsupported filtered version {}", header[8]));
+        return Ok(());
+    }
+    let u64_at = |data: &[u8], offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    let (new_size, old_count, new_count) = (u64_at(&header, 24), u64_at(&header, 32), u64_at(&header, 40));
+    info.target_size = Some(new_size);
+
+    let Some(tables_end) = old_count
+        .checked_add(new_count)
+        .and_then(|count| count.checked_mul(filter::REGION_LEN as u64))
+        .and_then(|len| len.checked_add(base + filter::HEADER_LEN as u64))
+        .filter(|&end| end <= info.size)
+    else {
+        info.issues.push(format!("region tables of {} and {} entries extend past the end of the file", old_count, new_count));
+        return Ok(());
+    };
+    // Filtering keeps the size, so the inner patch produces the new file's
+    inspect_inner(reader, tables_end, new_size, info)
+}
+
+/// Inspect the patch wrapped by an archive or filtered patch, which starts
+/// at `offset` and runs to the end of the file and should produce
+/// `expected_size` bytes, and add its blocks and issues to `info`.
+fn inspect_inner<R: Read + Seek>(reader: &mut R, offset: u64, expected_size: u64, info: &mut PatchInfo) -> std::io::Result<()> {
+    // Inspect the inner patch as if it were a file of its own
+    let mut inner = PatchInfo { target_size: None, blocks: Vec::new(), issues: Vec::new(), ..info.clone() };
+    let inner_header = read_at(reader, offset, BSDIFF40_HEADER_LEN as usize)?;
+    if inner_header.starts_with(BSDIFF40_MAGIC) {
+        inspect_bsdiff40(reader, &inner_header, offset, &mut inner)?;
+    } else if windowed::is_windowed(&inner_header) {
+        inspect_windowed(reader, offset, &mut inner)?;
+    } else if variant::is_variant(&inner_header) {
+        inspect_variant(reader, offset, &mut inner)?;
+    } else if inner_header.starts_with(ENDSLEY_MAGIC) {
+        inspect_endsley(reader, &inner_header, offset, &mut inner)?;
+    } else if vcdiff::is_vcdiff(&inner_header) {
+        inspect_vcdiff(reader, offset, &mut inner)?;
+    } else {
+        inner.issues.push("inner patch is not BSDIFF40, ENDSLEY/BSDIFF43, VCDIFF, variant or windowed".into());
+    }
+    if inner.target_size.is_some_and(|t| t != expected_size) {
+        inner.issues.push(format!("target size {} does not match expected size {}", inner.target_size.unwrap_or_default(), expected_size));
+    }
+    info.blocks.extend(inner.blocks);
+    info.issues.extend(inner.issues.into_iter().map(|issue| format!("inner patch: {}", issue)));
+    Ok(())
+}
+
+/// Parse an ENDSLEY/BSDIFF43 header that starts at `base` and describe its body.
+fn inspect_endsley<R: Read + Seek>(reader: &mut R, header: &[u8], base: u64, info: &mut PatchInfo) -> std::io::Result<()> {
+    let body = base + ENDSLEY_HEADER_LEN;
+    if info.size < body {
+        info.issues.push(truncated(info.size, body));
+        return Ok(());
+    }
+    let target_size = decode_offtin(&header[16..24]);
+    if target_size < 0 {
+        info.issues.push(format!("negative target size {}", target_size));
+    } else {
+        info.target_size = Some(target_size as u64);
+    }
+    info.blocks.push(read_block(reader, "body", body, info.size - body)?);
+    Ok(())
+}
+
+/// Walk the windows of a VCDIFF delta that starts at `base` and describe
+/// their sections, reading only the window headers.
+fn inspect_vcdiff<R: Read + Seek>(reader: &mut R, base: u64, info: &mut PatchInfo) -> std::io::Result<()> {
+    let header = match vcdiff::FileHeader::parse(&read_at(reader, base, 32)?) {
+        Ok(header) => header,
+        Err(reason) => {
+            info.issues.push(reason);
+            return Ok(());
+        }
+    };
+    if let Some(id) = header.secondary {
+        info.issues.push(format!("secondary compression (compressor id {}) is not supported", id));
+    }
+
+    let mut offset = base + header.len;
+    let mut target_size = 0u64;
+    while offset < info.size {
+        let window = match vcdiff::WindowHeader::parse(&read_at(reader, offset, vcdiff::MAX_WINDOW_HEADER_LEN)?, header.version) {
+            Ok(window) => window,
+            Err(reason) => {
+                info.issues.push(format!("window at {}: {}", offset, reason));
+                return Ok(());
+            }
+        };
+        let Some(end) = offset.checked_add(window.len).filter(|&end| end <= info.size) else {
+            info.issues.push(format!("window at {} extends past the end of the file", offset));
+            return Ok(());
+        };
+        if window.source.is_some_and(|source| source.from_target) {
+            info.issues.push(format!("window at {} copies from earlier output, which is not supported", offset));
+        }
+        let mut block_offset = offset + window.header_len;
+        let sections = [("data", window.data_len), ("instructions", window.inst_len), ("addresses", window.addr_len)];
+        for (bit, (name, length)) in sections.into_iter().enumerate() {
+            // Bits 0-2 of the delta indicator mark secondary compression
+            let compression = match window.delta_indicator >> bit & 1 {
+                0 => BlockCompression::None,
+                _ => BlockCompression::Unknown,
+            };
+            info.blocks.push(PatchBlock { name, offset: block_offset, length, compression });
+            block_offset += length;
+        }
+        target_size = target_size.saturating_add(window.target_len);
+        offset = end;
+    }
+    if offset > info.size {
+        info.issues.push(truncated(info.size, offset));
+    }
+    info.target_size = Some(target_size);
+    Ok(())
+}
+
+/// Parse a variant header that starts at `base` and describe its blocks.
+fn inspect_variant<R: Read + Seek>(reader: &mut R, base: u64, info: &mut PatchInfo) -> std::io::Result<()> {
+    let header = read_at(reader, base, variant::HEADER_LEN)?;
+    if header.len() < variant::HEADER_LEN {
+        info.issues.push(truncated(info.size, base + variant::HEADER_LEN as u64));
+        return Ok(());
+    }
+    if header[8] != variant::VERSION {
+        info.issues.push(format!("unsupported variant version {}", header[8]));
+        return Ok(());
+    }
+    let u64_at = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+    info.target_size = Some(u64_at(40));
+    let Some(compression) = PatchCompression::from_id(header[9]) else {
+        info.issues.push(format!("unknown compression id {}", header[9]));
+        return Ok(());
+    };
+
+    let mut offset = base + variant::HEADER_LEN as u64;
+    for (name, length) in [("control", u64_at(16)), ("diff", u64_at(24)), ("extra", u64_at(32))] {
+        let Some(end) = offset.checked_add(length).filter(|&end| end <= info.size) else {
+            info.issues.push(format!("{} block of {} bytes extends past the end of the file", name, length));
+            return Ok(());
+        };
+        info.blocks.push(PatchBlock { name, offset, length, compression: BlockCompression::declared(compression) });
+        offset = end;
+    }
+    if offset != info.size {
+        info.issues.push(format!("{} trailing bytes after the extra block", info.size - offset));
+    }
+    Ok(())
+}
+
+/// Parse a BSDIFF40 header that starts at `base` and describe its blocks.
+fn inspect_bsdiff40<R: Read + Seek>(reader: &mut R, header: &[u8], base: u64, info: &mut PatchInfo) -> std::io::Result<()> {
+    let body = base + BSDIFF40_HEADER_LEN;
+    if info.size < body {
+        info.issues.push(truncated(info.size, body));
+        return Ok(());
+    }
+
+    let control_length = decode_offtin(&header[8..16]);
+    let diff_length = decode_offtin(&header[16..24]);
+    let target_size = decode_offtin(&header[24..32]);
+    if target_size < 0 {
+        info.issues.push(format!("negative target size {}", target_size));
+    } else {
+        info.target_size = Some(target_size as u64);
+    }
+    if control_length < 0 || diff_length < 0 {
+        info.issues.push(format!("negative block length (control {}, diff {})", control_length, diff_length));
+        return Ok(());
+    }
+
+    let (control_length, diff_length) = (control_length as u64, diff_length as u64);
+    let extra_offset = match body.checked_add(control_length).and_then(|n| n.checked_add(diff_length)) {
+        Some(offset) if offset <= info.size => offset,
+        _ => {
+            info.issues.push(format!(
+                "control and diff blocks ({} + {} bytes) extend past the end of the file",
+                control_length, diff_length
+            ));
+            return Ok(());
+        }
+    };
+
+    info.blocks.push(read_block(reader, "control", body, control_length)?);
+    info.blocks.push(read_block(reader, "diff", body + control_length, diff_length)?);
+    info.blocks.push(read_block(reader, "extra", extra_offset, info.size - extra_offset)?);
+    Ok(())
+}
+
+fn read_block<R: Read + Seek>(reader: &mut R, name: &'static str, offset: u64, length: u64) -> std::io::Result<PatchBlock> {
+    let magic = read_at(reader, offset, length.min(8) as usize)?;
+    Ok(PatchBlock {
+        name,
+        offset,
+        length,
+        compression: BlockCompression::detect(&magic),
     })
 }
 
+/// Read up to `len` bytes at `offset`, stopping early at the end of the file.
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn truncated(size: u64, expected: u64) -> String {
+    format!("truncated header: {} bytes, expected at least {}", size, expected)
+}
+
+/// Decode a bsdiff sign-magnitude little-endian integer.
+pub(crate) fn decode_offtin(bytes: &[u8]) -> i64 {
+    let raw = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+    let magnitude = (raw & (i64::MAX as u64)) as i64;
+    if raw >> 63 == 0 {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
+
 /// Get file size in bytes.
-pub fn get_file_size(file_path: &str) -> Result<u64, Box<dyn std::error::Error>> {
-    let metadata = std::fs::metadata(file_path)?;
+pub fn get_file_size(file_path: &str) -> Result<u64, BsdiffError> {
+    file_size(file_path)
+}
+
+/// Get the size in bytes of a file given a path of any form.
+pub fn file_size(file_path: impl AsRef<Path>) -> Result<u64, BsdiffError> {
+    let file_path = file_path.as_ref();
+    let metadata = std::fs::metadata(file_path).map_err(|e| BsdiffError::io(file_path, e))?;
     Ok(metadata.len())
 }
 
 /// Check whether a file exists and is readable.
-pub fn check_file_access(file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let path = std::path::Path::new(file_path);
+pub fn check_file_access(file_path: &str) -> Result<(), BsdiffError> {
+    check_access(file_path)
+}
+
+/// Check whether a file, given a path of any form, exists and is readable.
+pub fn check_access(file_path: impl AsRef<Path>) -> Result<(), BsdiffError> {
+    let path = file_path.as_ref();
     if !path.exists() {
-        return Err(format!("File not found: {}", file_path).into());
+        return Err(BsdiffError::NotFound { what: "File", path: path.display().to_string() });
     }
     if !path.is_file() {
-        return Err(format!("Path is not a file: {}", file_path).into());
+        let source = std::io::Error::new(std::io::ErrorKind::InvalidInput, "Path is not a file");
+        return Err(BsdiffError::io(path, source));
     }
     // Try opening the file to verify readability
-    File::open(file_path)?;
+    File::open(path).map_err(|e| BsdiffError::io(path, e))?;
     Ok(())
 }
 
 /// Get compression ratio information.
-pub fn get_compression_ratio(old_file: &str, new_file: &str, patch_file: &str) -> Result<CompressionRatio, Box<dyn std::error::Error>> {
-    let old_size = get_file_size(old_file)?;
-    let new_size = get_file_size(new_file)?;
-    let patch_size = get_file_size(patch_file)?;
+pub fn get_compression_ratio(old_file: &str, new_file: &str, patch_file: &str) -> Result<CompressionRatio, BsdiffError> {
+    compression_ratio(old_file, new_file, patch_file)
+}
+
+/// Get compression ratio information given paths of any form.
+pub fn compression_ratio(
+    old_file: impl AsRef<Path>,
+    new_file: impl AsRef<Path>,
+    patch_file: impl AsRef<Path>,
+) -> Result<CompressionRatio, BsdiffError> {
+    let old_size = file_size(old_file)?;
+    let new_size = file_size(new_file)?;
+    let patch_size = file_size(patch_file)?;
     
     let total_size = old_size + new_size;
     let ratio = if total_size > 0 {
@@ -0,0 +1,296 @@
+use std::io::{self, Read, Write};
+use bzip2::read::BzDecoder;
+
+use crate::bsdiff_rust::{BsdiffError, PatchCompression, ProgressCounter, ProgressReader};
+use crate::utils;
+
+/// Magic bytes that open a variant patch.
+pub const MAGIC: &[u8; 8] = b"BSDIFFRZ";
+
+/// Variant format version written by this library.
+pub const VERSION: u8 = 1;
+
+/// Size of the variant header in bytes.
+///
+/// Layout (integers little-endian):
+///
+/// | Offset | Size | Field                         |
+/// | ------ | ---- | ----------------------------- |
+/// | 0      | 8    | `MAGIC`                       |
+/// | 8      | 1    | version                       |
+/// | 9      | 1    | compression id                |
+/// | 10     | 6    | reserved, zero                |
+/// | 16     | 8    | control block length          |
+/// | 24     | 8    | diff block length             |
+/// | 32     | 8    | extra block length            |
+/// | 40     | 8    | new size                      |
+///
+/// The control, diff and extra blocks follow, each compressed on its own.
+/// Decompressed, they hold exactly what the blocks of a BSDIFF40 patch hold.
+pub const HEADER_LEN: usize = 48;
+
+/// Size of a control entry: add, copy and seek lengths in bsdiff's
+/// sign-magnitude encoding.
+pub const CONTROL_LEN: usize = 24;
+
+/// Largest brotli window, as a power of two (16 MiB).
+const BROTLI_WINDOW_BITS: u32 = 24;
+
+impl PatchCompression {
+    /// Identifier recorded in the variant header. bzip2 has none, since it is
+    /// written as plain BSDIFF40.
+    pub(crate) fn id(self) -> Option<u8> {
+        match self {
+            Self::Bzip2 => None,
+            Self::None => Some(0),
+            Self::Zstd => Some(1),
+            Self::Xz => Some(2),
+            Self::Brotli => Some(3),
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        [Self::None, Self::Zstd, Self::Xz, Self::Brotli].into_iter().find(|c| c.id() == Some(id))
+    }
+}
+
+/// A parsed variant patch.
+#[derive(Debug, Clone)]
+pub struct VariantPatch<'p> {
+    pub compression: PatchCompression,
+    pub new_size: u64,
+    pub control: &'p [u8],
+    pub diff: &'p [u8],
+    pub extra: &'p [u8],
+}
+
+impl<'p> VariantPatch<'p> {
+    /// Parse a variant patch, checking that its block lengths add up to the
+    /// size of the data.
+    pub fn parse(data: &'p [u8]) -> Result<Self, BsdiffError> {
+        let corrupt = |reason: String| BsdiffError::CorruptPatch { reason };
+        if !is_variant(data) {
+            return Err(corrupt("missing variant magic".into()));
+        }
+        if data.len() < HEADER_LEN {
+            return Err(corrupt("truncated variant header".into()));
+        }
+        if data[8] != VERSION {
+            return Err(corrupt(format!("unsupported variant version {}", data[8])));
+        }
+        let compression = PatchCompression::from_id(data[9])
+            .ok_or_else(|| corrupt(format!("unknown compression id {}", data[9])))?;
+
+        let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let (control_length, diff_length, extra_length) = (u64_at(16), u64_at(24), u64_at(32));
+        let body = (data.len() - HEADER_LEN) as u64;
+        let blocks = control_length.checked_add(diff_length).and_then(|n| n.checked_add(extra_length));
+        if blocks != Some(body) {
+            return Err(corrupt(format!(
+                "blocks ({} + {} + {} bytes) do not match the {} bytes after the header",
+                control_length, diff_length, extra_length, body
+            )));
+        }
+
+        let (control, rest) = data[HEADER_LEN..].split_at(control_length as usize);
+        let (diff, extra) = rest.split_at(diff_length as usize);
+        Ok(Self { compression, new_size: u64_at(40), control, diff, extra })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LEN + self.control.len() + self.diff.len() + self.extra.len());
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.push(self.compression.id().expect("bzip2 patches are written as BSDIFF40"));
+        data.extend_from_slice(&[0; 6]);
+        for field in [self.control.len() as u64, self.diff.len() as u64, self.extra.len() as u64, self.new_size] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(self.control);
+        data.extend_from_slice(self.diff);
+        data.extend_from_slice(self.extra);
+        data
+    }
+
+    /// Apply the patch to `old_data`, writing the output in chunks of
+    /// `chunk_size` bytes.
+    ///
+    /// Errors from `writer` are passed through; anything wrong with the
+    /// patch itself is a `CorruptPatch`.
+    pub fn apply<W: Write>(&self, old_data: &[u8], writer: W, chunk_size: usize) -> Result<u64, BsdiffError> {
+        let corrupt = |reason: String| BsdiffError::CorruptPatch { reason };
+        let block_error = |name: &'static str| {
+            move |e: io::Error| BsdiffError::CorruptPatch { reason: format!("{} block: {}", name, e) }
+        };
+        let mut control = decoder(self.compression, self.control).map_err(block_error("control"))?;
+        let mut diff = decoder(self.compression, self.diff).map_err(block_error("diff"))?;
+        let mut extra = decoder(self.compression, self.extra).map_err(block_error("extra"))?;
+
+        let mut output = ChunkWriter::new(writer, chunk_size);
+        let mut entry = [0u8; CONTROL_LEN];
+        let mut old_pos = 0u64;
+        let mut written = 0u64;
+        while read_entry(&mut control, &mut entry).map_err(block_error("control"))? {
+            let add = utils::decode_offtin(&entry[0..8]);
+            let copy = utils::decode_offtin(&entry[8..16]);
+            let seek = utils::decode_offtin(&entry[16..24]);
+            if add < 0 || copy < 0 {
+                return Err(corrupt(format!("negative control lengths (add {}, copy {})", add, copy)));
+            }
+            let (add, copy) = (add as u64, copy as u64);
+            if written.checked_add(add).and_then(|n| n.checked_add(copy)).is_none_or(|n| n > self.new_size) {
+                return Err(corrupt(format!("control block produces more than the declared {} bytes", self.new_size)));
+            }
+            let mut source = usize::try_from(old_pos)
+                .ok()
+                .zip(usize::try_from(add).ok())
+                .and_then(|(start, len)| old_data.get(start..start.checked_add(len)?))
+                .ok_or_else(|| corrupt(format!("add of {} bytes at {} reads past the end of the old data", add, old_pos)))?;
+
+            output.extend_with(add, |chunk| {
+                diff.read_exact(chunk).map_err(block_error("diff"))?;
+                for (byte, old) in chunk.iter_mut().zip(source) {
+                    *byte = byte.wrapping_add(*old);
+                }
+                source = &source[chunk.len()..];
+                Ok(())
+            })?;
+            output.extend_with(copy, |chunk| extra.read_exact(chunk).map_err(block_error("extra")))?;
+
+            written += add + copy;
+            old_pos = (old_pos + add)
+                .checked_add_signed(seek)
+                .ok_or_else(|| corrupt(format!("seek of {} moves outside the old data", seek)))?;
+        }
+        output.finish()?;
+
+        if written != self.new_size {
+            return Err(corrupt(format!("patch produced {} bytes but the header declares {}", written, self.new_size)));
+        }
+        Ok(written)
+    }
+}
+
+/// Whether `patch_data` starts with the variant magic.
+pub fn is_variant(patch_data: &[u8]) -> bool {
+    patch_data.starts_with(MAGIC)
+}
+
+/// Recompress the blocks of a BSDIFF40 patch from qbsdiff with `compression`.
+///
+/// `level` is the 1-9 compression level of `DiffOptions`, mapped onto the
+/// range of each codec.
+pub fn transcode(
+    bsdiff40: &[u8],
+    compression: PatchCompression,
+    level: u32,
+    progress: &mut ProgressCounter<'_>
+) -> Result<Vec<u8>, BsdiffError> {
+    let header_len = utils::BSDIFF40_HEADER_LEN as usize;
+    let control_length = utils::decode_offtin(&bsdiff40[8..16]) as usize;
+    let diff_length = utils::decode_offtin(&bsdiff40[16..24]) as usize;
+    let new_size = utils::decode_offtin(&bsdiff40[24..32]) as u64;
+    let (control, rest) = bsdiff40[header_len..].split_at(control_length);
+    let (diff, extra) = rest.split_at(diff_length);
+
+    // The diff and extra blocks together hold one byte per byte of new data
+    let control = recompress(BzDecoder::new(control), compression, level)?;
+    let diff = recompress(ProgressReader { inner: BzDecoder::new(diff), progress: &mut *progress }, compression, level)?;
+    let extra = recompress(ProgressReader { inner: BzDecoder::new(extra), progress }, compression, level)?;
+    Ok(VariantPatch { compression, new_size, control: &control, diff: &diff, extra: &extra }.encode())
+}
+
+/// Compress the output of a bzip2 block's `decoder` again with `compression`.
+fn recompress(mut decoder: impl Read, compression: PatchCompression, level: u32) -> io::Result<Vec<u8>> {
+    match compression {
+        PatchCompression::Bzip2 => unreachable!("bzip2 blocks are kept as they are"),
+        PatchCompression::None => {
+            let mut data = Vec::new();
+            decoder.read_to_end(&mut data)?;
+            Ok(data)
+        }
+        PatchCompression::Zstd => {
+            // Levels 3 (zstd's default) to 19
+            let mut encoder = zstd::Encoder::new(Vec::new(), (level * 2 + 1) as i32)?;
+            io::copy(&mut decoder, &mut encoder)?;
+            encoder.finish()
+        }
+        PatchCompression::Xz => {
+            let mut encoder = xz2::write::XzEncoder::new(Vec::new(), level);
+            io::copy(&mut decoder, &mut encoder)?;
+            encoder.finish()
+        }
+        PatchCompression::Brotli => {
+            // Qualities 3 to 11
+            let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, level + 2, BROTLI_WINDOW_BITS);
+            io::copy(&mut decoder, &mut encoder)?;
+            encoder.flush()?;
+            Ok(encoder.into_inner())
+        }
+    }
+}
+
+fn decoder(compression: PatchCompression, block: &[u8]) -> io::Result<Box<dyn Read + '_>> {
+    Ok(match compression {
+        PatchCompression::Bzip2 => Box::new(BzDecoder::new(block)),
+        PatchCompression::None => Box::new(block),
+        PatchCompression::Zstd => Box::new(zstd::Decoder::with_buffer(block)?),
+        PatchCompression::Xz => Box::new(xz2::read::XzDecoder::new(block)),
+        PatchCompression::Brotli => Box::new(brotli::Decompressor::new(block, 4096)),
+    })
+}
+
+/// Read the next control entry, returning `false` at the end of the stream.
+pub fn read_entry<R: Read>(reader: &mut R, entry: &mut [u8; CONTROL_LEN]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < CONTROL_LEN {
+        match reader.read(&mut entry[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Writer that collects patch output into chunks of a fixed size.
+pub struct ChunkWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(inner: W, chunk_size: usize) -> Self {
+        Self { inner, buf: Vec::with_capacity(chunk_size), chunk_size }
+    }
+
+    /// Append `len` bytes, letting `fill` produce them in pieces of at most
+    /// one chunk. Each piece is zeroed before `fill` sees it.
+    pub fn extend_with<F>(&mut self, mut len: u64, mut fill: F) -> Result<(), BsdiffError>
+    where
+        F: FnMut(&mut [u8]) -> Result<(), BsdiffError>,
+    {
+        while len > 0 {
+            let start = self.buf.len();
+            let n = len.min((self.chunk_size - start) as u64) as usize;
+            self.buf.resize(start + n, 0);
+            fill(&mut self.buf[start..])?;
+            len -= n as u64;
+            if self.buf.len() == self.chunk_size {
+                self.inner.write_all(&self.buf)?;
+                self.buf.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out the last partial chunk and flush.
+    pub fn finish(mut self) -> Result<(), BsdiffError> {
+        self.inner.write_all(&self.buf)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
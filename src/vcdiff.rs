@@ -0,0 +1,740 @@
+use std::io::Write;
+use bzip2::read::BzDecoder;
+
+use crate::bsdiff_rust::{BsdiffError, ProgressCounter};
+use crate::utils::{self, VCDIFF_MAGIC};
+use crate::variant::{self, CONTROL_LEN};
+
+/// Version byte of RFC 3284 deltas, as written by this library and xdelta3.
+pub const VERSION: u8 = 0x00;
+
+/// Version byte of open-vcdiff's extended format, which stores window
+/// checksums as varints.
+const VERSION_SDCH: u8 = b'S';
+
+// Hdr_Indicator bits. VCD_APPHEADER is an xdelta3 extension.
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+// Win_Indicator bits. VCD_ADLER32 is an xdelta3 and open-vcdiff extension.
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+
+/// Target window size written by the encoder, xdelta3's default.
+pub const WINDOW_SIZE: u64 = 1 << 23;
+
+/// Largest target window accepted when decoding, open-vcdiff's default limit.
+pub const MAX_WINDOW_SIZE: u64 = 1 << 26;
+
+/// Upper bound on the size of a window header, up to its data section.
+pub const MAX_WINDOW_HEADER_LEN: usize = 96;
+
+const NEAR_SLOTS: usize = 4;
+const SAME_SLOTS: usize = 3;
+
+/// Exact matches shorter than this are cheaper to encode as added bytes.
+const MIN_COPY: usize = 6;
+
+/// Repeated bytes shorter than this are cheaper to encode as added bytes.
+const MIN_RUN: usize = 8;
+
+/// Whether `patch_data` starts with the VCDIFF magic.
+pub fn is_vcdiff(patch_data: &[u8]) -> bool {
+    patch_data.starts_with(VCDIFF_MAGIC)
+}
+
+/// The file header of a VCDIFF delta.
+#[derive(Debug, Clone)]
+pub struct FileHeader {
+    pub version: u8,
+    /// Secondary compressor id, if the sections of some windows are compressed.
+    pub secondary: Option<u8>,
+    /// Offset of the first window.
+    pub len: u64,
+}
+
+impl FileHeader {
+    /// Parse the header at the start of `data`. Application data, which
+    /// xdelta3 uses for file names, is skipped without being read.
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let mut bytes = Bytes::new(data);
+        if bytes.take(VCDIFF_MAGIC.len() as u64, "magic")? != VCDIFF_MAGIC {
+            return Err("missing VCDIFF magic".into());
+        }
+        let version = bytes.byte("version")?;
+        if version != VERSION && version != VERSION_SDCH {
+            return Err(format!("unsupported VCDIFF version {:#04x}", version));
+        }
+        let indicator = bytes.byte("header indicator")?;
+        if indicator & !(VCD_DECOMPRESS | VCD_CODETABLE | VCD_APPHEADER) != 0 {
+            return Err(format!("unknown header indicator {:#04x}", indicator));
+        }
+        let secondary = match indicator & VCD_DECOMPRESS {
+            0 => None,
+            _ => Some(bytes.byte("secondary compressor id")?),
+        };
+        if indicator & VCD_CODETABLE != 0 {
+            return Err("application-defined code tables are not supported".into());
+        }
+        let mut len = bytes.pos as u64;
+        if indicator & VCD_APPHEADER != 0 {
+            let app_header_len = bytes.varint("application header length")?;
+            len = (bytes.pos as u64).checked_add(app_header_len).ok_or("application header length overflows")?;
+        }
+        Ok(Self { version, secondary, len })
+    }
+}
+
+/// The segment of earlier data a window copies from.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub len: u64,
+    pub pos: u64,
+    /// Whether the segment lies in earlier output (`VCD_TARGET`) rather than
+    /// in the old data.
+    pub from_target: bool,
+}
+
+/// The header of one window of a VCDIFF delta.
+#[derive(Debug, Clone)]
+pub struct WindowHeader {
+    pub source: Option<Segment>,
+    pub target_len: u64,
+    /// Secondary compression bits for the data, instructions and addresses.
+    pub delta_indicator: u8,
+    pub data_len: u64,
+    pub inst_len: u64,
+    pub addr_len: u64,
+    /// Adler-32 of the target window.
+    pub checksum: Option<u32>,
+    /// Whether added data and addresses are interleaved with the instructions,
+    /// as open-vcdiff writes them with empty data and address sections.
+    pub interleaved: bool,
+    /// Offset of the data section within the window.
+    pub header_len: u64,
+    /// Length of the whole window.
+    pub len: u64,
+}
+
+impl WindowHeader {
+    /// Parse the window header at the start of `data`; the sections that
+    /// follow it are not read.
+    pub fn parse(data: &[u8], version: u8) -> Result<Self, String> {
+        let mut bytes = Bytes::new(data);
+        let indicator = bytes.byte("window indicator")?;
+        if indicator & !(VCD_SOURCE | VCD_TARGET | VCD_ADLER32) != 0 {
+            return Err(format!("unknown window indicator {:#04x}", indicator));
+        }
+        let source = match indicator & (VCD_SOURCE | VCD_TARGET) {
+            0 => None,
+            from if from == VCD_SOURCE | VCD_TARGET => return Err("window copies from both source and target".into()),
+            from => Some(Segment {
+                len: bytes.varint("source segment length")?,
+                pos: bytes.varint("source segment position")?,
+                from_target: from == VCD_TARGET,
+            }),
+        };
+        let delta_len = bytes.varint("delta encoding length")?;
+        let delta_start = bytes.pos as u64;
+        let target_len = bytes.varint("target window length")?;
+        let delta_indicator = bytes.byte("delta indicator")?;
+        if delta_indicator & !0x07 != 0 {
+            return Err(format!("unknown delta indicator {:#04x}", delta_indicator));
+        }
+        let data_len = bytes.varint("data section length")?;
+        let inst_len = bytes.varint("instructions section length")?;
+        let addr_len = bytes.varint("addresses section length")?;
+        let checksum = match (indicator & VCD_ADLER32, version) {
+            (0, _) => None,
+            (_, VERSION_SDCH) => {
+                let checksum = bytes.varint("checksum")?;
+                Some(u32::try_from(checksum).map_err(|_| format!("checksum {} does not fit Adler-32", checksum))?)
+            }
+            _ => Some(u32::from_be_bytes(bytes.take(4, "checksum")?.try_into().unwrap())),
+        };
+
+        let interleaved = version == VERSION_SDCH && data_len == 0 && addr_len == 0;
+
+        let header_len = bytes.pos as u64;
+        let sections = data_len.checked_add(inst_len).and_then(|n| n.checked_add(addr_len));
+        if sections.and_then(|n| n.checked_add(header_len - delta_start)) != Some(delta_len) {
+            return Err(format!(
+                "delta encoding length {} does not match its sections ({} + {} + {} bytes)",
+                delta_len, data_len, inst_len, addr_len
+            ));
+        }
+        let len = delta_start.checked_add(delta_len).ok_or("delta encoding length overflows")?;
+        Ok(Self { source, target_len, delta_indicator, data_len, inst_len, addr_len, checksum, interleaved, header_len, len })
+    }
+}
+
+/// Size of the output declared by the window headers.
+pub fn target_size(patch_data: &[u8]) -> Result<u64, BsdiffError> {
+    let mut target_size = 0u64;
+    for window in windows(patch_data)? {
+        let (header, _) = window?;
+        target_size = target_size
+            .checked_add(header.target_len)
+            .ok_or_else(|| corrupt("target size overflows".into()))?;
+    }
+    Ok(target_size)
+}
+
+/// Apply a VCDIFF delta to `old_data`, writing each target window to `writer`
+/// as it is decoded. Besides RFC 3284 deltas, this reads xdelta3's
+/// application header and checksums and open-vcdiff's interleaved format.
+///
+/// Windows copying from earlier output (`VCD_TARGET`), secondary compression
+/// and application-defined code tables are rejected as a `CorruptPatch`.
+/// Errors from `writer` are passed through.
+pub fn apply<W: Write>(patch_data: &[u8], old_data: &[u8], mut writer: W) -> Result<u64, BsdiffError> {
+    let header = FileHeader::parse(patch_data).map_err(corrupt)?;
+    if let Some(id) = header.secondary {
+        return Err(corrupt(format!("secondary compression (compressor id {}) is not supported", id)));
+    }
+    let version = header.version;
+
+    let mut target = Vec::new();
+    let mut written = 0u64;
+    for window in windows(patch_data)? {
+        let (header, window) = window?;
+        decode_window(&header, window, old_data, version, &mut target)?;
+        writer.write_all(&target)?;
+        written += target.len() as u64;
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+/// Iterate over the windows of a delta, each with its bytes.
+fn windows(patch_data: &[u8]) -> Result<impl Iterator<Item = Result<(WindowHeader, &[u8]), BsdiffError>>, BsdiffError> {
+    let header = FileHeader::parse(patch_data).map_err(corrupt)?;
+    let mut offset = usize::try_from(header.len)
+        .ok()
+        .filter(|&len| len <= patch_data.len())
+        .ok_or_else(|| corrupt("application header extends past the end of the patch".into()))?;
+    Ok(std::iter::from_fn(move || {
+        if offset == patch_data.len() {
+            return None;
+        }
+        let window = WindowHeader::parse(&patch_data[offset..], header.version)
+            .map_err(|reason| corrupt(format!("window at {}: {}", offset, reason)))
+            .and_then(|window| {
+                let data = usize::try_from(window.len)
+                    .ok()
+                    .and_then(|len| patch_data.get(offset..offset.checked_add(len)?))
+                    .ok_or_else(|| corrupt(format!("window at {} extends past the end of the patch", offset)))?;
+                Ok((window, data))
+            });
+        match &window {
+            Ok((_, data)) => offset += data.len(),
+            Err(_) => offset = patch_data.len(),
+        }
+        Some(window)
+    }))
+}
+
+/// Decode one window of a delta with the given `version` into `target`,
+/// replacing its contents.
+fn decode_window(header: &WindowHeader, window: &[u8], old_data: &[u8], version: u8, target: &mut Vec<u8>) -> Result<(), BsdiffError> {
+    if header.target_len > MAX_WINDOW_SIZE {
+        return Err(corrupt(format!("target window of {} bytes exceeds {} bytes", header.target_len, MAX_WINDOW_SIZE)));
+    }
+    if header.delta_indicator != 0 {
+        return Err(corrupt("secondary compression of window sections is not supported".into()));
+    }
+    let source = match header.source {
+        None => &[][..],
+        Some(Segment { from_target: true, .. }) => {
+            return Err(corrupt("windows copying from earlier output (VCD_TARGET) are not supported".into()));
+        }
+        Some(Segment { len, pos, .. }) => usize::try_from(pos)
+            .ok()
+            .zip(usize::try_from(len).ok())
+            .and_then(|(pos, len)| old_data.get(pos..pos.checked_add(len)?))
+            .ok_or_else(|| corrupt(format!("source segment of {} bytes at {} lies outside the old data", len, pos)))?,
+    };
+
+    let mut remaining = Bytes::new(&window[header.header_len as usize..]);
+    let mut sections = [
+        Bytes::new(remaining.take(header.data_len, "data section").map_err(corrupt)?),
+        Bytes::new(remaining.take(header.inst_len, "instructions section").map_err(corrupt)?),
+        Bytes::new(remaining.take(header.addr_len, "addresses section").map_err(corrupt)?),
+    ];
+    // Interleaved windows keep added data and addresses inline, each right
+    // after the instruction that uses it.
+    let (data, inst, addresses) = if header.interleaved { (1, 1, 1) } else { (0, 1, 2) };
+
+    target.clear();
+    target.reserve(header.target_len as usize);
+    let target_len = header.target_len as usize;
+    let mut cache = AddressCache::new();
+    while !sections[inst].is_empty() {
+        let codes = CODE_TABLE[sections[inst].byte("instruction").map_err(corrupt)? as usize];
+        for code in codes {
+            let size = match (code.inst, code.size) {
+                (Inst::Noop, _) => continue,
+                (_, 0) => sections[inst].varint("instruction size").map_err(corrupt)?,
+                (_, size) => size as u64,
+            };
+            let size = usize::try_from(size)
+                .ok()
+                .filter(|&size| size <= target_len - target.len())
+                .ok_or_else(|| corrupt(format!("instruction produces more than the {} byte target window", target_len)))?;
+            match code.inst {
+                Inst::Noop => {}
+                Inst::Add => target.extend_from_slice(sections[data].take(size as u64, "added data").map_err(corrupt)?),
+                Inst::Run => {
+                    let byte = sections[data].byte("run byte").map_err(corrupt)?;
+                    target.resize(target.len() + size, byte);
+                }
+                Inst::Copy(mode) => {
+                    let here = (source.len() + target.len()) as u64;
+                    let addr = cache.decode(mode, here, &mut sections[addresses]).map_err(corrupt)?;
+                    if addr >= here {
+                        return Err(corrupt(format!("copy address {} is not before {}", addr, here)));
+                    }
+                    copy_within(source, target, addr as usize, size);
+                }
+            }
+        }
+    }
+
+    if target.len() != target_len {
+        return Err(corrupt(format!("window produced {} bytes but declares {}", target.len(), target_len)));
+    }
+    if !sections[data].is_empty() || !sections[addresses].is_empty() {
+        return Err(corrupt("window has unused data or addresses".into()));
+    }
+    if let Some(expected) = header.checksum {
+        // open-vcdiff starts its Adler-32 from 0 rather than 1
+        let mut adler = match version {
+            VERSION_SDCH => adler2::Adler32::from_checksum(0),
+            _ => adler2::Adler32::new(),
+        };
+        adler.write_slice(target);
+        let actual = adler.checksum();
+        if actual != expected {
+            return Err(BsdiffError::ChecksumMismatch {
+                what: "VCDIFF window",
+                expected: format!("{:08x}", expected),
+                actual: format!("{:08x}", actual),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Append `len` bytes starting at `addr` in the combined address space of
+/// the source segment followed by the target window. A copy from the target
+/// may overlap the bytes it produces.
+fn copy_within(source: &[u8], target: &mut Vec<u8>, mut addr: usize, mut len: usize) {
+    if addr < source.len() {
+        let n = len.min(source.len() - addr);
+        target.extend_from_slice(&source[addr..addr + n]);
+        addr += n;
+        len -= n;
+    }
+    let mut start = addr.saturating_sub(source.len());
+    while len > 0 {
+        let n = len.min(target.len() - start);
+        target.extend_from_within(start..start + n);
+        start += n;
+        len -= n;
+    }
+}
+
+/// Encode the matches of a BSDIFF40 patch from qbsdiff as a VCDIFF delta.
+///
+/// Exact stretches of bsdiff's approximate matches become copies from the
+/// old data; everything else is added, or run-length encoded.
+pub fn encode(bsdiff40: &[u8], old_data: &[u8], new_data: &[u8], progress: &mut ProgressCounter<'_>) -> Result<Vec<u8>, BsdiffError> {
+    let header_len = utils::BSDIFF40_HEADER_LEN as usize;
+    let control_length = utils::decode_offtin(&bsdiff40[8..16]) as usize;
+    let mut control = BzDecoder::new(&bsdiff40[header_len..header_len + control_length]);
+
+    let mut encoder = Encoder::new(new_data);
+    let mut entry = [0u8; CONTROL_LEN];
+    let (mut old_pos, mut new_pos) = (0usize, 0usize);
+    while variant::read_entry(&mut control, &mut entry)? {
+        let add = utils::decode_offtin(&entry[0..8]) as usize;
+        let copy = utils::decode_offtin(&entry[8..16]) as usize;
+        let seek = utils::decode_offtin(&entry[16..24]);
+
+        let (old, new) = (&old_data[old_pos..old_pos + add], &new_data[new_pos..new_pos + add]);
+        let mut literal = 0;
+        let mut i = 0;
+        while i < add {
+            let matched = old[i..].iter().zip(&new[i..]).take_while(|(a, b)| a == b).count();
+            if matched >= MIN_COPY {
+                encoder.literal(new_pos + literal..new_pos + i);
+                encoder.push(Op::Copy { addr: (old_pos + i) as u64, len: matched as u64 });
+                literal = i + matched;
+            }
+            i += matched.max(1);
+        }
+        encoder.literal(new_pos + literal..new_pos + add + copy);
+
+        new_pos += add + copy;
+        old_pos = (old_pos + add).checked_add_signed(seek as isize).expect("qbsdiff seeks within the old data");
+        progress.advance((add + copy) as u64)?;
+    }
+    Ok(encoder.finish())
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    /// Bytes of the new data.
+    Add(std::ops::Range<usize>),
+    Run { byte: u8, len: u64 },
+    /// Bytes of the old data.
+    Copy { addr: u64, len: u64 },
+}
+
+impl Op {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Add(range) => range.len() as u64,
+            Self::Run { len, .. } | Self::Copy { len, .. } => *len,
+        }
+    }
+
+    /// Split off the first `n` bytes, leaving the rest in `self`.
+    fn split_front(&mut self, n: u64) -> Self {
+        match self {
+            Self::Add(range) => {
+                let front = range.start..range.start + n as usize;
+                range.start = front.end;
+                Self::Add(front)
+            }
+            Self::Run { byte, len } => {
+                *len -= n;
+                Self::Run { byte: *byte, len: n }
+            }
+            Self::Copy { addr, len } => {
+                let front = Self::Copy { addr: *addr, len: n };
+                *addr += n;
+                *len -= n;
+                front
+            }
+        }
+    }
+}
+
+/// Collects operations into target windows of `WINDOW_SIZE` bytes.
+struct Encoder<'a> {
+    new_data: &'a [u8],
+    out: Vec<u8>,
+    ops: Vec<Op>,
+    window_len: u64,
+}
+
+impl<'a> Encoder<'a> {
+    fn new(new_data: &'a [u8]) -> Self {
+        let mut out = VCDIFF_MAGIC.to_vec();
+        out.extend_from_slice(&[VERSION, 0]);
+        Self { new_data, out, ops: Vec::new(), window_len: 0 }
+    }
+
+    /// Add the bytes of the new data in `range`, run-length encoding
+    /// repeated bytes.
+    fn literal(&mut self, range: std::ops::Range<usize>) {
+        let data = &self.new_data[range.clone()];
+        let mut start = 0;
+        let mut i = 0;
+        while i < data.len() {
+            let run = data[i..].iter().take_while(|&&b| b == data[i]).count();
+            if run >= MIN_RUN {
+                self.push(Op::Add(range.start + start..range.start + i));
+                self.push(Op::Run { byte: data[i], len: run as u64 });
+                start = i + run;
+            }
+            i += run;
+        }
+        self.push(Op::Add(range.start + start..range.end));
+    }
+
+    fn push(&mut self, mut op: Op) {
+        while op.len() > 0 {
+            let room = WINDOW_SIZE - self.window_len;
+            let piece = if op.len() > room { op.split_front(room) } else { std::mem::replace(&mut op, Op::Add(0..0)) };
+            self.window_len += piece.len();
+            match (self.ops.last_mut(), piece) {
+                (Some(Op::Add(last)), Op::Add(range)) if last.end == range.start => last.end = range.end,
+                (Some(Op::Copy { addr, len }), Op::Copy { addr: next, len: n }) if *addr + *len == next => *len += n,
+                (_, piece) => self.ops.push(piece),
+            }
+            if self.window_len == WINDOW_SIZE {
+                self.flush();
+            }
+        }
+    }
+
+    /// Write the collected operations as one window.
+    fn flush(&mut self) {
+        let copies = self.ops.iter().filter_map(|op| match op {
+            Op::Copy { addr, len } => Some((*addr, *addr + *len)),
+            _ => None,
+        });
+        let segment = copies.reduce(|(lo, hi), (start, end)| (lo.min(start), hi.max(end)));
+        let (segment_pos, segment_len) = segment.map_or((0, 0), |(lo, hi)| (lo, hi - lo));
+
+        let (mut data, mut inst, mut addresses) = (Vec::new(), Vec::new(), Vec::new());
+        let mut cache = AddressCache::new();
+        let mut here = segment_len;
+        for op in self.ops.drain(..) {
+            let len = op.len();
+            match op {
+                Op::Add(range) => {
+                    match len {
+                        1..=17 => inst.push(1 + len as u8),
+                        _ => {
+                            inst.push(1);
+                            write_varint(&mut inst, len);
+                        }
+                    }
+                    data.extend_from_slice(&self.new_data[range]);
+                }
+                Op::Run { byte, len } => {
+                    inst.push(0);
+                    write_varint(&mut inst, len);
+                    data.push(byte);
+                }
+                Op::Copy { addr, len } => {
+                    let (mode, address) = cache.encode(addr - segment_pos, here);
+                    let base = 19 + 16 * mode;
+                    match len {
+                        4..=18 => inst.push(base + len as u8 - 3),
+                        _ => {
+                            inst.push(base);
+                            write_varint(&mut inst, len);
+                        }
+                    }
+                    match address {
+                        Address::Varint(value) => write_varint(&mut addresses, value),
+                        Address::Byte(byte) => addresses.push(byte),
+                    }
+                }
+            }
+            here += len;
+        }
+
+        let mut delta = Vec::new();
+        write_varint(&mut delta, self.window_len);
+        delta.push(0);
+        for section in [&data, &inst, &addresses] {
+            write_varint(&mut delta, section.len() as u64);
+        }
+        if segment.is_some() {
+            self.out.push(VCD_SOURCE);
+            write_varint(&mut self.out, segment_len);
+            write_varint(&mut self.out, segment_pos);
+        } else {
+            self.out.push(0);
+        }
+        write_varint(&mut self.out, (delta.len() + data.len() + inst.len() + addresses.len()) as u64);
+        for part in [delta, data, inst, addresses] {
+            self.out.extend_from_slice(&part);
+        }
+        self.window_len = 0;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.window_len > 0 {
+            self.flush();
+        }
+        self.out
+    }
+}
+
+/// RFC 3284's address cache, reset at the start of every window.
+struct AddressCache {
+    near: [u64; NEAR_SLOTS],
+    next_slot: usize,
+    same: [u64; SAME_SLOTS * 256],
+}
+
+/// An encoded copy address.
+enum Address {
+    Varint(u64),
+    Byte(u8),
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        Self { near: [0; NEAR_SLOTS], next_slot: 0, same: [0; SAME_SLOTS * 256] }
+    }
+
+    fn update(&mut self, addr: u64) {
+        self.near[self.next_slot] = addr;
+        self.next_slot = (self.next_slot + 1) % NEAR_SLOTS;
+        self.same[(addr % self.same.len() as u64) as usize] = addr;
+    }
+
+    /// Read the address of a copy in `mode` at position `here`.
+    fn decode(&mut self, mode: u8, here: u64, addresses: &mut Bytes) -> Result<u64, String> {
+        let mode = mode as usize;
+        let addr = match mode {
+            0 => addresses.varint("copy address")?,
+            1 => here.checked_sub(addresses.varint("copy address")?).ok_or("copy address before the start of the window")?,
+            _ if mode < 2 + NEAR_SLOTS => self.near[mode - 2]
+                .checked_add(addresses.varint("copy address")?)
+                .ok_or("copy address overflows")?,
+            _ => self.same[(mode - 2 - NEAR_SLOTS) * 256 + addresses.byte("copy address")? as usize],
+        };
+        self.update(addr);
+        Ok(addr)
+    }
+
+    /// Choose the mode that encodes `addr` at position `here` most compactly.
+    fn encode(&mut self, addr: u64, here: u64) -> (u8, Address) {
+        let slot = (addr % self.same.len() as u64) as usize;
+        let encoded = if self.same[slot] == addr {
+            ((2 + NEAR_SLOTS + slot / 256) as u8, Address::Byte(slot as u8))
+        } else {
+            let near = self.near.iter().enumerate().filter_map(|(i, &near)| Some((2 + i as u8, addr.checked_sub(near)?)));
+            let (mode, value) = [(0, addr), (1, here - addr)]
+                .into_iter()
+                .chain(near)
+                .min_by_key(|&(_, value)| value)
+                .unwrap();
+            (mode, Address::Varint(value))
+        };
+        self.update(addr);
+        encoded
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Inst {
+    Noop,
+    Add,
+    Run,
+    /// A copy in the given address mode.
+    Copy(u8),
+}
+
+/// One half of a code table entry. A size of zero is read from the
+/// instructions section.
+#[derive(Debug, Clone, Copy)]
+struct Code {
+    inst: Inst,
+    size: u8,
+}
+
+static CODE_TABLE: [[Code; 2]; 256] = default_code_table();
+
+/// The default code table of RFC 3284, section 5.6.
+const fn default_code_table() -> [[Code; 2]; 256] {
+    const NOOP: Code = Code { inst: Inst::Noop, size: 0 };
+    let mut table = [[NOOP; 2]; 256];
+    table[0][0] = Code { inst: Inst::Run, size: 0 };
+    let mut i = 1;
+    let mut size = 0;
+    while size <= 17 {
+        table[i][0] = Code { inst: Inst::Add, size };
+        i += 1;
+        size += 1;
+    }
+    let mut mode = 0;
+    while mode < 9 {
+        table[i][0] = Code { inst: Inst::Copy(mode), size: 0 };
+        i += 1;
+        size = 4;
+        while size <= 18 {
+            table[i][0] = Code { inst: Inst::Copy(mode), size };
+            i += 1;
+            size += 1;
+        }
+        mode += 1;
+    }
+    mode = 0;
+    while mode < 9 {
+        let mut add = 1;
+        while add <= 4 {
+            let (mut copy, last_copy) = if mode < 6 { (4, 6) } else { (4, 4) };
+            while copy <= last_copy {
+                table[i] = [Code { inst: Inst::Add, size: add }, Code { inst: Inst::Copy(mode), size: copy }];
+                i += 1;
+                copy += 1;
+            }
+            add += 1;
+        }
+        mode += 1;
+    }
+    mode = 0;
+    while mode < 9 {
+        table[i] = [Code { inst: Inst::Copy(mode), size: 4 }, Code { inst: Inst::Add, size: 1 }];
+        i += 1;
+        mode += 1;
+    }
+    table
+}
+
+/// Append `value` as an RFC 3284 integer.
+pub fn write_varint(out: &mut Vec<u8>, value: u64) {
+    let mut bytes = [0u8; 10];
+    let mut start = bytes.len() - 1;
+    bytes[start] = (value & 0x7F) as u8;
+    let mut rest = value >> 7;
+    while rest > 0 {
+        start -= 1;
+        bytes[start] = (rest & 0x7F) as u8 | 0x80;
+        rest >>= 7;
+    }
+    out.extend_from_slice(&bytes[start..]);
+}
+
+/// Reader over a section of a delta that reports running out of data as an
+/// error naming the field being read.
+struct Bytes<'p> {
+    data: &'p [u8],
+    pos: usize,
+}
+
+impl<'p> Bytes<'p> {
+    fn new(data: &'p [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn byte(&mut self, what: &str) -> Result<u8, String> {
+        let byte = *self.data.get(self.pos).ok_or_else(|| format!("truncated {}", what))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, len: u64, what: &str) -> Result<&'p [u8], String> {
+        let bytes = usize::try_from(len)
+            .ok()
+            .and_then(|len| self.data.get(self.pos..self.pos.checked_add(len)?))
+            .ok_or_else(|| format!("truncated {}", what))?;
+        self.pos += bytes.len();
+        Ok(bytes)
+    }
+
+    /// Read an RFC 3284 integer: base 128, most significant digit first.
+    fn varint(&mut self, what: &str) -> Result<u64, String> {
+        let mut value = 0u64;
+        for _ in 0..10 {
+            let byte = self.byte(what)?;
+            if value > u64::MAX >> 7 {
+                return Err(format!("{} overflows", what));
+            }
+            value = value << 7 | (byte & 0x7F) as u64;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(format!("{} overflows", what))
+    }
+}
+
+fn corrupt(reason: String) -> BsdiffError {
+    BsdiffError::CorruptPatch { reason }
+}
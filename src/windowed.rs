@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::bsdiff_rust::BsdiffError;
+
+/// Magic bytes that open a windowed patch.
+pub const MAGIC: &[u8; 8] = b"BSDIFFRW";
+
+/// Windowed format version written by this library.
+pub const VERSION: u8 = 1;
+
+/// Size of the windowed header in bytes.
+///
+/// Layout (integers little-endian):
+///
+/// | Offset | Size | Field                         |
+/// | ------ | ---- | ----------------------------- |
+/// | 0      | 8    | `MAGIC`                       |
+/// | 8      | 1    | version                       |
+/// | 9      | 7    | reserved, zero                |
+/// | 16     | 8    | old size                      |
+/// | 24     | 8    | new size                      |
+/// | 32     | 8    | window count                  |
+///
+/// A table of `ENTRY_LEN`-byte entries follows the header, one per window in
+/// output order, then the BSDIFF40 sub-patches in the same order.
+pub const HEADER_LEN: usize = 40;
+
+/// Size of a window table entry: old offset, old length, new length and
+/// sub-patch length, each a little-endian u64.
+pub const ENTRY_LEN: usize = 32;
+
+/// Smallest window size accepted by `DiffOptions::window_size`.
+pub const MIN_WINDOW_SIZE: u64 = 4096;
+
+/// Length of the content hashed at each anchor.
+const ANCHOR_LEN: usize = 32;
+
+/// One window of a windowed patch: `patch` turns
+/// `old[old_offset..old_offset + old_length]` into the next `new_length`
+/// bytes of the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window<'p> {
+    pub old_offset: u64,
+    pub old_length: u64,
+    pub new_length: u64,
+    pub patch: &'p [u8],
+}
+
+impl Window<'_> {
+    /// The region of `old_data` this window was diffed against.
+    pub fn old_region<'o>(&self, old_data: &'o [u8]) -> Result<&'o [u8], BsdiffError> {
+        usize::try_from(self.old_offset)
+            .ok()
+            .zip(usize::try_from(self.old_length).ok())
+            .and_then(|(start, len)| old_data.get(start..start.checked_add(len)?))
+            .ok_or_else(|| BsdiffError::CorruptPatch {
+                reason: format!(
+                    "window region {}+{} lies outside the old file",
+                    self.old_offset, self.old_length
+                ),
+            })
+    }
+}
+
+/// A parsed windowed patch.
+#[derive(Debug, Clone)]
+pub struct WindowedPatch<'p> {
+    pub old_size: u64,
+    pub new_size: u64,
+    pub windows: Vec<Window<'p>>,
+}
+
+impl<'p> WindowedPatch<'p> {
+    /// Parse a windowed patch, checking that its table is consistent with the
+    /// header and the sub-patches that follow it.
+    pub fn parse(data: &'p [u8]) -> Result<Self, BsdiffError> {
+        let corrupt = |reason: String| BsdiffError::CorruptPatch { reason };
+        if !is_windowed(data) {
+            return Err(corrupt("missing windowed magic".into()));
+        }
+        if data.len() < HEADER_LEN {
+            return Err(corrupt("truncated windowed header".into()));
+        }
+        if data[8] != VERSION {
+            return Err(corrupt(format!("unsupported windowed version {}", data[8])));
+        }
+
+        let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let (old_size, new_size, count) = (u64_at(16), u64_at(24), u64_at(32));
+        let table_len = count
+            .checked_mul(ENTRY_LEN as u64)
+            .filter(|&len| len <= (data.len() - HEADER_LEN) as u64)
+            .ok_or_else(|| corrupt(format!("window table of {} entries extends past the end of the patch", count)))?;
+
+        let mut body = &data[HEADER_LEN + table_len as usize..];
+        let mut windows = Vec::with_capacity(count as usize);
+        let mut produced = 0u64;
+        for entry in data[HEADER_LEN..HEADER_LEN + table_len as usize].chunks_exact(ENTRY_LEN) {
+            let field = |i: usize| u64::from_le_bytes(entry[i * 8..i * 8 + 8].try_into().unwrap());
+            let (old_offset, old_length, new_length, patch_length) = (field(0), field(1), field(2), field(3));
+            if old_offset.checked_add(old_length).is_none_or(|end| end > old_size) {
+                return Err(corrupt(format!(
+                    "window region {}+{} lies outside the old file of {} bytes",
+                    old_offset, old_length, old_size
+                )));
+            }
+            if patch_length > body.len() as u64 {
+                return Err(corrupt("window sub-patch extends past the end of the patch".into()));
+            }
+            produced = produced
+                .checked_add(new_length)
+                .ok_or_else(|| corrupt("window lengths overflow".into()))?;
+            let (patch, rest) = body.split_at(patch_length as usize);
+            windows.push(Window { old_offset, old_length, new_length, patch });
+            body = rest;
+        }
+        if produced != new_size {
+            return Err(corrupt(format!("windows produce {} bytes but the header declares {}", produced, new_size)));
+        }
+        if !body.is_empty() {
+            return Err(corrupt(format!("{} trailing bytes after the last window", body.len())));
+        }
+
+        Ok(Self { old_size, new_size, windows })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let patches_len: usize = self.windows.iter().map(|w| w.patch.len()).sum();
+        let mut data = Vec::with_capacity(HEADER_LEN + self.windows.len() * ENTRY_LEN + patches_len);
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.extend_from_slice(&[0; 7]);
+        for field in [self.old_size, self.new_size, self.windows.len() as u64] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        for window in &self.windows {
+            for field in [window.old_offset, window.old_length, window.new_length, window.patch.len() as u64] {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        for window in &self.windows {
+            data.extend_from_slice(window.patch);
+        }
+        data
+    }
+
+    /// Fail with `CorruptPatch` unless `old_data` has the size this patch was made for.
+    pub fn check_old(&self, old_data: &[u8]) -> Result<(), BsdiffError> {
+        if old_data.len() as u64 != self.old_size {
+            return Err(BsdiffError::CorruptPatch {
+                reason: format!(
+                    "patch was made for an old file of {} bytes, got {}",
+                    self.old_size,
+                    old_data.len()
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether `patch_data` starts with the windowed magic.
+pub fn is_windowed(patch_data: &[u8]) -> bool {
+    patch_data.starts_with(MAGIC)
+}
+
+/// Longest old region a window of `window_size` bytes is diffed against.
+pub fn region_len(window_size: u64) -> u64 {
+    window_size.saturating_mul(2)
+}
+
+/// Picks the region of the old file to diff each window of the new file against.
+///
+/// Content-defined anchors are found in both files with a rolling hash, so data
+/// that moved by any distance still lines up. Each window is matched with the
+/// old region its anchors point to most often, widened by half a window on
+/// either side.
+pub struct RegionMatcher {
+    old_len: u64,
+    new_len: u64,
+    window_size: u64,
+    /// Anchor hash to its first position in the old file.
+    old_anchors: HashMap<u64, u64>,
+    /// Anchors of the new file as (position, hash), in position order.
+    new_anchors: Vec<(u64, u64)>,
+}
+
+impl RegionMatcher {
+    pub fn new(old_data: &[u8], new_data: &[u8], window_size: u64) -> Self {
+        // Aim for a few hundred anchors per window, spaced 256 B to 64 KiB apart
+        let spacing = (window_size / 256).clamp(256, 64 * 1024).next_power_of_two();
+        let mut old_anchors = HashMap::new();
+        for (pos, hash) in anchors(old_data, spacing) {
+            old_anchors.entry(hash).or_insert(pos);
+        }
+        Self {
+            old_len: old_data.len() as u64,
+            new_len: new_data.len() as u64,
+            window_size,
+            old_anchors,
+            new_anchors: anchors(new_data, spacing),
+        }
+    }
+
+    /// Region of the old file to diff `new[start..start + len]` against.
+    pub fn region(&self, start: u64, len: u64) -> Range<usize> {
+        let region_len = region_len(self.window_size).min(self.old_len);
+
+        let first = self.new_anchors.partition_point(|&(pos, _)| pos < start);
+        let last = self.new_anchors.partition_point(|&(pos, _)| pos < start + len);
+        let mut votes: HashMap<i64, u32> = HashMap::new();
+        for &(pos, hash) in &self.new_anchors[first..last] {
+            if let Some(&old_pos) = self.old_anchors.get(&hash) {
+                *votes.entry(old_pos as i64 - pos as i64).or_default() += 1;
+            }
+        }
+        // Without a match, assume the files line up proportionally
+        let center = match votes.into_iter().max_by_key(|&(shift, count)| (count, std::cmp::Reverse(shift.unsigned_abs()))) {
+            Some((shift, _)) => (start + len / 2) as i64 + shift,
+            None if self.new_len > 0 => ((start + len / 2) as u128 * self.old_len as u128 / self.new_len as u128) as i64,
+            None => 0,
+        };
+
+        let region_start = (center - (region_len / 2) as i64).clamp(0, (self.old_len - region_len) as i64) as usize;
+        region_start..region_start + region_len as usize
+    }
+}
+
+/// Content-defined anchors of `data` as (position, hash of the `ANCHOR_LEN`
+/// bytes at that position), about `spacing` bytes apart on average.
+fn anchors(data: &[u8], spacing: u64) -> Vec<(u64, u64)> {
+    const BASE: u64 = 0x100000001B3;
+    if data.len() < ANCHOR_LEN {
+        return Vec::new();
+    }
+    let base_pow = (1..ANCHOR_LEN).fold(1u64, |acc, _| acc.wrapping_mul(BASE));
+    let bits = spacing.trailing_zeros();
+
+    let mut anchors = Vec::new();
+    let mut hash = data[..ANCHOR_LEN].iter().fold(0u64, |h, &b| h.wrapping_mul(BASE).wrapping_add(b as u64));
+    for pos in 0..=data.len() - ANCHOR_LEN {
+        if pos > 0 {
+            let (outgoing, incoming) = (data[pos - 1] as u64, data[pos + ANCHOR_LEN - 1] as u64);
+            hash = hash.wrapping_sub(outgoing.wrapping_mul(base_pow)).wrapping_mul(BASE).wrapping_add(incoming);
+        }
+        // Mix before testing so the anchor condition depends on every byte
+        if hash.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - bits) == 0 {
+            anchors.push((pos as u64, hash));
+        }
+    }
+    anchors
+}
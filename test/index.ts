@@ -1,5 +1,7 @@
 import path from 'path'
 import fs from 'fs'
+import { createHash } from 'crypto'
+import { Writable } from 'stream'
 import { strict as assert } from 'assert'
 import {
   diff,
@@ -8,17 +10,53 @@ import {
   patchSync,
   verifyPatch,
   verifyPatchSync,
+  verifyPatchDigest,
+  verifyPatchDigestSync,
+  DigestAlgorithm,
   diffWithStatsSync,
   patchWithStatsSync,
+  diffWithOptions,
   diffWithOptionsSync,
+  diffWithReverse,
+  diffWithReverseSync,
+  diffWithOptionsAndStatsSync,
   getPatchInfoSync,
   getFileSizeSync,
   checkFileAccessSync,
   getCompressionRatioSync,
+  diffBuffers,
+  diffBuffersSync,
+  patchBuffers,
+  patchBuffersSync,
+  patchStreamingSync,
+  patchToWritable,
+  patchWithOptionsSync,
+  patchWithStats,
+  applyChain,
+  applyChainSync,
+  composePatches,
+  composePatchesSync,
+  reversePatch,
+  reversePatchSync,
+  diffDirectory,
+  diffDirectorySync,
+  patchDirectory,
+  patchDirectorySync,
+  setMaxConcurrentDiffs,
+  setMaxThreads,
+  ErrorCode,
+  PatchFormat,
+  BlockCompression,
+  PatchCompression,
+  OutputFormat,
+  ExecutableFilter,
+  type ErrorDetailsJs,
   type PatchInfoJs,
   type CompressionRatioJs,
   type PerformanceStatsJs,
   type DiffOptionsJs,
+  type PatchOptionsJs,
+  type ProgressJs,
 } from '../index'
 
 describe('bsdiff (rust)', function () {
@@ -158,6 +196,32 @@ describe('bsdiff (rust)', function () {
 
       console.log(`📦 补丁信息: 大小=${(info.size / 1024).toFixed(2)} KB, 压缩=${info.compressed}`)
     })
+
+    it('should describe the BSDIFF40 header and blocks', () => {
+      diffSync(oldFile, newFile, patchFile)
+
+      const info: PatchInfoJs = getPatchInfoSync(patchFile)
+      assert.strictEqual(info.format, PatchFormat.Bsdiff40)
+      assert.strictEqual(info.targetSize, fs.statSync(newFile).size)
+      assert.deepStrictEqual(
+        info.blocks.map((b) => b.name),
+        ['control', 'diff', 'extra'],
+      )
+      assert.ok(info.blocks.every((b) => b.compression === BlockCompression.Bzip2))
+      assert.strictEqual(32 + info.blocks.reduce((sum, b) => sum + b.length, 0), info.size)
+      assert.deepStrictEqual(info.issues, [])
+    })
+
+    it('should report other formats and inconsistent headers', () => {
+      fs.writeFileSync(patchFile, Buffer.from([0xd6, 0xc3, 0xc4, 0x00, 0x00]))
+      assert.strictEqual(getPatchInfoSync(patchFile).format, PatchFormat.Vcdiff)
+
+      // Truncated BSDIFF40 header
+      fs.writeFileSync(patchFile, Buffer.from('BSDIFF40\x01\x02'))
+      const info = getPatchInfoSync(patchFile)
+      assert.strictEqual(info.format, PatchFormat.Bsdiff40)
+      assert.ok(info.issues.length > 0, 'Expected the truncated header to be reported')
+    })
   })
 
   describe('Compression ratio calculation', () => {
@@ -268,6 +332,610 @@ describe('bsdiff (rust)', function () {
     })
   })
 
+  describe('In-memory buffers', () => {
+    it('should round-trip patches between buffers without touching the file system', async function () {
+      this.timeout(10000)
+
+      const oldContent = fs.readFileSync(oldFile)
+      const newContent = fs.readFileSync(newFile)
+
+      const patchBuf = diffBuffersSync(oldContent, newContent)
+      assert.strictEqual(patchBuf.subarray(0, 8).toString(), 'BSDIFF40', 'Expected a BSDIFF40 patch buffer')
+      assert.ok(patchBuffersSync(oldContent, patchBuf).equals(newContent), 'Patched buffer should match original')
+
+      const patchAsync = await diffBuffers(oldContent, newContent, { compressionLevel: 9 })
+      const generated = await patchBuffers(oldContent, patchAsync)
+      assert.ok(generated.equals(newContent), 'Patched buffer should match original')
+
+      console.log(`diffBuffers: patch=${(patchAsync.length / 1024).toFixed(2)} KB`)
+    })
+  })
+
+  describe('Container patches', () => {
+    it('should record checksums and reject the wrong base file', () => {
+      const oldBuf = Buffer.from('Container: the old release artifact.'.repeat(32))
+      const newBuf = Buffer.from('Container: the new release artifact, with changes.'.repeat(32))
+
+      const patchBuf = diffBuffersSync(oldBuf, newBuf, { container: true })
+      assert.strictEqual(patchBuf.subarray(0, 8).toString(), 'BSDIFFRC')
+      assert.ok(patchBuffersSync(oldBuf, patchBuf).equals(newBuf))
+
+      assert.throws(
+        () => patchBuffersSync(newBuf, patchBuf),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.ChecksumMismatch && !!error.expected && !!error.actual,
+      )
+
+      // Headerless patches keep working
+      assert.ok(patchBuffersSync(oldBuf, diffBuffersSync(oldBuf, newBuf)).equals(newBuf))
+    })
+  })
+
+  describe('Compression backends', () => {
+    it('should write and auto-detect every compression', () => {
+      const oldBuf = Buffer.from('Compression: the old release artifact.'.repeat(512))
+      const newBuf = Buffer.from('Compression: the new release artifact, recompressed.'.repeat(512))
+
+      for (const compression of Object.values(PatchCompression)) {
+        const patchBuf = diffBuffersSync(oldBuf, newBuf, { compression })
+        assert.ok(patchBuffersSync(oldBuf, patchBuf).equals(newBuf), `${compression} patch should apply`)
+
+        fs.writeFileSync(patchFile, patchBuf)
+        const info = getPatchInfoSync(patchFile)
+        assert.deepStrictEqual(info.issues, [])
+        if (compression === PatchCompression.Bzip2) {
+          assert.strictEqual(info.format, PatchFormat.Bsdiff40)
+        } else {
+          assert.strictEqual(info.format, PatchFormat.Variant)
+          assert.deepStrictEqual(info.blocks.map((b) => b.compression), Array(3).fill(compression))
+        }
+      }
+    })
+  })
+
+  describe('ENDSLEY/BSDIFF43 patches', () => {
+    const vectorDir = path.join(resDir, 'bsdiff43')
+    const cases = ['empty', 'identical', 'text', 'binary', 'grow', 'reorder']
+
+    it('should apply reference patches and write ones the reference accepts', () => {
+      for (const name of cases) {
+        const read = (suffix: string) => fs.readFileSync(path.join(vectorDir, `${name}.${suffix}`))
+        const [oldBuf, newBuf] = [read('old'), read('new')]
+        assert.ok(patchBuffersSync(oldBuf, read('ref.patch')).equals(newBuf), `${name} reference patch should apply`)
+
+        const patchBuf = diffBuffersSync(oldBuf, newBuf, { format: OutputFormat.Endsley, enableParallel: false, compressionLevel: 9 })
+        assert.ok(patchBuf.subarray(0, 24).equals(read('lib.patch').subarray(0, 24)), `${name} header should match`)
+        assert.ok(patchBuffersSync(oldBuf, patchBuf).equals(newBuf))
+
+        fs.writeFileSync(patchFile, patchBuf)
+        assert.strictEqual(getPatchInfoSync(patchFile).format, PatchFormat.Endsley)
+      }
+    })
+
+    it('should reject other compressions', () => {
+      assert.throws(
+        () => diffBuffersSync(Buffer.from('old'), Buffer.from('new'), { format: OutputFormat.Endsley, compression: PatchCompression.Zstd }),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.InvalidOption && error.option === 'compression',
+      )
+    })
+  })
+
+  describe('VCDIFF deltas', () => {
+    it('should write and auto-detect VCDIFF', () => {
+      const oldBuf = Buffer.from('VCDIFF: the old release artifact. '.repeat(256))
+      const newBuf = Buffer.concat([oldBuf.subarray(0, 4000), Buffer.from('delta'), oldBuf.subarray(4500)])
+
+      const patchBuf = diffBuffersSync(oldBuf, newBuf, { format: OutputFormat.Vcdiff })
+      assert.ok(patchBuf.subarray(0, 4).equals(Buffer.from([0xd6, 0xc3, 0xc4, 0x00])))
+      assert.ok(patchBuffersSync(oldBuf, patchBuf).equals(newBuf))
+
+      fs.writeFileSync(patchFile, patchBuf)
+      const info = getPatchInfoSync(patchFile)
+      assert.strictEqual(info.format, PatchFormat.Vcdiff)
+      assert.strictEqual(info.targetSize, newBuf.length)
+      assert.deepStrictEqual(info.blocks.map((b) => b.name), ['data', 'instructions', 'addresses'])
+    })
+  })
+
+  describe('Patch chains', () => {
+    const chainFile = (name: string) => path.join(resDir, `chain-${name}`)
+    const v1 = Buffer.from('Release one of the chained artifact. '.repeat(400))
+    const v2 = Buffer.concat([v1.subarray(0, 5000), Buffer.from('second release'), v1.subarray(5000)])
+    const v3 = Buffer.concat([v2.subarray(2000), Buffer.from('third release')])
+
+    before(() => {
+      fs.writeFileSync(chainFile('v1'), v1)
+      fs.writeFileSync(chainFile('1-2.patch'), diffBuffersSync(v1, v2))
+      fs.writeFileSync(chainFile('2-3.patch'), diffBuffersSync(v2, v3))
+    })
+
+    after(() => {
+      for (const name of ['v1', 'v3', '1-2.patch', '2-3.patch', '1-3.patch']) {
+        if (fs.existsSync(chainFile(name))) fs.unlinkSync(chainFile(name))
+      }
+    })
+
+    it('should apply a chain without intermediate files', async () => {
+      const chain = [chainFile('1-2.patch'), chainFile('2-3.patch')]
+      const stats = applyChainSync(chainFile('v1'), chain, chainFile('v3'))
+      assert.ok(fs.readFileSync(chainFile('v3')).equals(v3))
+      assert.strictEqual(stats.newSize, v3.length)
+
+      fs.unlinkSync(chainFile('v3'))
+      await applyChain(chainFile('v1'), chain, chainFile('v3'), { expectedTargetSize: v3.length })
+      assert.ok(fs.readFileSync(chainFile('v3')).equals(v3))
+
+      assert.throws(
+        () => applyChainSync(chainFile('v1'), [], chainFile('v3')),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.InvalidOption && error.option === 'patches',
+      )
+    })
+
+    it('should compose a chain into one patch', async () => {
+      const chain = [chainFile('1-2.patch'), chainFile('2-3.patch')]
+      composePatchesSync(chainFile('v1'), chain, chainFile('1-3.patch'))
+      assert.ok(patchBuffersSync(v1, fs.readFileSync(chainFile('1-3.patch'))).equals(v3))
+
+      const stats = await composePatches(chainFile('v1'), chain, chainFile('1-3.patch'), { format: OutputFormat.Endsley })
+      const composed = fs.readFileSync(chainFile('1-3.patch'))
+      assert.strictEqual(composed.subarray(0, 16).toString(), 'ENDSLEY/BSDIFF43')
+      assert.strictEqual(stats.patchSize, composed.length)
+      assert.ok(patchBuffersSync(v1, composed).equals(v3))
+
+      assert.throws(
+        () => composePatchesSync(chainFile('v1'), chain, chainFile('1-3.patch'), undefined, { maxTargetSize: 16 }),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.LimitExceeded && error.limitName === 'maxTargetSize',
+      )
+    })
+  })
+
+  describe('Rollback patches', () => {
+    const rollbackFile = path.join(resDir, 'react-rollback.patch')
+    const derivedFile = path.join(resDir, 'react-derived.patch')
+
+    after(() => {
+      for (const file of [rollbackFile, derivedFile]) {
+        if (fs.existsSync(file)) fs.unlinkSync(file)
+      }
+    })
+
+    it('should write the reverse patch alongside the forward one', () => {
+      const stats = diffWithReverseSync(oldFile, newFile, patchFile, rollbackFile)
+      assert.strictEqual(stats.patchSize, fs.statSync(patchFile).size)
+      const oldBuf = fs.readFileSync(oldFile)
+      const newBuf = fs.readFileSync(newFile)
+      assert.ok(patchBuffersSync(oldBuf, fs.readFileSync(patchFile)).equals(newBuf))
+      assert.ok(patchBuffersSync(newBuf, fs.readFileSync(rollbackFile)).equals(oldBuf))
+
+      assert.throws(
+        () => diffWithReverseSync(oldFile, newFile, patchFile, patchFile),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.InvalidOption && error.option === 'reversePatch',
+      )
+    })
+
+    it('should write the reverse patch asynchronously', async () => {
+      await diffWithReverse(oldFile, newFile, patchFile, rollbackFile, { compression: 'zstd' })
+      const newBuf = fs.readFileSync(newFile)
+      assert.ok(patchBuffersSync(newBuf, fs.readFileSync(rollbackFile)).equals(fs.readFileSync(oldFile)))
+    })
+
+    it('should derive the rollback patch from the old file and the forward patch', async () => {
+      diffSync(oldFile, newFile, patchFile)
+      const stats = reversePatchSync(oldFile, patchFile, derivedFile)
+      assert.strictEqual(stats.oldSize, fs.statSync(newFile).size)
+      assert.strictEqual(stats.newSize, fs.statSync(oldFile).size)
+
+      await reversePatch(oldFile, patchFile, derivedFile, { container: true })
+      const oldBuf = fs.readFileSync(oldFile)
+      assert.ok(patchBuffersSync(fs.readFileSync(newFile), fs.readFileSync(derivedFile)).equals(oldBuf))
+
+      assert.throws(
+        () => reversePatchSync(oldFile, patchFile, derivedFile, null, { maxTargetSize: 16 }),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.LimitExceeded && error.limitName === 'maxTargetSize',
+      )
+    })
+  })
+
+  describe('Directory patches', () => {
+    const treeDir = path.join(resDir, 'tree')
+    const treeFile = (name: string) => path.join(treeDir, name)
+    const write = (file: string, data: string | Buffer) => {
+      fs.mkdirSync(path.dirname(file), { recursive: true })
+      fs.writeFileSync(file, data)
+    }
+
+    before(() => {
+      fs.rmSync(treeDir, { recursive: true, force: true })
+      write(treeFile('old/app.zip'), fs.readFileSync(oldFile))
+      write(treeFile('old/readme.txt'), 'version 1\n')
+      write(treeFile('old/lib/core.js'), 'module.exports = 1\n')
+      write(treeFile('old/obsolete.txt'), 'gone\n')
+      write(treeFile('new/app.zip'), fs.readFileSync(newFile))
+      write(treeFile('new/readme.txt'), 'version 2\n')
+      write(treeFile('new/src/core.js'), 'module.exports = 1\n')
+      write(treeFile('new/assets/logo.svg'), '<svg/>\n')
+    })
+
+    after(() => {
+      fs.rmSync(treeDir, { recursive: true, force: true })
+    })
+
+    const assertSameTree = (expected: string, actual: string) => {
+      const list = (dir: string) => (fs.readdirSync(dir, { recursive: true }) as string[]).sort()
+      assert.deepStrictEqual(list(actual), list(expected))
+      for (const name of list(expected)) {
+        const file = path.join(expected, name)
+        if (fs.statSync(file).isFile()) {
+          assert.ok(fs.readFileSync(path.join(actual, name)).equals(fs.readFileSync(file)), name)
+        }
+      }
+    }
+
+    it('should rebuild the new tree from the old one', async () => {
+      const stats = diffDirectorySync(treeFile('old'), treeFile('new'), treeFile('update.patch'))
+      assert.strictEqual(stats.patchSize, fs.statSync(treeFile('update.patch')).size)
+      const info = getPatchInfoSync(treeFile('update.patch'))
+      assert.strictEqual(info.format, PatchFormat.Directory)
+      assert.ok(info.blocks.every((block) => block.name === 'file'))
+
+      patchDirectorySync(treeFile('old'), treeFile('out'), treeFile('update.patch'))
+      assertSameTree(treeFile('new'), treeFile('out'))
+
+      const progress: ProgressJs[] = []
+      await diffDirectory(treeFile('old'), treeFile('new'), treeFile('update.patch'), { compression: PatchCompression.Zstd })
+      await patchDirectory(treeFile('old'), treeFile('out-async'), treeFile('update.patch'), null, (p) => progress.push(p))
+      assertSameTree(treeFile('new'), treeFile('out-async'))
+      assert.ok(progress.length > 0)
+    })
+
+    it('should refuse a non-empty output or a different old tree', () => {
+      diffDirectorySync(treeFile('old'), treeFile('new'), treeFile('update.patch'))
+      write(treeFile('busy/keep.txt'), 'keep\n')
+      assert.throws(
+        () => patchDirectorySync(treeFile('old'), treeFile('busy'), treeFile('update.patch')),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.IoError,
+      )
+
+      assert.throws(
+        () => patchDirectorySync(treeFile('new'), treeFile('wrong'), treeFile('update.patch')),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.ChecksumMismatch,
+      )
+      assert.ok(!fs.existsSync(treeFile('wrong')))
+    })
+  })
+
+  describe('Archive patches', () => {
+    const archivePatch = path.join(resDir, 'archive.patch')
+
+    after(() => {
+      fs.rmSync(archivePatch, { force: true })
+    })
+
+    it('should diff ZIP entries uncompressed and rebuild the new archive', () => {
+      const stats = diffWithOptionsAndStatsSync(oldFile, newFile, archivePatch, { archive: true })
+      const raw = diffBuffersSync(fs.readFileSync(oldFile), fs.readFileSync(newFile))
+      assert.ok(stats.patchSize <= raw.length)
+      assert.strictEqual(getPatchInfoSync(archivePatch).targetSize, fs.statSync(newFile).size)
+
+      const patched = patchBuffersSync(fs.readFileSync(oldFile), fs.readFileSync(archivePatch))
+      assert.ok(patched.equals(fs.readFileSync(newFile)))
+    })
+
+    it('should write an ordinary patch for other files', () => {
+      const oldBuf = Buffer.from('Archive: not a ZIP file.'.repeat(32))
+      const newBuf = Buffer.from('Archive: still not a ZIP file.'.repeat(32))
+
+      const patchBuf = diffBuffersSync(oldBuf, newBuf, { archive: true })
+      assert.strictEqual(patchBuf.subarray(0, 8).toString(), 'BSDIFF40')
+      assert.ok(patchBuffersSync(oldBuf, patchBuf).equals(newBuf))
+    })
+  })
+
+  describe('Executable filters', () => {
+    // Calls into a table of 509 stubs, shifted by code inserted in front of them
+    const code = (prologue: number) => {
+      const data = Buffer.alloc(prologue + 8 * 4096, 0x90)
+      for (let k = 0, seed = 1, pos = prologue; k < 4096; k++, pos += 8) {
+        seed = (seed * 48271) % 2147483647
+        data[pos] = 0xe8
+        data.writeInt32LE(-(pos + 5) + (seed % 509) * 16, pos + 1)
+      }
+      return data
+    }
+
+    it('should convert branch targets and restore them when patching', () => {
+      const [oldBuf, newBuf] = [code(0), code(48)]
+      const patchBuf = diffBuffersSync(oldBuf, newBuf, { filter: ExecutableFilter.X86 })
+      assert.strictEqual(patchBuf.subarray(0, 8).toString(), 'BSDIFFRF')
+      assert.ok(patchBuffersSync(oldBuf, patchBuf).equals(newBuf))
+      assert.ok(patchBuf.length < diffBuffersSync(oldBuf, newBuf).length)
+    })
+
+    it('should leave other files alone with auto', () => {
+      const [oldBuf, newBuf] = [code(0), code(48)]
+      const patchBuf = diffBuffersSync(oldBuf, newBuf, { filter: ExecutableFilter.Auto })
+      assert.strictEqual(patchBuf.subarray(0, 8).toString(), 'BSDIFF40')
+
+      assert.throws(
+        () => diffBuffersSync(oldBuf, newBuf, { filter: ExecutableFilter.Auto, archive: true }),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.InvalidOption && error.option === 'filter',
+      )
+    })
+  })
+
+  describe('Windowed patches', () => {
+    it('should diff in windows and patch transparently', () => {
+      const oldBuf = Buffer.alloc(256 * 1024)
+      for (let i = 0; i < oldBuf.length; i++) oldBuf[i] = (i * 2654435761) >>> 24
+      const newBuf = Buffer.concat([oldBuf.subarray(0, 10000), Buffer.from('inserted'.repeat(512)), oldBuf.subarray(10000)])
+
+      const patchBuf = diffBuffersSync(oldBuf, newBuf, { windowSize: 32 * 1024 })
+      assert.strictEqual(patchBuf.subarray(0, 8).toString(), 'BSDIFFRW')
+      assert.ok(patchBuffersSync(oldBuf, patchBuf).equals(newBuf))
+
+      assert.throws(
+        () => diffBuffersSync(oldBuf, newBuf, { windowSize: 1 }),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.InvalidOption && error.option === 'windowSize',
+      )
+    })
+  })
+
+  describe('Patch limits', () => {
+    it('should enforce limits from PatchOptionsJs before writing', () => {
+      diffSync(oldFile, newFile, patchFile)
+      const newSize = fs.statSync(newFile).size
+      if (fs.existsSync(generatedFile)) fs.unlinkSync(generatedFile)
+
+      assert.throws(
+        () => patchWithOptionsSync(oldFile, generatedFile, patchFile, { maxTargetSize: newSize - 1 }),
+        (error: Error & ErrorDetailsJs) =>
+          error.code === ErrorCode.LimitExceeded && error.limitName === 'maxTargetSize' && error.size === newSize,
+      )
+      assert.ok(!fs.existsSync(generatedFile), 'Rejected patch should not create output')
+
+      assert.throws(
+        () => patchStreamingSync(oldFile, generatedFile, patchFile, { maxMemory: 1024 }),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.LimitExceeded && error.limitName === 'maxMemory',
+      )
+
+      assert.throws(
+        () => patchWithOptionsSync(oldFile, generatedFile, patchFile, { expectedTargetSize: newSize + 1 }),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.SizeMismatch && error.expectedSize === newSize + 1,
+      )
+
+      patchWithOptionsSync(oldFile, generatedFile, patchFile, { maxTargetSize: newSize, expectedTargetSize: newSize })
+      assert.ok(fs.readFileSync(generatedFile).equals(fs.readFileSync(newFile)))
+
+      const invalid: [PatchOptionsJs, string][] = [
+        [{ maxTargetSize: -1 }, 'maxTargetSize'],
+        [{ maxMemory: Number.NaN }, 'maxMemory'],
+        [{ expectedTargetSize: newSize + 0.5 }, 'expectedTargetSize'],
+      ]
+      for (const [options, option] of invalid) {
+        assert.throws(
+          () => patchStreamingSync(oldFile, generatedFile, patchFile, options),
+          (error: Error & ErrorDetailsJs) => error.code === ErrorCode.InvalidOption && error.option === option,
+        )
+      }
+    })
+
+    it('should reject forged header sizes', () => {
+      const oldBuf = Buffer.from('Hostile: the old release artifact.'.repeat(32))
+      const patchBuf = diffBuffersSync(oldBuf, Buffer.from('Hostile: the new release artifact.'.repeat(32)))
+
+      const huge = Buffer.from(patchBuf)
+      huge.writeBigUInt64LE(BigInt(Number.MAX_SAFE_INTEGER), 24)
+      assert.throws(
+        () => patchBuffersSync(oldBuf, huge, { maxTargetSize: 1 << 20 }),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.LimitExceeded,
+      )
+
+      const negative = Buffer.from(patchBuf)
+      negative[15] |= 0x80
+      assert.throws(
+        () => patchBuffersSync(oldBuf, negative),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.CorruptPatch,
+      )
+    })
+  })
+
+  describe('Tuning options', () => {
+    it('should produce applicable patches and reject invalid settings', () => {
+      const oldBuf = Buffer.from('Tuning: the old release artifact.'.repeat(1024))
+      const newBuf = Buffer.from('Tuning: the new release artifact, retuned.'.repeat(1024))
+
+      const settings: DiffOptionsJs[] = [
+        { smallMatch: 0 },
+        { smallMatch: 32, bufferSize: 128 },
+        { parallelChunkSize: 256 * 1024 },
+        { parallelJobs: 2, compressionLevel: 1 },
+      ]
+      for (const options of settings) {
+        assert.ok(patchBuffersSync(oldBuf, diffBuffersSync(oldBuf, newBuf, options)).equals(newBuf))
+      }
+
+      const invalid: [DiffOptionsJs, string][] = [
+        [{ compressionLevel: 0 }, 'compressionLevel'],
+        [{ bufferSize: 64 }, 'bufferSize'],
+        [{ parallelChunkSize: 1024 }, 'parallelChunkSize'],
+        [{ parallelJobs: 2, parallelChunkSize: 256 * 1024 }, 'parallelJobs'],
+        [{ parallelJobs: 2, enableParallel: false }, 'parallelJobs'],
+        [{ windowSize: -1 }, 'windowSize'],
+        [{ threads: 1.5 }, 'threads'],
+        [{ parallelChunkSize: Number.POSITIVE_INFINITY }, 'parallelChunkSize'],
+      ]
+      for (const [options, option] of invalid) {
+        assert.throws(
+          () => diffBuffersSync(oldBuf, newBuf, options),
+          (error: Error & ErrorDetailsJs) => error.code === ErrorCode.InvalidOption && error.option === option,
+        )
+      }
+    })
+  })
+
+  describe('Threads and concurrency', () => {
+    afterEach(() => {
+      setMaxThreads(0)
+      setMaxConcurrentDiffs(0)
+    })
+
+    it('should diff with per-call and process-wide thread limits', async () => {
+      const oldBuf = Buffer.from('Threads: the old release artifact.'.repeat(4096))
+      const newBuf = Buffer.from('Threads: the new release artifact, rebuilt.'.repeat(4096))
+
+      assert.ok(patchBuffersSync(oldBuf, diffBuffersSync(oldBuf, newBuf, { threads: 2 })).equals(newBuf))
+
+      setMaxThreads(1)
+      setMaxConcurrentDiffs(1)
+      const patches = await Promise.all([1, 2, 3].map(() => diffBuffers(oldBuf, newBuf, { threads: 4 })))
+      for (const patchBuf of patches) {
+        assert.ok(patchBuffersSync(oldBuf, patchBuf).equals(newBuf))
+      }
+
+      assert.throws(
+        () => diffBuffersSync(oldBuf, newBuf, { threads: 0 }),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.InvalidOption && error.option === 'threads',
+      )
+    })
+  })
+
+  describe('Memory-mapped inputs', () => {
+    it('should diff and patch mapped files and report mapped bytes', () => {
+      const oldSize = fs.statSync(oldFile).size
+      const newSize = fs.statSync(newFile).size
+
+      const diffStats = diffWithOptionsAndStatsSync(oldFile, newFile, patchFile, { mmap: true })
+      assert.strictEqual(diffStats.mappedMemory, oldSize + newSize)
+      assert.strictEqual(diffStats.heapMemory + diffStats.mappedMemory, diffStats.estimatedPeakMemory)
+
+      const patchStats = patchStreamingSync(oldFile, generatedFile, patchFile, { mmap: true })
+      assert.strictEqual(patchStats.mappedMemory, oldSize + fs.statSync(patchFile).size)
+      assert.ok(fs.readFileSync(generatedFile).equals(fs.readFileSync(newFile)))
+
+      const heapStats = patchStreamingSync(oldFile, generatedFile, patchFile)
+      assert.strictEqual(heapStats.mappedMemory, 0)
+      assert.strictEqual(heapStats.heapMemory, heapStats.estimatedPeakMemory)
+    })
+  })
+
+  describe('Progress callbacks', () => {
+    it('should report phases and byte counts for diff and patch', async function () {
+      this.timeout(10000)
+
+      const diffEvents: ProgressJs[] = []
+      await diff(oldFile, newFile, patchFile, (p) => diffEvents.push(p))
+
+      const diffPhases = new Set(diffEvents.map((p) => p.phase))
+      assert.deepStrictEqual([...diffPhases], ['reading', 'diffing', 'writing'], 'Expected diff phases in order')
+      const lastRead = diffEvents.filter((p) => p.phase === 'reading').pop()!
+      assert.strictEqual(lastRead.bytesProcessed, fs.statSync(oldFile).size + fs.statSync(newFile).size)
+
+      const patchEvents: ProgressJs[] = []
+      const stats = await patchWithStats(oldFile, generatedFile, patchFile, (p) => patchEvents.push(p))
+      const patching = patchEvents.filter((p) => p.phase === 'patching')
+      assert.ok(patching.length > 0, 'Expected patching progress')
+      assert.strictEqual(patching[patching.length - 1].bytesProcessed, stats.newSize)
+
+      console.log(`progress: ${diffEvents.length} diff events, ${patchEvents.length} patch events`)
+    })
+
+    it('should report encoding when the patch is converted to another format', async function () {
+      this.timeout(10000)
+
+      const events: ProgressJs[] = []
+      await diffWithOptions(oldFile, newFile, patchFile, { format: 'endsley' }, (p) => events.push(p))
+      const encoding = events.filter((p) => p.phase === 'encoding')
+      assert.ok(encoding.length >= 2, 'Expected encoding progress')
+      assert.strictEqual(encoding[0].bytesProcessed, 0)
+      assert.strictEqual(encoding[encoding.length - 1].bytesProcessed, fs.statSync(newFile).size)
+    })
+  })
+
+  describe('Cancellation', () => {
+    it('should reject with an AbortError and leave no partial output', async function () {
+      this.timeout(10000)
+
+      diffSync(oldFile, newFile, patchFile)
+
+      const controller = new AbortController()
+      await assert.rejects(
+        patchWithStats(
+          oldFile,
+          generatedFile,
+          patchFile,
+          (p) => {
+            if (p.phase === 'writing') controller.abort()
+          },
+          controller.signal,
+        ),
+        (error: Error) => error.name === 'AbortError',
+        'Expected the patch to reject with an AbortError',
+      )
+      assert.ok(!fs.existsSync(generatedFile), 'Cancelled patch should not leave partial output')
+    })
+
+    it('should reject tasks aborted before they start', async () => {
+      const controller = new AbortController()
+      const pending = diff(oldFile, newFile, patchFile, null, controller.signal)
+      controller.abort()
+      await assert.rejects(pending, (error: Error) => error.name === 'AbortError')
+    })
+  })
+
+  describe('Streaming patch', () => {
+    it('should stream the patched output to disk', function () {
+      this.timeout(10000)
+
+      diffSync(oldFile, newFile, patchFile)
+      const stats: PerformanceStatsJs = patchStreamingSync(oldFile, generatedFile, patchFile)
+
+      assert.ok(fs.readFileSync(newFile).equals(fs.readFileSync(generatedFile)), 'Patched file should match original')
+      assert.ok(stats.estimatedPeakMemory < stats.oldSize + stats.patchSize + stats.newSize, 'Expected output not to be buffered')
+
+      console.log(`patchStreamingSync: peak memory ${(stats.estimatedPeakMemory / 1024 / 1024).toFixed(2)} MB`)
+    })
+
+    it('should stream the patched output to a Writable in bounded chunks', async function () {
+      this.timeout(10000)
+
+      diffSync(oldFile, newFile, patchFile)
+
+      const chunks: Buffer[] = []
+      const writable = new Writable({
+        write(chunk: Buffer, _encoding, callback) {
+          chunks.push(chunk)
+          setImmediate(callback)
+        },
+      })
+      const stats = await patchToWritable(oldFile, patchFile, writable)
+      writable.end()
+
+      assert.ok(Buffer.concat(chunks).equals(fs.readFileSync(newFile)), 'Streamed output should match original')
+      assert.ok(Math.max(...chunks.map((c) => c.length)) <= 128 * 1024, 'Expected chunks of at most 128 KB')
+      assert.strictEqual(stats.newSize, fs.statSync(newFile).size)
+    })
+  })
+
+  describe('Digest verification', () => {
+    it('should verify patch output against an expected digest without the new file', async function () {
+      this.timeout(10000)
+
+      diffSync(oldFile, newFile, patchFile)
+      const newBuf = fs.readFileSync(newFile)
+      const sha256 = createHash('sha256').update(newBuf).digest('hex')
+
+      const result = verifyPatchDigestSync(oldFile, patchFile, sha256, newBuf.length)
+      assert.ok(result.valid)
+      assert.strictEqual(result.bytesProduced, newBuf.length)
+      assert.strictEqual(result.actualDigest, sha256)
+
+      const mismatch = await verifyPatchDigest(oldFile, patchFile, sha256, newBuf.length, DigestAlgorithm.Blake3)
+      assert.strictEqual(mismatch.valid, false)
+      assert.strictEqual(mismatch.hashMatched, false)
+      assert.strictEqual(mismatch.sizeMatched, true)
+      assert.ok(!fs.existsSync(generatedFile), 'Verification should not write the output')
+    })
+  })
+
   describe('Patch verification - failure path', () => {
     it('should return false when patch does not match the target file', function () {
       this.timeout(10000)
@@ -305,6 +973,36 @@ describe('bsdiff (rust)', function () {
         'Expected error message to contain "Patch file not found"',
       )
     })
+
+    it('should expose stable error codes and fields', async () => {
+      const missing = path.join(resDir, 'non-existent-old.zip')
+      assert.throws(
+        () => diffSync(missing, newFile, patchFile),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.NotFound && error.path === missing,
+      )
+
+      await assert.rejects(
+        patch(missing, generatedFile, patchFile),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.NotFound && error.path === missing,
+      )
+
+      assert.throws(
+        () => patchBuffersSync(Buffer.from('old'), Buffer.from('not a patch')),
+        (error: Error & ErrorDetailsJs) => error.code === ErrorCode.CorruptPatch,
+      )
+    })
+
+    it('should tag cancellations with ERR_CANCELLED', async function () {
+      this.timeout(10000)
+
+      diffSync(oldFile, newFile, patchFile)
+
+      const controller = new AbortController()
+      await assert.rejects(
+        patchWithStats(oldFile, generatedFile, patchFile, () => controller.abort(), controller.signal),
+        (error: Error & ErrorDetailsJs) => error.name === 'AbortError' && error.code === ErrorCode.Cancelled,
+      )
+    })
   })
 
   describe('API compatibility', () => {
@@ -0,0 +1,61 @@
+#!/usr/bin/env python3
+"""Inputs for the ENDSLEY/BSDIFF43 golden vectors.
+
+Writes `<case>.old` and `<case>.new` for each case. The patches are built by
+`reference.rs` and the library; see README.md.
+
+    python3 generate.py
+"""
+
+import os
+import random
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+
+def cases():
+    rng = random.Random(43)
+    text = (
+        b"The quick brown fox jumps over the lazy dog.\n"
+        b"Pack my box with five dozen liquor jugs.\n"
+        b"How vexingly quick daft zebras jump!\n"
+    ) * 20
+    edited = (
+        text.replace(b"lazy dog", b"sleepy cat")
+        .replace(b"five dozen", b"six dozen")
+        .replace(b"How vexingly", b"Very vexingly")
+    )
+
+    binary = bytes(rng.randrange(256) for _ in range(4096))
+    mutated = bytearray(binary)
+    for i in range(0, len(mutated), 97):
+        mutated[i] = (mutated[i] + 1) & 0xFF
+    mutated = bytes(mutated[:1024]) + bytes(rng.randrange(256) for _ in range(200)) + bytes(mutated[1500:])
+
+    grow_old = bytes(rng.randrange(256) for _ in range(512))
+    grow_new = grow_old[:256] + bytes(range(256)) * 8 + grow_old[256:]
+
+    # Blocks moved around, so the controls seek backwards through the old data
+    blocks = [bytes(rng.randrange(256) for _ in range(300)) for _ in range(4)]
+    tail = bytes(rng.randrange(256) for _ in range(20))
+    reorder_old = b"".join(blocks)
+    reorder_new = blocks[2] + blocks[0] + b"inserted" + blocks[3] + blocks[1] + tail
+
+    yield "empty", b"", b""
+    yield "identical", text, text
+    yield "text", text, edited
+    yield "binary", binary, mutated
+    yield "grow", grow_old, grow_new
+    yield "reorder", reorder_old, reorder_new
+
+
+def generate():
+    for name, old, new in cases():
+        for suffix, data in (("old", old), ("new", new)):
+            with open(os.path.join(HERE, "%s.%s" % (name, suffix)), "wb") as f:
+                f.write(data)
+        print("%-10s %6d -> %6d bytes" % (name, len(old), len(new)))
+
+
+if __name__ == "__main__":
+    generate()
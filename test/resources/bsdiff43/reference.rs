@@ -0,0 +1,104 @@
+//! Reference encoder and patcher for the ENDSLEY/BSDIFF43 golden vectors.
+//!
+//! Builds against the `bsdiff` crate, a port of mendsley/bsdiff's `bsdiff.c`
+//! and `bspatch.c`, and frames its stream the way mendsley's command line
+//! tools do: the magic, the new size, then the stream compressed by libbz2 at
+//! block size 9. See README.md for the exact versions and how to run it.
+//!
+//!     bsdiff43-reference diff <dir>    # write <case>.ref.patch for every <case>.old
+//!     bsdiff43-reference check <dir>   # apply every <case>.*.patch, compare with <case>.new
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::{env, fs, process};
+
+use bzip2::read::BzDecoder;
+use bzip2::write::BzEncoder;
+use bzip2::Compression;
+
+const MAGIC: &[u8; 16] = b"ENDSLEY/BSDIFF43";
+
+fn offtout(value: i64) -> [u8; 8] {
+    let raw = value.unsigned_abs() | if value < 0 { 1 << 63 } else { 0 };
+    raw.to_le_bytes()
+}
+
+fn encode(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut stream = Vec::new();
+    bsdiff::diff(old, new, &mut stream).unwrap();
+    let mut patch = MAGIC.to_vec();
+    patch.extend_from_slice(&offtout(new.len() as i64));
+    let mut encoder = BzEncoder::new(patch, Compression::new(9));
+    encoder.write_all(&stream).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn apply(old: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < 24 || &patch[..16] != MAGIC {
+        return Err("bad header".into());
+    }
+    let raw = u64::from_le_bytes(patch[16..24].try_into().unwrap());
+    if raw >> 63 != 0 {
+        return Err("negative new size".into());
+    }
+    let mut stream = Vec::new();
+    BzDecoder::new(&patch[24..]).read_to_end(&mut stream).map_err(|e| e.to_string())?;
+    let mut new = Vec::new();
+    bsdiff::patch(old, &mut stream.as_slice(), &mut new).map_err(|e| e.to_string())?;
+    if new.len() as u64 != raw {
+        return Err(format!("produced {} bytes, header says {}", new.len(), raw));
+    }
+    Ok(new)
+}
+
+fn cases(dir: &Path) -> Vec<String> {
+    let mut cases = fs::read_dir(dir).unwrap()
+        .filter_map(|entry| entry.unwrap().file_name().to_str()?.strip_suffix(".old").map(str::to_owned))
+        .collect::<Vec<_>>();
+    cases.sort();
+    cases
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let (command, dir) = match &args[1..] {
+        [command, dir] => (command.as_str(), Path::new(dir)),
+        _ => {
+            eprintln!("usage: bsdiff43-reference diff|check <dir>");
+            process::exit(2);
+        }
+    };
+    let read = |case: &str, suffix: &str| fs::read(dir.join(format!("{}.{}", case, suffix)));
+
+    let mut ok = true;
+    for case in cases(dir) {
+        let (old, new) = (read(&case, "old").unwrap(), read(&case, "new").unwrap());
+        match command {
+            "diff" => {
+                let patch = encode(&old, &new);
+                assert_eq!(apply(&old, &patch).as_deref(), Ok(&new[..]), "{}", case);
+                fs::write(dir.join(format!("{}.ref.patch", case)), &patch).unwrap();
+                println!("{:<10} {:>6} -> {:>6} bytes, patch {} bytes", case, old.len(), new.len(), patch.len());
+            }
+            "check" => {
+                for suffix in ["ref.patch", "lib.patch"] {
+                    let result = match read(&case, suffix) {
+                        Ok(patch) => match apply(&old, &patch) {
+                            Ok(result) if result == new => "ok".to_owned(),
+                            Ok(_) => "MISMATCH".to_owned(),
+                            Err(e) => format!("error: {}", e),
+                        },
+                        Err(e) => format!("error: {}", e),
+                    };
+                    println!("{:<10} {:<9} {}", case, suffix, result);
+                    ok &= result == "ok";
+                }
+            }
+            _ => {
+                eprintln!("unknown command {}", command);
+                process::exit(2);
+            }
+        }
+    }
+    process::exit(if ok { 0 } else { 1 });
+}
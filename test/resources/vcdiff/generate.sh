@@ -0,0 +1,18 @@
+#!/bin/sh
+# Write VCDIFF deltas for the ENDSLEY input pairs with xdelta3 and
+# open-vcdiff; see README.md for the tools.
+set -eu
+
+XDELTA3=${XDELTA3:-xdelta3}
+OPEN_VCDIFF=${OPEN_VCDIFF:-open-vcdiff}
+out=$(cd "$(dirname "$0")" && pwd)
+cd "$out/../bsdiff43"
+
+for case in empty identical text binary grow reorder; do
+    "$XDELTA3" -e -f -s "$case.old" "$case.new" "$out/$case.xdelta3.vcdiff"
+    "$OPEN_VCDIFF" "$case.old" "$case.new" "$out/$case.open-vcdiff.vcdiff"
+    "$OPEN_VCDIFF" "$case.old" "$case.new" "$out/$case.open-vcdiff-interleaved.vcdiff" interleaved checksum
+done
+
+# Secondary compression, which the library rejects
+"$XDELTA3" -e -f -S djw -s text.old text.new "$out/text.xdelta3-djw.vcdiff"
@@ -0,0 +1,44 @@
+// Encode with open-vcdiff's VCDiffEncoder:
+//
+//   open-vcdiff <old> <new> <delta> [interleaved] [checksum]
+//
+// The flags add VCD_FORMAT_INTERLEAVED and VCD_FORMAT_CHECKSUM to the
+// default VCD_STANDARD_FORMAT.
+#include <cstring>
+#include <fstream>
+#include <iostream>
+#include <sstream>
+#include <string>
+
+#include "google/vcencoder.h"
+
+static std::string read_file(const char *path) {
+  std::ifstream in(path, std::ios::binary);
+  std::stringstream contents;
+  contents << in.rdbuf();
+  return contents.str();
+}
+
+int main(int argc, char **argv) {
+  if (argc < 4) {
+    std::cerr << "usage: open-vcdiff <old> <new> <delta> [interleaved] [checksum]\n";
+    return 2;
+  }
+  std::string old_data = read_file(argv[1]);
+  std::string new_data = read_file(argv[2]);
+  open_vcdiff::VCDiffFormatExtensionFlags flags = open_vcdiff::VCD_STANDARD_FORMAT;
+  for (int i = 4; i < argc; i++) {
+    if (!strcmp(argv[i], "interleaved")) flags |= open_vcdiff::VCD_FORMAT_INTERLEAVED;
+    if (!strcmp(argv[i], "checksum")) flags |= open_vcdiff::VCD_FORMAT_CHECKSUM;
+  }
+
+  open_vcdiff::VCDiffEncoder encoder(old_data.data(), old_data.size());
+  encoder.SetFormatFlags(flags);
+  std::string delta;
+  if (!encoder.Encode(new_data.data(), new_data.size(), &delta)) {
+    std::cerr << "encoding failed\n";
+    return 1;
+  }
+  std::ofstream(argv[3], std::ios::binary) << delta;
+  return 0;
+}